@@ -0,0 +1,246 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An engine for programs that match nothing but an alternation of literals.
+
+use aho_corasick::{Automaton, AcAutomaton, FullAcAutomaton};
+use {Engine, SearchConfig};
+use prefix::Prefix;
+use program::{Instructions, Program};
+use serialize::{write_bytes, write_u8, write_usize};
+use std::cmp::max;
+use std::io::{self, Write};
+
+/// An engine that searches for a set of literals using Aho-Corasick, without running a DFA.
+///
+/// It finds the same matches as the other engines would for a program matching exactly those
+/// literals: the leftmost one, and the shortest among those starting there.
+#[derive(Clone, Debug)]
+pub struct AcEngine {
+    ac: FullAcAutomaton<Vec<u8>>,
+    max_len: usize,
+}
+
+impl AcEngine {
+    /// Creates an engine that matches any of the given (non-empty) strings.
+    ///
+    /// For `SearchConfig::leftmost_first`, earlier strings are preferred over later ones.
+    pub fn new<S: AsRef<[u8]>, I: IntoIterator<Item=S>>(strings: I) -> AcEngine {
+        let strings: Vec<Vec<u8>> = strings.into_iter().map(|s| s.as_ref().to_vec()).collect();
+        assert!(strings.iter().all(|s| !s.is_empty()));
+        let max_len = strings.iter().map(|s| s.len()).max().unwrap_or(0);
+        AcEngine {
+            ac: FullAcAutomaton::new(AcAutomaton::new(strings)),
+            max_len,
+        }
+    }
+
+    /// If `prog` matches exactly an alternation of literals, returns an engine for them.
+    ///
+    /// This recognizes the programs for which `Prefix::from_program` returns an `Exact` prefix,
+    /// as long as the program can't go on from the end of a literal. (The prefix only needs the
+    /// shortest matches, so if the program matches "ca" and "cab" then its prefix only looks for
+    /// "ca". But this engine also has to find the longer matches, for `SearchConfig::anchored_end`
+    /// and `SearchConfig::leftmost_first`.)
+    pub fn from_program<I: Instructions>(prog: &Program<I>) -> Option<AcEngine> {
+        let strings = match Prefix::from_program(prog) {
+            Prefix::Exact(inner) => exact_strings(&inner)?,
+            _ => return None,
+        };
+        let start = prog.init.state_at_pos(&[], 0)?;
+        let ends_there = |lit: &[u8]| {
+            let end = lit.iter().try_fold(start, |st, &b| prog.step_byte(st, b).0);
+            end.is_some_and(|st| (0..=255u8).all(|b| prog.step_byte(st, b).0.is_none()))
+        };
+        if strings.iter().all(|lit| ends_there(lit)) {
+            Some(AcEngine::new(strings))
+        } else {
+            None
+        }
+    }
+
+    pub fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        let (start, end) = config.bounds(input)?;
+        let from = if config.anchored_end { max(start, end.saturating_sub(self.max_len)) } else { start };
+
+        // Overlapping matches come in order of their end positions, so once they end far enough
+        // to the right, nothing can start before the best one we've found.
+        let mut best: Option<(usize, usize)> = None;
+        let mut best_pat = 0;
+        for mat in self.ac.find_overlapping(&input[from..end]) {
+            let (s, e) = (from + mat.start, from + mat.end);
+            if best.is_some_and(|b| e >= b.0 + self.max_len)
+                    || (config.anchored_start && e > start + self.max_len) {
+                break;
+            }
+            if (config.anchored_start && s != start) || (config.anchored_end && e != end) {
+                continue;
+            }
+            let preferred = config.leftmost_first && mat.pati < best_pat
+                && best.is_some_and(|b| s == b.0);
+            if best.is_none_or(|b| s < b.0) || preferred {
+                best = Some((s, e));
+                best_pat = mat.pati;
+                if config.earliest {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+// Returns the strings that an (exact) prefix matches.
+fn exact_strings(prefix: &Prefix) -> Option<Vec<Vec<u8>>> {
+    match *prefix {
+        Prefix::Byte(b) => Some(vec![vec![b]]),
+        Prefix::Byte2(b1, b2) => Some(vec![vec![b1], vec![b2]]),
+        Prefix::Byte3(b1, b2, b3) => Some(vec![vec![b1], vec![b2], vec![b3]]),
+        Prefix::ByteSet(ref bs) => Some(bs.iter().map(|b| vec![b]).collect()),
+        Prefix::Lit(ref lit) | Prefix::LitRare(ref lit, _) => Some(vec![lit.clone()]),
+        Prefix::Ac(ref ac, _, _) => Some(ac.patterns().to_vec()),
+        _ => None,
+    }
+}
+
+impl Engine for AcEngine {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        AcEngine::search(self, input, config)
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(self.clone())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.ac.heap_bytes()
+    }
+
+    fn write_engine(&self, mut w: &mut dyn Write) -> io::Result<()> {
+        write_u8(&mut w, 3)?;
+        write_usize(&mut w, self.ac.patterns().len())?;
+        for pat in self.ac.patterns() {
+            write_bytes(&mut w, pat)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ac::AcEngine;
+    use backtracking::BacktrackingEngine;
+    use prefix::Prefix;
+    use program::{Program, TableInsts};
+    use test_util::{lit_prog, lits_prog, table_prog};
+    use {Engine, SearchConfig};
+
+    #[test]
+    fn test_from_program() {
+        assert!(AcEngine::from_program(&lits_prog(&["ab", "cde"])).is_some());
+        assert!(AcEngine::from_program(&lit_prog("abc")).is_some());
+        assert!(AcEngine::from_program(&lits_prog(&["a", "b"])).is_some());
+        // "a*b" isn't a finite set of literals.
+        assert!(AcEngine::from_program(&table_prog(3, &[(1, b'a', 1), (1, b'b', 2)], &[2]))
+                .is_none());
+        // "ca|cab" and "cc|ccc" go on after the end of a literal.
+        let ca_cab = table_prog(5, &[(1, b'c', 2), (2, b'a', 3), (3, b'b', 4)], &[3, 4]);
+        assert!(AcEngine::from_program(&ca_cab).is_none());
+        let cc_ccc = table_prog(5, &[(1, b'c', 2), (2, b'c', 3), (3, b'c', 4)], &[3, 4]);
+        assert!(AcEngine::from_program(&cc_ccc).is_none());
+        let bt = BacktrackingEngine::new(cc_ccc, Prefix::Empty);
+        let to_end = SearchConfig { anchored_end: true, ..SearchConfig::default() };
+        assert_eq!(bt.search(b"ccc", &to_end), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_search() {
+        let lits = ["abcde", "bc", "cd", "xyz"];
+        let prog = lits_prog(&lits);
+        let ac = AcEngine::from_program(&prog).unwrap();
+        let bt = BacktrackingEngine::new(prog.clone(), Prefix::Empty);
+        let configs = [
+            SearchConfig::default(),
+            SearchConfig { anchored_start: true, start: 1, ..SearchConfig::default() },
+            SearchConfig { anchored_end: true, ..SearchConfig::default() },
+            SearchConfig { anchored_end: true, end: Some(4), ..SearchConfig::default() },
+            SearchConfig { leftmost_first: true, ..SearchConfig::default() },
+            SearchConfig { leftmost_first: true, anchored_end: true, ..SearchConfig::default() },
+            // These are out of range for some of the inputs, so there is no match.
+            SearchConfig { start: 5, ..SearchConfig::default() },
+            SearchConfig { start: 3, end: Some(2), ..SearchConfig::default() },
+        ];
+        for input in &["abcdex", "xbcd", "abcd", "xyxyz", "", "bcbc"] {
+            for config in &configs {
+                assert_eq!(ac.search(input.as_bytes(), config),
+                           bt.search(input.as_bytes(), config), "{} {:?}", input, config);
+            }
+        }
+        assert_eq!(ac.search(b"abcde", &SearchConfig { end: Some(6), ..SearchConfig::default() }),
+                   None);
+        assert_eq!(ac.shortest_match("xxabcdex"), Some((2, 7)));
+
+        let earliest = SearchConfig { earliest: true, ..SearchConfig::default() };
+        assert_eq!(ac.search(b"abcde", &earliest), Some((1, 3)));
+
+        let ac = AcEngine::new(["ab", "a", "abc"]);
+        let lf = SearchConfig { leftmost_first: true, ..SearchConfig::default() };
+        assert_eq!(ac.search(b"xabc", &lf), Some((1, 3)));
+        assert_eq!(ac.search(b"xabc", &SearchConfig::default()), Some((1, 2)));
+    }
+    #[test]
+    fn test_tries() {
+        // Builds a program for some literals that share prefixes.
+        fn trie_prog(lits: &[&[u8]]) -> Program<TableInsts> {
+            let mut states = vec![Vec::new()];
+            let (mut edges, mut accept) = (Vec::new(), Vec::new());
+            for lit in lits {
+                let mut st = 1;
+                for i in 0..lit.len() {
+                    st = match states.iter().position(|s| s[..] == lit[..=i]) {
+                        Some(t) => t + 1,
+                        None => {
+                            states.push(lit[..=i].to_vec());
+                            edges.push((st, lit[i], states.len()));
+                            states.len()
+                        },
+                    };
+                }
+                accept.push(st);
+            }
+            table_prog(states.len() + 1, &edges, &accept)
+        }
+
+        let lits: [&[u8]; 6] = [b"a", b"ab", b"ba", b"abc", b"cc", b"ccc"];
+        let default = SearchConfig::default();
+        let configs = [
+            default,
+            SearchConfig { anchored_start: true, ..default },
+            SearchConfig { anchored_end: true, ..default },
+            SearchConfig { leftmost_first: true, ..default },
+        ];
+        let inputs = ["abc", "cccc", "xabab", "bac", "ccabcc", "a", ""];
+        for subset in 1..(1 << lits.len()) {
+            let chosen: Vec<&[u8]> =
+                (0..lits.len()).filter(|i| subset & (1 << i) != 0).map(|i| lits[i]).collect();
+            let prog = trie_prog(&chosen);
+            let ac = match AcEngine::from_program(&prog) {
+                Some(ac) => ac,
+                None => continue,
+            };
+            let bt = BacktrackingEngine::new(prog, Prefix::Empty);
+            for input in &inputs {
+                for config in &configs {
+                    assert_eq!(ac.search(input.as_bytes(), config),
+                               bt.search(input.as_bytes(), config),
+                               "{:?} on {} with {:?}", chosen, input, config);
+                }
+            }
+        }
+    }
+}
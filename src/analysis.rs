@@ -0,0 +1,294 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Questions about the set of strings that a program matches.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use program::{Instructions, Program};
+use std::collections::VecDeque;
+
+impl<Insts: Instructions> Program<Insts> {
+    /// Returns true if this program can never find a match, in any input.
+    ///
+    /// That is, none of the states that can be reached from a start state are accepting (either
+    /// before another byte or at the end of the input).
+    pub fn is_empty_language(&self) -> bool {
+        let reachable = self.reachable(self.init.states());
+        (0..self.num_states())
+            .all(|s| !reachable[s] || (self.accept(s).is_none() && self.check_eoi(s).is_none()))
+    }
+
+    /// Returns true if this program matches the whole of every input.
+    ///
+    /// That is, the complement of the program's language is empty: every state that can be
+    /// reached from the start of the input has a transition on every byte, and accepts (with
+    /// nothing to look back over) at the end of the input.
+    pub fn is_universal(&self) -> bool {
+        let start = match self.init.state_at_pos(&[], 0) {
+            Some(s) => s,
+            None => return false,
+        };
+        let reachable = self.reachable(vec![start]);
+        (0..self.num_states()).filter(|&s| reachable[s]).all(|s| {
+            self.ends_at_eoi(s)
+                && (0..=255u8).all(|b| self.step_byte(s, b).0.is_some())
+        })
+    }
+
+    /// Counts the strings of length `n` that this program matches in full (that is, the ones on
+    /// which it starts at the beginning and accepts at the end, as in `Program::sample`).
+    ///
+    /// The count saturates at `u128::MAX`, which is one less than the number of strings of length
+    /// 16.
+    pub fn count_matches_of_len(&self, n: usize) -> u128 {
+        let mut counts = vec![0u128; self.num_states()];
+        match self.init.state_at_pos(&[], 0) {
+            Some(s) => counts[s] = 1,
+            None => return 0,
+        }
+        for _ in 0..n {
+            let mut next = vec![0u128; self.num_states()];
+            for (s, &c) in counts.iter().enumerate().filter(|&(_, &c)| c > 0) {
+                for b in 0..=255u8 {
+                    if let Some(t) = self.step_byte(s, b).0 {
+                        next[t] = next[t].saturating_add(c);
+                    }
+                }
+            }
+            counts = next;
+        }
+        counts.iter().enumerate()
+            .filter(|&(s, _)| self.ends_at_eoi(s))
+            .fold(0u128, |acc, (_, &c)| acc.saturating_add(c))
+    }
+
+    /// Returns an iterator over the strings of length at most `max_len` that this program matches
+    /// in full, stopping after `limit` of them.
+    ///
+    /// The strings come out in lexicographic order (so "a" comes before "aa", which comes before
+    /// "b"). Parts of the program that can't reach an accepting state in time are skipped, so
+    /// it doesn't take long to find each string.
+    pub fn enumerate(&self, max_len: usize, limit: usize) -> Strings<'_, Insts> {
+        let dist = self.distances_to_accept();
+        let stack = match self.init.state_at_pos(&[], 0) {
+            Some(s) if dist[s] <= max_len => vec![(s, 0)],
+            _ => Vec::new(),
+        };
+        Strings {
+            prog: self,
+            dist,
+            max_len,
+            limit,
+            stack,
+            cur: Vec::new(),
+            entered: true,
+        }
+    }
+
+    /// Returns true if there is some string that takes `state` to a state that accepts at the end
+    /// of the input.
+    ///
+    /// This looks at the whole program, so to ask about many states, call `distances_to_accept`
+    /// once instead.
+    pub fn can_continue(&self, state: usize) -> bool {
+        self.distances_to_accept()[state] != usize::MAX
+    }
+
+    /// Returns true if `input` is the beginning of some string that this program matches in full.
+    pub fn matches_prefix(&self, input: &[u8]) -> bool {
+        let mut state = match self.init.state_at_pos(input, 0) {
+            Some(s) => s,
+            None => return false,
+        };
+        for &b in input {
+            match self.step_byte(state, b).0 {
+                Some(s) => state = s,
+                None => return false,
+            }
+        }
+        self.can_continue(state)
+    }
+
+    /// For each state, returns the length of the shortest string that takes it to a state that
+    /// accepts at the end of the input (or `usize::MAX` if there isn't one).
+    pub fn distances_to_accept(&self) -> Vec<usize> {
+        let n = self.num_states();
+        let mut preds = vec![Vec::new(); n];
+        for s in 0..n {
+            for b in 0..=255u8 {
+                if let Some(t) = self.step_byte(s, b).0 {
+                    preds[t].push(s);
+                }
+            }
+        }
+
+        let mut dist = vec![usize::MAX; n];
+        let mut queue = VecDeque::new();
+        for (s, d) in dist.iter_mut().enumerate() {
+            if self.ends_at_eoi(s) {
+                *d = 0;
+                queue.push_back(s);
+            }
+        }
+        while let Some(t) = queue.pop_front() {
+            for &s in &preds[t] {
+                if dist[s] == usize::MAX {
+                    dist[s] = dist[t] + 1;
+                    queue.push_back(s);
+                }
+            }
+        }
+        dist
+    }
+
+    // Marks the states that can be reached from `starts`.
+    fn reachable(&self, starts: Vec<usize>) -> Vec<bool> {
+        let mut ret = vec![false; self.num_states()];
+        for &s in &starts {
+            ret[s] = true;
+        }
+        let mut stack = starts;
+        while let Some(s) = stack.pop() {
+            for b in 0..=255u8 {
+                if let Some(t) = self.step_byte(s, b).0 {
+                    if !ret[t] {
+                        ret[t] = true;
+                        stack.push(t);
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// The iterator returned by `Program::enumerate`.
+#[derive(Clone, Debug)]
+pub struct Strings<'a, Insts: Instructions + 'a> {
+    prog: &'a Program<Insts>,
+    dist: Vec<usize>,
+    max_len: usize,
+    limit: usize,
+    // The states along the path to `cur`, along with the next byte to try from each of them.
+    stack: Vec<(usize, usize)>,
+    cur: Vec<u8>,
+    // True if we just arrived at the top of the stack, and haven't checked whether it accepts.
+    entered: bool,
+}
+
+impl<'a, Insts: Instructions> Iterator for Strings<'a, Insts> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        while self.limit > 0 {
+            let left = self.max_len - self.cur.len();
+            let &mut (state, ref mut next_byte) = self.stack.last_mut()?;
+            if self.entered {
+                self.entered = false;
+                if self.prog.ends_at_eoi(state) {
+                    self.limit -= 1;
+                    return Some(self.cur.clone());
+                }
+            }
+
+            let (prog, dist) = (self.prog, &self.dist);
+            let next = (*next_byte..256).filter_map(|b| {
+                prog.step_byte(state, b as u8).0.filter(|&t| dist[t] < left).map(|t| (b, t))
+            }).next();
+            match next {
+                Some((b, t)) => {
+                    *next_byte = b + 1;
+                    self.stack.push((t, 0));
+                    self.cur.push(b as u8);
+                    self.entered = true;
+                },
+                None => {
+                    self.stack.pop();
+                    self.cur.pop();
+                },
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "std"))]
+    use alloc::string::String;
+    #[cfg(not(feature = "std"))]
+    use std::prelude::v1::*;
+    use program::{Program, TableInsts};
+    use test_util::{lits_prog, table_prog};
+
+    #[test]
+    fn test_empty_and_universal() {
+        let ab = lits_prog(&["ab"]);
+        assert!(!ab.is_empty_language());
+        assert!(!ab.is_universal());
+
+        // State 2 accepts, but it can't be reached.
+        let unreachable = table_prog(3, &[(2, b'a', 2)], &[2]);
+        assert!(unreachable.is_empty_language());
+        assert!(!unreachable.is_universal());
+
+        let all: Vec<_> = (0..=255u8).map(|b| (1, b, 1)).collect();
+        let everything = table_prog(2, &all, &[1]);
+        assert!(!everything.is_empty_language());
+        assert!(everything.is_universal());
+
+        // Everything except for "a".
+        let mut almost = all.clone();
+        almost[b'a' as usize].2 = 2;
+        assert!(!table_prog(3, &almost, &[1]).is_universal());
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let strings = |prog: &Program<TableInsts>, max_len, limit| -> Vec<String> {
+            prog.enumerate(max_len, limit).map(|s| String::from_utf8(s).unwrap()).collect()
+        };
+        let lits = lits_prog(&["cd", "ab", "efg"]);
+        assert_eq!(strings(&lits, 3, 10), vec!["ab", "cd", "efg"]);
+        assert_eq!(strings(&lits, 2, 10), vec!["ab", "cd"]);
+        assert_eq!(strings(&lits, 3, 1), vec!["ab"]);
+        assert_eq!(strings(&lits, 1, 10), Vec::<String>::new());
+
+        // (a|b)*
+        let ab_star = table_prog(2, &[(1, b'a', 1), (1, b'b', 1)], &[1]);
+        assert_eq!(strings(&ab_star, 2, 10), vec!["", "a", "aa", "ab", "b", "ba", "bb"]);
+        assert_eq!(ab_star.enumerate(20, 1000).count(), 1000);
+    }
+
+    #[test]
+    fn test_matches_prefix() {
+        let lits = lits_prog(&["ab", "cd"]);
+        for &(input, expected) in &[("", true), ("a", true), ("ab", true), ("c", true),
+                                    ("b", false), ("abc", false), ("ac", false)] {
+            assert_eq!(lits.matches_prefix(input.as_bytes()), expected, "{}", input);
+        }
+        assert!(lits.can_continue(1));
+        assert!(!lits.can_continue(0));
+    }
+
+    #[test]
+    fn test_count_matches_of_len() {
+        let lits = lits_prog(&["ab", "cd", "efg"]);
+        assert_eq!(lits.count_matches_of_len(0), 0);
+        assert_eq!(lits.count_matches_of_len(2), 2);
+        assert_eq!(lits.count_matches_of_len(3), 1);
+
+        let all: Vec<_> = (0..=255u8).map(|b| (1, b, 1)).collect();
+        let everything = table_prog(2, &all, &[1]);
+        assert_eq!(everything.count_matches_of_len(0), 1);
+        assert_eq!(everything.count_matches_of_len(3), 1 << 24);
+        assert_eq!(everything.count_matches_of_len(15), 1 << 120);
+        assert_eq!(everything.count_matches_of_len(16), u128::MAX);
+    }
+}
@@ -6,73 +6,557 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use aho_corasick::Automaton;
-use Engine;
-use prefix::{Prefix, PrefixSearcher};
+use {Engine, SearchConfig};
+use error::ProgramError;
+use prefix::{Prefix, PrefixSearcher, PrefixSearcherFactory};
 use program::{Instructions, Program};
+use serialize::{write_any, write_insts_kind, write_u8};
+use std::io::{self, Write};
 
+/// An engine that runs its program from each position that the prefix finds, until one of them
+/// matches.
+///
+/// Searching doesn't recurse and uses a fixed amount of stack. The only memory that it uses
+/// (apart from whatever the prefix searcher needs) is for the program's state at one position, so
+/// it is safe to run with untrusted programs and inputs; the price is that it can take time
+/// quadratic in the length of the input.
 #[derive(Clone, Debug)]
-pub struct BacktrackingEngine<Insts: Instructions> {
+pub struct BacktrackingEngine<Insts: Instructions, P: PrefixSearcherFactory = Prefix> {
     prog: Program<Insts>,
-    prefix: Prefix,
+    prefix: P,
+    rev: Option<Program<Insts>>,
+    full_match: Option<usize>,
 }
 
-impl<Insts: Instructions> BacktrackingEngine<Insts> {
-    pub fn new(prog: Program<Insts>, pref: Prefix) -> BacktrackingEngine<Insts> {
+impl<Insts: Instructions, P: PrefixSearcherFactory> BacktrackingEngine<Insts, P> {
+    pub fn new(prog: Program<Insts>, pref: P) -> BacktrackingEngine<Insts, P> {
         BacktrackingEngine {
-            prog: prog,
+            full_match: prog.full_match_start(),
+            prog,
             prefix: pref,
+            rev: None,
         }
     }
 
-    fn shortest_match_from<'a>(&self, input: &[u8], pos: usize, mut state: usize)
-    -> Option<usize> {
-        for pos in pos..input.len() {
-            let (next_state, accepted) = self.prog.step(state, &input[pos..]);
-            if let Some(bytes_ago) = accepted {
-                // We need to use saturating_sub here because Nfa::determinize_for_shortest_match
-                // makes it so that bytes_ago can be positive even when start_idx == 0.
-                return Some(pos.saturating_sub(bytes_ago));
-            } else if let Some(next_state) = next_state {
-                state = next_state;
-            } else {
-                return None;
-            }
+    /// Like `new`, but first checks (with `Instructions::validate`) that running `prog` can't
+    /// panic, for programs that were built from untrusted data.
+    pub fn try_new(prog: Program<Insts>, pref: P)
+    -> Result<BacktrackingEngine<Insts, P>, ProgramError> {
+        prog.validate()?;
+        Ok(BacktrackingEngine::new(prog, pref))
+    }
+
+    /// Uses `rev` to find where matches start.
+    ///
+    /// Without this, a match starts wherever the prefix said that it could, which is only right if
+    /// the program is anchored at that point. `rev` should match the reversal of the strings
+    /// matched by the program, and it will be run backwards from the end of each match to find
+    /// the leftmost position at which that match could have started.
+    pub fn with_reverse_program(mut self, rev: Program<Insts>) -> BacktrackingEngine<Insts, P> {
+        self.rev = Some(rev);
+        self
+    }
+
+    /// Finds the shortest match in `input` that starts at or after `start` and ends at or before
+    /// `end`.
+    ///
+    /// Only the bytes in `start..end` are scanned, but the rest of `input` is still the context
+    /// of the search: a match can use a byte before `start` to decide where it may begin, and the
+    /// end-of-input conditions only hold when `end` is the end of `input`. Anchored programs are
+    /// anchored at `start`.
+    pub fn shortest_match_in(&self, input: &[u8], start: usize, end: usize)
+    -> Option<(usize, usize)> {
+        self.search(input, &SearchConfig { start, end: Some(end), ..SearchConfig::default() })
+    }
+
+    /// Searches for a match in `input`, according to `config`.
+    ///
+    /// This engine always returns the first match that it finds, so `config.earliest` makes no
+    /// difference.
+    pub fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        self.leftmost_shortest(input, config).map(|m| self.prog.extend_match(input, config, m))
+    }
+
+    // Finds the leftmost match, and the shortest one starting there.
+    fn leftmost_shortest(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        let (start, end) = config.bounds(input)?;
+        if let (Some(state), 0, true) = (self.full_match, start, end == input.len()) {
+            return self.prog.full_match(state, input);
+        } else if self.prog.num_states() == 0 {
+            return None;
+        } else if config.anchored_start || self.prog.init.anchored().is_some() {
+            let state = self.prog.init.anchored()
+                .or_else(|| self.prog.init.state_at_pos(input, start))?;
+            return self.match_at(input, start, end, state, config).map(|x| (start, x));
         }
 
-        if let Some(bytes_ago) = self.prog.check_eoi(state) {
-            Some(input.len().saturating_sub(bytes_ago))
+        let exact = self.prefix.is_exact();
+        let ret = if exact && config.anchored_end {
+            // An exact prefix only finds the shortest match at each position, which might not be
+            // the one that ends at `end`.
+            (start..=end).filter_map(|pos| {
+                let state = self.prog.init.state_at_pos(input, pos)?;
+                self.match_at(input, pos, end, state, config).map(|x| (pos, x))
+            }).next()
         } else {
-            None
+            self.prefix.with_searcher(&input[..end], &mut |searcher| {
+                if exact {
+                    searcher.skip_to(start);
+                    searcher.leftmost_match(self.prefix.out_of_order_len(),
+                                            &mut |res| Some((res.start_pos, res.end_pos)))
+                } else {
+                    searcher.skip_to(start);
+                    self.shortest_match_from_searcher(input, end, config, searcher)
+                }
+            })
+        };
+        match (ret, self.rev.as_ref()) {
+            (Some((s, e)), Some(rev)) if !exact =>
+                Some((rev.leftmost_start(input, start, e).unwrap_or(s), e)),
+            _ => ret,
         }
     }
 
-    fn shortest_match_from_searcher(&self, input: &[u8], search: &mut PrefixSearcher)
+    /// Returns the end of a match starting from `state` at position `pos`.
+    fn match_at(&self, input: &[u8], pos: usize, end: usize, state: usize, config: &SearchConfig)
+    -> Option<usize> {
+        if config.anchored_end {
+            if self.prog.matches_to(input, pos, end, state) { Some(end) } else { None }
+        } else {
+            self.prog.shortest_match_from(input, pos, end, state)
+        }
+    }
+
+    fn shortest_match_from_searcher(&self, input: &[u8], end: usize, config: &SearchConfig,
+                                    search: &mut dyn PrefixSearcher)
     -> Option<(usize, usize)> {
-        while let Some(res) = search.search() {
-            if let Some(end) = self.shortest_match_from(input, res.end_pos, res.end_state) {
-                return Some((res.start_pos, end));
+        search.leftmost_match(self.prefix.out_of_order_len(), &mut |res| {
+            let state = res.end_state.or_else(|| self.prog.init.state_at_pos(input, res.end_pos))?;
+            self.match_at(input, res.end_pos, end, state, config).map(|e| (res.start_pos, e))
+        })
+    }
+}
+
+impl<I, P> Engine for BacktrackingEngine<I, P>
+where I: Instructions + 'static, P: PrefixSearcherFactory + Clone + 'static {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        BacktrackingEngine::search(self, input, config)
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(self.clone())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.prog.memory_usage() + self.rev.as_ref().map_or(0, |r| r.memory_usage())
+            + self.prefix.memory_usage()
+    }
+
+    fn explain(&self, input: &[u8], m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        self.prog.explain(input, m)
+    }
+
+    fn matches_prefix(&self, input: &[u8]) -> Option<bool> {
+        Some(self.prog.matches_prefix(input))
+    }
+
+    fn write_engine(&self, mut w: &mut dyn Write) -> io::Result<()> {
+        write_u8(&mut w, 0)?;
+        write_insts_kind::<I, _>(&mut w)?;
+        write_any(&mut w, &self.prog)?;
+        write_any(&mut w, &self.prefix)?;
+        match self.rev {
+            Some(ref rev) => { write_u8(&mut w, 1)?; write_any(&mut w, rev) },
+            None => write_u8(&mut w, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use byte_set::ByteSet;
+    use error::ProgramError;
+    use prefix::{Prefix, PrefixLimits, PrefixSearcherFactory};
+    use program::{InitStates, TableInsts};
+    use std::cell::Cell;
+    use std::ops::ControlFlow;
+    use std::sync::Arc;
+    use test_util::{lit_prog, lits_prog, table_prog, AfterPipe};
+    use threaded::ThreadedEngine;
+    use {Engine, Matches, SearchConfig};
+
+    #[test]
+    fn test_custom_prefix() {
+        let eng = BacktrackingEngine::new(lit_prog("ab"), Prefix::Empty);
+        assert_eq!(eng.shortest_match("ab|ab"), Some((0, 2)));
+
+        let eng = BacktrackingEngine::new(lit_prog("ab"), AfterPipe);
+        assert_eq!(eng.shortest_match("ab|ab"), Some((3, 5)));
+        assert_eq!(eng.shortest_match("ab|cab"), None);
+
+        let pref: Arc<dyn PrefixSearcherFactory> = Arc::new(AfterPipe);
+        let eng = BacktrackingEngine::new(lit_prog("ab"), pref);
+        assert_eq!(eng.clone_box().shortest_match("ab|ab"), Some((3, 5)));
+    }
+
+    #[test]
+    fn test_exact_prefix() {
+        let prog = lits_prog(&["abc", "de"]);
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match("xxabcdex"), Some((2, 5)));
+        assert_eq!(eng.shortest_match("xxdxab"), None);
+
+        // "bc" ends first, but "abcd" starts first.
+        let prog = lits_prog(&["abcd", "bc"]);
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        let empty = BacktrackingEngine::new(prog, Prefix::Empty);
+        for input in &["abcd", "xabcx", "abcbc"] {
+            assert_eq!(eng.shortest_match(input), empty.shortest_match(input), "{}", input);
+        }
+        assert_eq!(eng.shortest_match("abcd"), Some((0, 4)));
+
+        // With tight limits, the prefix only looks for first bytes and isn't exact.
+        let prog = lits_prog(&["ab", "cd"]);
+        let limits = PrefixLimits { max_strings: 1, ..PrefixLimits::default() };
+        let pref = Prefix::from_program_with_limits(&prog, &limits);
+        let eng = BacktrackingEngine::new(prog, pref);
+        assert_eq!(eng.shortest_match("ax"), None);
+        assert_eq!(eng.shortest_match("axcd"), Some((2, 4)));
+
+        let prog = lit_prog("abc");
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match("xxabcab"), Some((2, 5)));
+    }
+
+    #[test]
+    fn test_loop_at_least() {
+        // "[0-9]{4}": the start state has to loop on digits 4 times before it accepts.
+        let digits: ByteSet = (b'0'..=b'9').collect();
+        let edges: Vec<_> = (1..5)
+            .flat_map(|s| digits.iter().map(move |b| (s, b, s + 1)))
+            .collect();
+        let prog = table_prog(6, &edges, &[5]);
+        let inputs = ["12 12345 x1234 123", "123", "1234", "x123x4567", "", "12x34"];
+        for pref in &[Prefix::LoopWhile(digits, 4), Prefix::LoopWhile(digits, 2)] {
+            let engines: [Box<dyn Engine>; 2] = [
+                Box::new(BacktrackingEngine::new(prog.clone(), pref.clone())),
+                Box::new(ThreadedEngine::new(prog.clone(), pref.clone())),
+            ];
+            let expected = BacktrackingEngine::new(prog.clone(), Prefix::Empty);
+            let expected: &dyn Engine = &expected;
+            let config = SearchConfig::default();
+            for eng in &engines {
+                for input in &inputs {
+                    assert_eq!(eng.matches(input.as_bytes(), &config).collect::<Vec<_>>(),
+                               expected.matches(input.as_bytes(), &config).collect::<Vec<_>>(),
+                               "{:?} on {:?}", pref, input);
+                }
+                assert_eq!(eng.shortest_match("12 123 x12345"), Some((8, 12)));
+                assert_eq!(eng.shortest_match("12 123 x123"), None);
             }
         }
+    }
+
+    #[test]
+    fn test_after_byte() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::AfterByte(b'\n', 1);
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match("ab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("xab\nab"), Some((4, 6)));
+        assert_eq!(eng.shortest_match("xab\nxab"), None);
 
-        None
+        // The program is still correct with a prefix that doesn't know about lines.
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.shortest_match("xab\nab"), Some((4, 6)));
     }
-}
 
-impl<I: Instructions + 'static> Engine for BacktrackingEngine<I> {
-    fn shortest_match(&self, s: &str) -> Option<(usize, usize)> {
-        let input = s.as_bytes();
-        if self.prog.num_states() == 0 {
-            return None;
-        } else if self.prog.is_anchored {
-            return self.shortest_match_from(input, 0, 0).map(|x| (0, x));
+    #[test]
+    fn test_anchored() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::Anchored(1);
+        // The prefix would find later matches, but anchored programs don't use it.
+        let eng = BacktrackingEngine::new(prog, Prefix::Lit(b"ab".to_vec()));
+        assert_eq!(eng.shortest_match("abab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("xab"), None);
+        assert_eq!(eng.shortest_match("a"), None);
+        assert_eq!(eng.shortest_match(""), None);
+
+        // An anchored program that matches the empty string.
+        let mut empty = table_prog(2, &[(1, b'a', 1)], &[1]);
+        empty.init = InitStates::Anchored(1);
+        let eng = BacktrackingEngine::new(empty, Prefix::Empty);
+        assert_eq!(eng.shortest_match("xyz"), Some((0, 0)));
+        assert_eq!(eng.shortest_match(""), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_try_new() {
+        let mut prog = lit_prog("ab");
+        assert!(BacktrackingEngine::try_new(prog.clone(), Prefix::Empty).is_ok());
+        prog.init = InitStates::Anchored(10);
+        assert_eq!(BacktrackingEngine::try_new(prog, Prefix::Empty).unwrap_err(),
+                   ProgramError::BadInitState(10));
+    }
+
+    #[test]
+    fn test_pointers() {
+        fn search<E: Engine>(eng: E) -> Option<(usize, usize)> {
+            eng.search(b"xab", &SearchConfig::default())
         }
 
-        let mut searcher = self.prefix.make_searcher(input);
-        self.shortest_match_from_searcher(input, &mut *searcher)
+        let eng = BacktrackingEngine::new(lit_prog("ab"), Prefix::Empty);
+        let boxed: Box<dyn Engine> = Box::new(eng.clone());
+        assert_eq!(search(&eng), Some((1, 3)));
+        assert_eq!(search(&boxed), Some((1, 3)));
+        assert_eq!(search(Box::new(eng.clone())), Some((1, 3)));
+        assert_eq!(search(Arc::new(eng)), Some((1, 3)));
+        assert_eq!(search(boxed.clone_box()), Some((1, 3)));
     }
 
-    fn clone_box(&self) -> Box<Engine> {
-        Box::new(self.clone())
+    #[test]
+    fn test_for_each_match() {
+        let eng = BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty);
+        let config = SearchConfig::default();
+        let mut found = Vec::new();
+        let ret = eng.for_each_match(b"abxcdab", &config, &mut |m| {
+            found.push(m);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(ret, ControlFlow::Continue(()));
+        assert_eq!(found, Matches::new(&eng, b"abxcdab", &config).collect::<Vec<_>>());
+
+        found.clear();
+        let ret = eng.for_each_match(b"abxcdab", &config, &mut |m| {
+            found.push(m);
+            if m.0 > 0 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(ret, ControlFlow::Break(()));
+        assert_eq!(found, vec![(0, 2), (3, 5)]);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let prog = lits_prog(&["ab", "cd"]);
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog))),
+            Box::new(ThreadedEngine::new(prog, Prefix::Empty)),
+        ];
+        let leftmost_first = SearchConfig { leftmost_first: true, ..SearchConfig::default() };
+        let configs = [
+            SearchConfig { start: 5, ..SearchConfig::default() },
+            SearchConfig { start: 5, ..leftmost_first },
+            SearchConfig { end: Some(5), ..SearchConfig::default() },
+            SearchConfig { start: 2, end: Some(1), ..SearchConfig::default() },
+        ];
+        for eng in &engines {
+            for config in &configs {
+                assert_eq!(eng.search(b"abcd", config), None, "{:?}", config);
+                assert_eq!(eng.search_str("abcd", config), None, "{:?}", config);
+                assert_eq!(Matches::new(&**eng, b"abcd", config).next(), None, "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_at_most_n() {
+        // Counts the searches, to check that we stop early.
+        #[derive(Debug)]
+        struct Counting(BacktrackingEngine<TableInsts>, Cell<usize>);
+
+        impl Engine for Counting {
+            fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+                self.1.set(self.1.get() + 1);
+                self.0.search(input, config)
+            }
+
+            fn clone_box(&self) -> Box<dyn Engine> {
+                Box::new(Counting(self.0.clone(), self.1.clone()))
+            }
+        }
+
+        let eng = Counting(BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty),
+                           Cell::new(0));
+        let config = SearchConfig::default();
+        assert_eq!(eng.find_at_most_n(b"abcdabcd", &config, 2), vec![(0, 2), (2, 4)]);
+        assert_eq!(eng.1.get(), 2);
+        assert_eq!(eng.find_at_most_n(b"abcdabcd", &config, 10).len(), 4);
+        eng.1.set(0);
+        assert_eq!(eng.find_at_most_n(b"abcdabcd", &config, 0), vec![]);
+        assert_eq!(eng.1.get(), 0);
+        assert_eq!((&eng as &dyn Engine).find_at_most_n(b"xcd", &config, 1), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn test_shared_program() {
+        let prog = lits_prog(&["ab", "cd"]).into_shared();
+        let bt = BacktrackingEngine::new(prog.clone(), Prefix::Empty);
+        let threaded = ThreadedEngine::new(prog.clone(), Prefix::Empty);
+        assert_eq!(Arc::strong_count(&prog.instructions), 3);
+        assert_eq!(bt.shortest_match("xcdab"), Some((1, 3)));
+        assert_eq!(threaded.shortest_match("xcdab"), Some((1, 3)));
+
+        let mut buf = Vec::new();
+        bt.save(&mut buf).unwrap();
+        let loaded = <dyn Engine>::load(&mut &buf[..]).unwrap();
+        assert_eq!(loaded.shortest_match("xcdab"), Some((1, 3)));
+    }
+
+    #[test]
+    fn test_shortest_match_in() {
+        let prog = lit_prog("ab");
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match_in(b"abxab", 1, 5), Some((3, 5)));
+        assert_eq!(eng.shortest_match_in(b"abxab", 0, 4), Some((0, 2)));
+        assert_eq!(eng.shortest_match_in(b"abxab", 1, 4), None);
+        assert_eq!(eng.shortest_match_in(b"abxab", 3, 3), None);
+
+        // A program that matches "ab" only at the end of the input: the end of the window
+        // doesn't count.
+        let mut prog = lit_prog("ab");
+        let table = prog.instructions.table.clone();
+        prog.instructions = TableInsts::new(table, vec![usize::MAX; 4]);
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.shortest_match_in(b"abab", 0, 4), Some((2, 4)));
+        assert_eq!(eng.shortest_match_in(b"abab", 0, 2), None);
+
+        // Conversely, a match that needs another byte after it is fine at the end of the window.
+        let mut prog = lit_prog("ab");
+        prog.accept_at_eoi[3] = usize::MAX;
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.shortest_match_in(b"abab", 2, 4), None);
+        assert_eq!(eng.shortest_match_in(b"abab", 0, 2), Some((0, 2)));
+
+        // Line starts are taken from the whole input, and anchors from the window.
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::AfterByte(b'\n', 1);
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match_in(b"\nabxab", 1, 6), Some((1, 3)));
+        assert_eq!(eng.shortest_match_in(b"xabxab", 1, 6), None);
+        prog.init = InitStates::Anchored(1);
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.shortest_match_in(b"xabab", 1, 5), Some((1, 3)));
+        assert_eq!(eng.shortest_match_in(b"xabab", 0, 5), None);
+    }
+
+    #[test]
+    fn test_reverse_program() {
+        // Matches anything ending in "ab", so the forward pass can't tell where matches start.
+        let mut edges: Vec<_> = (0..=255u8).map(|b| (1, b, 1)).chain((0..=255u8).map(|b| (2, b, 1)))
+            .collect();
+        edges.extend_from_slice(&[(1, b'a', 2), (2, b'a', 2), (2, b'b', 3)]);
+        let prog = table_prog(4, &edges, &[3]);
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match("xxab"), Some((0, 4)));
+
+        let eng = eng.with_reverse_program(lit_prog("ba"));
+        assert_eq!(eng.shortest_match("xxab"), Some((2, 4)));
+        assert_eq!(eng.shortest_match("abab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match_in(b"xxabx", 1, 4), Some((2, 4)));
+    }
+
+    #[test]
+    fn test_full_match() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::Anchored(1);
+        let table = prog.instructions.table.clone();
+        prog.instructions = TableInsts::new(table, vec![usize::MAX; 4]);
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.full_match, Some(1));
+        assert_eq!(eng.shortest_match("ab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("abab"), None);
+        assert_eq!(eng.shortest_match("xab"), None);
+        assert_eq!(eng.shortest_match_in(b"abab", 0, 2), None);
+    }
+
+    #[test]
+    fn test_search_config() {
+        // Matches "a" or "ab".
+        let prog = table_prog(4, &[(1, b'a', 2), (2, b'b', 3)], &[2, 3]);
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        let config = SearchConfig::default();
+        assert_eq!(eng.search(b"xab", &config), Some((1, 2)));
+
+        let anchored = SearchConfig { anchored_start: true, ..config };
+        assert_eq!(eng.search(b"xab", &anchored), None);
+        assert_eq!(eng.search(b"xab", &SearchConfig { start: 1, ..anchored }), Some((1, 2)));
+
+        let to_end = SearchConfig { anchored_end: true, ..config };
+        assert_eq!(eng.search(b"xab", &to_end), Some((1, 3)));
+        assert_eq!(eng.search(b"xabx", &to_end), None);
+        assert_eq!(eng.search(b"xabx", &SearchConfig { end: Some(3), ..to_end }), Some((1, 3)));
+        let both = SearchConfig { anchored_start: true, anchored_end: true, ..config };
+        assert_eq!(eng.search(b"xab", &SearchConfig { start: 1, ..both }), Some((1, 3)));
+        assert_eq!(eng.search(b"xaab", &SearchConfig { start: 1, ..both }), None);
+    }
+
+    #[test]
+    fn test_explain() {
+        // Matches "a" or "ab".
+        let prog = table_prog(4, &[(1, b'a', 2), (2, b'b', 3)], &[2, 3]);
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        let to_end = SearchConfig { anchored_end: true, ..SearchConfig::default() };
+        let m = eng.search(b"xab", &to_end).unwrap();
+        assert_eq!(eng.explain(b"xab", m), Some(vec![(1, 1), (2, 2), (3, 3)]));
+        assert_eq!(eng.explain(b"xab", (0, 2)), None);
+        assert_eq!(eng.explain(b"xab", (1, 4)), None);
+        assert_eq!(eng.explain(b"xab", (5, 6)), None);
+        assert_eq!(eng.explain(b"xab", (2, 1)), None);
+    }
+
+    #[test]
+    fn test_matches_prefix() {
+        let eng = BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty);
+        assert_eq!(eng.matches_prefix(b"a"), Some(true));
+        assert_eq!(eng.matches_prefix(b"cd"), Some(true));
+        assert_eq!(eng.matches_prefix(b"xa"), Some(false));
+    }
+
+    #[test]
+    fn test_munch() {
+        // Matches "a", "ab" or "abcd".
+        let prog = table_prog(6, &[(1, b'a', 2), (2, b'b', 3), (3, b'c', 4), (4, b'd', 5)],
+                              &[2, 3, 5]);
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.munch(b"abcdab", 0), Some(4));
+        assert_eq!(eng.munch(b"abcdab", 4), Some(6));
+        assert_eq!(eng.munch(b"abcab", 0), Some(2));
+        assert_eq!(eng.munch(b"xabc", 0), None);
+    }
+
+    #[test]
+    fn test_search_str() {
+        // "é" is [0xc3, 0xa9]. This matches [0xc3], [0xc3, 0xa9] or [0xa9].
+        let prog = table_prog(4, &[(1, 0xc3, 2), (2, 0xa9, 3), (1, 0xa9, 3)], &[2, 3]);
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.search("é".as_bytes(), &SearchConfig::default()), Some((0, 1)));
+        assert_eq!(eng.shortest_match("é"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("xé"), Some((1, 3)));
+
+        // This only matches [0xa9], which is never at a boundary.
+        let prog = table_prog(3, &[(1, 0xa9, 2)], &[2]);
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.search("é".as_bytes(), &SearchConfig::default()), Some((1, 2)));
+        assert_eq!(eng.shortest_match("éé"), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        let eng = BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty);
+        let eng: &dyn Engine = &eng;
+        let all: Vec<_> = eng.matches(b"abxcdab", &SearchConfig::default()).collect();
+        assert_eq!(all, vec![(0, 2), (3, 5), (5, 7)]);
+        let anchored = SearchConfig { anchored_start: true, ..SearchConfig::default() };
+        assert_eq!(eng.matches(b"abcdxab", &anchored).collect::<Vec<_>>(), vec![(0, 2), (2, 4)]);
+
+        // a*, which matches the empty string at every position (including the end).
+        let eng = BacktrackingEngine::new(table_prog(2, &[(1, b'a', 1)], &[1]), Prefix::Empty);
+        let eng: &dyn Engine = &eng;
+        let all: Vec<_> = eng.matches(b"xa", &SearchConfig::default()).collect();
+        assert_eq!(all, vec![(0, 0), (1, 1), (2, 2)]);
+        let to_end = SearchConfig { end: Some(1), ..SearchConfig::default() };
+        assert_eq!(eng.matches(b"xa", &to_end).collect::<Vec<_>>(), vec![(0, 0), (1, 1)]);
+        assert_eq!(eng.matches(b"xa", &anchored).collect::<Vec<_>>(), vec![(0, 0)]);
     }
 }
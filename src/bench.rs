@@ -0,0 +1,168 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Comparing the engines on a corpus, to help decide which one to use.
+//!
+//! This is only available with the `bench` feature.
+
+use {Engine, Matches, SearchConfig};
+use backtracking::BacktrackingEngine;
+use prefix::{Prefix, PrefixSearcher};
+use program::{Program, TableInsts};
+use std::fmt::{Display, Formatter, Error as FmtError};
+use std::time::{Duration, Instant};
+use threaded::ThreadedEngine;
+use twopass::TwoPassEngine;
+
+/// The result of running one engine, with one prefix, over a corpus.
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+    /// The name of the engine.
+    pub engine: &'static str,
+    /// The kind of prefix, or "none".
+    pub prefix: String,
+    /// The number of (non-overlapping) matches found in the whole corpus.
+    pub matches: usize,
+    /// The number of candidate positions that the prefix found in the whole corpus.
+    pub candidates: usize,
+    /// The total size of the corpus (times the number of iterations).
+    pub bytes: usize,
+    /// The time taken to find all the matches (in every iteration).
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    /// The number of bytes searched per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+impl Display for BenchResult {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{:<12} {:<16} {:>8} matches {:>10} candidates {:>10.1} MB/s",
+               self.engine, self.prefix, self.matches, self.candidates,
+               self.throughput() / 1_000_000.0)
+    }
+}
+
+/// Runs every engine that can run a program, with and without a prefix, over a corpus.
+#[derive(Clone, Debug)]
+pub struct Bench {
+    prog: Program<TableInsts>,
+    rev: Option<Program<TableInsts>>,
+    iterations: usize,
+}
+
+impl Bench {
+    pub fn new(prog: Program<TableInsts>) -> Bench {
+        Bench {
+            prog,
+            rev: None,
+            iterations: 1,
+        }
+    }
+
+    /// Also benchmarks the engines that need the reversal of the program: the backtracking
+    /// engine with a reverse program, and the `TwoPassEngine`.
+    pub fn with_reverse_program(mut self, rev: Program<TableInsts>) -> Bench {
+        self.rev = Some(rev);
+        self
+    }
+
+    /// Searches the corpus this many times with each engine, to get more accurate timings.
+    pub fn with_iterations(mut self, iterations: usize) -> Bench {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Runs all the benchmarks on `corpus`.
+    pub fn run<S: AsRef<[u8]>>(&self, corpus: &[S]) -> Vec<BenchResult> {
+        let mut ret = Vec::new();
+        for pref in &[Prefix::Empty, Prefix::from_program(&self.prog)] {
+            let mut engines: Vec<(&'static str, Box<dyn Engine>)> = vec![
+                ("backtracking", Box::new(BacktrackingEngine::new(self.prog.clone(), pref.clone()))),
+                ("threaded", Box::new(ThreadedEngine::new(self.prog.clone(), pref.clone()))),
+            ];
+            if let Some(shuffle) = self.prog.to_shuffle() {
+                engines.push(("shuffle", Box::new(BacktrackingEngine::new(shuffle, pref.clone()))));
+            }
+            if let Some(ref rev) = self.rev {
+                let bt = BacktrackingEngine::new(self.prog.clone(), pref.clone())
+                    .with_reverse_program(rev.clone());
+                engines.push(("backtracking+rev", Box::new(bt)));
+                if let Some(eng) = TwoPassEngine::new(self.prog.clone(), rev.clone(), pref.clone()) {
+                    engines.push(("two-pass", Box::new(eng)));
+                }
+            }
+
+            let candidates: usize = corpus.iter().map(|s| count_candidates(pref, s.as_ref())).sum();
+            let config = SearchConfig::default();
+            for (name, eng) in engines {
+                let now = Instant::now();
+                let mut matches = 0;
+                for _ in 0..self.iterations {
+                    matches = corpus.iter().map(|s| Matches::new(&*eng, s.as_ref(), &config).count()).sum();
+                }
+                ret.push(BenchResult {
+                    engine: name,
+                    prefix: prefix_name(pref),
+                    matches,
+                    candidates,
+                    bytes: corpus.iter().map(|s| s.as_ref().len()).sum::<usize>() * self.iterations,
+                    elapsed: now.elapsed(),
+                });
+            }
+        }
+        ret
+    }
+}
+
+fn prefix_name(pref: &Prefix) -> String {
+    match *pref {
+        Prefix::Empty => "none".to_owned(),
+        Prefix::Exact(ref inner) => format!("exact {}", prefix_name(inner)),
+        _ => {
+            let debug = format!("{:?}", pref);
+            debug.split('(').next().unwrap_or("").to_owned()
+        },
+    }
+}
+
+fn count_candidates(pref: &Prefix, input: &[u8]) -> usize {
+    let mut searcher = pref.make_searcher(input);
+    let mut count = 0;
+    while searcher.search().is_some() {
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use bench::Bench;
+    use test_util::lits_prog;
+
+    #[test]
+    fn test_run() {
+        let bench = Bench::new(lits_prog(&["ab", "cd"]))
+            .with_reverse_program(lits_prog(&["ba", "dc"]))
+            .with_iterations(2);
+        let results = bench.run(&["xxabxxcdab", "", "abab"]);
+        // Five engines (the program is small enough for `ShuffleInsts`), with two prefixes each.
+        assert_eq!(results.len(), 10);
+        for res in &results {
+            assert_eq!(res.matches, 5, "{}", res);
+            assert_eq!(res.bytes, 28);
+        }
+        assert_eq!(results[0].prefix, "none");
+        // Without a prefix, every position (including the end of each input) is a candidate.
+        assert_eq!(results[0].candidates, 11 + 1 + 5);
+        assert!(results[5].candidates < 17);
+    }
+}
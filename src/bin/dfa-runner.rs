@@ -0,0 +1,180 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A command-line tool for inspecting saved programs and engines, and for running them on files.
+//!
+//! This is only built with the `cli` feature. There is no pattern compiler yet, so the programs
+//! must come from somewhere else: a file is either an engine written by `Engine::save`, or a
+//! program with `TableInsts` written by `Serialize::write_to`.
+
+extern crate dfa_runner;
+
+use dfa_runner::{Engine, Matches, SearchConfig};
+use dfa_runner::builder::EngineBuilder;
+use dfa_runner::dot;
+use dfa_runner::prefix::{Prefix, PrefixSearcherFactory};
+use dfa_runner::program::{Instructions, Program, TableInsts};
+use dfa_runner::serialize::{self, Serialize, MAGIC};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+use std::time::Instant;
+
+const USAGE: &str = "\
+usage: dfa-runner stats FILE
+       dfa-runner dot FILE
+       dfa-runner run FILE [INPUT...]
+
+FILE is a saved engine, or a saved program with table instructions.
+  stats  prints the size of FILE (and, for programs, what they match)
+  dot    draws the prefix of a program in the DOT language
+  run    prints the matches in each INPUT (or in stdin), and how long it took";
+
+// The contents of a file given on the command line.
+enum Loaded {
+    Program(Box<Program<TableInsts>>),
+    Engine(Box<dyn Engine>),
+}
+
+impl Loaded {
+    // Saved engines start with a header, but programs don't.
+    fn from_bytes(mut bytes: &[u8]) -> io::Result<Loaded> {
+        if bytes.starts_with(MAGIC) {
+            Ok(Loaded::Engine(<dyn Engine>::load(&mut bytes)?))
+        } else {
+            let prog = Program::read_from(&mut bytes)?;
+            if !bytes.is_empty() {
+                return Err(serialize::invalid("trailing data after the program"));
+            }
+            Ok(Loaded::Program(Box::new(prog)))
+        }
+    }
+
+    fn into_engine(self) -> io::Result<Box<dyn Engine>> {
+        match self {
+            Loaded::Engine(eng) => Ok(eng),
+            Loaded::Program(prog) => EngineBuilder::new(*prog)
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+fn stats<W: Write>(w: &mut W, loaded: &Loaded) -> io::Result<()> {
+    match *loaded {
+        Loaded::Engine(ref eng) => {
+            writeln!(w, "engine:       {:?}", eng)?;
+            writeln!(w, "memory usage: {} bytes", eng.memory_usage())
+        },
+        Loaded::Program(ref prog) => {
+            let pref = Prefix::from_program(prog);
+            writeln!(w, "states:       {}", prog.num_states())?;
+            writeln!(w, "memory usage: {} bytes", prog.memory_usage())?;
+            writeln!(w, "prefix:       {:?} ({} bytes)", pref, pref.memory_usage())?;
+            writeln!(w, "matches nothing:    {}", prog.is_empty_language())?;
+            writeln!(w, "matches everything: {}", prog.is_universal())
+        },
+    }
+}
+
+fn run<W: Write>(w: &mut W, eng: &dyn Engine, name: &str, input: &[u8]) -> io::Result<()> {
+    let now = Instant::now();
+    let matches: Vec<(usize, usize)> =
+        Matches::new(eng, input, &SearchConfig::default()).collect();
+    let elapsed = now.elapsed();
+    for &(start, end) in &matches {
+        writeln!(w, "{}:{}-{}", name, start, end)?;
+    }
+    eprintln!("{}: {} matches in {} bytes, {:.3} ms", name, matches.len(), input.len(),
+              elapsed.as_secs_f64() * 1000.0);
+    Ok(())
+}
+
+fn main_result(args: &[String]) -> io::Result<()> {
+    let usage = || io::Error::new(io::ErrorKind::InvalidInput, USAGE);
+    if args.len() < 2 {
+        return Err(usage());
+    }
+    let loaded = Loaded::from_bytes(&fs::read(&args[1])?)?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match (&args[0][..], loaded) {
+        ("stats", ref loaded) if args.len() == 2 => stats(&mut out, loaded),
+        ("dot", Loaded::Program(ref prog)) if args.len() == 2 =>
+            dot::write_prefix(&mut out, &Prefix::from_program(prog)),
+        ("dot", Loaded::Engine(_)) =>
+            Err(serialize::invalid("only the prefixes of programs can be drawn")),
+        ("run", loaded) => {
+            let eng = loaded.into_engine()?;
+            if args.len() == 2 {
+                let mut input = Vec::new();
+                io::stdin().read_to_end(&mut input)?;
+                run(&mut out, &*eng, "-", &input)
+            } else {
+                for path in &args[2..] {
+                    run(&mut out, &*eng, path, &fs::read(path)?)?;
+                }
+                Ok(())
+            }
+        },
+        _ => Err(usage()),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = main_result(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfa_runner::Engine;
+    use dfa_runner::backtracking::BacktrackingEngine;
+    use dfa_runner::prefix::Prefix;
+    use dfa_runner::program::{InitStates, Program, TableInsts};
+    use dfa_runner::serialize::Serialize;
+    use super::{run, stats, Loaded};
+
+    // A program for "ab".
+    fn ab() -> Program<TableInsts> {
+        let mut table = vec![0; 4 * 256];
+        table[256 + b'a' as usize] = 2;
+        table[2 * 256 + b'b' as usize] = 3;
+        let accept = vec![usize::MAX, usize::MAX, usize::MAX, 0];
+        Program {
+            accept_at_eoi: accept.clone(),
+            instructions: TableInsts::new(table, accept),
+            init: InitStates::Constant(1),
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let mut buf = Vec::new();
+        ab().write_to(&mut buf).unwrap();
+        let loaded = Loaded::from_bytes(&buf).unwrap();
+        assert!(matches!(loaded, Loaded::Program(_)));
+        let mut out = Vec::new();
+        stats(&mut out, &loaded).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("states:       4\n"));
+        buf.push(0);
+        assert!(Loaded::from_bytes(&buf).is_err());
+
+        let mut buf = Vec::new();
+        BacktrackingEngine::new(ab(), Prefix::Empty).save(&mut buf).unwrap();
+        let eng = Loaded::from_bytes(&buf).unwrap().into_engine().unwrap();
+        let mut out = Vec::new();
+        run(&mut out, &*eng, "x", b"abxab").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "x:0-2\nx:3-5\n");
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Building any of the engines that run a `Program`, with all of their options in one place.
+
+use {Engine, SearchConfig};
+use backtracking::BacktrackingEngine;
+use error::BuildError;
+use prefix::Prefix;
+use program::{Instructions, Program, TableInsts};
+use simple::SimpleEngine;
+use threaded::ThreadedEngine;
+use twopass::TwoPassEngine;
+
+/// The engines that an `EngineBuilder` can build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineKind {
+    /// The `BacktrackingEngine`.
+    Backtracking,
+    /// The `ThreadedEngine`.
+    Threaded,
+    /// The `TwoPassEngine`, which needs a reverse program.
+    TwoPass,
+    /// The `SimpleEngine`, which doesn't use a prefix.
+    Simple,
+}
+
+/// Builds an engine that runs a `Program`.
+///
+/// By default, this builds a `TwoPassEngine` if there is a reverse program (and its DFA isn't too
+/// big), and a `BacktrackingEngine` otherwise, with a prefix taken from the program. The program
+/// (and the reverse program, if there is one) is checked with `Instructions::validate`, so it is
+/// fine if it came from somewhere untrusted.
+#[derive(Clone, Debug)]
+pub struct EngineBuilder<Insts: Instructions = TableInsts> {
+    prog: Program<Insts>,
+    rev: Option<Program<Insts>>,
+    prefix: Option<Prefix>,
+    kind: Option<EngineKind>,
+    max_threads: usize,
+    anchored_start: bool,
+    anchored_end: bool,
+    leftmost_first: bool,
+}
+
+impl<Insts: Instructions + 'static> EngineBuilder<Insts> {
+    pub fn new(prog: Program<Insts>) -> EngineBuilder<Insts> {
+        EngineBuilder {
+            prog,
+            rev: None,
+            prefix: None,
+            kind: None,
+            max_threads: usize::MAX,
+            anchored_start: false,
+            anchored_end: false,
+            leftmost_first: false,
+        }
+    }
+
+    /// Chooses which engine to build.
+    pub fn with_kind(mut self, kind: EngineKind) -> EngineBuilder<Insts> {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Uses `pref` instead of the prefix that `Prefix::from_program` would find. To search
+    /// without a prefix, use `Prefix::Empty`.
+    pub fn with_prefix(mut self, pref: Prefix) -> EngineBuilder<Insts> {
+        self.prefix = Some(pref);
+        self
+    }
+
+    /// Gives the reversal of the program, as in `BacktrackingEngine::with_reverse_program`. The
+    /// `TwoPassEngine` needs one, and the `BacktrackingEngine` uses one if it is there.
+    pub fn with_reverse_program(mut self, rev: Program<Insts>) -> EngineBuilder<Insts> {
+        self.rev = Some(rev);
+        self
+    }
+
+    /// Limits the number of threads that the `ThreadedEngine` uses, as in
+    /// `ThreadedEngine::with_max_threads`. The other engines don't have threads.
+    pub fn with_max_threads(mut self, max_threads: usize) -> EngineBuilder<Insts> {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// Makes every search anchored at its start, as if `SearchConfig::anchored_start` was set.
+    pub fn with_anchored_start(mut self, anchored: bool) -> EngineBuilder<Insts> {
+        self.anchored_start = anchored;
+        self
+    }
+
+    /// Makes every search anchored at its end, as if `SearchConfig::anchored_end` was set.
+    pub fn with_anchored_end(mut self, anchored: bool) -> EngineBuilder<Insts> {
+        self.anchored_end = anchored;
+        self
+    }
+
+    /// Makes every search return the match that the program prefers, as if
+    /// `SearchConfig::leftmost_first` was set.
+    pub fn with_leftmost_first(mut self, leftmost_first: bool) -> EngineBuilder<Insts> {
+        self.leftmost_first = leftmost_first;
+        self
+    }
+
+    /// Builds the engine.
+    ///
+    /// If any of the options that apply to every search were set, the engine is wrapped so that
+    /// it sets them (and then it can't be saved with `Engine::save`).
+    pub fn build(self) -> Result<Box<dyn Engine>, BuildError> {
+        self.prog.validate().map_err(BuildError::Program)?;
+        if let Some(ref rev) = self.rev {
+            rev.validate().map_err(BuildError::Program)?;
+        }
+
+        let pref = match self.prefix {
+            Some(pref) => pref,
+            None => Prefix::from_program(&self.prog),
+        };
+        let eng: Box<dyn Engine> = match self.kind {
+            None => match self.rev {
+                Some(rev) => {
+                    match TwoPassEngine::new(self.prog.clone(), rev.clone(), pref.clone()) {
+                        Some(eng) => Box::new(eng),
+                        None => {
+                            let eng = BacktrackingEngine::new(self.prog, pref);
+                            Box::new(eng.with_reverse_program(rev))
+                        },
+                    }
+                },
+                None => Box::new(BacktrackingEngine::new(self.prog, pref)),
+            },
+            Some(EngineKind::Backtracking) => {
+                let eng = BacktrackingEngine::new(self.prog, pref);
+                match self.rev {
+                    Some(rev) => Box::new(eng.with_reverse_program(rev)),
+                    None => Box::new(eng),
+                }
+            },
+            Some(EngineKind::Threaded) =>
+                Box::new(ThreadedEngine::new(self.prog, pref).with_max_threads(self.max_threads)),
+            Some(EngineKind::TwoPass) => {
+                let rev = self.rev.ok_or(BuildError::NoReverseProgram)?;
+                Box::new(TwoPassEngine::new(self.prog, rev, pref).ok_or(BuildError::TooBig)?)
+            },
+            Some(EngineKind::Simple) => Box::new(SimpleEngine::new(self.prog)),
+        };
+
+        let defaults = SearchConfig {
+            anchored_start: self.anchored_start,
+            anchored_end: self.anchored_end,
+            leftmost_first: self.leftmost_first,
+            ..SearchConfig::default()
+        };
+        if defaults == SearchConfig::default() {
+            Ok(eng)
+        } else {
+            Ok(Box::new(WithDefaults { eng, defaults }))
+        }
+    }
+}
+
+// An engine that turns on some options for every search.
+#[derive(Debug)]
+struct WithDefaults {
+    eng: Box<dyn Engine>,
+    defaults: SearchConfig,
+}
+
+impl Engine for WithDefaults {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        let config = SearchConfig {
+            anchored_start: config.anchored_start || self.defaults.anchored_start,
+            anchored_end: config.anchored_end || self.defaults.anchored_end,
+            leftmost_first: config.leftmost_first || self.defaults.leftmost_first,
+            ..*config
+        };
+        self.eng.search(input, &config)
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(WithDefaults { eng: self.eng.clone_box(), defaults: self.defaults })
+    }
+
+    fn explain(&self, input: &[u8], m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        self.eng.explain(input, m)
+    }
+
+    fn matches_prefix(&self, input: &[u8]) -> Option<bool> {
+        self.eng.matches_prefix(input)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.eng.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use builder::{EngineBuilder, EngineKind};
+    use error::BuildError;
+    use prefix::Prefix;
+    use program::Instructions;
+    use test_util::lits_prog;
+
+    #[test]
+    fn test_kinds() {
+        let prog = lits_prog(&["ab", "cd"]);
+        let rev = lits_prog(&["ba", "dc"]);
+        for &kind in &[EngineKind::Backtracking, EngineKind::Threaded, EngineKind::TwoPass,
+                       EngineKind::Simple] {
+            let eng = EngineBuilder::new(prog.clone())
+                .with_reverse_program(rev.clone())
+                .with_kind(kind)
+                .build()
+                .unwrap();
+            assert_eq!(eng.shortest_match("xxcdab"), Some((2, 4)), "{:?}", kind);
+            assert!(eng.memory_usage() >= prog.memory_usage());
+            let eng = EngineBuilder::new(prog.clone())
+                .with_prefix(Prefix::Empty)
+                .with_kind(kind)
+                .build();
+            match kind {
+                EngineKind::TwoPass => assert_eq!(eng.unwrap_err(), BuildError::NoReverseProgram),
+                _ => assert_eq!(eng.unwrap().shortest_match("xxcdab"), Some((2, 4))),
+            }
+        }
+
+        // With a reverse program, the default is the two-pass engine.
+        let eng = EngineBuilder::new(prog.clone()).with_reverse_program(rev).build().unwrap();
+        assert!(format!("{:?}", eng).starts_with("TwoPassEngine"));
+        assert_eq!(eng.shortest_match("xxcdab"), Some((2, 4)));
+        let eng = EngineBuilder::new(prog.clone()).build().unwrap();
+        assert!(format!("{:?}", eng).starts_with("BacktrackingEngine"));
+
+        let mut bad = prog.clone();
+        bad.accept_at_eoi.pop();
+        assert!(matches!(EngineBuilder::new(bad).build(), Err(BuildError::Program(_))));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let eng = EngineBuilder::new(lits_prog(&["ab", "cd"]))
+            .with_kind(EngineKind::Threaded)
+            .with_anchored_start(true)
+            .build()
+            .unwrap();
+        assert_eq!(eng.shortest_match("xcd"), None);
+        assert_eq!(eng.shortest_match("cdx"), Some((0, 2)));
+        assert_eq!(eng.clone_box().shortest_match("abcd"), Some((0, 2)));
+        assert!(eng.save(&mut Vec::new()).is_err());
+
+        let eng = EngineBuilder::new(lits_prog(&["ab", "cd"]))
+            .with_anchored_end(true)
+            .build()
+            .unwrap();
+        assert_eq!(eng.shortest_match("abcdx"), None);
+        assert_eq!(eng.shortest_match("abxcd"), Some((3, 5)));
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::fmt::{Debug, Formatter, Error as FmtError};
+use std::iter::FromIterator;
+
+/// A set of bytes, stored as a 256-bit bitset.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    /// Creates an empty set.
+    pub fn new() -> ByteSet {
+        ByteSet([0; 4])
+    }
+
+    /// Creates a set containing every byte.
+    pub fn full() -> ByteSet {
+        ByteSet([!0; 4])
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, b: u8) -> bool {
+        (self.0[(b >> 6) as usize] >> (b & 63)) & 1 != 0
+    }
+
+    pub fn insert(&mut self, b: u8) {
+        self.0[(b >> 6) as usize] |= 1 << (b & 63);
+    }
+
+    pub fn remove(&mut self, b: u8) {
+        self.0[(b >> 6) as usize] &= !(1 << (b & 63));
+    }
+
+    /// The number of bytes in this set.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// Returns the set of bytes that are not in this set.
+    pub fn complement(&self) -> ByteSet {
+        ByteSet([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+
+    /// Iterates over the bytes in this set, in increasing order.
+    pub fn iter(&self) -> Iter {
+        Iter {
+            set: *self,
+            next: 0,
+        }
+    }
+}
+
+impl FromIterator<u8> for ByteSet {
+    fn from_iter<I: IntoIterator<Item=u8>>(iter: I) -> ByteSet {
+        let mut ret = ByteSet::new();
+        for b in iter {
+            ret.insert(b);
+        }
+        ret
+    }
+}
+
+impl IntoIterator for &ByteSet {
+    type Item = u8;
+    type IntoIter = Iter;
+    fn into_iter(self) -> Iter {
+        self.iter()
+    }
+}
+
+impl Debug for ByteSet {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// An iterator over the bytes in a `ByteSet`.
+pub struct Iter {
+    set: ByteSet,
+    next: usize,
+}
+
+impl Iterator for Iter {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        while self.next < 256 {
+            let b = self.next as u8;
+            self.next += 1;
+            if self.set.contains(b) {
+                return Some(b);
+            }
+        }
+        None
+    }
+}
+
+/// A partition of the set of bytes into classes, such that bytes in the same class are
+/// indistinguishable by some `TableInsts`.
+///
+/// A `TableInsts` only stores one transition per class, so the number of classes is the stride
+/// of its table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteClasses {
+    classes: [u8; 256],
+    num_classes: usize,
+    // True if `classes[b] == b` for every `b`.
+    singletons: bool,
+}
+
+impl ByteClasses {
+    /// Creates the partition that puts byte `b` in class `classes[b]`.
+    ///
+    /// The number of classes is one more than the largest class. It's fine if some classes are
+    /// empty, but they still take up space in the tables.
+    pub const fn new(classes: [u8; 256]) -> ByteClasses {
+        let mut max = 0;
+        let mut singletons = true;
+        let mut b = 0;
+        while b < 256 {
+            if classes[b] > max {
+                max = classes[b];
+            }
+            singletons &= classes[b] as usize == b;
+            b += 1;
+        }
+        ByteClasses {
+            classes,
+            num_classes: max as usize + 1,
+            singletons,
+        }
+    }
+
+    /// The partition that puts every byte in its own class.
+    pub const fn singletons() -> ByteClasses {
+        let mut classes = [0; 256];
+        let mut b = 0;
+        while b < 256 {
+            classes[b] = b as u8;
+            b += 1;
+        }
+        ByteClasses::new(classes)
+    }
+
+    /// Returns the class that `b` belongs to.
+    #[inline(always)]
+    pub fn class(&self, b: u8) -> usize {
+        self.classes[b as usize] as usize
+    }
+
+    /// The number of classes.
+    pub fn num_classes(&self) -> usize {
+        self.num_classes
+    }
+
+    /// Returns true if every byte is in its own class, and that class is the byte itself.
+    #[inline(always)]
+    pub fn is_singletons(&self) -> bool {
+        self.singletons
+    }
+
+    /// The class of every byte, as in `ByteClasses::new`.
+    pub fn as_array(&self) -> &[u8; 256] {
+        &self.classes
+    }
+
+    /// Returns one byte from each (non-empty) class, ordered by class.
+    pub fn representatives(&self) -> Vec<u8> {
+        let mut ret = vec![None; self.num_classes];
+        for b in 0..256 {
+            let c = self.class(b as u8);
+            if ret[c].is_none() {
+                ret[c] = Some(b as u8);
+            }
+        }
+        ret.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "std"))]
+    use std::prelude::v1::*;
+    use byte_set::{ByteClasses, ByteSet};
+
+    #[test]
+    fn test_byte_set() {
+        let mut set: ByteSet = b"aeiou".iter().cloned().collect();
+        assert_eq!(set.len(), 5);
+        assert!(set.contains(b'a'));
+        assert!(!set.contains(b'b'));
+
+        set.insert(255);
+        set.insert(0);
+        set.remove(b'e');
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0, b'a', b'i', b'o', b'u', 255]);
+        assert_eq!(set.complement().len(), 250);
+        assert!(ByteSet::new().is_empty());
+        assert_eq!(ByteSet::full().len(), 256);
+    }
+
+    #[test]
+    fn test_byte_classes() {
+        let mut classes = [0; 256];
+        classes[b'a' as usize] = 1;
+        classes[b'z' as usize] = 3;
+        let classes = ByteClasses::new(classes);
+        assert_eq!(classes.num_classes(), 4);
+        assert_eq!(classes.class(b'a'), 1);
+        assert_eq!(classes.class(b'b'), 0);
+        // Class 2 is empty.
+        assert_eq!(classes.representatives(), vec![0, b'a', b'z']);
+        assert!(!classes.is_singletons());
+        assert!(ByteClasses::singletons().is_singletons());
+        assert_eq!(ByteClasses::singletons().class(200), 200);
+
+        // 256 classes, but not in the order of the bytes.
+        let mut swapped = *ByteClasses::singletons().as_array();
+        swapped.swap(b'a' as usize, b'b' as usize);
+        let swapped = ByteClasses::new(swapped);
+        assert_eq!(swapped.num_classes(), 256);
+        assert!(!swapped.is_singletons());
+    }
+}
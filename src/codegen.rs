@@ -0,0 +1,156 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Generating Rust source for programs, so that they can be built ahead of time.
+//!
+//! This is meant to be used from a build script: build the program there, write it to a file in
+//! `OUT_DIR` with `write_program`, and `include!` that file. The tables end up as `static` arrays
+//! in the binary, so nothing needs to be compiled at startup (and with a `TableRef`, they don't
+//! even need to be copied).
+
+use program::{InitStates, Program, TableInsts};
+use std::fmt::Display;
+use std::io::{self, Write};
+
+// How many numbers to write on each line of an array.
+const PER_LINE: usize = 16;
+
+/// Writes Rust source defining `static` arrays holding the tables of `prog`, and a function
+/// called `name` that returns `prog`.
+///
+/// The arrays are named after `name`, in upper case: for example, `NAME_TABLE`. The function
+/// copies them into a new `Program<TableInsts>`. There is also a function called `name_ref`
+/// returning a `Program<TableRef<'static>>` that borrows the tables instead (it only copies the
+/// small `accept_at_eoi` array). The generated code refers to this crate as `::dfa_runner`.
+///
+/// If the table has one column per byte class (see `TableInsts::compress`), there is also a
+/// `static` called `NAME_CLASSES` holding the classes.
+pub fn write_program<W: Write>(w: &mut W, name: &str, prog: &Program<TableInsts>)
+-> io::Result<()> {
+    assert!(is_ident(name), "not an identifier: {}", name);
+    let upper = name.to_uppercase();
+    let insts = &prog.instructions;
+
+    write_array(w, &format!("{}_TABLE", upper), "u32", &insts.table)?;
+    write_array(w, &format!("{}_ACCEPT", upper), "usize", &usizes(&insts.accept))?;
+    write_array(w, &format!("{}_ACCEPT_AT_EOI", upper), "usize", &usizes(&prog.accept_at_eoi))?;
+    if insts.classes.is_singletons() {
+        write_fn(w, name, "TableInsts", &upper, "TableInsts::new(\
+                 {0}_TABLE.to_vec(), {0}_ACCEPT.to_vec())", &prog.init)?;
+        write_fn(w, &format!("{}_ref", name), "TableRef<'static>", &upper, "TableRef::new(\
+                 &{0}_TABLE, &{0}_ACCEPT)", &prog.init)
+    } else {
+        write_classes(w, &format!("{}_CLASSES", upper), insts.classes.as_array())?;
+        write_fn(w, name, "TableInsts", &upper, "TableInsts::new(\
+                 {0}_TABLE.to_vec(), {0}_ACCEPT.to_vec()).with_classes({0}_CLASSES.clone())",
+                 &prog.init)?;
+        write_fn(w, &format!("{}_ref", name), "TableRef<'static>", &upper, "TableRef::new(\
+                 &{0}_TABLE, &{0}_ACCEPT).with_classes(&{0}_CLASSES)", &prog.init)
+    }
+}
+
+// Writes a function called `name` returning a `Program<insts_ty>`, whose instructions are given
+// by `insts` (with `{0}` standing for the upper-case name of the arrays).
+fn write_fn<W: Write>(w: &mut W, name: &str, insts_ty: &str, upper: &str, insts: &str,
+                      init: &InitStates)
+-> io::Result<()> {
+    writeln!(w, "pub fn {}()\n\
+                 -> ::dfa_runner::program::Program<::dfa_runner::program::{}> {{", name, insts_ty)?;
+    writeln!(w, "    ::dfa_runner::program::Program {{")?;
+    writeln!(w, "        accept_at_eoi: {}_ACCEPT_AT_EOI.to_vec(),", upper)?;
+    writeln!(w, "        instructions: ::dfa_runner::program::{},", insts.replace("{0}", upper))?;
+    writeln!(w, "        init: ::dfa_runner::program::InitStates::{},", init_expr(init))?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "}}")
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Formats some `usize`s, writing our `usize::MAX` sentinel so that it works on any platform.
+fn usizes(xs: &[usize]) -> Vec<String> {
+    xs.iter().map(|&x| if x == usize::MAX { "usize::MAX".to_owned() } else { x.to_string() })
+        .collect()
+}
+
+fn write_array<W: Write, T: Display>(w: &mut W, name: &str, ty: &str, xs: &[T])
+-> io::Result<()> {
+    writeln!(w, "pub static {}: [{}; {}] = [", name, ty, xs.len())?;
+    for chunk in xs.chunks(PER_LINE) {
+        let line: Vec<String> = chunk.iter().map(|x| x.to_string()).collect();
+        writeln!(w, "    {},", line.join(", "))?;
+    }
+    writeln!(w, "];")
+}
+
+// Writes a `static` `ByteClasses`, which is built at compile time from the class of each byte.
+fn write_classes<W: Write>(w: &mut W, name: &str, classes: &[u8; 256]) -> io::Result<()> {
+    writeln!(w, "pub static {}: ::dfa_runner::byte_set::ByteClasses = \
+                 ::dfa_runner::byte_set::ByteClasses::new([", name)?;
+    for chunk in classes.chunks(PER_LINE) {
+        let line: Vec<String> = chunk.iter().map(|x| x.to_string()).collect();
+        writeln!(w, "    {},", line.join(", "))?;
+    }
+    writeln!(w, "]);")
+}
+
+// The expression (without the `InitStates::`) for `init`.
+fn init_expr(init: &InitStates) -> String {
+    match *init {
+        InitStates::Anchored(s) => format!("Anchored({})", s),
+        InitStates::Constant(s) => format!("Constant({})", s),
+        InitStates::AfterByte(b, s) => format!("AfterByte({}, {})", b, s),
+        InitStates::Contextual(b, s, t) => format!("Contextual({}, {}, {})", b, s, t),
+        InitStates::ByPrevByte(s, ref table) => {
+            let table: Vec<String> = table.iter().map(|x| x.to_string()).collect();
+            format!("ByPrevByte({}, vec![{}])", s, table.join(", "))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codegen::write_program;
+    use program::{InitStates, Program};
+    use test_util::lit_prog;
+
+    #[test]
+    fn test_write_program() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::AfterByte(b'\n', 1);
+        let mut buf = Vec::new();
+        write_program(&mut buf, "ab_prog", &prog).unwrap();
+        let src = String::from_utf8(buf).unwrap();
+
+        let table_len = prog.instructions.table.len();
+        assert!(src.contains(&format!("pub static AB_PROG_TABLE: [u32; {}] = [", table_len)));
+        assert!(src.contains("pub static AB_PROG_ACCEPT: [usize; 4] = [\n    usize::MAX, "));
+        assert!(src.contains("pub fn ab_prog()\n-> ::dfa_runner::program::Program<"));
+        assert!(src.contains("init: ::dfa_runner::program::InitStates::AfterByte(10, 1),"));
+        assert!(src.contains("pub fn ab_prog_ref()\n\
+                              -> ::dfa_runner::program::Program<\
+                              ::dfa_runner::program::TableRef<'static>> {"));
+        assert!(src.contains("TableRef::new(&AB_PROG_TABLE, &AB_PROG_ACCEPT),"));
+        assert_eq!(src.matches('[').count(), src.matches(']').count());
+        assert!(!src.contains("CLASSES"));
+
+        let mut buf = Vec::new();
+        let compressed = Program { instructions: prog.instructions.compress(), ..prog };
+        write_program(&mut buf, "ab_prog", &compressed).unwrap();
+        let src = String::from_utf8(buf).unwrap();
+        assert!(src.contains("pub static AB_PROG_TABLE: [u32; 12] = ["));
+        assert!(src.contains("pub static AB_PROG_CLASSES: ::dfa_runner::byte_set::ByteClasses = \
+                              ::dfa_runner::byte_set::ByteClasses::new([\n    0, 0, "));
+        assert!(src.contains("TableRef::new(&AB_PROG_TABLE, &AB_PROG_ACCEPT)\
+                              .with_classes(&AB_PROG_CLASSES),"));
+        assert_eq!(src.matches('[').count(), src.matches(']').count());
+    }
+}
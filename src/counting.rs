@@ -0,0 +1,193 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use prefix::{Prefix, PrefixSearcherFactory};
+use program::{Instructions, Program};
+
+/// An engine that counts matches, without finding out where they are.
+///
+/// Since it doesn't keep track of where matches start, this engine counts matches in the order
+/// in which they end: it finds the match that ends first, then the match that ends first among
+/// those starting after it, and so on. This can give a different answer from repeatedly calling
+/// `Engine::shortest_match`, which prefers matches that start first. For example, if the program
+/// matches "abcde", "bc" and "de" then the input "abcde" has two matches for this engine, but
+/// only one for `shortest_match`.
+#[derive(Clone, Debug)]
+pub struct CountingEngine<Insts: Instructions, P: PrefixSearcherFactory = Prefix> {
+    prog: Program<Insts>,
+    prefix: P,
+}
+
+impl<Insts: Instructions, P: PrefixSearcherFactory> CountingEngine<Insts, P> {
+    pub fn new(prog: Program<Insts>, pref: P) -> CountingEngine<Insts, P> {
+        CountingEngine {
+            prog,
+            prefix: pref,
+        }
+    }
+
+    /// Counts the non-overlapping matches in `input`.
+    pub fn count(&self, input: &[u8]) -> usize {
+        let n = self.prog.num_states();
+        let mut cur = StateSet::new(n);
+        let mut next = StateSet::new(n);
+        let mut count = 0;
+        let anchored = self.prog.init.anchored().is_some();
+
+        self.prefix.with_searcher(input, &mut |searcher| {
+            let mut pos = 0;
+            loop {
+                // If there are no threads, any match that we find here is empty.
+                let fresh = cur.states.is_empty();
+                if fresh && !anchored {
+                    pos = searcher.search_from(pos)?.start_pos;
+                }
+                // The thread that starts here, unless one was already in its state.
+                let mut started = None;
+                if !anchored || pos == 0 {
+                    if let Some(state) = self.prog.init.state_at_pos(input, pos) {
+                        if !cur.present[state] {
+                            started = Some(state);
+                        }
+                        cur.insert(state);
+                    }
+                }
+
+                let at_end = pos == input.len();
+                let accepting = |s: usize| {
+                    if at_end { self.prog.check_eoi(s).is_some() } else { self.prog.accept(s).is_some() }
+                };
+                if cur.states.iter().any(|&s| accepting(s)) {
+                    // If only the thread that started here accepts, the match is empty.
+                    let empty = cur.states.iter().all(|&s| !accepting(s) || Some(s) == started);
+                    count += 1;
+                    cur.clear();
+                    if anchored || (empty && at_end) {
+                        return None;
+                    } else if empty {
+                        // Don't find the same empty match again.
+                        pos += 1;
+                    }
+                    continue;
+                }
+                if at_end {
+                    return None;
+                }
+
+                let b = input[pos];
+                for &s in &cur.states {
+                    if let (Some(t), _) = self.prog.step_byte(s, b) {
+                        next.insert(t);
+                    }
+                }
+                cur.clear();
+                ::std::mem::swap(&mut cur, &mut next);
+                pos += 1;
+                if cur.states.is_empty() && anchored {
+                    return None;
+                }
+            }
+        });
+        count
+    }
+}
+
+// A set of states that can be cleared in time proportional to its size.
+struct StateSet {
+    states: Vec<usize>,
+    present: Vec<bool>,
+}
+
+impl StateSet {
+    fn new(n: usize) -> StateSet {
+        StateSet {
+            states: Vec::new(),
+            present: vec![false; n],
+        }
+    }
+
+    fn insert(&mut self, s: usize) {
+        if !self.present[s] {
+            self.present[s] = true;
+            self.states.push(s);
+        }
+    }
+
+    fn clear(&mut self) {
+        for &s in &self.states {
+            self.present[s] = false;
+        }
+        self.states.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use counting::CountingEngine;
+    use nfa::{DeterminizeConfig, Nfa};
+    use prefix::Prefix;
+    use program::InitStates;
+    use test_util::{lit_prog, lits_prog, table_prog};
+    use {Engine, SearchConfig};
+
+    #[test]
+    fn test_count() {
+        let prog = lits_prog(&["abcde", "bc", "de"]);
+        let eng = CountingEngine::new(prog.clone(), Prefix::Empty);
+        assert_eq!(eng.count(b"abcde"), 2);
+        assert_eq!(eng.count(b"xbcxbcbc"), 3);
+        assert_eq!(eng.count(b""), 0);
+
+        let eng = CountingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.count(b"xxxbcxxxxdexx"), 2);
+
+        // Matches don't overlap.
+        let eng = CountingEngine::new(lit_prog("aa"), Prefix::Empty);
+        assert_eq!(eng.count(b"aaaaa"), 2);
+    }
+
+    #[test]
+    fn test_empty_matches() {
+        // Matches the empty string, and "a".
+        let eng = CountingEngine::new(table_prog(3, &[(1, b'a', 2)], &[1, 2]), Prefix::Empty);
+        assert_eq!(eng.count(b"xyz"), 4);
+        assert_eq!(eng.count(b""), 1);
+
+        // "\b|a+c": the empty match at the end of "aa" is found while the thread for "a+c" is
+        // still running, and it should only be counted once.
+        let mut nfa = Nfa::new();
+        let (boundary, a_plus) = (nfa.add_state(), nfa.add_state());
+        nfa.add_eps(0, boundary);
+        nfa.add_eps(0, a_plus);
+        let end = nfa.add_state();
+        nfa.states[boundary].word_boundary_eps.push(end);
+        nfa.states[end].accept = true;
+        let a = nfa.add_literal(a_plus, b"a");
+        nfa.add_eps(a, a_plus);
+        let c = nfa.add_literal(a, b"c");
+        nfa.states[c].accept = true;
+        let prog = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+        let eng = CountingEngine::new(prog.clone(), Prefix::Empty);
+        let bt = BacktrackingEngine::new(prog, Prefix::Empty);
+        for input in &["aa", "aac", "a a", "a", ""] {
+            let matches = (&bt as &dyn Engine).matches(input.as_bytes(), &SearchConfig::default());
+            assert_eq!(eng.count(input.as_bytes()), matches.count(), "{:?}", input);
+        }
+        assert_eq!(eng.count(b"aa"), 2);
+    }
+
+    #[test]
+    fn test_anchored() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::Anchored(1);
+        let eng = CountingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.count(b"abab"), 1);
+        assert_eq!(eng.count(b"xab"), 0);
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checking that several engines agree on many inputs.
+//!
+//! This is only available with the `differential` feature, and is meant for tests: build the
+//! engines that should agree, generate some inputs, and `run` them all. When two engines disagree,
+//! the input is shrunk as far as possible before it is reported.
+
+use {Engine, SearchConfig};
+use backtracking::BacktrackingEngine;
+use prefix::Prefix;
+use program::{Program, TableInsts};
+use sample::{Rng, XorShift};
+use std::fmt::{Display, Formatter, Error as FmtError};
+use threaded::ThreadedEngine;
+
+/// An input on which some engines gave different results.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// The (minimized) input.
+    pub input: Vec<u8>,
+    /// The options that the input was searched with.
+    pub config: SearchConfig,
+    /// The name of each engine, and what it returned.
+    pub results: Vec<(String, Option<(usize, usize)>)>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        writeln!(f, "engines disagree on {:?} with {:?}:",
+                 String::from_utf8_lossy(&self.input), self.config)?;
+        for &(ref name, res) in &self.results {
+            writeln!(f, "    {}: {:?}", name, res)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs some engines over generated inputs, and checks that they all return the same thing.
+#[derive(Debug)]
+pub struct Differential {
+    engines: Vec<(String, Box<dyn Engine>)>,
+    configs: Vec<SearchConfig>,
+    alphabet: Vec<u8>,
+    max_len: usize,
+    random: usize,
+    seed: u64,
+}
+
+impl Default for Differential {
+    fn default() -> Differential {
+        let anchored_start = SearchConfig { anchored_start: true, ..SearchConfig::default() };
+        let anchored_end = SearchConfig { anchored_end: true, ..SearchConfig::default() };
+        let anchored = SearchConfig { anchored_end: true, ..anchored_start };
+        Differential {
+            engines: Vec::new(),
+            configs: vec![SearchConfig::default(), anchored_start, anchored_end, anchored],
+            alphabet: b"ab".to_vec(),
+            max_len: 6,
+            random: 0,
+            seed: 1,
+        }
+    }
+}
+
+impl Differential {
+    pub fn new() -> Differential {
+        Differential::default()
+    }
+
+    /// Compares the `BacktrackingEngine` and the `ThreadedEngine` running `prog`, with and
+    /// without a prefix.
+    pub fn for_program(prog: &Program<TableInsts>) -> Differential {
+        let pref = Prefix::from_program(prog);
+        let bt = |p: &Prefix| Box::new(BacktrackingEngine::new(prog.clone(), p.clone()));
+        let th = |p: &Prefix| Box::new(ThreadedEngine::new(prog.clone(), p.clone()));
+        Differential::new()
+            .with_engine("backtracking", bt(&Prefix::Empty))
+            .with_engine("threaded", th(&Prefix::Empty))
+            .with_engine("backtracking+prefix", bt(&pref))
+            .with_engine("threaded+prefix", th(&pref))
+    }
+
+    /// Adds another engine to the comparison.
+    pub fn with_engine(mut self, name: &str, eng: Box<dyn Engine>) -> Differential {
+        self.engines.push((name.to_owned(), eng));
+        self
+    }
+
+    /// Searches every input with each of `configs`, instead of with the default ones (which try
+    /// all the combinations of `anchored_start` and `anchored_end`).
+    pub fn with_configs(mut self, configs: Vec<SearchConfig>) -> Differential {
+        self.configs = configs;
+        self
+    }
+
+    /// Generates inputs from these bytes. The default is "ab".
+    pub fn with_alphabet(mut self, alphabet: &[u8]) -> Differential {
+        self.alphabet = alphabet.to_vec();
+        self
+    }
+
+    /// Tries every input of at most this length. The default is 6.
+    pub fn with_max_len(mut self, max_len: usize) -> Differential {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Also tries `count` random inputs, each up to four times as long as the maximum length.
+    ///
+    /// The inputs only depend on `seed`, so failures can be reproduced.
+    pub fn with_random(mut self, count: usize, seed: u64) -> Differential {
+        self.random = count;
+        self.seed = seed;
+        self
+    }
+
+    /// Runs all the inputs, and returns the first one (after minimizing it) on which the engines
+    /// disagreed.
+    pub fn run(&self) -> Result<(), Divergence> {
+        if let Some(input) = self.exhaustive().find(|i| self.diverges(i).is_some()) {
+            return Err(self.minimize(input));
+        }
+        let mut rng = XorShift::new(self.seed);
+        for _ in 0..self.random {
+            let len = rng.below(self.max_len * 4 + 1);
+            let input: Vec<u8> =
+                (0..len).map(|_| self.alphabet[rng.below(self.alphabet.len())]).collect();
+            if self.diverges(&input).is_some() {
+                return Err(self.minimize(input));
+            }
+        }
+        Ok(())
+    }
+
+    // Iterates over all the inputs of length at most `max_len`, shortest first.
+    fn exhaustive<'a>(&'a self) -> impl Iterator<Item=Vec<u8>> + 'a {
+        let alpha = &self.alphabet;
+        (0..=self.max_len).flat_map(move |len| {
+            let count = alpha.len().checked_pow(len as u32).unwrap_or(usize::MAX);
+            (0..count).map(move |mut idx| {
+                (0..len).map(|_| {
+                    let b = alpha[idx % alpha.len()];
+                    idx /= alpha.len();
+                    b
+                }).collect()
+            })
+        })
+    }
+
+    // If the engines disagree on `input`, returns the config that they disagree with.
+    fn diverges(&self, input: &[u8]) -> Option<SearchConfig> {
+        self.configs.iter().cloned().find(|config| {
+            let mut results = self.engines.iter().map(|e| e.1.search(input, config));
+            results.next().is_some_and(|first| results.any(|r| r != first))
+        })
+    }
+
+    // Shrinks `input`, by removing chunks of it and then by replacing bytes with the first byte
+    // of the alphabet, for as long as the engines still disagree.
+    fn minimize(&self, mut input: Vec<u8>) -> Divergence {
+        let mut chunk = input.len().max(1);
+        while chunk > 0 {
+            let mut i = 0;
+            while i + chunk <= input.len() {
+                let mut smaller = input.clone();
+                smaller.drain(i..i + chunk);
+                if self.diverges(&smaller).is_some() {
+                    input = smaller;
+                } else {
+                    i += 1;
+                }
+            }
+            chunk /= 2;
+        }
+        let simplest = self.alphabet[0];
+        for i in 0..input.len() {
+            let old = input[i];
+            input[i] = simplest;
+            if self.diverges(&input).is_none() {
+                input[i] = old;
+            }
+        }
+
+        let config = self.diverges(&input).expect("the input stopped diverging");
+        let results = self.engines.iter()
+            .map(|(name, eng)| (name.clone(), eng.search(&input, &config)))
+            .collect();
+        Divergence {
+            input,
+            config,
+            results,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Engine, SearchConfig};
+    use backtracking::BacktrackingEngine;
+    use differential::Differential;
+    use prefix::Prefix;
+    use program::{Program, TableInsts};
+    use test_util::lits_prog;
+
+    // An engine that gets things wrong whenever the input contains a 'c'.
+    #[derive(Clone, Debug)]
+    struct Broken(BacktrackingEngine<TableInsts, Prefix>);
+
+    impl Engine for Broken {
+        fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+            if input.contains(&b'c') { None } else { self.0.search(input, config) }
+        }
+
+        fn clone_box(&self) -> Box<dyn Engine> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn broken(prog: &Program<TableInsts>) -> Box<dyn Engine> {
+        Box::new(Broken(BacktrackingEngine::new(prog.clone(), Prefix::Empty)))
+    }
+
+    #[test]
+    fn test_agree() {
+        let prog = lits_prog(&["ab", "ba", "aaa"]);
+        assert_eq!(Differential::for_program(&prog).with_random(100, 7).run(), Ok(()));
+    }
+
+    #[test]
+    fn test_minimize() {
+        let prog = lits_prog(&["cd"]);
+        let diff = Differential::for_program(&prog)
+            .with_engine("broken", broken(&prog))
+            .with_alphabet(b"abcd");
+        let div = diff.run().unwrap_err();
+        // The shortest input where "broken" is wrong.
+        assert_eq!(div.input, b"cd");
+        assert_eq!(div.config, SearchConfig::default());
+        assert_eq!(div.results[0].1, Some((0, 2)));
+        assert_eq!(div.results[4], ("broken".to_owned(), None));
+
+        // Random inputs get shrunk too (the exhaustive ones are too short to find anything here).
+        let div = diff.with_max_len(1).with_random(100, 3).run().unwrap_err();
+        assert_eq!(div.input, b"cd");
+    }
+}
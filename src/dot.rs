@@ -0,0 +1,226 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Drawing prefixes in the DOT language, for viewing with Graphviz.
+
+use aho_corasick::Automaton;
+use byte_set::ByteSet;
+use prefix::{AcMatchKind, Prefix};
+use std::io::{self, Write};
+
+/// Writes a DOT graph showing what `pref` looks for.
+///
+/// Candidates are found by following the edges from the start node to a node drawn with a
+/// double circle. Literals become chains of nodes, the strings of an Aho-Corasick prefix become a
+/// trie (whose leaves are labelled with the DFA state that each string leads to), and sets of
+/// bytes become edges labelled with the bytes in the set.
+pub fn write_prefix<W: Write>(w: &mut W, pref: &Prefix) -> io::Result<()> {
+    let mut g = Graph { nodes: vec![String::new()], edges: Vec::new() };
+    let title = g.add(pref, 0);
+    writeln!(w, "digraph prefix {{")?;
+    writeln!(w, "    label=\"{}\";", title)?;
+    writeln!(w, "    start [shape=point];")?;
+    writeln!(w, "    start -> n0;")?;
+    for (i, attrs) in g.nodes.iter().enumerate() {
+        writeln!(w, "    n{} [label=\"\"{}];", i, attrs)?;
+    }
+    for &(from, to, ref label) in &g.edges {
+        writeln!(w, "    n{} -> n{} [label=\"{}\"];", from, to, label)?;
+    }
+    writeln!(w, "}}")
+}
+
+// The nodes (given by their extra attributes) and edges of a graph that we're building.
+struct Graph {
+    nodes: Vec<String>,
+    edges: Vec<(usize, usize, String)>,
+}
+
+impl Graph {
+    fn node(&mut self) -> usize {
+        self.nodes.push(String::new());
+        self.nodes.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, label: String) -> usize {
+        let to = self.node();
+        self.edges.push((from, to, label));
+        to
+    }
+
+    fn accept(&mut self, node: usize) {
+        self.nodes[node].push_str(", shape=doublecircle");
+    }
+
+    // Adds a chain of nodes for `lit`, starting at `from`, and returns the last one.
+    fn lit(&mut self, from: usize, lit: &[u8], label: &dyn Fn(usize, u8) -> String) -> usize {
+        lit.iter().enumerate().fold(from, |cur, (i, &b)| self.edge(cur, label(i, b)))
+    }
+
+    // Adds the nodes for `pref`, starting at `from`, and returns a description of it.
+    fn add(&mut self, pref: &Prefix, from: usize) -> String {
+        let plain = |_, b| byte_label(b);
+        match *pref {
+            Prefix::Empty => {
+                self.accept(from);
+                "every position".to_owned()
+            },
+            Prefix::ByteSet(ref bs) => self.set(from, bs, "a byte in a set"),
+            Prefix::Byte(b) => self.set(from, &Some(b).into_iter().collect(), "a byte"),
+            Prefix::Byte2(b1, b2) => self.set(from, &[b1, b2].iter().cloned().collect(), "2 bytes"),
+            Prefix::Byte3(b1, b2, b3) =>
+                self.set(from, &[b1, b2, b3].iter().cloned().collect(), "3 bytes"),
+            Prefix::Lit(ref lit) => {
+                let end = self.lit(from, lit, &plain);
+                self.accept(end);
+                "a literal".to_owned()
+            },
+            Prefix::LitRare(ref lit, off) => {
+                let rare = |i, b| {
+                    if i == off { format!("{} (rare)", byte_label(b)) } else { byte_label(b) }
+                };
+                let end = self.lit(from, lit, &rare);
+                self.accept(end);
+                "a literal, found by its rarest byte".to_owned()
+            },
+            Prefix::LitCaseless(ref lit) => {
+                let caseless = |_, b: u8| if b.is_ascii_alphabetic() {
+                    format!("{}/{}", byte_label(b), byte_label(b.to_ascii_uppercase()))
+                } else {
+                    byte_label(b)
+                };
+                let end = self.lit(from, lit, &caseless);
+                self.accept(end);
+                "a literal, ignoring case".to_owned()
+            },
+            Prefix::Ac(ref ac, ref map, kind) => {
+                self.trie(from, ac.patterns(), map);
+                match kind {
+                    AcMatchKind::Overlapping => "several literals, overlapping".to_owned(),
+                    AcMatchKind::NonOverlapping => "several literals".to_owned(),
+                }
+            },
+            Prefix::LoopWhile(ref bs, min) => {
+                self.edges.push((from, from, set_label(bs)));
+                self.accept(from);
+                if min == 0 {
+                    "the start of each run of bytes in a set".to_owned()
+                } else {
+                    format!("positions followed by at least {} bytes in a set", min)
+                }
+            },
+            Prefix::InnerLit(ref lit, ref bs) => {
+                // We find the literal first, and then scan backwards through the set.
+                self.edges.push((from, from, set_label(bs)));
+                let end = self.lit(from, lit, &plain);
+                self.accept(end);
+                "a literal inside the match, preceded by bytes in a set".to_owned()
+            },
+            Prefix::Exact(ref inner) => format!("{} (the exact matches)", self.add(inner, from)),
+            Prefix::LitThenSet(ref lit, ref bs) => {
+                let end = self.lit(from, lit, &plain);
+                let end = self.edge(end, format!("followed by {}", set_label(bs)));
+                self.accept(end);
+                "a literal, followed by a byte in a set".to_owned()
+            },
+            Prefix::AfterByte(b) => {
+                let end = self.edge(from, format!("start of input, or after {}", byte_label(b)));
+                self.accept(end);
+                "positions after a byte".to_owned()
+            },
+        }
+    }
+
+    fn set(&mut self, from: usize, bs: &ByteSet, desc: &str) -> String {
+        let end = self.edge(from, set_label(bs));
+        self.accept(end);
+        desc.to_owned()
+    }
+
+    // Adds a trie of `strings`, where string `i` leads to the DFA state `states[i]`.
+    fn trie(&mut self, root: usize, strings: &[Vec<u8>], states: &[usize]) {
+        // The children of each node, by the byte leading to them.
+        let mut children: Vec<(usize, u8, usize)> = Vec::new();
+        for (s, &state) in strings.iter().zip(states) {
+            let mut cur = root;
+            for &b in s {
+                cur = match children.iter().find(|c| c.0 == cur && c.1 == b) {
+                    Some(c) => c.2,
+                    None => {
+                        let next = self.edge(cur, byte_label(b));
+                        children.push((cur, b, next));
+                        next
+                    },
+                };
+            }
+            self.accept(cur);
+            self.nodes[cur] = format!(", xlabel=\"state {}\"{}", state, self.nodes[cur]);
+        }
+    }
+}
+
+// Describes a byte, escaped for a DOT string.
+fn byte_label(b: u8) -> String {
+    match b {
+        b'"' => "\\\"".to_owned(),
+        b'\\' => "\\\\".to_owned(),
+        b' '..=b'~' => (b as char).to_string(),
+        _ => format!("\\\\x{:02x}", b),
+    }
+}
+
+// Describes a set of bytes as a character class, merging runs of consecutive bytes.
+fn set_label(bs: &ByteSet) -> String {
+    let bytes: Vec<u8> = bs.iter().collect();
+    let mut ret = "[".to_owned();
+    let mut i = 0;
+    while i < bytes.len() {
+        let mut j = i;
+        while j + 1 < bytes.len() && bytes[j + 1] == bytes[j] + 1 {
+            j += 1;
+        }
+        ret.push_str(&byte_label(bytes[i]));
+        if j > i {
+            ret.push('-');
+            ret.push_str(&byte_label(bytes[j]));
+        }
+        i = j + 1;
+    }
+    ret.push(']');
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_set::ByteSet;
+    use dot::write_prefix;
+    use prefix::Prefix;
+
+    fn dot(pref: &Prefix) -> String {
+        let mut buf = Vec::new();
+        write_prefix(&mut buf, pref).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_write_prefix() {
+        let lit = dot(&Prefix::Lit(b"ab".to_vec()));
+        assert!(lit.contains("n0 -> n1 [label=\"a\"];"));
+        assert!(lit.contains("n1 -> n2 [label=\"b\"];"));
+        assert!(lit.contains("n2 [label=\"\", shape=doublecircle];"));
+
+        let bs: ByteSet = b"abcx\"".iter().cloned().collect();
+        assert!(dot(&Prefix::ByteSet(bs)).contains("[label=\"[\\\"a-cx]\"]"));
+
+        // "ab" and "ac" share the edge for 'a'.
+        let ac = dot(&Prefix::from_strings(vec![("ab", 3), ("ac", 4)].into_iter()));
+        assert_eq!(ac.matches("[label=\"a\"]").count(), 1);
+        assert!(ac.contains("xlabel=\"state 3\""));
+        assert!(ac.contains("xlabel=\"state 4\""));
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#[cfg(feature = "std")]
+use std::error::Error;
+use std::fmt::{Display, Formatter, Error as FmtError};
+
+/// The ways in which a search can fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchError {
+    /// The search needed more than this many threads at once.
+    TooManyThreads(usize),
+}
+
+impl Display for SearchError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match *self {
+            SearchError::TooManyThreads(n) => write!(f, "search needed more than {} threads", n),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for SearchError {}
+
+/// The ways in which compiling a program can fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompileError {
+    /// The program would have been bigger than the configured limits.
+    TooBig,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match *self {
+            CompileError::TooBig => write!(f, "compiled program would exceed the size limit"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for CompileError {}
+
+/// The ways in which a program can be malformed, so that running it would index out of bounds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramError {
+    /// The parts of the program disagree on the number of states, or one of its tables has the
+    /// wrong size.
+    WrongSize,
+    /// The transitions out of this state (or, for `VmInsts`, this instruction) refer to something
+    /// that doesn't exist.
+    BadState(usize),
+    /// This start state doesn't exist.
+    BadInitState(usize),
+    /// This state has accept data, but it is outside of `TableInsts::accept_range`, so it would
+    /// never accept.
+    BadAcceptRange(usize),
+}
+
+impl Display for ProgramError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match *self {
+            ProgramError::WrongSize => write!(f, "program has tables of the wrong size"),
+            ProgramError::BadState(s) => write!(f, "state {} refers to something nonexistent", s),
+            ProgramError::BadInitState(s) => write!(f, "start state {} doesn't exist", s),
+            ProgramError::BadAcceptRange(s) =>
+                write!(f, "accepting state {} is outside of the accept range", s),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for ProgramError {}
+
+/// The ways in which building an engine with `EngineBuilder` can fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The program (or the reverse program) is malformed.
+    Program(ProgramError),
+    /// The engine needs a reverse program, but there wasn't one.
+    NoReverseProgram,
+    /// The engine would have been too big.
+    TooBig,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match *self {
+            BuildError::Program(e) => write!(f, "bad program: {}", e),
+            BuildError::NoReverseProgram => write!(f, "engine needs a reverse program"),
+            BuildError::TooBig => write!(f, "engine would be too big"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BuildError::Program(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The ways in which loading a table with `TableInsts::from_bytes` can fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The data ended before the table did.
+    TooShort,
+    /// There was more data after the end of the table.
+    TooLong,
+    /// The table doesn't have one transition per byte class for every state.
+    WrongSize,
+    /// The transition at this index of the table goes to a state that doesn't exist.
+    BadState(usize),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match *self {
+            LoadError::TooShort => write!(f, "table data is too short"),
+            LoadError::TooLong => write!(f, "table data is too long"),
+            LoadError::WrongSize => write!(f, "table has the wrong number of transitions"),
+            LoadError::BadState(i) => write!(f, "transition {} goes to a nonexistent state", i),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for LoadError {}
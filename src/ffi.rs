@@ -0,0 +1,251 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A C interface, for running saved engines from other languages.
+//!
+//! This is only available with the `ffi` feature. To build a shared library, run
+//! `cargo rustc --release --features ffi --crate-type cdylib`, and write the declarations for C
+//! with `write_c_header`.
+//!
+//! Programs and engines are passed around as opaque pointers, which must be freed with
+//! `dfa_program_free` and `dfa_engine_free`. None of the functions take ownership of the buffers
+//! that they are passed.
+
+use Engine;
+use builder::EngineBuilder;
+use program::{Program, TableInsts};
+use serialize::Serialize;
+use std::io::{self, Write};
+use std::os::raw::c_int;
+use std::ptr;
+use std::slice;
+
+/// A `Program`, as seen from C.
+#[derive(Debug)]
+pub struct DfaProgram {
+    prog: Program<TableInsts>,
+}
+
+/// An engine, as seen from C.
+#[derive(Debug)]
+pub struct DfaEngine {
+    eng: Box<dyn Engine>,
+}
+
+/// The declarations of everything in this module, for C.
+const C_HEADER: &str = "\
+#ifndef DFA_RUNNER_H
+#define DFA_RUNNER_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+
+typedef struct DfaProgram DfaProgram;
+typedef struct DfaEngine DfaEngine;
+
+DfaProgram *dfa_program_load(const unsigned char *data, size_t len);
+void dfa_program_free(DfaProgram *prog);
+DfaEngine *dfa_engine_new(const DfaProgram *prog);
+DfaEngine *dfa_engine_load(const unsigned char *data, size_t len);
+void dfa_engine_free(DfaEngine *eng);
+int dfa_engine_shortest_match(const DfaEngine *eng, const unsigned char *data, size_t len,
+                              size_t *start, size_t *end);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
+";
+
+/// Writes a C header declaring the functions in this module.
+pub fn write_c_header<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(C_HEADER.as_bytes())
+}
+
+// Turns a pointer and length from C into a slice. C callers often pass a null pointer along with
+// a zero length, which isn't allowed for slices.
+unsafe fn as_slice<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        if len == 0 { Some(&[]) } else { None }
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+/// Reads a program with `TableInsts` that was written by `Serialize::write_to`. Returns null if
+/// the data is invalid.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes (or be null, if `len` is zero).
+#[no_mangle]
+pub unsafe extern "C" fn dfa_program_load(data: *const u8, len: usize) -> *mut DfaProgram {
+    let mut data = match as_slice(data, len) {
+        Some(d) => d,
+        None => return ptr::null_mut(),
+    };
+    match Program::read_from(&mut data) {
+        Ok(prog) => Box::into_raw(Box::new(DfaProgram { prog })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Frees a program returned by `dfa_program_load`. Does nothing if `prog` is null.
+///
+/// # Safety
+///
+/// `prog` must have come from `dfa_program_load`, and it must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn dfa_program_free(prog: *mut DfaProgram) {
+    if !prog.is_null() {
+        drop(Box::from_raw(prog));
+    }
+}
+
+/// Creates an engine (with the defaults of `EngineBuilder`) that runs a copy of `prog`. Returns
+/// null if `prog` is null.
+///
+/// # Safety
+///
+/// `prog` must be null or have come from `dfa_program_load`.
+#[no_mangle]
+pub unsafe extern "C" fn dfa_engine_new(prog: *const DfaProgram) -> *mut DfaEngine {
+    match prog.as_ref().map(|p| EngineBuilder::new(p.prog.clone()).build()) {
+        Some(Ok(eng)) => Box::into_raw(Box::new(DfaEngine { eng })),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Reads an engine that was written by `Engine::save`. Returns null if the data is invalid.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes (or be null, if `len` is zero).
+#[no_mangle]
+pub unsafe extern "C" fn dfa_engine_load(data: *const u8, len: usize) -> *mut DfaEngine {
+    let mut data = match as_slice(data, len) {
+        Some(d) => d,
+        None => return ptr::null_mut(),
+    };
+    match <dyn Engine>::load(&mut data) {
+        Ok(eng) => Box::into_raw(Box::new(DfaEngine { eng })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Frees an engine returned by `dfa_engine_new` or `dfa_engine_load`. Does nothing if `eng` is
+/// null.
+///
+/// # Safety
+///
+/// `eng` must have come from one of those functions, and it must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn dfa_engine_free(eng: *mut DfaEngine) {
+    if !eng.is_null() {
+        drop(Box::from_raw(eng));
+    }
+}
+
+/// Searches for the shortest match in a buffer, as in `Engine::shortest_match`.
+///
+/// Returns 1 if there is a match (and then writes its start and end to `start` and `end`, if they
+/// aren't null), 0 if there isn't, and -1 if `eng` is null or the buffer is invalid.
+///
+/// # Safety
+///
+/// `eng` must be null or have come from `dfa_engine_new` or `dfa_engine_load`. `data` must point
+/// to `len` readable bytes (or be null, if `len` is zero). `start` and `end` must each be null or
+/// point to a writable `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn dfa_engine_shortest_match(eng: *const DfaEngine, data: *const u8,
+                                                   len: usize, start: *mut usize, end: *mut usize)
+-> c_int {
+    let (eng, input) = match (eng.as_ref(), as_slice(data, len)) {
+        (Some(e), Some(d)) => (e, d),
+        _ => return -1,
+    };
+    match eng.eng.search(input, &Default::default()) {
+        Some((s, e)) => {
+            if !start.is_null() {
+                *start = s;
+            }
+            if !end.is_null() {
+                *end = e;
+            }
+            1
+        },
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Engine;
+    use backtracking::BacktrackingEngine;
+    use ffi::*;
+    use prefix::Prefix;
+    use serialize::Serialize;
+    use std::ptr;
+    use test_util::lits_prog;
+
+    unsafe fn shortest_match(eng: *const DfaEngine, input: &[u8]) -> (c_int, usize, usize) {
+        let (mut start, mut end) = (0, 0);
+        let ret = dfa_engine_shortest_match(eng, input.as_ptr(), input.len(), &mut start, &mut end);
+        (ret, start, end)
+    }
+
+    #[test]
+    fn test_program() {
+        let mut buf = Vec::new();
+        lits_prog(&["ab", "cd"]).write_to(&mut buf).unwrap();
+        unsafe {
+            let prog = dfa_program_load(buf.as_ptr(), buf.len());
+            assert!(!prog.is_null());
+            let eng = dfa_engine_new(prog);
+            dfa_program_free(prog);
+            assert_eq!(shortest_match(eng, b"xxcdab"), (1, 2, 4));
+            assert_eq!(shortest_match(eng, b"xxx").0, 0);
+            assert_eq!(dfa_engine_shortest_match(eng, ptr::null(), 0, ptr::null_mut(),
+                                                 ptr::null_mut()), 0);
+            assert_eq!(dfa_engine_shortest_match(eng, ptr::null(), 1, ptr::null_mut(),
+                                                 ptr::null_mut()), -1);
+            dfa_engine_free(eng);
+
+            assert!(dfa_program_load(buf.as_ptr(), buf.len() - 1).is_null());
+            assert!(dfa_engine_new(ptr::null()).is_null());
+            assert_eq!(shortest_match(ptr::null(), b"ab").0, -1);
+        }
+    }
+
+    #[test]
+    fn test_engine() {
+        let mut buf = Vec::new();
+        BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty).save(&mut buf).unwrap();
+        unsafe {
+            let eng = dfa_engine_load(buf.as_ptr(), buf.len());
+            assert_eq!(shortest_match(eng, b"xxcdab"), (1, 2, 4));
+            dfa_engine_free(eng);
+            assert!(dfa_engine_load(buf.as_ptr(), 3).is_null());
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mut buf = Vec::new();
+        write_c_header(&mut buf).unwrap();
+        let header = String::from_utf8(buf).unwrap();
+        for name in &["dfa_program_load", "dfa_program_free", "dfa_engine_new",
+                      "dfa_engine_load", "dfa_engine_free", "dfa_engine_shortest_match"] {
+            assert!(header.contains(&format!("{}(", name)), "{}", name);
+        }
+    }
+}
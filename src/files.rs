@@ -0,0 +1,168 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Searching files and directory trees, one line at a time.
+//!
+//! Files are read through a buffer, so only the current line needs to be in memory. Every line
+//! is searched on its own (without its line terminator), so matches can't span lines.
+
+use {Engine, Matches, SearchConfig};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// A match found by a `FileSearcher`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineMatch {
+    /// The file that the match is in.
+    pub path: PathBuf,
+    /// The line that the match is on, counting from 1.
+    pub line_number: usize,
+    /// The offset (in bytes) of the start of the line in the file.
+    pub line_start: usize,
+    /// The start and end of the match, relative to the start of the line.
+    pub span: (usize, usize),
+}
+
+/// Searches the lines of files with an engine.
+#[derive(Debug)]
+pub struct FileSearcher<'a> {
+    engine: &'a dyn Engine,
+    config: SearchConfig,
+}
+
+impl<'a> FileSearcher<'a> {
+    pub fn new(engine: &'a dyn Engine) -> FileSearcher<'a> {
+        FileSearcher {
+            engine,
+            config: SearchConfig::default(),
+        }
+    }
+
+    /// Searches every line with these options. The `start` and `end` fields are ignored.
+    pub fn with_config(mut self, config: &SearchConfig) -> FileSearcher<'a> {
+        self.config = SearchConfig { start: 0, end: None, ..*config };
+        self
+    }
+
+    /// Finds the matches on each line that `r` produces, as if they came from the file `path`.
+    ///
+    /// Lines end with "\n" or "\r\n", and neither is part of the line that is searched.
+    pub fn search_reader<R: BufRead>(&self, path: &Path, mut r: R) -> io::Result<Vec<LineMatch>> {
+        let mut ret = Vec::new();
+        let mut line = Vec::new();
+        let mut line_start = 0;
+        let mut line_number = 1;
+        while r.read_until(b'\n', &mut line)? > 0 {
+            let len = line.len();
+            let mut end = len;
+            if line.ends_with(b"\n") {
+                end -= 1;
+                if line[..end].ends_with(b"\r") {
+                    end -= 1;
+                }
+            }
+            for span in Matches::new(self.engine, &line[..end], &self.config) {
+                ret.push(LineMatch { path: path.to_owned(), line_number, line_start, span });
+            }
+            line.clear();
+            line_start += len;
+            line_number += 1;
+        }
+        Ok(ret)
+    }
+
+    /// Finds the matches on each line of a file.
+    pub fn search_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<LineMatch>> {
+        let path = path.as_ref();
+        self.search_reader(path, BufReader::new(File::open(path)?))
+    }
+
+    /// Finds the matches on each line of a file, or of every file in a directory tree.
+    ///
+    /// The files in each directory are searched in order of their names. Symbolic links to
+    /// directories are not followed (but symbolic links to files are searched). Stops at the first
+    /// file or directory that can't be read.
+    pub fn search_path<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<LineMatch>> {
+        let mut ret = Vec::new();
+        self.search_path_into(path.as_ref(), &mut ret)?;
+        Ok(ret)
+    }
+
+    fn search_path_into(&self, path: &Path, ret: &mut Vec<LineMatch>) -> io::Result<()> {
+        if !fs::symlink_metadata(path)?.is_dir() {
+            if path.is_file() {
+                ret.extend(self.search_file(path)?);
+            }
+            return Ok(());
+        }
+
+        let mut entries = fs::read_dir(path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            self.search_path_into(&entry, ret)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use SearchConfig;
+    use backtracking::BacktrackingEngine;
+    use files::{FileSearcher, LineMatch};
+    use prefix::Prefix;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use test_util::lits_prog;
+
+    #[test]
+    fn test_reader() {
+        let eng = BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty);
+        let searcher = FileSearcher::new(&eng);
+        let path = Path::new("input");
+        let found = searcher.search_reader(path, &b"xab\r\n\ncdab\nxxcd"[..]).unwrap();
+        let spans: Vec<_> = found.iter().map(|m| (m.line_number, m.line_start, m.span)).collect();
+        assert_eq!(spans, vec![(1, 0, (1, 3)), (3, 6, (0, 2)), (3, 6, (2, 4)), (4, 11, (2, 4))]);
+        assert_eq!(found[0].path, path);
+
+        // Line terminators aren't searched, so they can't be the end of an anchored match.
+        let config = SearchConfig { anchored_end: true, ..SearchConfig::default() };
+        let searcher = searcher.with_config(&config);
+        let found = searcher.search_reader(path, &b"xab\r\nabx\n"[..]).unwrap();
+        assert_eq!(found, vec![LineMatch { path: path.to_owned(), line_number: 1, line_start: 0,
+                                           span: (1, 3) }]);
+    }
+
+    #[test]
+    fn test_path() {
+        let dir = env::temp_dir().join(format!("dfa-runner-files-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("b.txt"), "ab\n").unwrap();
+        fs::write(dir.join("a.txt"), "xx\nxcd\n").unwrap();
+        fs::write(dir.join("sub").join("c.txt"), "abab").unwrap();
+
+        let eng = BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty);
+        let searcher = FileSearcher::new(&eng);
+        let found = searcher.search_path(&dir).unwrap();
+        let found: Vec<_> = found.iter()
+            .map(|m| (m.path.strip_prefix(&dir).unwrap().to_owned(), m.line_number, m.span))
+            .collect();
+        assert_eq!(found, vec![(Path::new("a.txt").to_owned(), 2, (1, 3)),
+                               (Path::new("b.txt").to_owned(), 1, (0, 2)),
+                               (Path::new("sub/c.txt").to_owned(), 1, (0, 2)),
+                               (Path::new("sub/c.txt").to_owned(), 1, (2, 4))]);
+        assert_eq!(searcher.search_file(dir.join("b.txt")).unwrap().len(), 1);
+        assert!(searcher.search_path(dir.join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An engine that builds the states of a DFA as it needs them.
+//!
+//! This runs an `Nfa` at close to the speed of a `TableInsts` program, as long as the number of
+//! DFA states that the input actually visits is small. The states are stored in a `LazyCache`,
+//! which is emptied whenever it grows past its budget.
+//!
+//! The `Nfa`'s assertions are supported by remembering, in each DFA state, what kind of byte came
+//! before it, and by looking at the next byte before deciding whether a state accepts.
+
+use {Engine, SearchConfig};
+use nfa::{Context, Nfa};
+use prefix::{Prefix, PrefixSearcherFactory};
+use serialize::{write_any, write_u8, write_usize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The dead state, which is the empty set of NFA states.
+const DEAD: u32 = 0;
+// A transition that we haven't computed yet.
+const UNKNOWN: u32 = u32::MAX;
+
+// Roughly the number of bytes used by a state in the cache, not counting its NFA states.
+const STATE_BYTES: usize = 256 * 4 + 64;
+
+/// The default value for `LazyDfaEngine::with_cache_capacity`.
+pub const DEFAULT_CACHE_CAPACITY: usize = 2 * 1024 * 1024;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The DFA states that a `LazyDfaEngine` has built so far.
+///
+/// A `LazyCache` can only be used with one engine at a time: it is emptied if it is used with a
+/// different engine than last time.
+#[derive(Clone, Debug)]
+pub struct LazyCache {
+    // The id of the engine that these states belong to.
+    engine_id: usize,
+    // The transitions out of state `s` are `table[256 * s..256 * (s + 1)]`.
+    table: Vec<u32>,
+    // The sets of NFA states making up each DFA state (closed under the epsilon-transitions that
+    // we know we can take), and what came before them.
+    sets: Vec<(Vec<usize>, Context)>,
+    // Whether each state accepts before a byte `b`, indexed by the context after `b`.
+    accept: Vec<[bool; 3]>,
+    accept_at_eoi: Vec<bool>,
+    ids: HashMap<(Vec<usize>, Context), u32>,
+    // The start state in each context.
+    start: [u32; 3],
+    // True if the `Nfa` has word boundaries. Otherwise, we don't need to keep track of contexts.
+    boundaries: bool,
+    bytes: usize,
+    clears: usize,
+}
+
+// An index for each context, for `LazyCache::start` and `LazyCache::accept`.
+fn context_idx(ctx: Context) -> usize {
+    match ctx {
+        Context::LineStart => 0,
+        Context::Word => 1,
+        Context::Other => 2,
+    }
+}
+
+impl LazyCache {
+    pub fn new() -> LazyCache {
+        LazyCache {
+            engine_id: 0,
+            table: Vec::new(),
+            sets: Vec::new(),
+            accept: Vec::new(),
+            accept_at_eoi: Vec::new(),
+            ids: HashMap::new(),
+            start: [DEAD; 3],
+            boundaries: false,
+            bytes: 0,
+            clears: 0,
+        }
+    }
+
+    /// The number of DFA states in the cache.
+    pub fn num_states(&self) -> usize {
+        self.sets.len()
+    }
+
+    /// The number of times that the cache has been emptied because it was full.
+    pub fn clears(&self) -> usize {
+        self.clears
+    }
+
+    /// Roughly how many bytes the cached states use. This is the number that is compared with
+    /// `LazyDfaEngine::with_cache_capacity`.
+    pub fn memory_usage(&self) -> usize {
+        self.bytes
+    }
+
+    fn reset(&mut self, nfa: &Nfa, engine_id: usize) {
+        self.engine_id = engine_id;
+        self.table.clear();
+        self.sets.clear();
+        self.accept.clear();
+        self.accept_at_eoi.clear();
+        self.ids.clear();
+        self.bytes = 0;
+        self.boundaries = nfa.states.iter().any(|s| !s.word_boundary_eps.is_empty());
+        self.add(nfa, Vec::new(), Context::Other);
+        for &ctx in &[Context::LineStart, Context::Word, Context::Other] {
+            let mut start = vec![0];
+            nfa.closure(&mut start, ctx == Context::LineStart, false);
+            self.start[context_idx(ctx)] = self.add(nfa, start, ctx);
+        }
+    }
+
+    // Returns the id of the DFA state made up of `set`, adding it if necessary.
+    fn add(&mut self, nfa: &Nfa, set: Vec<usize>, ctx: Context) -> u32 {
+        // Without word boundaries, the context only matters for the closure (which `set` has
+        // already been closed under).
+        let ctx = if self.boundaries && !set.is_empty() { ctx } else { Context::Other };
+        let key = (set, ctx);
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+        let id = self.sets.len() as u32;
+        let accepts = |next: Option<u8>| {
+            self.before(nfa, &key.0, ctx, next).iter().any(|&s| nfa.states[s].accepts_before(next))
+        };
+        let accept = [accepts(Some(b'\n')), accepts(Some(b'a')), accepts(Some(b' '))];
+        let accept_at_eoi = accepts(None);
+        self.accept.push(accept);
+        self.accept_at_eoi.push(accept_at_eoi);
+        self.table.extend_from_slice(&[UNKNOWN; 256]);
+        self.bytes += STATE_BYTES + 2 * key.0.len() * mem::size_of::<usize>();
+        self.ids.insert(key.clone(), id);
+        self.sets.push(key);
+        id
+    }
+
+    // The NFA states that we could be in while in the DFA state made up of `set`, right before
+    // reading `next` (or at the end of the input, if `next` is `None`).
+    fn before(&self, nfa: &Nfa, set: &[usize], ctx: Context, next: Option<u8>) -> Vec<usize> {
+        let mut set = set.to_vec();
+        let next_word = next.is_some_and(|b| Context::after(b) == Context::Word);
+        if self.boundaries && next_word != (ctx == Context::Word) {
+            nfa.closure(&mut set, ctx == Context::LineStart, true);
+        }
+        set
+    }
+
+    // The start state at position `pos` of `input`.
+    fn start(&self, input: &[u8], pos: usize) -> u32 {
+        self.start[context_idx(Context::at(input, pos))]
+    }
+
+    // Returns true if `state` accepts at position `pos` of `input`.
+    fn accepts(&self, state: u32, input: &[u8], pos: usize) -> bool {
+        match input.get(pos) {
+            Some(&b) => self.accept[state as usize][context_idx(Context::after(b))],
+            None => self.accept_at_eoi[state as usize],
+        }
+    }
+
+    // Returns the state that we get to from `state` by consuming `b`, building it if necessary.
+    //
+    // If the cache is full, this empties it first. In that case, the ids of all the other states
+    // are no longer valid.
+    fn next(&mut self, nfa: &Nfa, capacity: usize, state: u32, b: u8) -> u32 {
+        let idx = state as usize * 256 + b as usize;
+        if self.table[idx] != UNKNOWN {
+            return self.table[idx];
+        }
+
+        let (ref set, ctx) = self.sets[state as usize];
+        let set = nfa.step(&self.before(nfa, set, ctx, Some(b)), b);
+        if self.bytes > capacity {
+            let engine_id = self.engine_id;
+            self.reset(nfa, engine_id);
+            self.clears += 1;
+            // We don't record the transition, because `state` isn't in the cache anymore.
+            return self.add(nfa, set, Context::after(b));
+        }
+        let next = self.add(nfa, set, Context::after(b));
+        self.table[idx] = next;
+        next
+    }
+}
+
+impl Default for LazyCache {
+    fn default() -> LazyCache {
+        LazyCache::new()
+    }
+}
+
+thread_local! {
+    // The cache used by `Engine::search`, which doesn't take one.
+    static CACHE: RefCell<LazyCache> = RefCell::new(LazyCache::new());
+}
+
+/// An engine that runs an `Nfa` by building the states of the equivalent DFA as it needs them.
+///
+/// Like `BacktrackingEngine`, it tries one candidate position at a time, so it returns the first
+/// match that it finds. It doesn't know about the priorities in the `Nfa`, so with
+/// `SearchConfig::leftmost_first` it returns the longest match starting there.
+#[derive(Clone, Debug)]
+pub struct LazyDfaEngine<P: PrefixSearcherFactory = Prefix> {
+    nfa: Nfa,
+    prefix: P,
+    capacity: usize,
+    id: usize,
+}
+
+impl<P: PrefixSearcherFactory> LazyDfaEngine<P> {
+    pub fn new(nfa: Nfa, pref: P) -> LazyDfaEngine<P> {
+        LazyDfaEngine {
+            nfa,
+            prefix: pref,
+            capacity: DEFAULT_CACHE_CAPACITY,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Sets roughly how many bytes the DFA states can use before the cache gets emptied.
+    pub fn with_cache_capacity(mut self, bytes: usize) -> LazyDfaEngine<P> {
+        self.capacity = bytes;
+        self
+    }
+
+    /// Like `Engine::search`, but uses `cache` to store the DFA states.
+    pub fn search_with(&self, cache: &mut LazyCache, input: &[u8], config: &SearchConfig)
+    -> Option<(usize, usize)> {
+        let (start, end) = config.bounds(input)?;
+        if cache.engine_id != self.id {
+            cache.reset(&self.nfa, self.id);
+        }
+        if config.anchored_start || self.nfa.anchored {
+            return self.match_at(cache, input, start, end, config).map(|x| (start, x));
+        }
+
+        let exact = self.prefix.is_exact();
+        self.prefix.with_searcher(&input[..end], &mut |searcher| {
+            searcher.skip_to(start);
+            let out_of_order_len = self.prefix.out_of_order_len();
+            if exact && !config.anchored_end && !config.leftmost_first {
+                return searcher.leftmost_match(out_of_order_len,
+                                               &mut |res| Some((res.start_pos, res.end_pos)));
+            }
+            searcher.leftmost_match(out_of_order_len, &mut |res| {
+                self.match_at(cache, input, res.start_pos, end, config).map(|x| (res.start_pos, x))
+            })
+        })
+    }
+
+    // Returns the end of the shortest match starting at `pos` (or the longest one, with
+    // `leftmost_first`, or the end of the only match ending at `end`, if the search is anchored
+    // there).
+    fn match_at(&self, cache: &mut LazyCache, input: &[u8], mut pos: usize, end: usize,
+                config: &SearchConfig)
+    -> Option<usize> {
+        let mut state = cache.start(input, pos);
+        let mut last = None;
+        while pos < end {
+            if cache.accepts(state, input, pos) && !config.anchored_end {
+                if !config.leftmost_first {
+                    return Some(pos);
+                }
+                last = Some(pos);
+            }
+            state = cache.next(&self.nfa, self.capacity, state, input[pos]);
+            if state == DEAD {
+                return last;
+            }
+            pos += 1;
+        }
+
+        if cache.accepts(state, input, end) { Some(end) } else { last }
+    }
+}
+
+impl<P> Engine for LazyDfaEngine<P>
+where P: PrefixSearcherFactory + Clone + 'static {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        let mut cache = CACHE.with(|c| mem::take(&mut *c.borrow_mut()));
+        let ret = self.search_with(&mut cache, input, config);
+        CACHE.with(|c| *c.borrow_mut() = cache);
+        ret
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(self.clone())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.nfa.memory_usage() + self.prefix.memory_usage()
+    }
+
+    fn write_engine(&self, mut w: &mut dyn Write) -> io::Result<()> {
+        write_u8(&mut w, 4)?;
+        self.nfa.write_to(&mut w)?;
+        write_any(&mut w, &self.prefix)?;
+        write_usize(&mut w, self.capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_set::ByteSet;
+    use lazy::{LazyCache, LazyDfaEngine};
+    use nfa::Nfa;
+    use prefix::Prefix;
+    use test_util::{assertions_nfa, ASSERTIONS_MATCHES};
+    use {Engine, SearchConfig};
+
+    // Matches "a", followed by any number of 'b's and 'c's, followed by "d".
+    fn nfa() -> Nfa {
+        let mut nfa = Nfa::new();
+        let a = nfa.add_literal(0, b"a");
+        let bc: ByteSet = b"bc".iter().cloned().collect();
+        nfa.add_transition(a, bc, a);
+        let d = nfa.add_literal(a, b"d");
+        nfa.states[d].accept = true;
+        nfa
+    }
+
+    #[test]
+    fn test_search() {
+        let eng = LazyDfaEngine::new(nfa(), Prefix::Empty);
+        assert_eq!(eng.shortest_match("xxabcbdx"), Some((2, 7)));
+        assert_eq!(eng.shortest_match("xxabxd"), None);
+        assert_eq!(eng.shortest_match("ad"), Some((0, 2)));
+
+        let eng = LazyDfaEngine::new(nfa(), Prefix::Byte(b'a'));
+        assert_eq!(eng.shortest_match("xxabcbdx"), Some((2, 7)));
+
+        let anchored = SearchConfig { anchored_start: true, ..SearchConfig::default() };
+        assert_eq!(eng.search(b"xad", &anchored), None);
+        assert_eq!(eng.search(b"xad", &SearchConfig { start: 1, ..anchored }), Some((1, 3)));
+    }
+
+    #[test]
+    fn test_accept_at_eoi() {
+        let mut nfa = Nfa::new();
+        let b = nfa.add_literal(0, b"ab");
+        nfa.states[b].accept_at_eoi = true;
+        let eng = LazyDfaEngine::new(nfa, Prefix::Empty);
+        assert_eq!(eng.shortest_match("abab"), Some((2, 4)));
+        assert_eq!(eng.shortest_match("abx"), None);
+        assert_eq!(eng.search(b"abab", &SearchConfig { end: Some(2), ..SearchConfig::default() }),
+                   None);
+    }
+
+    #[test]
+    fn test_assertions() {
+        let eng = LazyDfaEngine::new(assertions_nfa(), Prefix::Empty);
+        for &(input, m) in ASSERTIONS_MATCHES {
+            assert_eq!(eng.shortest_match(input), m, "{:?}", input);
+        }
+        // The '\n' after the end of the search still counts as a line end.
+        let config = SearchConfig { end: Some(2), ..SearchConfig::default() };
+        assert_eq!(eng.search(b"ab\n", &config), Some((0, 2)));
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let big = LazyDfaEngine::new(nfa(), Prefix::Empty);
+        let small = LazyDfaEngine::new(nfa(), Prefix::Empty).with_cache_capacity(0);
+        let input = b"xxabcbcbbcbcbcbcdxx";
+
+        let mut cache = LazyCache::new();
+        assert_eq!(big.search_with(&mut cache, input, &SearchConfig::default()), Some((2, 17)));
+        assert_eq!(cache.clears(), 0);
+        let num_states = cache.num_states();
+        assert!(num_states > 2);
+        assert_eq!(big.search_with(&mut cache, input, &SearchConfig::default()), Some((2, 17)));
+        assert_eq!(cache.num_states(), num_states);
+
+        // The cache belongs to `big`, so `small` starts again from scratch.
+        assert_eq!(small.search_with(&mut cache, input, &SearchConfig::default()), Some((2, 17)));
+        assert!(cache.clears() > 0);
+    }
+}
@@ -1,19 +1,430 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 extern crate aho_corasick;
+#[cfg(feature = "std")]
 extern crate memchr;
+#[cfg(feature = "std")]
 extern crate memmem;
+#[cfg(not(feature = "std"))]
+#[macro_use] extern crate alloc;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 #[macro_use] extern crate matches;
 
+// Reports a step of running a program to the tracer, if the `trace` feature is enabled.
+macro_rules! trace_step {
+    ($($field:ident: $val:expr),*) => {
+        #[cfg(feature = "trace")]
+        ::trace::report(&::trace::Step { $($field: $val),* });
+        #[cfg(not(feature = "trace"))]
+        { let _ = ::trace::Step { $($field: $val),* }; }
+    }
+}
+
+// Without the standard library, this stands in for it, so that the rest of the crate can still
+// refer to everything through `std`.
+#[cfg(not(feature = "std"))]
+mod std {
+    pub use alloc::{collections, sync};
+    pub use core::{cmp, fmt, iter, mem, ops};
+
+    pub mod prelude {
+        pub mod v1 {
+            pub use alloc::boxed::Box;
+            pub use alloc::vec::Vec;
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
 use std::fmt::Debug;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::ControlFlow;
+use std::sync::Arc;
 
 pub trait Engine: Debug {
-    fn shortest_match(&self, s: &str) -> Option<(usize, usize)>;
-    fn clone_box(&self) -> Box<Engine>;
+    /// Searches for a match in `input`, according to `config`.
+    ///
+    /// This works on bytes, so if `input` is UTF-8, the match can start or end in the middle of a
+    /// character. Use `search_str` to avoid that.
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)>;
+
+    /// Searches for a match in `input` that starts and ends on character boundaries.
+    ///
+    /// Matches that start in the middle of a character are skipped, and matches that end in the
+    /// middle of one are extended to the next boundary where the program matches (if there is
+    /// one). This costs nothing extra unless the program finds matches that aren't aligned, which
+    /// never happens for programs that only match whole characters.
+    fn search_str(&self, input: &str, config: &SearchConfig) -> Option<(usize, usize)> {
+        let bytes = input.as_bytes();
+        let end = config.bounds(bytes)?.1;
+        let mut pos = config.start;
+        loop {
+            let m = self.search(bytes, &SearchConfig { start: pos, ..*config })?;
+            if input.is_char_boundary(m.0) {
+                if input.is_char_boundary(m.1) {
+                    return Some(m);
+                }
+                let to = SearchConfig { anchored_start: true, anchored_end: true, start: m.0,
+                                        ..*config };
+                for e in (m.1 + 1..=end).filter(|&e| input.is_char_boundary(e)) {
+                    if let Some(m) = self.search(bytes, &SearchConfig { end: Some(e), ..to }) {
+                        return Some(m);
+                    }
+                }
+            }
+            if config.anchored_start || m.0 >= end {
+                return None;
+            }
+            pos = m.0 + 1;
+        }
+    }
+
+    /// Returns the shortest match starting at the leftmost position, as in `search_str`.
+    fn shortest_match(&self, s: &str) -> Option<(usize, usize)> {
+        self.search_str(s, &SearchConfig::default())
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine>;
+
+    /// Returns the end of the longest match that starts exactly at `pos` (the "maximal munch"
+    /// that a tokenizer wants).
+    ///
+    /// This is a search with `anchored_start` and `leftmost_first`, so it is only the longest
+    /// match if the program wasn't built with `DeterminizeConfig::leftmost_first` (otherwise, it
+    /// is the match that the program prefers). Engines running an `Nfa` without priorities (the
+    /// `LazyDfaEngine` and `OnePassEngine`) always return the longest match.
+    fn munch(&self, input: &[u8], pos: usize) -> Option<usize> {
+        let config = SearchConfig {
+            anchored_start: true,
+            leftmost_first: true,
+            start: pos,
+            ..SearchConfig::default()
+        };
+        self.search(input, &config).map(|m| m.1)
+    }
+
+    /// Replays the match `m` (as returned by searching `input` with this engine), and returns the
+    /// position and state at each step from the start of the match to its end.
+    ///
+    /// This shows which path through the program produced the match. Returns `None` if the
+    /// program doesn't follow `m` to its end, if `m` isn't a range of `input`, or if this engine
+    /// doesn't run a `Program`.
+    fn explain(&self, _input: &[u8], _m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        None
+    }
+
+    /// Returns true if `input` is the beginning of some string that this engine's program matches
+    /// in full (starting at the beginning and accepting at the end). This is the question that an
+    /// autocompleting or validating text field asks while the text is still being typed.
+    ///
+    /// Returns `None` if this engine doesn't run a `Program`.
+    fn matches_prefix(&self, _input: &[u8]) -> Option<bool> {
+        None
+    }
+
+    /// Calls `f` on each of the non-overlapping matches in `input` (the same ones that `Matches`
+    /// finds), until it returns `ControlFlow::Break`.
+    ///
+    /// This doesn't allocate, and unlike `Matches` it doesn't need a `dyn Engine`. Returns
+    /// `ControlFlow::Break` if `f` did.
+    fn for_each_match(&self, input: &[u8], config: &SearchConfig,
+                      f: &mut dyn FnMut((usize, usize)) -> ControlFlow<()>)
+    -> ControlFlow<()> {
+        let mut pos = Some(config.start);
+        while let Some(p) = pos {
+            let (m, next) = next_match(self, input, config, p);
+            match m {
+                Some(m) => f(m)?,
+                None => break,
+            }
+            pos = next;
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Returns the first `n` of the non-overlapping matches in `input` (or all of them, if there
+    /// are fewer). The search stops as soon as the `n`th match is found, so the rest of the input
+    /// isn't scanned.
+    fn find_at_most_n(&self, input: &[u8], config: &SearchConfig, n: usize)
+    -> Vec<(usize, usize)> {
+        let mut ret = Vec::new();
+        if n > 0 {
+            let _ = self.for_each_match(input, config, &mut |m| {
+                ret.push(m);
+                if ret.len() < n { ControlFlow::Continue(()) } else { ControlFlow::Break(()) }
+            });
+        }
+        ret
+    }
+
+    /// Returns roughly how many bytes this engine holds on the heap (its tables, byte sets,
+    /// prefix and so on).
+    ///
+    /// This doesn't count scratch space that the engine doesn't own, like a `threaded::Cache` or
+    /// a `lazy::LazyCache` (which report their own sizes). Engines that don't know return 0.
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    /// Writes this engine, along with its program, prefix and options, so that it can be read
+    /// back with `Engine::load`.
+    ///
+    /// Engines running a `Program` can only be saved if it has `TableInsts` or `VmInsts`, and
+    /// if their prefix is a `Prefix`. The `OnePassEngine` and the `SimpleEngine` can't be saved
+    /// at all.
+    #[cfg(feature = "std")]
+    fn save(&self, mut w: &mut dyn Write) -> io::Result<()> {
+        serialize::write_header(&mut w)?;
+        self.write_engine(w)
+    }
+
+    /// Like `save`, but without the header that says which version of the format this is.
+    #[cfg(feature = "std")]
+    fn write_engine(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Err(serialize::unsupported())
+    }
+}
+
+// Implements `Engine` for a pointer to an engine, by forwarding everything to the engine.
+macro_rules! forward_engine {
+    (impl<$($lt:lifetime),*> for $ty:ty) => {
+        impl<$($lt,)* E: Engine + ?Sized> Engine for $ty {
+            fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+                (**self).search(input, config)
+            }
+
+            fn search_str(&self, input: &str, config: &SearchConfig) -> Option<(usize, usize)> {
+                (**self).search_str(input, config)
+            }
+
+            fn shortest_match(&self, s: &str) -> Option<(usize, usize)> {
+                (**self).shortest_match(s)
+            }
+
+            fn clone_box(&self) -> Box<dyn Engine> {
+                (**self).clone_box()
+            }
+
+            fn munch(&self, input: &[u8], pos: usize) -> Option<usize> {
+                (**self).munch(input, pos)
+            }
+
+            fn explain(&self, input: &[u8], m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+                (**self).explain(input, m)
+            }
+
+            fn matches_prefix(&self, input: &[u8]) -> Option<bool> {
+                (**self).matches_prefix(input)
+            }
+
+            fn for_each_match(&self, input: &[u8], config: &SearchConfig,
+                              f: &mut dyn FnMut((usize, usize)) -> ControlFlow<()>)
+            -> ControlFlow<()> {
+                (**self).for_each_match(input, config, f)
+            }
+
+            fn find_at_most_n(&self, input: &[u8], config: &SearchConfig, n: usize)
+            -> Vec<(usize, usize)> {
+                (**self).find_at_most_n(input, config, n)
+            }
+
+            fn memory_usage(&self) -> usize {
+                (**self).memory_usage()
+            }
+
+            #[cfg(feature = "std")]
+            fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+                (**self).save(w)
+            }
+
+            #[cfg(feature = "std")]
+            fn write_engine(&self, w: &mut dyn Write) -> io::Result<()> {
+                (**self).write_engine(w)
+            }
+        }
+    }
+}
+
+forward_engine!(impl<'a> for &'a E);
+forward_engine!(impl<> for Box<E>);
+forward_engine!(impl<> for Arc<E>);
+
+// The number of bytes that `v` has allocated (not counting anything that its elements own).
+fn vec_bytes<T>(v: &Vec<T>) -> usize {
+    v.capacity() * mem::size_of::<T>()
+}
+
+impl dyn Engine {
+    /// Reads an engine that was written by `Engine::save`.
+    ///
+    /// Fails if the data was written by an incompatible version of this library, or on a
+    /// platform with a different layout.
+    #[cfg(feature = "std")]
+    pub fn load(mut r: &mut dyn Read) -> io::Result<Box<dyn Engine>> {
+        serialize::read_header(&mut r)?;
+        serialize::read_engine(&mut r)
+    }
+
+    /// Returns an iterator over the non-overlapping matches in `input`, as in `Matches::new`.
+    pub fn matches<'a>(&'a self, input: &'a [u8], config: &SearchConfig) -> Matches<'a> {
+        Matches::new(self, input, config)
+    }
+}
+
+/// An iterator over the non-overlapping matches of an engine.
+///
+/// Each search starts where the last match ended, except that after an empty match it starts one
+/// byte later, so that the same empty match isn't found forever. Empty matches can be found at
+/// any position, including the end of the input. If `anchored_start` is set, every match must
+/// start where the last one ended, so the iteration stops at the first gap or empty match.
+#[derive(Debug)]
+pub struct Matches<'a> {
+    engine: &'a dyn Engine,
+    input: &'a [u8],
+    config: SearchConfig,
+    // Where to search next, or `None` if we are done.
+    pos: Option<usize>,
 }
 
+impl<'a> Matches<'a> {
+    /// Creates an iterator over the matches that `engine` finds in `input`, between `config.start`
+    /// and `config.end`.
+    pub fn new(engine: &'a dyn Engine, input: &'a [u8], config: &SearchConfig) -> Matches<'a> {
+        Matches {
+            engine,
+            input,
+            config: *config,
+            pos: Some(config.start),
+        }
+    }
+}
+
+impl<'a> Iterator for Matches<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let (m, next) = next_match(self.engine, self.input, &self.config, self.pos?);
+        self.pos = next;
+        m
+    }
+}
+
+// Finds the next match at or after `pos`, for `Matches` and `Engine::for_each_match`. Also
+// returns where to look for the match after that (or `None` if there can't be one).
+fn next_match<E: Engine + ?Sized>(engine: &E, input: &[u8], config: &SearchConfig, pos: usize)
+-> (Option<(usize, usize)>, Option<usize>) {
+    let config = SearchConfig { start: pos, ..*config };
+    let m = engine.search(input, &config);
+    let next = match (m, config.bounds(input)) {
+        (Some((s, e)), _) if s < e => Some(e),
+        (Some((_, e)), Some((_, end))) if e < end && !config.anchored_start => Some(e + 1),
+        _ => None,
+    };
+    (m, next)
+}
+
+/// Options for a single search.
+///
+/// The default options search the whole input for the leftmost match, and return the shortest
+/// match starting there.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchConfig {
+    /// Only look for matches that start at `start`.
+    pub anchored_start: bool,
+    /// Only look for matches that end at `end`.
+    pub anchored_end: bool,
+    /// Return the first match that is found, even if there could be a match that starts earlier.
+    pub earliest: bool,
+    /// Among the matches starting at the leftmost position, return the one that the program
+    /// prefers instead of the shortest one.
+    ///
+    /// For an `Nfa`, the order of each state's epsilon-transitions gives their priority, so
+    /// `ab|a` prefers "ab"; an accepting state prefers to follow its own transitions, if it has
+    /// any. A `Program` has no priorities of its own; engines running one return the last match
+    /// that it reports before dying, so the program should be built with
+    /// `DeterminizeConfig::leftmost_first` (otherwise this gives the longest match). The
+    /// `LazyDfaEngine` and `OnePassEngine` don't keep track of priorities, so they also return the
+    /// longest match.
+    pub leftmost_first: bool,
+    /// Where to start searching. Bytes before this are still used for deciding where a match can
+    /// start (for example, if the program starts at the beginning of lines).
+    pub start: usize,
+    /// Where to stop searching, or `None` to search until the end of the input. Matches can only
+    /// use the end-of-input conditions if this is the end of the input.
+    ///
+    /// If this is past the end of the input, or if `start` is past this, there is no match.
+    pub end: Option<usize>,
+}
+
+impl SearchConfig {
+    /// Returns the range of `input` that should be searched, or `None` if `start` or `end` is
+    /// out of range (in which case there is no match).
+    fn bounds(&self, input: &[u8]) -> Option<(usize, usize)> {
+        let end = self.end.unwrap_or(input.len());
+        if self.start <= end && end <= input.len() { Some((self.start, end)) } else { None }
+    }
+}
+
+#[cfg(feature = "std")]
+pub mod ac;
+pub mod analysis;
+#[cfg(feature = "std")]
 pub mod backtracking;
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(feature = "std")]
+pub mod builder;
+pub mod byte_set;
+#[cfg(feature = "std")]
+pub mod codegen;
+#[cfg(feature = "std")]
+pub mod counting;
+#[cfg(feature = "differential")]
+pub mod differential;
+#[cfg(feature = "std")]
+pub mod dot;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod files;
+#[cfg(feature = "std")]
+pub mod lazy;
+pub mod lines;
+#[cfg(feature = "std")]
+pub mod nfa;
+#[cfg(feature = "std")]
+pub mod onepass;
+#[cfg(feature = "std")]
+pub mod optimize;
+#[cfg(feature = "std")]
+pub mod pikevm;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "std")]
 pub mod prefix;
+pub mod profile;
 pub mod program;
+pub mod sample;
+#[cfg(feature = "std")]
+pub mod serialize;
+#[cfg(feature = "std")]
+pub mod shuffle;
+#[cfg(feature = "std")]
+pub mod simd;
+pub mod simple;
+#[cfg(feature = "std")]
 pub mod threaded;
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod twopass;
+pub mod window;
+
+#[cfg(test)]
+mod test_util;
 
@@ -0,0 +1,84 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Turning the positions of matches into line and column numbers.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+/// The positions of the lines in some input, for finding the line and column of each match.
+///
+/// Building the index takes one pass over the input, and then each lookup is a binary search.
+/// Lines end after each "\n"; a "\r" before it is counted as part of the line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineIndex {
+    // The position of the start of each line. The first line always starts at 0.
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl LineIndex {
+    pub fn new(input: &[u8]) -> LineIndex {
+        let mut starts = vec![0];
+        starts.extend(input.iter().enumerate().filter(|&(_, &b)| b == b'\n').map(|(i, _)| i + 1));
+        LineIndex {
+            starts,
+            len: input.len(),
+        }
+    }
+
+    /// The number of lines. This is one more than the number of "\n"s, so an input that ends with
+    /// "\n" has an empty last line.
+    pub fn num_lines(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// Returns the line and column of the byte at `pos`, both counting from 1. Columns are
+    /// counted in bytes.
+    ///
+    /// `pos` can be the length of the input (for example, if it is the end of a match), but it
+    /// can't be any bigger.
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        assert!(pos <= self.len, "position {} is past the end of the input", pos);
+        // The number of lines that start at or before `pos`, which is at least 1.
+        let line = self.starts.partition_point(|&s| s <= pos);
+        (line, pos - self.starts[line - 1] + 1)
+    }
+
+    /// Returns the start and end of a line (counting from 1), not including its "\n".
+    pub fn line_range(&self, line: usize) -> (usize, usize) {
+        let start = self.starts[line - 1];
+        let end = self.starts.get(line).map(|&s| s - 1).unwrap_or(self.len);
+        (start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lines::LineIndex;
+
+    #[test]
+    fn test_line_col() {
+        let idx = LineIndex::new(b"ab\ncd\r\n\nef");
+        assert_eq!(idx.num_lines(), 4);
+        assert_eq!(idx.line_col(0), (1, 1));
+        assert_eq!(idx.line_col(2), (1, 3));
+        assert_eq!(idx.line_col(3), (2, 1));
+        assert_eq!(idx.line_col(5), (2, 3));
+        assert_eq!(idx.line_col(7), (3, 1));
+        assert_eq!(idx.line_col(10), (4, 3));
+        assert_eq!(idx.line_range(2), (3, 6));
+        assert_eq!(idx.line_range(3), (7, 7));
+        assert_eq!(idx.line_range(4), (8, 10));
+
+        let empty = LineIndex::new(b"");
+        assert_eq!(empty.num_lines(), 1);
+        assert_eq!(empty.line_col(0), (1, 1));
+        assert_eq!(LineIndex::new(b"a\n").line_range(2), (2, 2));
+    }
+}
@@ -0,0 +1,884 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Nondeterministic automata, for engines that determinize on the fly.
+//!
+//! An `Nfa` can also be turned into a `Program` ahead of time, using `Nfa::determinize`.
+
+use vec_bytes;
+use byte_set::ByteSet;
+use error::CompileError;
+use program::{InitStates, Program, TableInsts, TableStateIdx, ENDS_HERE_TOO};
+use std::collections::HashMap;
+use std::mem;
+
+/// Limits on the size of the DFA produced by `Nfa::determinize`.
+///
+/// Determinizing can produce exponentially many states, so these should be set when compiling
+/// patterns that come from untrusted sources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeterminizeConfig {
+    /// The maximum number of DFA states (including the dead state).
+    pub max_states: usize,
+    /// The maximum number of bytes to use, counting both the resulting table and the sets of
+    /// `Nfa` states that are needed while building it.
+    pub max_memory: usize,
+    /// Ignore the case of ASCII letters: every transition on a letter also applies to the same
+    /// letter in the other case.
+    pub case_insensitive: bool,
+    /// Keep track of the priorities of the `Nfa`'s threads (given by the order of the
+    /// epsilon-transitions), and drop the ones that can only give matches of lower priority than
+    /// one that was already found. Then the last match that the DFA reports, starting from some
+    /// position, is the one with the highest priority; this is what engines look for when
+    /// searching with `SearchConfig::leftmost_first`. An accepting state's own transitions have a
+    /// higher priority than its accepting, so they are greedy.
+    pub leftmost_first: bool,
+    /// Treat "\r\n" as a line terminator, like "\n": `accept_at_line_end` states also match
+    /// right before a "\r\n", but not between its '\r' and its '\n' (except for matches that
+    /// start there). The start of a line is still right after a '\n', which is also right after a
+    /// "\r\n".
+    pub crlf: bool,
+}
+
+impl Default for DeterminizeConfig {
+    fn default() -> DeterminizeConfig {
+        DeterminizeConfig {
+            max_states: 10_000,
+            max_memory: 16 * 1024 * 1024,
+            case_insensitive: false,
+            leftmost_first: false,
+            crlf: false,
+        }
+    }
+}
+
+/// A state in an `Nfa`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NfaState {
+    /// Transitions that consume a byte in the set.
+    pub transitions: Vec<(ByteSet, usize)>,
+    /// Transitions that don't consume anything.
+    pub eps: Vec<usize>,
+    /// Transitions that don't consume anything, but can only be taken at the start of a line
+    /// (that is, at the start of the input or right after a `'\n'`). `OnePassEngine` doesn't
+    /// support these.
+    pub line_start_eps: Vec<usize>,
+    /// Transitions that don't consume anything, but can only be taken at an ASCII word boundary
+    /// (that is, between a byte in `[0-9A-Za-z_]` and one that isn't, or the start or end of the
+    /// input). `OnePassEngine` doesn't support these.
+    pub word_boundary_eps: Vec<usize>,
+    /// If true, we have a match whenever we reach this state.
+    pub accept: bool,
+    /// If true, we have a match if we reach this state at the end of the input.
+    pub accept_at_eoi: bool,
+    /// If true, we have a match if we reach this state at the end of the input or right before a
+    /// `'\n'` (or a "\r\n", with `DeterminizeConfig::crlf`; the engines that run an `Nfa`
+    /// directly only look for a `'\n'`). `OnePassEngine` doesn't support this.
+    pub accept_at_line_end: bool,
+    /// If this is `Some(slot)`, reaching this state records the current position in the capture
+    /// slot `slot`. Only engines that report captures look at this.
+    pub save: Option<usize>,
+}
+
+impl NfaState {
+    // Returns true if this state can't consume anything or accept (but it might still have
+    // epsilon-transitions).
+    fn is_dead_end(&self) -> bool {
+        self.transitions.is_empty() && !self.accept && !self.accept_at_eoi && !self.accept_at_line_end
+    }
+
+    /// Returns true if we have a match when we are in this state right before the byte `next`
+    /// (or at the end of the input, if `next` is `None`).
+    pub fn accepts_before(&self, next: Option<u8>) -> bool {
+        self.accept
+            || (self.accept_at_eoi && next.is_none())
+            || (self.accept_at_line_end && next.is_none_or(|b| b == b'\n'))
+    }
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// Returns true if position `pos` of `input` is an ASCII word boundary, in the sense of
+/// `NfaState::word_boundary_eps`.
+pub fn is_word_boundary(input: &[u8], pos: usize) -> bool {
+    let before = Context::at(input, pos) == Context::Word;
+    before != input.get(pos).is_some_and(|&b| is_word_byte(b))
+}
+
+/// What kind of byte came before some position, as far as the assertions in an `Nfa` care.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Context {
+    /// The start of the input, or after a `'\n'`.
+    LineStart,
+    /// After a byte in `[0-9A-Za-z_]`.
+    Word,
+    Other,
+}
+
+impl Context {
+    /// The context at position `pos` of `input`.
+    pub fn at(input: &[u8], pos: usize) -> Context {
+        if pos == 0 { Context::LineStart } else { Context::after(input[pos - 1]) }
+    }
+
+    /// The context right after the byte `b`.
+    pub fn after(b: u8) -> Context {
+        if b == b'\n' {
+            Context::LineStart
+        } else if is_word_byte(b) {
+            Context::Word
+        } else {
+            Context::Other
+        }
+    }
+}
+
+// A state of the DFA built by `Nfa::determinize`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct DState {
+    // The `Nfa` states, closed under the epsilon-transitions that we know we can take.
+    set: Vec<usize>,
+    ctx: Context,
+    // If there was a match that ended shortly before the current position, how many bytes ago
+    // it ended (otherwise, zero).
+    ended: usize,
+    // In CRLF mode, this is `Some(pending)` if the last byte was a '\r', where `pending` is true
+    // if a match could have ended right before it (if the next byte is a '\n').
+    cr: Option<bool>,
+}
+
+impl DState {
+    fn dead() -> DState {
+        DState { set: Vec::new(), ctx: Context::Other, ended: 0, cr: None }
+    }
+
+    // Returns true if this state might still report a match that has already ended.
+    fn pending(&self) -> bool {
+        self.ended > 0 || self.cr == Some(true)
+    }
+}
+
+/// A nondeterministic automaton.
+///
+/// An `Nfa` starts in state 0, and it matches when it reaches an accepting state. Unlike a
+/// `Program`, it doesn't have a dead state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Nfa {
+    pub states: Vec<NfaState>,
+    /// If true, matches can only start at the beginning of the input.
+    pub anchored: bool,
+}
+
+impl Nfa {
+    /// Creates an `Nfa` with just a start state, which doesn't accept anything.
+    pub fn new() -> Nfa {
+        Nfa {
+            states: vec![NfaState::default()],
+            anchored: false,
+        }
+    }
+
+    /// Returns roughly how many bytes this `Nfa` holds on the heap.
+    pub fn memory_usage(&self) -> usize {
+        vec_bytes(&self.states) + self.states.iter().map(|s| {
+            vec_bytes(&s.transitions) + vec_bytes(&s.eps) + vec_bytes(&s.line_start_eps)
+                + vec_bytes(&s.word_boundary_eps)
+        }).sum::<usize>()
+    }
+
+    /// Adds a new state and returns its index.
+    pub fn add_state(&mut self) -> usize {
+        self.states.push(NfaState::default());
+        self.states.len() - 1
+    }
+
+    pub fn add_transition(&mut self, from: usize, bytes: ByteSet, to: usize) {
+        self.states[from].transitions.push((bytes, to));
+    }
+
+    pub fn add_eps(&mut self, from: usize, to: usize) {
+        self.states[from].eps.push(to);
+    }
+
+    /// Adds states that match `lit`, starting from `from`. Returns the last of the new states.
+    pub fn add_literal(&mut self, from: usize, lit: &[u8]) -> usize {
+        let mut cur = from;
+        for &b in lit {
+            let next = self.add_state();
+            self.add_transition(cur, Some(b).into_iter().collect(), next);
+            cur = next;
+        }
+        cur
+    }
+
+    /// Replaces `states` by the set of states that can be reached from them using only
+    /// epsilon-transitions, assuming that we aren't at the start of a line. The result is sorted.
+    pub fn eps_closure(&self, states: &mut Vec<usize>) {
+        self.closure(states, false, false)
+    }
+
+    /// Like `eps_closure`, but assumes that we are at the start of a line, so that the
+    /// `line_start_eps` transitions can be taken too.
+    pub fn line_start_closure(&self, states: &mut Vec<usize>) {
+        self.closure(states, true, false)
+    }
+
+    /// Like `eps_closure`, but also follows `line_start_eps` if `line_start` is true, and
+    /// `word_boundary_eps` if `boundary` is true.
+    pub fn closure(&self, states: &mut Vec<usize>, line_start: bool, boundary: bool) {
+        self.ordered_closure(states, line_start, boundary);
+        states.sort_unstable();
+    }
+
+    // Like `closure`, but keeps the states in order of priority: each state comes right after the
+    // one that reached it first, and `states` are in order of decreasing priority.
+    fn ordered_closure(&self, states: &mut Vec<usize>, line_start: bool, boundary: bool) {
+        let mut seen = vec![false; self.states.len()];
+        let mut stack: Vec<usize> = states.iter().rev().cloned().collect();
+        states.clear();
+        while let Some(s) = stack.pop() {
+            if !seen[s] {
+                seen[s] = true;
+                states.push(s);
+                // Push them in reverse so that the first one is explored first.
+                let st = &self.states[s];
+                if boundary {
+                    stack.extend(st.word_boundary_eps.iter().rev());
+                }
+                if line_start {
+                    stack.extend(st.line_start_eps.iter().rev());
+                }
+                stack.extend(st.eps.iter().rev());
+            }
+        }
+    }
+
+    // Like `ordered_closure`, but if `leftmost_first` is true then it also drops the states
+    // after the first accepting one. That one is kept, since its own transitions have a higher
+    // priority than its accepting. Otherwise, the result is sorted.
+    fn priority_closure(&self, states: &mut Vec<usize>, line_start: bool, boundary: bool,
+                        leftmost_first: bool) {
+        if leftmost_first {
+            self.ordered_closure(states, line_start, boundary);
+            if let Some(i) = states.iter().position(|&s| self.states[s].accept) {
+                states.truncate(i + 1);
+            }
+        } else {
+            self.closure(states, line_start, boundary);
+        }
+    }
+
+    /// Returns the (epsilon-closed) set of states that we can get to from `states` by consuming
+    /// `b`.
+    pub fn step(&self, states: &[usize], b: u8) -> Vec<usize> {
+        self.priority_step(states, b, false)
+    }
+
+    // Like `step`, but uses `priority_closure`.
+    fn priority_step(&self, states: &[usize], b: u8, leftmost_first: bool) -> Vec<usize> {
+        let mut ret: Vec<usize> = states.iter()
+            .flat_map(|&s| self.states[s].transitions.iter())
+            .filter(|t| t.0.contains(b))
+            .map(|t| t.1)
+            .collect();
+        self.priority_closure(&mut ret, b == b'\n', false, leftmost_first);
+        ret
+    }
+
+    /// Builds a DFA that matches the same strings as this `Nfa`, using the subset construction.
+    ///
+    /// State 0 of the result is the dead state (the empty set of `Nfa` states). The engines stop
+    /// at the first accepting state, so a DFA state accepts (with data 0, since the match ends
+    /// right there) as soon as any of its `Nfa` states do; its transitions are still kept, since
+    /// they are needed for searches that are anchored at the end (and for leftmost-first
+    /// searches, which keep going to find a match of higher priority).
+    ///
+    /// Some matches can only be seen once we have read the byte after them: those that end with
+    /// `accept_at_line_end` (which need a `'\n'` next) and those that end after a
+    /// `word_boundary_eps` transition. For these, the DFA state after that byte accepts with
+    /// data 1 (adding `ENDS_HERE_TOO` if another match ends right after that byte). If the `Nfa`
+    /// has `line_start_eps` or `word_boundary_eps` transitions, the program's start state depends
+    /// on the byte before it (see `InitStates`).
+    ///
+    /// This doesn't recurse, and apart from some scratch space the size of the `Nfa`, the memory
+    /// that it uses is counted towards `config.max_memory`. It returns `CompileError::TooBig` if
+    /// the DFA would exceed the limits in `config`.
+    pub fn determinize(&self, config: &DeterminizeConfig) -> Result<Program<TableInsts>, CompileError> {
+        if config.case_insensitive {
+            let folded = self.fold_case();
+            let config = DeterminizeConfig { case_insensitive: false, ..*config };
+            return folded.determinize(&config);
+        }
+
+        // We only need to remember what kind of byte came before if there are word boundaries.
+        let boundaries = self.states.iter().any(|s| !s.word_boundary_eps.is_empty());
+        let lf = config.leftmost_first;
+        let context = |c: Context| if boundaries { c } else { Context::Other };
+
+        let mut states: Vec<DState> = Vec::new();
+        let mut ids: HashMap<DState, usize> = HashMap::new();
+        let state_bytes = 256 * mem::size_of::<TableStateIdx>() + 4 * mem::size_of::<usize>();
+        let mut memory = 0;
+        let mut add = |st: DState, states: &mut Vec<DState>, memory: &mut usize| -> usize {
+            // Every empty set of `Nfa` states is the dead state.
+            let st = if st.set.is_empty() && !st.pending() { DState::dead() } else { st };
+            *ids.entry(st.clone()).or_insert_with(|| {
+                // The set is stored twice: once in `states` and once in `ids`.
+                *memory += state_bytes + 2 * st.set.len() * mem::size_of::<usize>();
+                states.push(st);
+                states.len() - 1
+            })
+        };
+        add(DState::dead(), &mut states, &mut memory);
+
+        // The start states after each kind of byte.
+        let mut start = |ctx: Context, states: &mut Vec<DState>, memory: &mut usize| {
+            let mut set = vec![0];
+            self.priority_closure(&mut set, ctx == Context::LineStart, false, lf);
+            add(DState { set, ctx: context(ctx), ended: 0, cr: None }, states, memory)
+        };
+        let line_start = start(Context::LineStart, &mut states, &mut memory);
+        let word = start(Context::Word, &mut states, &mut memory);
+        let other = start(Context::Other, &mut states, &mut memory);
+        let init = if self.anchored {
+            InitStates::Anchored(line_start)
+        } else if line_start == word && word == other {
+            InitStates::Constant(line_start)
+        } else if word == other && self.never_accepts(&states[other]) {
+            InitStates::AfterByte(b'\n', line_start)
+        } else if word == other {
+            InitStates::Contextual(b'\n', line_start, other)
+        } else {
+            let table = (0..=255u8).map(|b| match Context::after(b) {
+                Context::LineStart => line_start,
+                Context::Word => word,
+                Context::Other => other,
+            }).collect();
+            InitStates::ByPrevByte(line_start, table)
+        };
+
+        let mut table: Vec<TableStateIdx> = vec![0; 256];
+        let mut cur = 1;
+        while cur < states.len() {
+            if states.len() > config.max_states || memory > config.max_memory {
+                return Err(CompileError::TooBig);
+            }
+            let st = states[cur].clone();
+            for b in 0..=255u8 {
+                // If `st` itself accepts, the engines have already seen that match.
+                let set = self.before(&st, Some(b), lf);
+                let accepts = |set: &[usize]| set.iter().any(|&s| self.states[s].accept);
+                let line_end = set.iter().any(|&s| self.states[s].accept_at_line_end);
+                let mut ended = if accepts(&set) && !accepts(&st.set) { 1 } else { 0 };
+                if b == b'\n' && st.cr == Some(true) {
+                    // The match ended before the '\r'.
+                    ended = 2;
+                } else if b == b'\n' && line_end && st.cr.is_none() {
+                    ended = 1;
+                }
+                let next = DState {
+                    set: self.priority_step(&set, b, lf),
+                    ctx: context(Context::after(b)),
+                    ended,
+                    cr: if config.crlf && b == b'\r' { Some(line_end) } else { None },
+                };
+                table.push(add(next, &mut states, &mut memory) as TableStateIdx);
+            }
+            cur += 1;
+        }
+        if states.len() > config.max_states || memory > config.max_memory {
+            return Err(CompileError::TooBig);
+        }
+
+        // A match that ended before the last byte ends earlier than one that ends now, so it comes
+        // first. If both are there, the one that ends now is flagged with `ENDS_HERE_TOO`.
+        let data = |ended: usize, acc: bool| match (ended, acc) {
+            (0, true) => 0,
+            (0, false) => usize::MAX,
+            (_, true) => ended | ENDS_HERE_TOO,
+            (_, false) => ended,
+        };
+        let accept = states.iter()
+            .map(|st| data(st.ended, st.set.iter().any(|&s| self.states[s].accept)))
+            .collect();
+        let accept_at_eoi = states.iter()
+            .map(|st| {
+                let set = self.before(st, None, lf);
+                let acc = set.iter().map(|&s| &self.states[s])
+                    .any(|s| s.accept || s.accept_at_eoi || s.accept_at_line_end);
+                data(st.ended, acc)
+            })
+            .collect();
+        Ok(Program {
+            accept_at_eoi,
+            instructions: TableInsts::new(table, accept),
+            init,
+        })
+    }
+
+    // The `Nfa` states that we could be in while in the DFA state `st`, just before reading `next`
+    // (or at the end of the input, if `next` is `None`).
+    fn before(&self, st: &DState, next: Option<u8>, leftmost_first: bool) -> Vec<usize> {
+        let next_word = next.is_some_and(is_word_byte);
+        if next_word != (st.ctx == Context::Word) {
+            let mut set = st.set.clone();
+            self.priority_closure(&mut set, st.ctx == Context::LineStart, true, leftmost_first);
+            set
+        } else {
+            st.set.clone()
+        }
+    }
+
+    // Returns true if the DFA state `st` can never accept.
+    fn never_accepts(&self, st: &DState) -> bool {
+        let mut set = st.set.clone();
+        self.closure(&mut set, st.ctx == Context::LineStart, true);
+        !st.pending() && set.iter().all(|&s| self.states[s].is_dead_end())
+    }
+
+    // Returns a copy of this `Nfa` in which every transition on an ASCII letter also applies to
+    // the letter in the other case.
+    fn fold_case(&self) -> Nfa {
+        let mut ret = self.clone();
+        for st in &mut ret.states {
+            for &mut (ref mut bytes, _) in &mut st.transitions {
+                for b in bytes.iter().filter(|b| b.is_ascii_alphabetic()) {
+                    bytes.insert(b ^ 0x20);
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl Default for Nfa {
+    fn default() -> Nfa {
+        Nfa::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use lazy::LazyDfaEngine;
+    use byte_set::ByteSet;
+    use onepass::OnePassEngine;
+    use error::CompileError;
+    use nfa::{DeterminizeConfig, Nfa};
+    use optimize::OptimizeConfig;
+    use pikevm::PikeVmEngine;
+    use prefix::Prefix;
+    use program::{InitStates, Instructions};
+    use test_util::{assertions_nfa, ASSERTIONS_MATCHES};
+    use threaded::ThreadedEngine;
+    use {Engine, SearchConfig};
+
+    // "ab" or "ac".
+    fn ab_or_ac() -> Nfa {
+        let mut nfa = Nfa::new();
+        let a1 = nfa.add_state();
+        let a2 = nfa.add_state();
+        nfa.add_eps(0, a1);
+        nfa.add_eps(0, a2);
+        let b = nfa.add_literal(a1, b"ab");
+        let c = nfa.add_literal(a2, b"ac");
+        nfa.states[b].accept = true;
+        nfa.states[c].accept = true;
+        nfa
+    }
+
+    #[test]
+    fn test_determinize() {
+        let mut nfa = ab_or_ac();
+        // Also match "x" at the end of the input.
+        let x = nfa.add_literal(0, b"x");
+        nfa.states[x].accept_at_eoi = true;
+
+        let prog = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+        // The dead state, the start state, and the states after "a", "ab", "ac" and "x".
+        assert_eq!(prog.num_states(), 6);
+        let eng = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+        let lazy = LazyDfaEngine::new(nfa, Prefix::Empty);
+        for input in &["ab", "xxac", "aab", "ad", "x", "xa", "axbac", ""] {
+            assert_eq!(eng.shortest_match(input), lazy.shortest_match(input), "{}", input);
+        }
+        assert_eq!(eng.shortest_match("xxac"), Some((2, 4)));
+        assert_eq!(eng.shortest_match("zzx"), Some((2, 3)));
+    }
+
+    #[test]
+    fn test_determinize_case_insensitive() {
+        let config = DeterminizeConfig { case_insensitive: true, ..DeterminizeConfig::default() };
+        let prog = ab_or_ac().determinize(&config).unwrap();
+        let pref = Prefix::from_program(&prog);
+        assert!(matches!(pref, Prefix::Exact(_)));
+        let eng = BacktrackingEngine::new(prog, pref);
+        assert_eq!(eng.shortest_match("xxAB"), Some((2, 4)));
+        assert_eq!(eng.shortest_match("xxaC"), Some((2, 4)));
+        assert_eq!(eng.shortest_match("xxad"), None);
+    }
+
+    #[test]
+    fn test_determinize_multiline() {
+        // "^ab$" or "c", in multiline mode.
+        let mut nfa = Nfa::new();
+        let (s1, s2) = (nfa.add_state(), nfa.add_state());
+        nfa.states[0].line_start_eps.push(s1);
+        nfa.add_eps(0, s2);
+        let ab = nfa.add_literal(s1, b"ab");
+        nfa.states[ab].accept_at_line_end = true;
+        let c = nfa.add_literal(s2, b"c");
+        nfa.states[c].accept = true;
+
+        let prog = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+        assert!(matches!(prog.init, InitStates::Contextual(b'\n', _, _)));
+        let optimized = prog.optimize(&OptimizeConfig::default());
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog))),
+            Box::new(ThreadedEngine::new(prog, Prefix::Empty)),
+            Box::new(BacktrackingEngine::new(optimized, Prefix::Empty)),
+        ];
+        for eng in &engines {
+            assert_eq!(eng.shortest_match("xab\nab\nc"), Some((4, 6)));
+            assert_eq!(eng.shortest_match("ab"), Some((0, 2)));
+            assert_eq!(eng.shortest_match("abx\nc"), Some((4, 5)));
+            assert_eq!(eng.shortest_match("xab"), None);
+        }
+
+        // With no alternative, matches can only start at the start of a line.
+        nfa.states[0].eps.clear();
+        let prog = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+        assert!(matches!(prog.init, InitStates::AfterByte(b'\n', _)));
+    }
+
+    #[test]
+    fn test_determinize_crlf() {
+        // "^ab$" or "^c\r$", in multiline mode.
+        let mut nfa = Nfa::new();
+        let (s1, s2) = (nfa.add_state(), nfa.add_state());
+        nfa.states[0].line_start_eps.push(s1);
+        nfa.states[0].line_start_eps.push(s2);
+        let ab = nfa.add_literal(s1, b"ab");
+        nfa.states[ab].accept_at_line_end = true;
+        let c = nfa.add_literal(s2, b"c\r");
+        nfa.states[c].accept_at_line_end = true;
+
+        let crlf = DeterminizeConfig { crlf: true, ..DeterminizeConfig::default() };
+        let prog = nfa.determinize(&crlf).unwrap();
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog))),
+            Box::new(ThreadedEngine::new(prog.clone(), Prefix::Empty)),
+            Box::new(BacktrackingEngine::new(prog.optimize(&OptimizeConfig::default()),
+                                             Prefix::Empty)),
+        ];
+        for eng in &engines {
+            assert_eq!(eng.shortest_match("ab\r\n"), Some((0, 2)));
+            assert_eq!(eng.shortest_match("x\r\nab\r\nab"), Some((3, 5)));
+            assert_eq!(eng.shortest_match("ab\n"), Some((0, 2)));
+            assert_eq!(eng.shortest_match("ab\r"), None);
+            assert_eq!(eng.shortest_match("ab\rx"), None);
+            // This would end between the '\r' and the '\n'.
+            assert_eq!(eng.shortest_match("c\r\n"), None);
+            assert_eq!(eng.shortest_match("c\r\r\n"), Some((0, 2)));
+        }
+
+        let prog = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+        let eng = BacktrackingEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.shortest_match("ab\r\n"), None);
+        assert_eq!(eng.shortest_match("c\r\n"), Some((0, 2)));
+    }
+
+    #[test]
+    fn test_determinize_word_boundary() {
+        // "\bab\b".
+        let mut nfa = Nfa::new();
+        let s1 = nfa.add_state();
+        nfa.states[0].word_boundary_eps.push(s1);
+        let ab = nfa.add_literal(s1, b"ab");
+        let end = nfa.add_state();
+        nfa.states[ab].word_boundary_eps.push(end);
+        nfa.states[end].accept = true;
+
+        let prog = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+        assert!(matches!(prog.init, InitStates::ByPrevByte(..)));
+        let optimized = prog.optimize(&OptimizeConfig::default());
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog))),
+            Box::new(ThreadedEngine::new(prog, Prefix::Empty)),
+            Box::new(BacktrackingEngine::new(optimized, Prefix::Empty)),
+        ];
+        for eng in &engines {
+            assert_eq!(eng.shortest_match("ab"), Some((0, 2)));
+            assert_eq!(eng.shortest_match("x ab."), Some((2, 4)));
+            assert_eq!(eng.shortest_match("abc ab"), Some((4, 6)));
+            assert_eq!(eng.shortest_match("xab abx"), None);
+        }
+    }
+
+    #[test]
+    fn test_determinize_too_big() {
+        // "(a|b)*a(a|b){n}" needs 2^(n+1) states.
+        let n = 8;
+        let mut nfa = Nfa::new();
+        let ab: ByteSet = b"ab".iter().cloned().collect();
+        nfa.add_transition(0, ab, 0);
+        let mut cur = nfa.add_literal(0, b"a");
+        for _ in 0..n {
+            let next = nfa.add_state();
+            nfa.add_transition(cur, ab, next);
+            cur = next;
+        }
+        nfa.states[cur].accept = true;
+
+        let small = DeterminizeConfig { max_states: 100, ..DeterminizeConfig::default() };
+        assert_eq!(nfa.determinize(&small).unwrap_err(), CompileError::TooBig);
+        let small = DeterminizeConfig { max_memory: 50_000, ..DeterminizeConfig::default() };
+        assert_eq!(nfa.determinize(&small).unwrap_err(), CompileError::TooBig);
+        assert!(nfa.determinize(&DeterminizeConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn test_determinize_leftmost_first() {
+        // "ab|a", and "a|ab".
+        let alternation = |first: &[u8], second: &[u8]| {
+            let mut nfa = Nfa::new();
+            let (s1, s2) = (nfa.add_state(), nfa.add_state());
+            nfa.add_eps(0, s1);
+            nfa.add_eps(0, s2);
+            let e1 = nfa.add_literal(s1, first);
+            let e2 = nfa.add_literal(s2, second);
+            nfa.states[e1].accept = true;
+            nfa.states[e2].accept = true;
+            nfa
+        };
+        let config = DeterminizeConfig { leftmost_first: true, ..DeterminizeConfig::default() };
+        let lf = SearchConfig { leftmost_first: true, ..SearchConfig::default() };
+        for &(ref nfa, ab) in &[(alternation(b"ab", b"a"), Some((1, 3))),
+                            (alternation(b"a", b"ab"), Some((1, 2)))] {
+            let prog = nfa.determinize(&config).unwrap();
+            let bt = BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog));
+            let th = ThreadedEngine::new(prog, Prefix::Empty);
+            let pike = PikeVmEngine::new(nfa.clone(), Prefix::Empty);
+            assert_eq!(bt.search(b"xab", &lf), ab);
+            for input in &["xab", "ac", "aab", "b", ""] {
+                let input = input.as_bytes();
+                assert_eq!(bt.search(input, &lf), pike.search(input, &lf));
+                assert_eq!(th.search(input, &lf), pike.search(input, &lf));
+                // Without `leftmost_first`, we still get the shortest match.
+                assert_eq!(bt.search(input, &SearchConfig::default()),
+                           pike.search(input, &SearchConfig::default()));
+            }
+        }
+
+        // Accepting states that have transitions of their own: "a*" where the start state
+        // accepts (anchored and not), and "ab?c?" where the 'a' and 'b' states accept. The
+        // transitions are preferred over accepting, so these are greedy.
+        let mut star = Nfa::new();
+        star.states[0].accept = true;
+        star.add_transition(0, Some(b'a').into_iter().collect(), 0);
+        let mut anchored_star = star.clone();
+        anchored_star.anchored = true;
+        let mut optional = Nfa::new();
+        let a = optional.add_literal(0, b"a");
+        let b = optional.add_literal(a, b"b");
+        let c = optional.add_state();
+        optional.add_transition(a, Some(b'c').into_iter().collect(), c);
+        optional.add_transition(b, Some(b'c').into_iter().collect(), c);
+        for &s in &[a, b, c] {
+            optional.states[s].accept = true;
+        }
+        let anchored = SearchConfig { anchored_start: true, ..lf };
+        for nfa in &[star, anchored_star.clone(), optional] {
+            let prog = nfa.determinize(&config).unwrap();
+            let bt = BacktrackingEngine::new(prog.clone(), Prefix::Empty);
+            let th = ThreadedEngine::new(prog, Prefix::Empty);
+            let pike = PikeVmEngine::new(nfa.clone(), Prefix::Empty);
+            for input in &["a", "aa", "xaa", "ab", "abc", "ac", "xabcx", "bc", ""] {
+                let input = input.as_bytes();
+                for config in &[lf, anchored] {
+                    assert_eq!(pike.search(input, config), bt.search(input, config),
+                               "{:?} on {:?} with {:?}", nfa, input, config);
+                    assert_eq!(th.search(input, config), bt.search(input, config));
+                }
+            }
+        }
+        let pike = PikeVmEngine::new(anchored_star, Prefix::Empty);
+        assert_eq!(pike.search(b"a", &lf), Some((0, 1)));
+        assert_eq!(pike.search(b"aab", &lf), Some((0, 2)));
+    }
+
+    #[test]
+    fn test_engines_with_assertions() {
+        // "\b(ab|a)\b" (so that a boundary can come after a match that has already accepted),
+        // and "^b|c$" in multiline mode.
+        let mut boundaries = Nfa::new();
+        let (s, end) = (boundaries.add_state(), boundaries.add_state());
+        boundaries.states[0].word_boundary_eps.push(s);
+        let a = boundaries.add_literal(s, b"a");
+        let ab = boundaries.add_literal(a, b"b");
+        boundaries.states[a].word_boundary_eps.push(end);
+        boundaries.states[ab].word_boundary_eps.push(end);
+        boundaries.states[end].accept = true;
+        let mut lines = Nfa::new();
+        let s = lines.add_state();
+        lines.states[0].line_start_eps.push(s);
+        let b = lines.add_literal(s, b"b");
+        lines.states[b].accept = true;
+        let c = lines.add_literal(0, b"c");
+        lines.states[c].accept_at_line_end = true;
+        // "\b(a)?\b", which can have an empty match that is only seen after the 'a', and then a
+        // longer match right there.
+        let mut optional = Nfa::new();
+        let (s, skip, end) = (optional.add_state(), optional.add_state(), optional.add_state());
+        optional.states[0].word_boundary_eps.push(s);
+        let a = optional.add_literal(s, b"a");
+        optional.add_eps(s, skip);
+        optional.add_eps(a, skip);
+        optional.states[skip].word_boundary_eps.push(end);
+        optional.states[end].accept = true;
+
+        // Every string of length at most 4 over these bytes.
+        let mut inputs = vec![Vec::new()];
+        for len in 0..4 {
+            let longer: Vec<Vec<u8>> = inputs.iter()
+                .filter(|i| i.len() == len)
+                .flat_map(|i| b"abcd \n".iter().map(move |&b| {
+                    let mut i = i.clone();
+                    i.push(b);
+                    i
+                }))
+                .collect();
+            inputs.extend(longer);
+        }
+        inputs.extend(ASSERTIONS_MATCHES.iter().map(|m| m.0.as_bytes().to_vec()));
+
+        let default = SearchConfig::default();
+        let configs = [
+            default,
+            SearchConfig { anchored_end: true, ..default },
+            SearchConfig { anchored_start: true, anchored_end: true, ..default },
+            SearchConfig { leftmost_first: true, ..default },
+        ];
+        for nfa in &[assertions_nfa(), boundaries, lines.clone(), optional.clone()] {
+            let prog = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+            let expected = BacktrackingEngine::new(prog.clone(), Prefix::Empty);
+            let engines: Vec<Box<dyn Engine>> = vec![
+                Box::new(LazyDfaEngine::new(nfa.clone(), Prefix::Empty)),
+                Box::new(PikeVmEngine::new(nfa.clone(), Prefix::Empty)),
+                Box::new(ThreadedEngine::new(prog, Prefix::Empty)),
+            ];
+            assert!(OnePassEngine::new(nfa, Prefix::Empty).is_none());
+            for input in &inputs {
+                // Windows that end before the input does still see the assertions after them.
+                let len = input.len();
+                let windows = (0..=len).flat_map(|s| (s..=len).map(move |e| (s, e)));
+                for (start, end) in windows {
+                    for config in &configs {
+                        let config = SearchConfig { start, end: Some(end), ..*config };
+                        for eng in &engines {
+                            assert_eq!(eng.search(input, &config), expected.search(input, &config),
+                                       "{:?} on {:?} with {:?}", eng, input, config);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Multiline "c$" in a window that ends before the '\n'.
+        let eng = BacktrackingEngine::new(
+            lines.determinize(&DeterminizeConfig::default()).unwrap(), Prefix::Empty);
+        assert_eq!(eng.search(b"c\n", &SearchConfig { end: Some(1), ..default }), Some((0, 1)));
+        assert_eq!(eng.search(b"cx", &SearchConfig { end: Some(1), ..default }), None);
+
+        let eng = BacktrackingEngine::new(
+            optional.determinize(&DeterminizeConfig::default()).unwrap(), Prefix::Empty);
+        let anchored = SearchConfig { anchored_start: true, anchored_end: true, ..default };
+        assert_eq!(eng.search(b"a", &anchored), Some((0, 1)));
+        assert_eq!(eng.search(b"a", &SearchConfig { leftmost_first: true, ..default }),
+                   Some((0, 1)));
+        assert_eq!(eng.search(b"a", &default), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_leftmost_start_in_window() {
+        // "c\b", as the reversal of "\bc".
+        let mut nfa = Nfa::new();
+        let c = nfa.add_literal(0, b"c");
+        let end = nfa.add_state();
+        nfa.states[c].word_boundary_eps.push(end);
+        nfa.states[end].accept = true;
+        let rev = nfa.determinize(&DeterminizeConfig::default()).unwrap();
+        // The boundary before the window can only be seen by looking at the byte before it.
+        assert_eq!(rev.leftmost_start(b" c", 1, 2), Some(1));
+        assert_eq!(rev.leftmost_start(b"ac", 1, 2), None);
+        assert_eq!(rev.leftmost_start(b"c", 0, 1), Some(0));
+    }
+
+    #[test]
+    fn test_munch() {
+        // "a|ab": the lazy DFA and the engines running a `Program` ignore the priorities, but
+        // the Pike VM prefers "a".
+        let mut a_or_ab = Nfa::new();
+        let (s1, s2) = (a_or_ab.add_state(), a_or_ab.add_state());
+        a_or_ab.add_eps(0, s1);
+        a_or_ab.add_eps(0, s2);
+        let a = a_or_ab.add_literal(s1, b"a");
+        let ab = a_or_ab.add_literal(s2, b"ab");
+        a_or_ab.states[a].accept = true;
+        a_or_ab.states[ab].accept = true;
+        let prog = a_or_ab.determinize(&DeterminizeConfig::default()).unwrap();
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(LazyDfaEngine::new(a_or_ab.clone(), Prefix::Empty)),
+            Box::new(BacktrackingEngine::new(prog.clone(), Prefix::from_program(&prog))),
+            Box::new(ThreadedEngine::new(prog, Prefix::Empty)),
+        ];
+        for eng in &engines {
+            assert_eq!(eng.munch(b"abab", 0), Some(2), "{:?}", eng);
+            assert_eq!(eng.munch(b"abab", 2), Some(4), "{:?}", eng);
+            assert_eq!(eng.munch(b"aab", 0), Some(1), "{:?}", eng);
+            assert_eq!(eng.shortest_match("abab"), Some((0, 1)), "{:?}", eng);
+        }
+        assert_eq!(PikeVmEngine::new(a_or_ab, Prefix::Empty).munch(b"abab", 0), Some(1));
+
+        // "ab?", which is one-pass.
+        let mut greedy = Nfa::new();
+        let a = greedy.add_literal(0, b"a");
+        let (acc, b) = (greedy.add_state(), greedy.add_literal(a, b"b"));
+        greedy.add_eps(a, acc);
+        greedy.states[acc].accept = true;
+        greedy.states[b].accept = true;
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(LazyDfaEngine::new(greedy.clone(), Prefix::Empty)),
+            Box::new(PikeVmEngine::new(greedy.clone(), Prefix::Empty)),
+            Box::new(OnePassEngine::new(&greedy, Prefix::Empty).unwrap()),
+        ];
+        for eng in &engines {
+            assert_eq!(eng.munch(b"abab", 0), Some(2), "{:?}", eng);
+            assert_eq!(eng.munch(b"ac", 0), Some(1), "{:?}", eng);
+            assert_eq!(eng.munch(b"xab", 0), None, "{:?}", eng);
+            assert_eq!(eng.shortest_match("xab"), Some((1, 2)), "{:?}", eng);
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let nfa = ab_or_ac();
+        let (a1, a2, b, c) = (1, 2, 4, 6);
+
+        let mut start = vec![0];
+        nfa.eps_closure(&mut start);
+        assert_eq!(start, vec![0, a1, a2]);
+        let after_a = nfa.step(&start, b'a');
+        assert_eq!(after_a.len(), 2);
+        assert_eq!(nfa.step(&after_a, b'b'), vec![b]);
+        assert_eq!(nfa.step(&after_a, b'c'), vec![c]);
+        assert!(nfa.step(&after_a, b'a').is_empty());
+    }
+}
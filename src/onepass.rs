@@ -0,0 +1,312 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An engine for finding captures in a single pass, for `Nfa`s that are "one-pass."
+//!
+//! An `Nfa` is one-pass if, from any state that we can be in after consuming a byte, there is
+//! only one path through epsilon-transitions that leads to consuming the next byte (and only one
+//! that leads to accepting). Then, given a starting position, there is only ever one thread, so we
+//! can find its captures without the overhead of the Pike VM.
+
+use {vec_bytes, Engine, SearchConfig};
+use nfa::{Nfa, NfaState};
+use pikevm::Captures;
+use prefix::{Prefix, PrefixSearcherFactory};
+
+// A transition in the one-pass automaton.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Trans {
+    // The index of the state we go to.
+    next: usize,
+    // The index (in `OnePass::actions`) of the slots to save before consuming the byte.
+    action: usize,
+}
+
+// What happens when we accept in some state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Accept {
+    // If false, we can only accept at the end of the input.
+    anywhere: bool,
+    action: usize,
+}
+
+/// The automaton used by a `OnePassEngine`.
+#[derive(Clone, Debug, PartialEq)]
+struct OnePass {
+    // The transitions out of state `s` are `table[256 * s..256 * (s + 1)]`. State 0 is the start.
+    table: Vec<Option<Trans>>,
+    accept: Vec<Option<Accept>>,
+    // Lists of slots to save.
+    actions: Vec<Vec<usize>>,
+}
+
+impl OnePass {
+    fn new(nfa: &Nfa) -> Option<OnePass> {
+        // The automaton doesn't know what is around the current position, so it can't check
+        // assertions.
+        let assertions = |s: &NfaState| {
+            !s.line_start_eps.is_empty() || !s.word_boundary_eps.is_empty() || s.accept_at_line_end
+        };
+        if nfa.states.iter().any(assertions) {
+            return None;
+        }
+
+        // The states of the one-pass automaton are the start state of the `Nfa`, and the targets
+        // of its consuming transitions.
+        let mut index = vec![None; nfa.states.len()];
+        let mut states = vec![0];
+        index[0] = Some(0);
+        for st in &nfa.states {
+            for &(_, t) in &st.transitions {
+                if index[t].is_none() {
+                    index[t] = Some(states.len());
+                    states.push(t);
+                }
+            }
+        }
+
+        let mut ret = OnePass {
+            table: vec![None; 256 * states.len()],
+            accept: vec![None; states.len()],
+            actions: Vec::new(),
+        };
+        for (i, &s) in states.iter().enumerate() {
+            // Follow the epsilon-transitions from `s`, keeping track of the slots that we pass.
+            let mut seen = vec![false; nfa.states.len()];
+            let mut stack = vec![(s, Vec::new())];
+            while let Some((cur, mut saves)) = stack.pop() {
+                if seen[cur] {
+                    // There are two ways to get to `cur`.
+                    return None;
+                }
+                seen[cur] = true;
+                let st = &nfa.states[cur];
+                if let Some(slot) = st.save {
+                    saves.push(slot);
+                }
+                for &t in &st.eps {
+                    stack.push((t, saves.clone()));
+                }
+
+                let action = ret.actions.len();
+                let mut used = false;
+                if st.accept || st.accept_at_eoi {
+                    if ret.accept[i].is_some() {
+                        return None;
+                    }
+                    ret.accept[i] = Some(Accept { anywhere: st.accept, action });
+                    used = true;
+                }
+                for &(ref bytes, t) in &st.transitions {
+                    for b in bytes {
+                        let entry = &mut ret.table[256 * i + b as usize];
+                        if entry.is_some() {
+                            return None;
+                        }
+                        *entry = Some(Trans { next: index[t].unwrap(), action });
+                        used = true;
+                    }
+                }
+                if used {
+                    ret.actions.push(saves);
+                }
+            }
+        }
+        Some(ret)
+    }
+
+    fn memory_usage(&self) -> usize {
+        vec_bytes(&self.table) + vec_bytes(&self.accept) + vec_bytes(&self.actions)
+            + self.actions.iter().map(vec_bytes).sum::<usize>()
+    }
+}
+
+/// Returns true if `nfa` is one-pass, meaning that it can be used with a `OnePassEngine`.
+///
+/// An `Nfa` with assertions (`line_start_eps`, `word_boundary_eps` or `accept_at_line_end`) is
+/// never one-pass.
+pub fn is_one_pass(nfa: &Nfa) -> bool {
+    OnePass::new(nfa).is_some()
+}
+
+/// An engine that finds captures for one-pass `Nfa`s.
+///
+/// Like `BacktrackingEngine`, it tries one candidate position at a time, but at each position it
+/// finds the captures as it goes. With `SearchConfig::leftmost_first`, it returns the longest match
+/// starting there.
+#[derive(Clone, Debug)]
+pub struct OnePassEngine<P: PrefixSearcherFactory = Prefix> {
+    dfa: OnePass,
+    prefix: P,
+    anchored: bool,
+    num_slots: usize,
+}
+
+impl<P: PrefixSearcherFactory> OnePassEngine<P> {
+    /// Creates a new engine, or returns `None` if `nfa` isn't one-pass (see `is_one_pass`).
+    pub fn new(nfa: &Nfa, pref: P) -> Option<OnePassEngine<P>> {
+        let num_slots = nfa.states.iter().filter_map(|s| s.save).map(|s| s + 1).max().unwrap_or(0);
+        OnePass::new(nfa).map(|dfa| OnePassEngine {
+            dfa,
+            prefix: pref,
+            anchored: nfa.anchored,
+            num_slots,
+        })
+    }
+
+    /// Searches for a match in `input`, and returns it along with its captures.
+    pub fn captures(&self, input: &[u8], config: &SearchConfig) -> Option<Captures> {
+        let (start, end) = config.bounds(input)?;
+        if config.anchored_start || self.anchored {
+            return self.captures_at(input, start, end, config);
+        }
+
+        // The searcher can only return a span, so we pass the captures out on the side.
+        let mut ret = None;
+        self.prefix.with_searcher(&input[..end], &mut |searcher| {
+            searcher.skip_to(start);
+            searcher.leftmost_match(self.prefix.out_of_order_len(), &mut |res| {
+                let caps = self.captures_at(input, res.start_pos, end, config)?;
+                let span = (caps.start, caps.end);
+                ret = Some(caps);
+                Some(span)
+            })
+        });
+        ret
+    }
+
+    fn captures_at(&self, input: &[u8], start: usize, end: usize, config: &SearchConfig)
+    -> Option<Captures> {
+        let mut slots = vec![None; self.num_slots];
+        let mut state = 0;
+        let mut pos = start;
+        let save = |slots: &mut Vec<Option<usize>>, action: usize, pos: usize| {
+            for &slot in &self.dfa.actions[action] {
+                slots[slot] = Some(pos);
+            }
+        };
+
+        // With `leftmost_first`, we keep going to find the longest match.
+        let mut last = None;
+        while pos < end {
+            if let Some(acc) = self.dfa.accept[state] {
+                if acc.anywhere && !config.anchored_end {
+                    if !config.leftmost_first {
+                        save(&mut slots, acc.action, pos);
+                        return Some(Captures { start, end: pos, slots });
+                    }
+                    let mut acc_slots = slots.clone();
+                    save(&mut acc_slots, acc.action, pos);
+                    last = Some(Captures { start, end: pos, slots: acc_slots });
+                }
+            }
+            let trans = match self.dfa.table[256 * state + input[pos] as usize] {
+                Some(t) => t,
+                None => return last,
+            };
+            save(&mut slots, trans.action, pos);
+            state = trans.next;
+            pos += 1;
+        }
+
+        match self.dfa.accept[state] {
+            Some(acc) if acc.anywhere || end == input.len() => {
+                save(&mut slots, acc.action, end);
+                Some(Captures { start, end, slots })
+            },
+            _ => last,
+        }
+    }
+}
+
+impl<P> Engine for OnePassEngine<P>
+where P: PrefixSearcherFactory + Clone + 'static {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        self.captures(input, config).map(|c| (c.start, c.end))
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(self.clone())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.dfa.memory_usage() + self.prefix.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_set::ByteSet;
+    use nfa::Nfa;
+    use onepass::{is_one_pass, OnePassEngine};
+    use pikevm::PikeVmEngine;
+    use prefix::Prefix;
+    use test_util::assertions_nfa;
+    use {Engine, SearchConfig};
+
+    // Matches "a", then some 'b's (captured in slots 0 and 1), then "c".
+    fn nfa() -> Nfa {
+        let mut nfa = Nfa::new();
+        let a = nfa.add_literal(0, b"a");
+        let open = nfa.add_state();
+        nfa.states[open].save = Some(0);
+        nfa.add_eps(a, open);
+        let b = nfa.add_literal(open, b"b");
+        let b_set: ByteSet = Some(b'b').into_iter().collect();
+        nfa.add_transition(b, b_set, b);
+        let close = nfa.add_state();
+        nfa.states[close].save = Some(1);
+        nfa.add_eps(b, close);
+        let c = nfa.add_literal(close, b"c");
+        nfa.states[c].accept = true;
+        nfa
+    }
+
+    #[test]
+    fn test_is_one_pass() {
+        assert!(is_one_pass(&nfa()));
+
+        // "ab" or "ac" isn't one-pass, because we don't know which 'a' to take.
+        let mut nfa = Nfa::new();
+        let (s1, s2) = (nfa.add_state(), nfa.add_state());
+        nfa.add_eps(0, s1);
+        nfa.add_eps(0, s2);
+        let b = nfa.add_literal(s1, b"ab");
+        let c = nfa.add_literal(s2, b"ac");
+        nfa.states[b].accept = true;
+        nfa.states[c].accept = true;
+        assert!(!is_one_pass(&nfa));
+        assert!(OnePassEngine::new(&nfa, Prefix::Empty).is_none());
+
+        // The one-pass automaton can't check assertions, even where there is only one path.
+        assert!(!is_one_pass(&assertions_nfa()));
+        let mut line_start = Nfa::new();
+        let s = line_start.add_state();
+        line_start.states[0].line_start_eps.push(s);
+        let ab = line_start.add_literal(s, b"ab");
+        line_start.states[ab].accept = true;
+        assert!(OnePassEngine::new(&line_start, Prefix::Empty).is_none());
+    }
+
+    #[test]
+    fn test_captures() {
+        let eng = OnePassEngine::new(&nfa(), Prefix::Empty).unwrap();
+        let pike = PikeVmEngine::new(nfa(), Prefix::Empty);
+        let config = SearchConfig::default();
+        for input in &["xxabbbcx", "abc", "ac", "abbx", "aabcabbc", ""] {
+            assert_eq!(eng.captures(input.as_bytes(), &config),
+                       pike.captures(input.as_bytes(), &config), "{}", input);
+        }
+        let caps = eng.captures(b"xxabbbcx", &config).unwrap();
+        assert_eq!(caps.slots, vec![Some(3), Some(6)]);
+
+        let to_end = SearchConfig { anchored_end: true, ..config };
+        assert_eq!(eng.search(b"abcabbc", &to_end), Some((3, 7)));
+        assert_eq!(eng.search(b"abcx", &to_end), None);
+    }
+}
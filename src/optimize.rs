@@ -0,0 +1,469 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+pub use byte_set::ByteClasses;
+use profile::Profile;
+use program::{Instructions, Program, TableInsts};
+use std::collections::{HashMap, VecDeque};
+
+/// Chooses which passes are run by `Program::optimize`.
+///
+/// The passes are always run in the same order (the order of the fields below), since some of
+/// them are much more effective after others have been run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptimizeConfig {
+    /// Remove states that are unreachable from the start state, or from which we can never
+    /// accept.
+    pub remove_dead_states: bool,
+    /// Partition the input bytes into classes that behave identically in every state, and
+    /// compress the table to one column for each class (see `TableInsts::compress`). The later
+    /// passes then only need to look at one byte from each class.
+    pub byte_classes: bool,
+    /// Merge all states that are equivalent (i.e. they accept the same inputs).
+    pub minimize: bool,
+    /// Merge states whose transitions and accept data are identical. This is subsumed by
+    /// `minimize`, but it is cheaper.
+    pub dedup: bool,
+    /// Renumber the states in breadth-first order from the start state, so that states that
+    /// are close together in the DFA are close together in memory.
+    pub reorder_states: bool,
+    /// Renumber the states so that the accepting ones are contiguous, which makes checking for
+    /// acceptance cheaper (see `TableInsts::accept_range`).
+    pub group_accepting: bool,
+}
+
+impl Default for OptimizeConfig {
+    fn default() -> OptimizeConfig {
+        OptimizeConfig {
+            remove_dead_states: true,
+            byte_classes: true,
+            minimize: true,
+            dedup: true,
+            reorder_states: true,
+            group_accepting: true,
+        }
+    }
+}
+
+impl ByteClasses {
+    /// Finds the coarsest partition of bytes that is respected by every state in `insts`.
+    pub fn from_table(insts: &TableInsts) -> ByteClasses {
+        let mut classes = vec![0usize; 256];
+
+        for state in 0..insts.num_states() {
+            let mut new_classes = HashMap::new();
+            for (b, class) in classes.iter_mut().enumerate() {
+                let key = (*class, insts.transition(state, b as u8));
+                let len = new_classes.len();
+                *class = *new_classes.entry(key).or_insert(len);
+            }
+        }
+
+        let mut ret = [0u8; 256];
+        for (r, &c) in ret.iter_mut().zip(&classes) {
+            *r = c as u8;
+        }
+        ByteClasses::new(ret)
+    }
+}
+
+impl TableInsts {
+    /// Returns an equivalent table with one column for each class of bytes that can be told
+    /// apart (see `ByteClasses::from_table`).
+    pub fn compress(&self) -> TableInsts {
+        let classes = ByteClasses::from_table(self);
+        let reps = classes.representatives();
+        let table = (0..self.num_states())
+            .flat_map(|s| reps.iter().map(move |&b| self.transition(s, b)))
+            .collect();
+        TableInsts::new(table, self.accept.clone()).with_classes(classes)
+    }
+}
+
+impl Program<TableInsts> {
+    /// Runs the optimization passes selected by `config`, returning an equivalent program.
+    ///
+    /// State 0 is still the dead state, but the other states will generally be renumbered. In
+    /// particular, any `Prefix` referring to states of this program should be constructed after
+    /// optimizing.
+    pub fn optimize(&self, config: &OptimizeConfig) -> Program<TableInsts> {
+        let mut ret = self.clone();
+        if ret.num_states() <= 1 {
+            return ret;
+        }
+
+        if config.remove_dead_states {
+            ret = ret.remove_dead_states();
+        }
+        let classes = if config.byte_classes {
+            ret.instructions = ret.instructions.compress();
+            ret.instructions.classes.clone()
+        } else {
+            ByteClasses::singletons()
+        };
+        if config.minimize {
+            ret = ret.minimize(&classes);
+        }
+        if config.dedup {
+            ret = ret.dedup();
+        }
+        if config.reorder_states {
+            ret = ret.reorder_states();
+        }
+        if config.group_accepting {
+            ret = ret.group_accepting();
+        }
+        ret
+    }
+
+    /// Renumbers the states so that the ones visited most often in `profile` come first (after
+    /// the dead state), which puts the hot parts of the table close together in memory.
+    ///
+    /// This undoes `OptimizeConfig::reorder_states` and `OptimizeConfig::group_accepting`.
+    /// Returns `None` if `profile` doesn't have the same number of states as this program.
+    pub fn reorder_by_profile(&self, profile: &Profile) -> Option<Program<TableInsts>> {
+        if profile.counts().len() != self.num_states() {
+            return None;
+        }
+        let mut order: Vec<usize> = (1..self.num_states()).collect();
+        order.sort_by(|&a, &b| profile.counts()[b].cmp(&profile.counts()[a]));
+
+        let mut map = vec![Some(0); self.num_states()];
+        for (new_s, &s) in order.iter().enumerate() {
+            map[s] = Some(new_s + 1);
+        }
+        Some(self.renumber(&map))
+    }
+
+    fn is_accepting(&self, state: usize) -> bool {
+        self.accept(state).is_some() || self.check_eoi(state).is_some()
+    }
+
+    fn table_next(&self, state: usize, b: u8) -> Option<usize> {
+        let next = self.instructions.transition(state, b);
+        if next != 0 { Some(next as usize) } else { None }
+    }
+
+    fn remove_dead_states(&self) -> Program<TableInsts> {
+        let n = self.num_states();
+        let mut reachable = vec![false; n];
+        let mut stack = self.init.states();
+        for &s in &stack {
+            reachable[s] = true;
+        }
+        while let Some(s) = stack.pop() {
+            for b in 0..256 {
+                if let Some(t) = self.table_next(s, b as u8) {
+                    if !reachable[t] {
+                        reachable[t] = true;
+                        stack.push(t);
+                    }
+                }
+            }
+        }
+
+        let mut rev_edges = vec![Vec::new(); n];
+        for s in 0..n {
+            for b in 0..256 {
+                if let Some(t) = self.table_next(s, b as u8) {
+                    rev_edges[t].push(s);
+                }
+            }
+        }
+        let mut live: Vec<bool> = (0..n).map(|s| self.is_accepting(s)).collect();
+        let mut stack: Vec<usize> = (0..n).filter(|&s| live[s]).collect();
+        while let Some(t) = stack.pop() {
+            for &s in &rev_edges[t] {
+                if !live[s] {
+                    live[s] = true;
+                    stack.push(s);
+                }
+            }
+        }
+
+        let mut next_idx = 0;
+        let map: Vec<Option<usize>> = (0..n).map(|s| {
+            if s == 0 || (reachable[s] && live[s]) {
+                // The dead state is always kept, and it stays at index 0.
+                next_idx += 1;
+                Some(next_idx - 1)
+            } else {
+                None
+            }
+        }).collect();
+        self.renumber(&map)
+    }
+
+    fn minimize(&self, classes: &ByteClasses) -> Program<TableInsts> {
+        let n = self.num_states();
+        let reps = classes.representatives();
+        let dead = usize::MAX;
+
+        // Start by separating states according to their accept data.
+        let mut blocks = HashMap::new();
+        let mut block: Vec<usize> = (0..n).map(|s| {
+            let len = blocks.len();
+            *blocks.entry((self.instructions.accept[s], self.accept_at_eoi[s])).or_insert(len)
+        }).collect();
+        let mut num_blocks = blocks.len();
+
+        loop {
+            let mut sigs = HashMap::new();
+            let new_block: Vec<usize> = (0..n).map(|s| {
+                let mut sig = Vec::with_capacity(reps.len() + 1);
+                sig.push(block[s]);
+                sig.extend(reps.iter()
+                    .map(|&b| self.table_next(s, b).map(|t| block[t]).unwrap_or(dead)));
+                let len = sigs.len();
+                *sigs.entry(sig).or_insert(len)
+            }).collect();
+
+            block = new_block;
+            if sigs.len() == num_blocks {
+                break;
+            }
+            num_blocks = sigs.len();
+        }
+
+        self.merge(&block)
+    }
+
+    fn dedup(&self) -> Program<TableInsts> {
+        let mut ret = self.clone();
+        loop {
+            let n = ret.num_states();
+            let mut rows = HashMap::new();
+            let map: Vec<usize> = (0..n).map(|s| {
+                let row = ret.instructions.row(s);
+                let key = (row, ret.instructions.accept[s], ret.accept_at_eoi[s]);
+                let len = rows.len();
+                *rows.entry(key).or_insert(len)
+            }).collect();
+
+            if rows.len() == n {
+                return ret;
+            }
+            ret = ret.merge(&map);
+        }
+    }
+
+    fn reorder_states(&self) -> Program<TableInsts> {
+        let n = self.num_states();
+        let mut map = vec![None; n];
+        let mut queue = VecDeque::new();
+        let mut next_idx = 1;
+        map[0] = Some(0);
+        for s in self.init.states() {
+            if map[s].is_none() {
+                map[s] = Some(next_idx);
+                next_idx += 1;
+                queue.push_back(s);
+            }
+        }
+
+        while let Some(s) = queue.pop_front() {
+            for b in 0..256 {
+                if let Some(t) = self.table_next(s, b as u8) {
+                    if map[t].is_none() {
+                        map[t] = Some(next_idx);
+                        next_idx += 1;
+                        queue.push_back(t);
+                    }
+                }
+            }
+        }
+        // Unreachable states go at the end, in their original order.
+        for idx in &mut map {
+            if idx.is_none() {
+                *idx = Some(next_idx);
+                next_idx += 1;
+            }
+        }
+
+        self.renumber(&map)
+    }
+
+    fn group_accepting(&self) -> Program<TableInsts> {
+        // The dead state stays at 0, and the accepting states go last.
+        let n = self.num_states();
+        let mut order: Vec<usize> = (0..n).filter(|&s| self.accept(s).is_none()).collect();
+        order.extend((0..n).filter(|&s| self.accept(s).is_some()));
+
+        let mut map = vec![None; n];
+        for (new_s, &s) in order.iter().enumerate() {
+            map[s] = Some(new_s);
+        }
+        self.renumber(&map)
+    }
+
+    /// Merges states according to `map`, in which equal values mean that states should be merged.
+    /// The block containing the dead state stays dead.
+    fn merge(&self, map: &[usize]) -> Program<TableInsts> {
+        // Block indices are assigned in order of first appearance, so merging is a special case
+        // of renumbering.
+        let mut compact = HashMap::new();
+        let map: Vec<Option<usize>> = map.iter().map(|&b| {
+            let len = compact.len();
+            Some(*compact.entry(b).or_insert(len))
+        }).collect();
+        self.renumber(&map)
+    }
+
+    /// Builds a new program in which state `s` becomes `map[s]`, where `map[0]` must be `0`. If
+    /// several states map to the same new state, the first one is kept. States that map to `None`
+    /// are merged into the dead state.
+    fn renumber(&self, map: &[Option<usize>]) -> Program<TableInsts> {
+        debug_assert!(map[0] == Some(0));
+        let new_len = map.iter().filter_map(|&x| x).max().map(|x| x + 1).unwrap_or(1);
+        let stride = self.instructions.stride();
+        let mut table = vec![0; new_len * stride];
+        let mut accept = vec![usize::MAX; new_len];
+        let mut accept_at_eoi = vec![usize::MAX; new_len];
+        let mut done = vec![false; new_len];
+        done[0] = true;
+
+        for (s, &new_s) in map.iter().enumerate() {
+            if let Some(new_s) = new_s {
+                if done[new_s] {
+                    continue;
+                }
+                done[new_s] = true;
+
+                for (c, &t) in self.instructions.row(s).iter().enumerate() {
+                    if let Some(t) = map[t as usize] {
+                        table[new_s * stride + c] = t as u32;
+                    }
+                }
+                accept[new_s] = self.instructions.accept[s];
+                accept_at_eoi[new_s] = self.accept_at_eoi[s];
+            }
+        }
+
+        Program {
+            accept_at_eoi,
+            instructions: TableInsts::new(table, accept)
+                .with_classes(self.instructions.classes.clone()),
+            init: self.init.map(|s| map[s].unwrap_or(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use SearchConfig;
+    use optimize::*;
+    use program::*;
+    use test_util::table_prog;
+
+    // Returns the end of the anchored match, if there is one.
+    fn match_end(prog: &Program<TableInsts>, input: &str) -> Option<usize> {
+        let start = prog.init.state_at_pos(&[], 0).unwrap();
+        match prog.run(start, input.as_bytes()) {
+            RunResult::Accept { pos, .. } => Some(pos),
+            RunResult::Dead { .. } => None,
+            RunResult::Eoi { state } => prog.check_eoi(state).map(|_| input.len()),
+        }
+    }
+
+    fn check_equiv(a: &Program<TableInsts>, b: &Program<TableInsts>, inputs: &[&str]) {
+        for input in inputs {
+            assert_eq!(match_end(a, input), match_end(b, input), "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_byte_classes() {
+        let prog = table_prog(3, &[(1, b'a', 2), (1, b'b', 2), (2, b'c', 2)], &[2]);
+        let classes = ByteClasses::from_table(&prog.instructions);
+        assert_eq!(classes.num_classes(), 3);
+        assert_eq!(classes.class(b'a'), classes.class(b'b'));
+        assert!(classes.class(b'a') != classes.class(b'c'));
+        assert_eq!(classes.class(b'x'), classes.class(b'y'));
+        assert_eq!(classes.representatives().len(), 3);
+    }
+
+    #[test]
+    fn test_compress() {
+        let prog = table_prog(5,
+            &[(1, b'a', 2), (1, b'b', 2), (2, b'c', 3), (3, b'c', 3), (3, b'd', 4)], &[4]);
+        let inputs = ["acd", "bcccd", "ac", "abd", "", "d", "acdd"];
+        let small = Program { instructions: prog.instructions.compress(), ..prog.clone() };
+        assert_eq!(small.instructions.stride(), 4);
+        assert_eq!(small.instructions.table.len(), 5 * 4);
+        check_equiv(&prog, &small, &inputs);
+
+        // The passes work on compressed tables, and keep them compressed.
+        let opt = small.optimize(&OptimizeConfig::default());
+        assert_eq!(opt.instructions.stride(), 4);
+        assert_eq!(opt.num_states(), prog.optimize(&OptimizeConfig::default()).num_states());
+        check_equiv(&prog, &opt, &inputs);
+        assert_eq!(opt.instructions.to_full().compress().table, opt.instructions.table);
+    }
+
+    #[test]
+    fn test_optimize() {
+        // Two redundant branches for "a" and "b", an unreachable state 5, and a state 6 that
+        // can never accept.
+        let prog = table_prog(7,
+            &[(1, b'a', 2), (1, b'b', 3), (2, b'c', 4), (3, b'c', 4), (5, b'c', 4), (1, b'x', 6)],
+            &[4]);
+        let inputs = ["ac", "bc", "acc", "ab", "x", "xc", "", "a", "cc"];
+
+        let opt = prog.optimize(&OptimizeConfig::default());
+        assert_eq!(opt.num_states(), 4);
+        // The table is compressed to the classes {a}, {b}, {c} and everything else ('x' only
+        // went to a dead state).
+        assert_eq!(opt.instructions.stride(), 4);
+        check_equiv(&prog, &opt, &inputs);
+
+        let only_dead = OptimizeConfig {
+            remove_dead_states: true,
+            byte_classes: false,
+            minimize: false,
+            dedup: false,
+            reorder_states: false,
+            group_accepting: false,
+        };
+        let opt = prog.optimize(&only_dead);
+        assert_eq!(opt.num_states(), 5);
+        assert_eq!(opt.instructions.stride(), 256);
+        check_equiv(&prog, &opt, &inputs);
+
+        let only_dedup = OptimizeConfig { dedup: true, remove_dead_states: false, ..only_dead };
+        let opt = prog.optimize(&only_dedup);
+        // States 2, 3 and 5 all have the same row, and state 6 is the same as the dead state.
+        assert_eq!(opt.num_states(), 4);
+        check_equiv(&prog, &opt, &inputs);
+    }
+
+    #[test]
+    fn test_group_accepting() {
+        let prog = table_prog(5, &[(1, b'a', 2), (2, b'b', 3), (3, b'c', 4)], &[2, 4]);
+        let config = OptimizeConfig { group_accepting: true, ..OptimizeConfig::default() };
+        let opt = prog.optimize(&config);
+        let (start, end) = opt.instructions.accept_range;
+        assert_eq!(end - start, 2);
+        assert!((start..end).all(|s| opt.accept(s).is_some()));
+        check_equiv(&prog, &opt, &["a", "ab", "abc", "abcd", "b", ""]);
+    }
+
+    #[test]
+    fn test_reorder_by_profile() {
+        // "ab*c", where we mostly see 'b's.
+        let prog = table_prog(4, &[(1, b'a', 2), (2, b'b', 2), (2, b'c', 3)], &[3]);
+        let mut profile = Profile::new(prog.num_states());
+        profile.search(&prog, b"abbbbbbc", &SearchConfig::default());
+        assert_eq!(profile.hottest(1), vec![(2, 7)]);
+
+        assert!(prog.reorder_by_profile(&Profile::new(3)).is_none());
+        let opt = prog.reorder_by_profile(&profile).unwrap();
+        assert_eq!(opt.init.state_at_pos(b"", 0), Some(2));
+        assert_eq!(opt.table_next(1, b'b'), Some(1));
+        check_equiv(&prog, &opt, &["abc", "abbbbc", "ac", "a", "", "bc"]);
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An engine that simulates an `Nfa` directly, keeping track of capture slots.
+//!
+//! This is the classic "Pike VM": we keep a list of threads, one per NFA state, ordered by
+//! priority. Since there is at most one thread per state, the running time is linear in the
+//! length of the input (times the size of the `Nfa`).
+
+use {Engine, SearchConfig};
+use nfa::{is_word_boundary, Context, Nfa};
+use prefix::{Prefix, PrefixSearcherFactory};
+use serialize::{write_any, write_u8, Serialize};
+use std::io::{self, Write};
+use std::mem;
+
+/// A match, together with the positions recorded in the capture slots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captures {
+    pub start: usize,
+    pub end: usize,
+    /// `slots[i]` is the position at which the matching thread last passed through a state whose
+    /// `save` is `Some(i)`.
+    pub slots: Vec<Option<usize>>,
+}
+
+#[derive(Clone, Debug)]
+struct Thread {
+    state: usize,
+    start: usize,
+    slots: Vec<Option<usize>>,
+}
+
+// The threads at one position, in order of decreasing priority.
+struct Threads {
+    threads: Vec<Thread>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(num_states: usize) -> Threads {
+        Threads {
+            threads: Vec::new(),
+            seen: vec![false; num_states],
+        }
+    }
+
+    // Keeps only the threads satisfying `f`, which is given each thread and its index.
+    fn keep<F: Fn(usize, &Thread) -> bool>(&mut self, f: F) {
+        let mut i = 0;
+        let seen = &mut self.seen;
+        self.threads.retain(|th| {
+            let keep = f(i, th);
+            seen[th.state] = keep;
+            i += 1;
+            keep
+        });
+    }
+
+    fn clear(&mut self) {
+        for th in &self.threads {
+            self.seen[th.state] = false;
+        }
+        self.threads.clear();
+    }
+}
+
+/// An engine that simulates an `Nfa`, and can report the positions of captures.
+///
+/// Like the other engines, this looks for the leftmost match and then returns the shortest match
+/// starting there (or the one with the highest priority, with `SearchConfig::leftmost_first`).
+/// Among the threads that find that match, the one with the highest priority determines the
+/// captures: the order of each state's epsilon-transitions gives their priority, and an accepting
+/// state's transitions have a higher priority than its accepting.
+///
+/// Following epsilon-transitions uses an explicit stack instead of recursing, so deeply nested
+/// `Nfa`s can't overflow the call stack. There is at most one thread per `Nfa` state, and each
+/// thread has its own capture slots, so a search uses memory proportional to the number of states
+/// times the number of slots.
+#[derive(Clone, Debug)]
+pub struct PikeVmEngine<P: PrefixSearcherFactory = Prefix> {
+    nfa: Nfa,
+    prefix: P,
+    num_slots: usize,
+}
+
+impl<P: PrefixSearcherFactory> PikeVmEngine<P> {
+    pub fn new(nfa: Nfa, pref: P) -> PikeVmEngine<P> {
+        let num_slots = nfa.states.iter().filter_map(|s| s.save).map(|s| s + 1).max().unwrap_or(0);
+        PikeVmEngine {
+            nfa,
+            prefix: pref,
+            num_slots,
+        }
+    }
+
+    /// Searches for a match in `input`, and returns it along with its captures.
+    pub fn captures(&self, input: &[u8], config: &SearchConfig) -> Option<Captures> {
+        let (start, end) = config.bounds(input)?;
+        let anchored = config.anchored_start || self.nfa.anchored;
+        let mut cur = Threads::new(self.nfa.states.len());
+        let mut next = Threads::new(self.nfa.states.len());
+        let mut ret: Option<Captures> = None;
+
+        self.prefix.with_searcher(&input[..end], &mut |searcher| {
+            let mut pos = if anchored {
+                start
+            } else {
+                searcher.search_from(start)?.start_pos
+            };
+            loop {
+                if ret.is_none() && (!anchored || pos == start) {
+                    self.add_thread(&mut cur, 0, pos, vec![None; self.num_slots], input, pos);
+                }
+
+                // Look for accepting threads, in order of priority. Once one accepts, the
+                // threads after it can only give matches that start later (or end later, or have
+                // lower priority).
+                let at_end = pos == end;
+                for i in 0..cur.threads.len() {
+                    let st = &self.nfa.states[cur.threads[i].state];
+                    let accepts = st.accepts_before(input.get(pos).cloned())
+                        && (at_end || !config.anchored_end);
+                    // With `leftmost_first`, the threads that are still alive all have a
+                    // higher priority than the match we already found.
+                    let better = |r: &Captures| cur.threads[i].start < r.start
+                        || (config.leftmost_first && cur.threads[i].start == r.start);
+                    if accepts && ret.as_ref().is_none_or(better) {
+                        let th = &cur.threads[i];
+                        ret = Some(Captures { start: th.start, end: pos, slots: th.slots.clone() });
+                        let start = th.start;
+                        let lf = config.leftmost_first;
+                        // This thread's own transitions have a higher priority than its accepting
+                        // (as in `DeterminizeConfig::leftmost_first`), so it keeps running.
+                        cur.keep(|j, th| j <= i && (th.start < start || (lf && th.start == start)));
+                        break;
+                    }
+                }
+                if at_end || (ret.is_some() && (config.earliest || cur.threads.is_empty())) {
+                    return None;
+                }
+
+                let b = input[pos];
+                for th in &cur.threads {
+                    for &(ref bytes, target) in &self.nfa.states[th.state].transitions {
+                        if bytes.contains(b) {
+                            let slots = th.slots.clone();
+                            self.add_thread(&mut next, target, th.start, slots, input, pos + 1);
+                        }
+                    }
+                }
+                cur.clear();
+                mem::swap(&mut cur, &mut next);
+                pos += 1;
+
+                if cur.threads.is_empty() && ret.is_none() {
+                    if anchored {
+                        return None;
+                    }
+                    // Skip ahead to the next place that a match could start.
+                    pos = searcher.search_from(pos)?.start_pos;
+                }
+            }
+        });
+        ret
+    }
+
+    // Adds a thread in `state`, along with all the threads that it reaches by epsilon-transitions
+    // at position `pos` of `input`.
+    fn add_thread(&self, threads: &mut Threads, state: usize, start: usize,
+                  slots: Vec<Option<usize>>, input: &[u8], pos: usize) {
+        let line_start = Context::at(input, pos) == Context::LineStart;
+        let boundary = is_word_boundary(input, pos);
+        let mut stack = vec![(state, slots)];
+        while let Some((state, mut slots)) = stack.pop() {
+            if threads.seen[state] {
+                continue;
+            }
+            threads.seen[state] = true;
+            let st = &self.nfa.states[state];
+            if let Some(slot) = st.save {
+                slots[slot] = Some(pos);
+            }
+            // Push them in reverse so that the first one is explored first. The plain
+            // epsilon-transitions come before the assertions, as in `Nfa::closure`.
+            let mut push = |targets: &[usize]| {
+                for &t in targets.iter().rev() {
+                    stack.push((t, slots.clone()));
+                }
+            };
+            if boundary {
+                push(&st.word_boundary_eps);
+            }
+            if line_start {
+                push(&st.line_start_eps);
+            }
+            push(&st.eps);
+            threads.threads.push(Thread { state, start, slots });
+        }
+    }
+}
+
+impl<P> Engine for PikeVmEngine<P>
+where P: PrefixSearcherFactory + Clone + 'static {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        self.captures(input, config).map(|c| (c.start, c.end))
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(self.clone())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.nfa.memory_usage() + self.prefix.memory_usage()
+    }
+
+    fn write_engine(&self, mut w: &mut dyn Write) -> io::Result<()> {
+        write_u8(&mut w, 5)?;
+        self.nfa.write_to(&mut w)?;
+        write_any(&mut w, &self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_set::ByteSet;
+    use nfa::Nfa;
+    use pikevm::PikeVmEngine;
+    use prefix::Prefix;
+    use test_util::{assertions_nfa, ASSERTIONS_MATCHES};
+    use {Engine, SearchConfig};
+
+    // Matches "a", then some 'b's (captured in slots 0 and 1), then "c".
+    fn nfa() -> Nfa {
+        let mut nfa = Nfa::new();
+        let a = nfa.add_literal(0, b"a");
+        let open = nfa.add_state();
+        nfa.states[open].save = Some(0);
+        nfa.add_eps(a, open);
+        let b = nfa.add_literal(open, b"b");
+        let b_set: ByteSet = Some(b'b').into_iter().collect();
+        nfa.add_transition(b, b_set, b);
+        let close = nfa.add_state();
+        nfa.states[close].save = Some(1);
+        nfa.add_eps(b, close);
+        let c = nfa.add_literal(close, b"c");
+        nfa.states[c].accept = true;
+        nfa
+    }
+
+    #[test]
+    fn test_captures() {
+        let eng = PikeVmEngine::new(nfa(), Prefix::Empty);
+        let caps = eng.captures(b"xxabbbcx", &SearchConfig::default()).unwrap();
+        assert_eq!((caps.start, caps.end), (2, 7));
+        assert_eq!(caps.slots, vec![Some(3), Some(6)]);
+        assert_eq!(eng.shortest_match("xxacabx"), None);
+
+        let eng = PikeVmEngine::new(nfa(), Prefix::Byte(b'a'));
+        assert_eq!(eng.shortest_match("aabca"), Some((1, 4)));
+    }
+
+    #[test]
+    fn test_leftmost() {
+        // "abcde" or "bc".
+        let mut nfa = Nfa::new();
+        let (s1, s2) = (nfa.add_state(), nfa.add_state());
+        nfa.add_eps(0, s1);
+        nfa.add_eps(0, s2);
+        let long = nfa.add_literal(s1, b"abcde");
+        let short = nfa.add_literal(s2, b"bc");
+        nfa.states[long].accept = true;
+        nfa.states[short].accept = true;
+
+        let eng = PikeVmEngine::new(nfa, Prefix::Empty);
+        assert_eq!(eng.shortest_match("xabcdex"), Some((1, 6)));
+        assert_eq!(eng.shortest_match("xabcdx"), Some((2, 4)));
+        let earliest = SearchConfig { earliest: true, ..SearchConfig::default() };
+        assert_eq!(eng.search(b"xabcdex", &earliest), Some((2, 4)));
+    }
+
+    #[test]
+    fn test_leftmost_first() {
+        // "a+", greedy or not depending on the order of the epsilon-transitions.
+        let repeat = |greedy: bool| {
+            let mut nfa = Nfa::new();
+            let (a, acc) = (nfa.add_state(), nfa.add_state());
+            nfa.add_transition(0, Some(b'a').into_iter().collect(), a);
+            if greedy { nfa.add_eps(a, 0); }
+            nfa.add_eps(a, acc);
+            if !greedy { nfa.add_eps(a, 0); }
+            nfa.states[acc].accept = true;
+            PikeVmEngine::new(nfa, Prefix::Empty)
+        };
+        let lf = SearchConfig { leftmost_first: true, ..SearchConfig::default() };
+        assert_eq!(repeat(true).search(b"xaaax", &lf), Some((1, 4)));
+        assert_eq!(repeat(false).search(b"xaaax", &lf), Some((1, 2)));
+        assert_eq!(repeat(true).search(b"xaaax", &SearchConfig::default()), Some((1, 2)));
+    }
+
+    #[test]
+    fn test_assertions() {
+        let eng = PikeVmEngine::new(assertions_nfa(), Prefix::Empty);
+        for &(input, m) in ASSERTIONS_MATCHES {
+            assert_eq!(eng.shortest_match(input), m, "{:?}", input);
+        }
+        let config = SearchConfig { end: Some(2), ..SearchConfig::default() };
+        assert_eq!(eng.search(b"ab\n", &config), Some((0, 2)));
+    }
+
+    #[test]
+    fn test_config() {
+        let eng = PikeVmEngine::new(nfa(), Prefix::Empty);
+        let anchored = SearchConfig { anchored_start: true, ..SearchConfig::default() };
+        assert_eq!(eng.search(b"xabc", &anchored), None);
+        assert_eq!(eng.search(b"xabc", &SearchConfig { start: 1, ..anchored }), Some((1, 4)));
+
+        let to_end = SearchConfig { anchored_end: true, ..SearchConfig::default() };
+        assert_eq!(eng.search(b"abcx", &to_end), None);
+        assert_eq!(eng.search(b"abcabbc", &to_end), Some((3, 7)));
+        assert_eq!(eng.search(b"abcx", &SearchConfig { end: Some(3), ..to_end }), Some((0, 3)));
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Sharing scratch space between the threads that search with an engine.
+//!
+//! Engines that need scratch space (like the `ThreadedEngine` with its `threaded::Cache`, or the
+//! `LazyDfaEngine` with its `lazy::LazyCache`) keep one per thread when they are searched through
+//! the `Engine` trait. That is wasteful if there are many short-lived threads, and it doesn't
+//! bound the memory used. A `Pool` instead hands out scratch spaces to whichever thread asks for
+//! one, and takes them back when the search is done. The pool is only locked while a scratch
+//! space is taken or returned, never during a search.
+
+use std::fmt::{self, Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// A pool of values (usually scratch space for searching) that can be shared between threads.
+pub struct Pool<T> {
+    idle: Mutex<Vec<T>>,
+    create: Box<dyn Fn() -> T + Send + Sync>,
+    max_idle: usize,
+}
+
+impl<T: Send> Pool<T> {
+    /// Creates an empty pool that calls `create` whenever it needs a new value.
+    pub fn new<F: Fn() -> T + Send + Sync + 'static>(create: F) -> Pool<T> {
+        Pool {
+            idle: Mutex::new(Vec::new()),
+            create: Box::new(create),
+            max_idle: usize::MAX,
+        }
+    }
+
+    /// Keeps at most this many values that aren't in use; values returned to a full pool are
+    /// dropped. This bounds the memory held after a burst of concurrent searches.
+    pub fn with_max_idle(mut self, max_idle: usize) -> Pool<T> {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Takes a value out of the pool (or creates a new one, if they are all in use). The value
+    /// goes back into the pool when the guard is dropped.
+    pub fn get(&self) -> PoolGuard<'_, T> {
+        let value = self.lock().pop().unwrap_or_else(|| (self.create)());
+        PoolGuard { pool: self, value: Some(value) }
+    }
+
+    /// The number of values that are in the pool and not in use.
+    pub fn idle(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Vec<T>> {
+        // Nothing can panic while the lock is held, so it can't really be poisoned.
+        self.idle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn put(&self, value: T) {
+        let mut idle = self.lock();
+        if idle.len() < self.max_idle {
+            idle.push(value);
+        }
+    }
+}
+
+impl<T> Debug for Pool<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Pool").field("max_idle", &self.max_idle).finish()
+    }
+}
+
+/// A value that was taken out of a `Pool` by `Pool::get`.
+pub struct PoolGuard<'a, T: Send + 'a> {
+    pool: &'a Pool<T>,
+    // This is only `None` while the guard is being dropped.
+    value: Option<T>,
+}
+
+impl<'a, T: Send> Deref for PoolGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: Send> DerefMut for PoolGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: Send> Drop for PoolGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.pool.put(value);
+        }
+    }
+}
+
+impl<'a, T: Send + Debug> Debug for PoolGuard<'a, T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_tuple("PoolGuard").field(&**self).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lazy::{LazyCache, LazyDfaEngine};
+    use nfa::Nfa;
+    use pool::Pool;
+    use prefix::Prefix;
+    use std::sync::Arc;
+    use std::thread;
+    use test_util::lits_prog;
+    use threaded::ThreadedEngine;
+
+    #[test]
+    fn test_reuse() {
+        let pool = Pool::new(Vec::<u8>::new).with_max_idle(1);
+        {
+            let mut a = pool.get();
+            a.push(1);
+            let b = pool.get();
+            assert!(b.is_empty());
+            assert_eq!(pool.idle(), 0);
+            drop(a);
+            drop(b);
+        }
+        // Only the first one that was returned was kept.
+        assert_eq!(pool.idle(), 1);
+        let a = pool.get();
+        assert_eq!(*a, vec![1]);
+    }
+
+    #[test]
+    fn test_threads() {
+        let eng = Arc::new(ThreadedEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty));
+        let pool = Arc::new(eng.cache_pool());
+        let handles: Vec<_> = (0..4).map(|_| {
+            let (eng, pool) = (eng.clone(), pool.clone());
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(eng.shortest_match_with(&mut pool.get(), b"xxcdab"), Some((2, 4)));
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(pool.idle() >= 1 && pool.idle() <= 4);
+
+        let mut nfa = Nfa::new();
+        let ab = nfa.add_literal(0, b"ab");
+        nfa.states[ab].accept = true;
+        let lazy = LazyDfaEngine::new(nfa, Prefix::Empty);
+        let pool = Pool::new(LazyCache::new);
+        assert_eq!(lazy.search_with(&mut pool.get(), b"xab", &Default::default()), Some((1, 3)));
+    }
+}
@@ -6,9 +6,17 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use aho_corasick::{Automaton, AcAutomaton, FullAcAutomaton, MatchesOverlapping};
-use memchr::memchr;
-use memmem::{Searcher, TwoWaySearcher};
+use aho_corasick::{Automaton, AcAutomaton, FullAcAutomaton, Matches, MatchesOverlapping};
+use byte_set::ByteSet;
+use memchr::{memchr, memchr2, memchr3};
+use memmem::{Searcher as MemSearcher, TwoWaySearcher};
+use program::{InitStates, Instructions, Program};
+use simd::ByteSetFinder;
+use std::cmp;
+use std::fmt::Debug;
+use std::mem;
+use std::sync::Arc;
+use vec_bytes;
 
 /// A `Prefix` is the first part of a DFA. Anything matching the DFA should start with
 /// something matching the `Prefix`.
@@ -20,16 +28,28 @@ pub enum Prefix {
     // Matches every position.
     Empty,
     // Matches a single byte in a particular set.
-    ByteSet(Vec<bool>),
+    ByteSet(ByteSet),
     // Matches one specific byte.
     Byte(u8),
+    // Matches either of two specific bytes.
+    Byte2(u8, u8),
+    // Matches any of three specific bytes.
+    Byte3(u8, u8, u8),
     // Matches a specific sequence of bytes.
     Lit(Vec<u8>),
+    // Matches a specific sequence of bytes, but instead of searching for the whole sequence we
+    // look for the byte at the given offset (which should be a rare one) and then check whether
+    // the rest of the sequence is around it.
+    LitRare(Vec<u8>, usize),
+    // Matches a specific sequence of bytes, ignoring ASCII case. The sequence is stored in lower
+    // case.
+    LitCaseless(Vec<u8>),
     // Matches one of several sequences of bytes. The sequences are contained in the
     // `FullAcAutomaton`. The `Vec<usize>` tells us which state the DFA should start in after
     // matching each sequence. That is, `vec[i] == s` if after finding sequence `i` we should
-    // start in state `s`.
-    Ac(FullAcAutomaton<Vec<u8>>, Vec<usize>),
+    // start in state `s`. The `AcMatchKind` says whether we need to look for overlapping
+    // matches.
+    Ac(FullAcAutomaton<Vec<u8>>, Vec<usize>, AcMatchKind),
     // Matches a maximal (but possibly non-empty) sequence of bytes each of which belong to a
     // particular set of bytes.
     //
@@ -39,14 +59,131 @@ pub enum Prefix {
     // bytes that keeps us in the first state then there's no point in trying to start in the
     // middle of that sequence of bytes: even if that would give a match, we would get an earlier
     // match from starting at the beginning of the sequence.
-    LoopWhile(Vec<bool>),
+    //
+    // If the `usize` (the minimum run length) is non-zero, this prefix behaves differently: it
+    // matches (with an empty interval) every position that is followed by at least that many
+    // bytes in the set. This is intended for DFAs that need to see a run of bytes in the set
+    // before they can match, as in `[0-9]{4,}`.
+    LoopWhile(ByteSet, usize),
+    // Matches the positions that could start a match containing a specific sequence of bytes,
+    // which needn't be at the start of the match.
+    //
+    // The `ByteSet` must contain every byte that can appear in a match before the first occurrence
+    // of the sequence. Having found the sequence, we scan backwards through bytes in the set to
+    // find the earliest possible start, and then every position from there up to the sequence is
+    // a candidate. The backwards scan never revisits positions that were already returned, so
+    // the total amount of scanning is linear.
+    InnerLit(Vec<u8>, ByteSet),
+    // Matches the same thing as the inner `Prefix`, but with the additional promise that the
+    // strings it matches are exactly the matches of the DFA. That is, the engines don't need to
+    // run the DFA at all: they can just return the first thing that the `Prefix` finds.
+    Exact(Box<Prefix>),
+    // Matches a specific sequence of bytes, but only if it is followed by a byte in a particular
+    // set.
+    LitThenSet(Vec<u8>, ByteSet),
+    // Matches the beginning of the input and every position right after a specific byte. This
+    // is intended for programs that can only start at the beginning of a line.
+    AfterByte(u8),
+}
+
+/// The ways in which an Aho-Corasick prefix can look for its strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcMatchKind {
+    /// Finds every occurrence of every string, even if they overlap. This is always correct,
+    /// but if the strings share suffixes it can return a lot of redundant candidates.
+    Overlapping,
+    /// After finding a string, continues looking from the end of that string. This is only
+    /// correct if the strings can't overlap one another, or if the first match is all we need
+    /// (as for an `Exact` prefix).
+    NonOverlapping,
+}
+
+/// Limits on the size of a `Prefix` that is built from a set of strings.
+///
+/// Searching for a large number of strings at once can be slower (and take more memory) than
+/// just running the DFA, so if there are too many strings we fall back to something simpler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixLimits {
+    /// The maximum number of strings.
+    pub max_strings: usize,
+    /// The maximum total length of all the strings.
+    pub max_total_len: usize,
+    /// What to do if the strings exceed the limits.
+    pub fallback: LimitFallback,
+    /// How common each byte is in the data that will be searched. If this is given, we won't
+    /// look for bytes that are too common to be worth it, and we look for a single literal by
+    /// its rarest byte (as in `Prefix::with_rare_byte_in`).
+    pub byte_frequencies: Option<ByteFrequencies>,
+}
+
+/// How often each byte appears in the data that will be searched.
+///
+/// Looking for a few bytes with `memchr` only saves time if those bytes are rare, and which ones
+/// are rare depends on the data: spaces are everywhere in text, and zeros are everywhere in
+/// binary files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteFrequencies {
+    counts: [u64; 256],
+    total: u64,
+}
+
+impl ByteFrequencies {
+    /// Counts the bytes in a sample of the data.
+    pub fn from_sample(sample: &[u8]) -> ByteFrequencies {
+        let mut counts = [0u64; 256];
+        for &b in sample {
+            counts[b as usize] += 1;
+        }
+        ByteFrequencies::from_counts(counts)
+    }
+
+    /// Uses `counts[b]` as the number of times that the byte `b` appears (or any other number that
+    /// is proportional to it).
+    pub fn from_counts(counts: [u64; 256]) -> ByteFrequencies {
+        ByteFrequencies {
+            counts,
+            total: counts.iter().fold(0u64, |acc, &c| acc.saturating_add(c)),
+        }
+    }
+
+    /// The number of times that `b` appears.
+    pub fn count(&self, b: u8) -> u64 {
+        self.counts[b as usize]
+    }
+
+    // Returns true if the bytes in `bs` are too common to be worth looking for.
+    fn is_common(&self, bs: &ByteSet) -> bool {
+        let count = bs.iter().fold(0u64, |acc, b| acc.saturating_add(self.count(b)));
+        count > self.total / COMMON_BYTES_DENOMINATOR
+    }
+}
+
+/// What kind of `Prefix` to build if some strings exceed the `PrefixLimits`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitFallback {
+    /// Match any of the strings' first bytes.
+    FirstByte,
+    /// Match every position.
+    Empty,
+}
+
+impl Default for PrefixLimits {
+    fn default() -> PrefixLimits {
+        PrefixLimits {
+            max_strings: 1000,
+            max_total_len: 16 * 1024,
+            fallback: LimitFallback::FirstByte,
+            byte_frequencies: None,
+        }
+    }
 }
 
 /// The result of scanning through the input for a `Prefix`.
 ///
 /// The semi-open interval `[start_pos, end_pos)` is the part of the interval that was consumed by
 /// the `Prefix`. The state `end_state` is the DFA state at which we should start to continue
-/// matching; that is, the DFA should begin at position `end_pos` in state `end_state`.
+/// matching; that is, the DFA should begin at position `end_pos` in state `end_state`. If
+/// `end_state` is `None`, the DFA should begin at position `end_pos` in its initial state.
 ///
 /// Note that some `Prefix`es return empty intervals (`start_pos == end_pos`). This doesn't mean
 /// necessarily that the `Prefix` didn't match any input, only that it's simpler (and fast) just
@@ -55,7 +192,26 @@ pub enum Prefix {
 pub struct PrefixResult {
     pub start_pos: usize,
     pub end_pos: usize,
-    pub end_state: usize,
+    pub end_state: Option<usize>,
+}
+
+impl PrefixResult {
+    fn shift(self, offset: usize) -> PrefixResult {
+        PrefixResult {
+            start_pos: self.start_pos + offset,
+            end_pos: self.end_pos + offset,
+            end_state: self.end_state,
+        }
+    }
+
+    /// The earliest position at which this candidate, or any candidate found after it, can
+    /// start (see `PrefixSearcherFactory::out_of_order_len`).
+    pub fn earliest_start(&self, out_of_order_len: Option<usize>) -> usize {
+        match out_of_order_len {
+            Some(len) => cmp::min(self.start_pos, self.end_pos.saturating_sub(len)),
+            None => self.start_pos,
+        }
+    }
 }
 
 /// Encapsulates the `Prefix` and the input string, and allows iteration over all matches.
@@ -65,6 +221,149 @@ pub trait PrefixSearcher {
     /// From the current position in the input, finds the next substring matching the `Prefix`
     /// and advances the "cursor" past that point.
     fn search(&mut self) -> Option<PrefixResult>;
+
+    /// Finds the next substring matching the `Prefix` that starts at or after `pos`.
+    fn search_from(&mut self, pos: usize) -> Option<PrefixResult> {
+        self.skip_to(pos);
+        self.search()
+    }
+
+    /// Calls `f` on the candidates from the current position, and returns the match (found by
+    /// `f`) that starts first.
+    ///
+    /// If the candidates come in order of where they start, this stops at the first match.
+    /// Otherwise (see `PrefixSearcherFactory::out_of_order_len`), it keeps going until no later
+    /// candidate could start before the best match so far.
+    fn leftmost_match(&mut self, out_of_order_len: Option<usize>,
+                      f: &mut dyn FnMut(&PrefixResult) -> Option<(usize, usize)>)
+    -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        while let Some(res) = self.search() {
+            if let Some(b) = best {
+                if res.earliest_start(out_of_order_len) >= b.0 {
+                    break;
+                } else if res.start_pos >= b.0 {
+                    continue;
+                }
+            }
+            if let Some(m) = f(&res) {
+                best = Some(m);
+                if out_of_order_len.is_none() {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+impl<S: PrefixSearcher + ?Sized> PrefixSearcher for Box<S> {
+    fn skip_to(&mut self, pos: usize) { (**self).skip_to(pos) }
+    fn search(&mut self) -> Option<PrefixResult> { (**self).search() }
+}
+
+/// A search that runs with a `PrefixSearcher`, returning the interval of a match.
+pub type SearchFn<'a> = dyn FnMut(&mut dyn PrefixSearcher) -> Option<(usize, usize)> + 'a;
+
+/// Something that can produce `PrefixSearcher`s.
+///
+/// The engines use one of these to find candidate starting positions. The built-in
+/// implementation is `Prefix`, but applications can supply their own in order to plug in
+/// domain-specific skipping logic.
+pub trait PrefixSearcherFactory: Debug {
+    /// Takes an input string and prepares for quickly finding candidate matches in it.
+    fn searcher<'a>(&'a self, input: &'a [u8]) -> Box<dyn PrefixSearcher + 'a>;
+
+    /// Calls `f` with a searcher for `input`.
+    ///
+    /// This is what the engines use. The default implementation just calls `searcher`, but
+    /// implementations can override it to avoid allocating the searcher on the heap.
+    fn with_searcher(&self,
+            input: &[u8],
+            f: &mut SearchFn)
+    -> Option<(usize, usize)> {
+        f(&mut *self.searcher(input))
+    }
+
+    /// Returns true if the intervals found by the searchers are exactly the matches of the
+    /// program, so that the engines can skip running the program.
+    fn is_exact(&self) -> bool { false }
+
+    /// Returns `None` if the searchers find candidates in order of where they start. Otherwise,
+    /// returns a length such that a candidate is never found after another one that ends this
+    /// many bytes (or more) after it starts.
+    ///
+    /// For example, an `Ac` prefix for "abcd" and "bc" finds "bc" before "abcd" in "abcd", since
+    /// it ends first. Since the strings are at most 4 bytes long, this returns `Some(4)`.
+    fn out_of_order_len(&self) -> Option<usize> { None }
+
+    /// Returns roughly how many bytes this holds on the heap. The default implementation returns
+    /// 0.
+    fn memory_usage(&self) -> usize { 0 }
+}
+
+impl PrefixSearcherFactory for Prefix {
+    fn searcher<'a>(&'a self, input: &'a [u8]) -> Box<dyn PrefixSearcher + 'a> {
+        Box::new(self.make_adaptive_searcher(input))
+    }
+
+    fn with_searcher(&self,
+            input: &[u8],
+            f: &mut SearchFn)
+    -> Option<(usize, usize)> {
+        f(&mut self.make_adaptive_searcher(input))
+    }
+
+    fn is_exact(&self) -> bool {
+        matches!(*self, Prefix::Exact(_))
+    }
+
+    fn out_of_order_len(&self) -> Option<usize> {
+        match *self {
+            // Non-overlapping matches of strings that can't overlap come in order.
+            Prefix::Ac(_, _, AcMatchKind::Overlapping) => self.max_len(),
+            Prefix::Exact(ref inner) => inner.out_of_order_len(),
+            _ => None,
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        use prefix::Prefix::*;
+
+        match *self {
+            Lit(ref lit) | LitRare(ref lit, _) | LitCaseless(ref lit) | InnerLit(ref lit, _)
+                | LitThenSet(ref lit, _) => vec_bytes(lit),
+            Ac(ref ac, ref map, _) => ac.heap_bytes() + vec_bytes(map),
+            Exact(ref inner) => mem::size_of::<Prefix>() + inner.memory_usage(),
+            Empty | ByteSet(_) | Byte(_) | Byte2(..) | Byte3(..) | LoopWhile(..) | AfterByte(_) =>
+                0,
+        }
+    }
+}
+
+impl<P: PrefixSearcherFactory + ?Sized> PrefixSearcherFactory for Arc<P> {
+    fn searcher<'a>(&'a self, input: &'a [u8]) -> Box<dyn PrefixSearcher + 'a> {
+        (**self).searcher(input)
+    }
+
+    fn with_searcher(&self,
+            input: &[u8],
+            f: &mut SearchFn)
+    -> Option<(usize, usize)> {
+        (**self).with_searcher(input, f)
+    }
+
+    fn is_exact(&self) -> bool {
+        (**self).is_exact()
+    }
+
+    fn out_of_order_len(&self) -> Option<usize> {
+        (**self).out_of_order_len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        (**self).memory_usage()
+    }
 }
 
 impl Prefix {
@@ -73,47 +372,640 @@ impl Prefix {
     /// The `state` part of each `(string, state)` pair is the DFA state that we would be in after
     /// matching the `string`.
     pub fn from_strings<P: AsRef<[u8]>, I: Iterator<Item=(P, usize)>>(it: I) -> Prefix {
+        Prefix::from_strings_with_limits(it, &PrefixLimits::default())
+    }
+
+    /// Like `from_strings`, but if there are too many strings (according to `limits`), falls back
+    /// to a simpler `Prefix`.
+    pub fn from_strings_with_limits<P, I>(it: I, limits: &PrefixLimits) -> Prefix
+    where P: AsRef<[u8]>, I: Iterator<Item=(P, usize)> {
         let strings: Vec<(Vec<u8>, usize)> = it
             .filter(|x| !x.0.as_ref().is_empty())
             .map(|(s, x)| (s.as_ref().to_vec(), x))
             .collect();
+        let freqs = limits.byte_frequencies.as_ref();
+        let first_bytes = |strings: Vec<(Vec<u8>, usize)>| {
+            Prefix::from_byte_set_in(strings.into_iter().map(|x| x.0[0]).collect(), freqs)
+        };
 
         if strings.is_empty() {
             Prefix::Empty
         } else if strings.len() == 1 {
             if strings[0].0.len() == 1 {
-                Prefix::Byte(strings[0].0[0])
+                first_bytes(strings)
             } else {
-                Prefix::Lit(strings.into_iter().next().unwrap().0)
+                let lit = Prefix::Lit(strings.into_iter().next().unwrap().0);
+                match freqs {
+                    Some(f) => lit.with_rare_byte_in(f),
+                    None => lit,
+                }
             }
         } else if strings.iter().map(|x| x.0.len()).min() == Some(1) {
-            let mut bs = vec![false; 256];
-            for (s, _) in strings.into_iter() {
-                bs[s[0] as usize] = true;
+            first_bytes(strings)
+        } else if strings.len() > limits.max_strings
+                || strings.iter().map(|x| x.0.len()).sum::<usize>() > limits.max_total_len {
+            match limits.fallback {
+                LimitFallback::FirstByte => first_bytes(strings),
+                LimitFallback::Empty => Prefix::Empty,
             }
-            Prefix::ByteSet(bs)
         } else {
             let state_map: Vec<_> = strings.iter().map(|x| x.1).collect();
+            let kind = if can_overlap(&strings) {
+                AcMatchKind::Overlapping
+            } else {
+                AcMatchKind::NonOverlapping
+            };
             let ac = FullAcAutomaton::new(AcAutomaton::new(strings.into_iter().map(|x| x.0)));
-            Prefix::Ac(ac, state_map)
+            Prefix::Ac(ac, state_map, kind)
         }
     }
 
-    /// Takes an input string and prepares for quickly finding matches in it.
-    pub fn make_searcher<'a>(&'a self, input: &'a [u8]) -> Box<PrefixSearcher + 'a> {
+    /// If this is a `Lit`, converts it into a `LitRare` that searches for the rarest byte in the
+    /// literal. Other kinds of `Prefix` are returned unchanged.
+    ///
+    /// This is often faster than searching for the whole literal when the literal starts with a
+    /// common byte, like a space or an 'e'.
+    pub fn with_rare_byte(self) -> Prefix {
+        self.with_rare_byte_in(&ByteFrequencies::from_counts([0; 256]))
+    }
+
+    /// Like `with_rare_byte`, but finds the rarest byte according to `freqs` (breaking ties
+    /// according to how common the bytes are in typical text).
+    pub fn with_rare_byte_in(self, freqs: &ByteFrequencies) -> Prefix {
+        match self {
+            Prefix::Lit(lit) => {
+                let offset = (0..lit.len())
+                    .min_by_key(|&i| (freqs.count(lit[i]), byte_rank(lit[i])))
+                    .unwrap_or(0);
+                Prefix::LitRare(lit, offset)
+            },
+            Prefix::Exact(inner) => Prefix::Exact(Box::new(inner.with_rare_byte_in(freqs))),
+            p => p,
+        }
+    }
+
+    /// The length of the longest piece of input that this `Prefix` needs to look at in order to
+    /// decide on a match, or `None` if it could need to look arbitrarily far.
+    pub fn max_len(&self) -> Option<usize> {
         use prefix::Prefix::*;
 
+        match *self {
+            Empty | ByteSet(_) | Byte(_) | Byte2(..) | Byte3(..) => Some(1),
+            LoopWhile(_, min) => Some(cmp::max(min, 1)),
+            Lit(ref l) | LitRare(ref l, _) | LitCaseless(ref l) => Some(l.len()),
+            Ac(ref ac, _, _) => ac.patterns().iter().map(|p| p.len()).max(),
+            InnerLit(..) => None,
+            Exact(ref inner) => inner.max_len(),
+            // A `StreamSearcher` will also report the start of every chunk, which is wasteful but
+            // harmless.
+            AfterByte(_) => Some(1),
+            LitThenSet(ref l, _) => Some(l.len() + 1),
+        }
+    }
+
+    /// If this is an `Ac`, changes the way it looks for matches. Other kinds of `Prefix` are
+    /// returned unchanged.
+    pub fn with_ac_match_kind(self, kind: AcMatchKind) -> Prefix {
         match self {
-            &Empty => Box::new(SimpleSearcher::new((), input)),
-            &ByteSet(ref bs) => Box::new(SimpleSearcher::new(&bs[..], input)),
-            &Byte(b) => Box::new(SimpleSearcher::new(b, input)),
-            &Lit(ref l) => Box::new(lit_searcher(l, input)),
-            &LoopWhile(ref bs) => Box::new(loop_searcher(&bs[..], input)),
-            &Ac(ref ac, ref map) => Box::new(AcSearcher::new(ac, map, input)),
+            Prefix::Ac(ac, map, _) => Prefix::Ac(ac, map, kind),
+            Prefix::Exact(inner) => Prefix::Exact(Box::new(inner.with_ac_match_kind(kind))),
+            p => p,
+        }
+    }
+
+    /// Finds a `Prefix` by looking at the transitions out of the start state of `prog`.
+    ///
+    /// If the start state loops back to itself, this returns a `LoopWhile`. Otherwise, it follows
+    /// the transitions out of the start state to find the strings that every match must start
+    /// with. If those strings turn out to be the only things that the program matches, the
+    /// returned `Prefix` is `Exact`.
+    pub fn from_program<I: Instructions>(prog: &Program<I>) -> Prefix {
+        Prefix::from_program_with_limits(prog, &PrefixLimits::default())
+    }
+
+    /// Like `from_program`, but builds the `Prefix` from the strings that it finds using
+    /// `from_strings_with_limits`.
+    pub fn from_program_with_limits<I: Instructions>(prog: &Program<I>, limits: &PrefixLimits)
+    -> Prefix {
+        if prog.num_states() == 0 || prog.init.anchored().is_some() {
+            return Prefix::Empty;
+        } else if let InitStates::AfterByte(b, _) = prog.init {
+            return Prefix::AfterByte(b);
+        } else if let InitStates::Contextual(..) | InitStates::ByPrevByte(..) = prog.init {
+            // Matches can start anywhere, and in different states.
+            return Prefix::Empty;
+        }
+        let start = match prog.init.state_at_pos(&[], 0) {
+            Some(s) => s,
+            None => return Prefix::Empty,
+        };
+        let accepts = |st: usize| prog.accept(st).is_some() || prog.check_eoi(st).is_some();
+        let transitions = |st: usize| (0..256).filter_map(move |b| {
+            prog.step_byte(st, b as u8).0.map(|t| (b as u8, t))
+        });
+        if accepts(start) {
+            return Prefix::Empty;
+        }
+
+        let loop_bytes: ByteSet = transitions(start).filter(|x| x.1 == start).map(|x| x.0).collect();
+        if !loop_bytes.is_empty() {
+            return Prefix::LoopWhile(loop_bytes, 0);
+        }
+
+        // Keep extending the strings by one byte until doing so would give too many of them.
+        let mut lits = vec![(Vec::new(), start)];
+        loop {
+            let mut next = Vec::new();
+            let mut changed = false;
+            for &(ref lit, st) in &lits {
+                if accepts(st) || lit.len() >= FROM_PROGRAM_MAX_LEN {
+                    next.push((lit.clone(), st));
+                    continue;
+                }
+                changed = true;
+                for (b, t) in transitions(st) {
+                    let mut ext = lit.clone();
+                    ext.push(b);
+                    next.push((ext, t));
+                }
+            }
+
+            if !changed || next.len() > FROM_PROGRAM_MAX_LITS {
+                break;
+            }
+            lits = next;
+        }
+
+        if lits.len() == 1 && lits[0].0.is_empty() {
+            // There are too many transitions out of the start state to make literals, but maybe
+            // we can still skip some bytes.
+            let first: ByteSet = transitions(start).map(|x| x.0).collect();
+            if first.len() == 256 {
+                Prefix::Empty
+            } else {
+                Prefix::from_byte_set_in(first, limits.byte_frequencies.as_ref())
+            }
+        } else {
+            let ends_match = |st: usize| prog.accept(st) == Some(0) && prog.check_eoi(st) == Some(0);
+            let max_len = lits.iter().map(|x| x.0.len()).max().unwrap_or(0);
+            let exact = lits.iter().all(|x| ends_match(x.1));
+
+            if lits.len() == 1 && !accepts(lits[0].1) {
+                // The literal needs to be followed by something, so check that too.
+                let next: ByteSet = transitions(lits[0].1).map(|x| x.0).collect();
+                if next.len() < 256 {
+                    return Prefix::LitThenSet(lits.into_iter().next().unwrap().0, next);
+                }
+            }
+
+            let prefix = match caseless_lit(&lits) {
+                Some(lit) => Prefix::LitCaseless(lit),
+                None => Prefix::from_strings_with_limits(lits.into_iter(), limits),
+            };
+            // The prefix only finds the matches if it still looks for the whole strings. It might
+            // not: the strings might have been too many, or too common, to look for, in which case
+            // `from_strings` falls back to their first bytes (which are only whole strings if the
+            // strings have length 1), or to nothing.
+            let keeps_strings = match prefix {
+                Prefix::Lit(_) | Prefix::LitRare(..) | Prefix::LitCaseless(_) => true,
+                Prefix::Ac(..) => true,
+                Prefix::Empty => false,
+                _ => max_len == 1,
+            };
+            if exact && keeps_strings {
+                // The strings are only searched for without overlaps if they can't overlap, since
+                // otherwise the leftmost match could be skipped.
+                Prefix::Exact(Box::new(prefix))
+            } else {
+                prefix
+            }
+        }
+    }
+
+    /// Like `from_strings`, but the returned `Prefix` ignores ASCII case when matching the strings.
+    pub fn from_strings_caseless<P: AsRef<[u8]>, I: Iterator<Item=(P, usize)>>(it: I) -> Prefix {
+        let strings: Vec<(Vec<u8>, usize)> = it
+            .filter(|x| !x.0.as_ref().is_empty())
+            .map(|(s, x)| (s.as_ref().to_ascii_lowercase(), x))
+            .collect();
+
+        if strings.len() == 1 && strings[0].0.len() > 1 {
+            return Prefix::LitCaseless(strings.into_iter().next().unwrap().0);
+        }
+
+        let num_variants = strings.iter()
+            .map(|s| s.0.iter().filter(|b| b.is_ascii_lowercase()).count())
+            .map(|letters| 1usize.checked_shl(letters as u32).unwrap_or(usize::MAX))
+            .fold(0usize, |acc, n| acc.saturating_add(n));
+        let min_len = strings.iter().map(|x| x.0.len()).min();
+
+        if min_len == Some(1) || num_variants > MAX_CASE_VARIANTS {
+            Prefix::from_byte_set(strings.iter()
+                .flat_map(|x| vec![x.0[0], x.0[0].to_ascii_uppercase()])
+                .collect())
+        } else {
+            let variants = strings.into_iter()
+                .flat_map(|(s, state)| case_variants(&s).into_iter().map(move |v| (v, state)));
+            Prefix::from_strings(variants)
+        }
+    }
+
+    /// Like `from_byte_set`, but returns `Prefix::Empty` if the bytes are too common (according to
+    /// `freqs`) to be worth looking for.
+    fn from_byte_set_in(bs: ByteSet, freqs: Option<&ByteFrequencies>) -> Prefix {
+        if freqs.is_some_and(|f| f.is_common(&bs)) {
+            Prefix::Empty
+        } else {
+            Prefix::from_byte_set(bs)
+        }
+    }
+
+    /// Creates a `Prefix` matching any byte in the set, using `memchr` if the set is small enough.
+    fn from_byte_set(bs: ByteSet) -> Prefix {
+        let bytes: Vec<u8> = bs.iter().collect();
+        match bytes.len() {
+            0 => Prefix::Empty,
+            1 => Prefix::Byte(bytes[0]),
+            2 => Prefix::Byte2(bytes[0], bytes[1]),
+            3 => Prefix::Byte3(bytes[0], bytes[1], bytes[2]),
+            _ => Prefix::ByteSet(bs),
+        }
+    }
+
+    /// Takes an input string and prepares for quickly finding matches in it.
+    ///
+    /// The returned `Searcher` doesn't need any allocation, so it's cheap to make one per search.
+    pub fn make_searcher<'a>(&'a self, input: &'a [u8]) -> Searcher<'a> {
+        use prefix::Prefix::*;
+
+        let inner = match *self {
+            Empty => SearcherImpl::Empty(SimpleSearcher::new((), input)),
+            ByteSet(ref bs) =>
+                SearcherImpl::ByteSet(SimpleSearcher::new(ByteSetFinder::new(bs), input)),
+            Byte(b) => SearcherImpl::Byte(SimpleSearcher::new(b, input)),
+            Byte2(b1, b2) => SearcherImpl::Byte2(SimpleSearcher::new((b1, b2), input)),
+            Byte3(b1, b2, b3) => SearcherImpl::Byte3(SimpleSearcher::new((b1, b2, b3), input)),
+            Lit(ref l) => SearcherImpl::Lit(lit_searcher(l, input)),
+            LitRare(ref l, off) =>
+                SearcherImpl::LitRare(SimpleSearcher::new(RareByteLit(l, off), input)),
+            LitCaseless(ref l) =>
+                SearcherImpl::LitCaseless(SimpleSearcher::new(CaselessLit(l), input)),
+            LoopWhile(ref bs, 0) => SearcherImpl::LoopWhile(loop_searcher(bs, input)),
+            LoopWhile(ref bs, min) => SearcherImpl::LoopAtLeast(LoopAtLeastSearcher {
+                in_set: ByteSetFinder::new(bs),
+                not_in_set: ByteSetFinder::new(&bs.complement()),
+                min,
+                input,
+                pos: 0,
+                run_end: 0,
+            }),
+            Ac(ref ac, ref map, kind) => SearcherImpl::Ac(AcSearcher::new(ac, map, kind, input)),
+            InnerLit(ref lit, ref bs) =>
+                SearcherImpl::InnerLit(InnerLitSearcher::new(lit, bs, input)),
+            Exact(ref inner) => {
+                let mut ret = inner.make_searcher(input);
+                // The kinds other than `Ac` only report where their strings start, but all of
+                // their strings have the same length.
+                if !matches!(**inner, Ac(..)) {
+                    ret.fixed_len = Some(inner.max_len().unwrap_or(0));
+                }
+                return ret;
+            },
+            AfterByte(b) => SearcherImpl::AfterByte(AfterByteSearcher { byte: b, input, pos: 0 }),
+            LitThenSet(ref l, ref bs) => SearcherImpl::LitThenSet(SimpleSearcher::new(
+                LitFollowedBy { lit: TwoWaySearcher::new(l), len: l.len(), set: bs },
+                input,
+            )),
+        };
+        Searcher { inner, fixed_len: None }
+    }
+
+    /// Like `make_searcher`, but the returned searcher keeps track of how much input the `Prefix`
+    /// allows us to skip. If it turns out to be too little to be worth the overhead, it gives up
+    /// on the `Prefix` and just returns every position.
+    ///
+    /// `Empty` and `Exact` prefixes are never given up on: the first because there would be no
+    /// point, and the second because that would break its promise.
+    pub fn make_adaptive_searcher<'a>(&'a self, input: &'a [u8]) -> AdaptiveSearcher<Searcher<'a>> {
+        let mut ret = AdaptiveSearcher::new(self.make_searcher(input), input.len());
+        if let Prefix::Empty | Prefix::Exact(_) = *self {
+            ret.never_disable = true;
+        }
+        ret
+    }
+}
+
+/// A `PrefixSearcher` for one of the built-in kinds of `Prefix`.
+pub struct Searcher<'a> {
+    inner: SearcherImpl<'a>,
+    // If this is `Some(len)`, the candidates are `len` bytes long, and `inner` only reports where
+    // they start.
+    fixed_len: Option<usize>,
+}
+
+enum SearcherImpl<'a> {
+    Empty(SimpleSearcher<'a, ()>),
+    ByteSet(SimpleSearcher<'a, ByteSetFinder>),
+    Byte(SimpleSearcher<'a, u8>),
+    Byte2(SimpleSearcher<'a, (u8, u8)>),
+    Byte3(SimpleSearcher<'a, (u8, u8, u8)>),
+    Lit(SimpleSearcher<'a, TwoWaySearcher<'a>>),
+    LitRare(SimpleSearcher<'a, RareByteLit<'a>>),
+    LitCaseless(SimpleSearcher<'a, CaselessLit<'a>>),
+    LoopWhile(SimpleSearcher<'a, LoopWhile>),
+    LoopAtLeast(LoopAtLeastSearcher<'a>),
+    Ac(AcSearcher<'a, 'a, 'a>),
+    InnerLit(InnerLitSearcher<'a>),
+    AfterByte(AfterByteSearcher<'a>),
+    LitThenSet(SimpleSearcher<'a, LitFollowedBy<'a>>),
+}
+
+macro_rules! dispatch {
+    ($self_:expr, $s:ident => $e:expr) => {
+        match $self_.inner {
+            SearcherImpl::Empty(ref mut $s) => $e,
+            SearcherImpl::ByteSet(ref mut $s) => $e,
+            SearcherImpl::Byte(ref mut $s) => $e,
+            SearcherImpl::Byte2(ref mut $s) => $e,
+            SearcherImpl::Byte3(ref mut $s) => $e,
+            SearcherImpl::Lit(ref mut $s) => $e,
+            SearcherImpl::LitRare(ref mut $s) => $e,
+            SearcherImpl::LitCaseless(ref mut $s) => $e,
+            SearcherImpl::LoopWhile(ref mut $s) => $e,
+            SearcherImpl::LoopAtLeast(ref mut $s) => $e,
+            SearcherImpl::Ac(ref mut $s) => $e,
+            SearcherImpl::InnerLit(ref mut $s) => $e,
+            SearcherImpl::AfterByte(ref mut $s) => $e,
+            SearcherImpl::LitThenSet(ref mut $s) => $e,
+        }
+    };
+}
+
+impl<'a> PrefixSearcher for Searcher<'a> {
+    fn skip_to(&mut self, pos: usize) {
+        dispatch!(self, s => s.skip_to(pos))
+    }
+
+    fn search(&mut self) -> Option<PrefixResult> {
+        let ret = dispatch!(self, s => s.search())?;
+        match self.fixed_len {
+            Some(len) => Some(PrefixResult {
+                start_pos: ret.start_pos,
+                end_pos: ret.start_pos + len,
+                end_state: None,
+            }),
+            None => Some(ret),
         }
     }
 }
 
+/// Don't judge a prefix until it has returned this many candidates.
+const ADAPTIVE_MIN_CANDIDATES: usize = 64;
+
+/// If a prefix skips fewer than this many bytes per candidate (on average), it isn't worth it.
+const ADAPTIVE_MIN_SKIP: usize = 4;
+
+/// A `PrefixSearcher` that stops using its underlying searcher if it doesn't skip enough input.
+pub struct AdaptiveSearcher<S: PrefixSearcher> {
+    inner: S,
+    input_len: usize,
+    // The first position that the last candidate didn't cover.
+    pos: usize,
+    candidates: usize,
+    skipped: usize,
+    disabled: bool,
+    never_disable: bool,
+}
+
+impl<S: PrefixSearcher> AdaptiveSearcher<S> {
+    pub fn new(inner: S, input_len: usize) -> AdaptiveSearcher<S> {
+        AdaptiveSearcher {
+            inner,
+            input_len,
+            pos: 0,
+            candidates: 0,
+            skipped: 0,
+            disabled: false,
+            never_disable: false,
+        }
+    }
+
+    /// Returns true if we have given up on the underlying searcher.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+}
+
+impl<S: PrefixSearcher> PrefixSearcher for AdaptiveSearcher<S> {
+    fn skip_to(&mut self, pos: usize) {
+        self.pos = pos;
+        if !self.disabled {
+            self.inner.skip_to(pos);
+        }
+    }
+
+    fn search(&mut self) -> Option<PrefixResult> {
+        if self.disabled {
+            if self.pos > self.input_len {
+                return None;
+            }
+            self.pos += 1;
+            return Some(PrefixResult {
+                start_pos: self.pos - 1,
+                end_pos: self.pos - 1,
+                end_state: None,
+            });
+        }
+
+        let ret = self.inner.search()?;
+        if self.never_disable {
+            return Some(ret);
+        }
+        self.candidates += 1;
+        self.skipped += ret.start_pos.saturating_sub(self.pos);
+        self.pos = ret.start_pos + 1;
+
+        if self.candidates >= ADAPTIVE_MIN_CANDIDATES
+                && self.skipped < self.candidates * ADAPTIVE_MIN_SKIP {
+            self.disabled = true;
+        }
+        Some(ret)
+    }
+}
+
+/// Searches for a `Prefix` in input that arrives in chunks.
+///
+/// Matches of the `Prefix` that straddle a chunk boundary are found by holding on to the last
+/// few bytes of each chunk. The positions in the returned `PrefixResult`s are relative to the
+/// start of the whole stream. An `InnerLit` might need to look arbitrarily far back, so it is
+/// treated like `Empty`.
+pub struct StreamSearcher<'p> {
+    prefix: &'p Prefix,
+    max_len: usize,
+    // The end of the input so far, which is always shorter than `max_len`.
+    tail: Vec<u8>,
+    // The position of `tail` in the stream.
+    tail_pos: usize,
+}
+
+static EMPTY: Prefix = Prefix::Empty;
+
+impl<'p> StreamSearcher<'p> {
+    pub fn new(prefix: &'p Prefix) -> StreamSearcher<'p> {
+        let (prefix, max_len) = match prefix.max_len() {
+            Some(len) => (prefix, len),
+            None => (&EMPTY, 1),
+        };
+        StreamSearcher {
+            prefix,
+            max_len,
+            tail: Vec::new(),
+            tail_pos: 0,
+        }
+    }
+
+    /// Searches the next chunk of input, adding the results to `out`.
+    ///
+    /// Results that start near the end of the chunk might depend on the next chunk, so they are
+    /// held back until the next call to `feed` or `finish`.
+    pub fn feed(&mut self, chunk: &[u8], out: &mut Vec<PrefixResult>) {
+        let tail_len = self.tail.len();
+        let len = tail_len + chunk.len();
+
+        // Everything that starts at or before `limit` fits inside the input we have.
+        if let Some(limit) = len.checked_sub(self.max_len) {
+            // Anything starting in the tail ends within `max_len - 1` bytes of the chunk start.
+            let mut boundary = self.tail.clone();
+            boundary.extend_from_slice(&chunk[..cmp::min(chunk.len(), self.max_len - 1)]);
+            let mut searcher = self.prefix.make_searcher(&boundary);
+            while let Some(res) = searcher.search() {
+                if res.start_pos < tail_len && res.start_pos <= limit {
+                    out.push(res.shift(self.tail_pos));
+                }
+            }
+
+            let mut searcher = self.prefix.make_searcher(chunk);
+            while let Some(res) = searcher.search() {
+                if res.start_pos + tail_len <= limit {
+                    out.push(res.shift(self.tail_pos + tail_len));
+                }
+            }
+        }
+
+        let keep = cmp::min(len, self.max_len - 1);
+        let drop = len - keep;
+        if drop <= tail_len {
+            self.tail.drain(..drop);
+            self.tail.extend_from_slice(chunk);
+        } else {
+            self.tail.clear();
+            self.tail.extend_from_slice(&chunk[(drop - tail_len)..]);
+        }
+        self.tail_pos += drop;
+    }
+
+    /// Signals the end of the input, adding any remaining results to `out`.
+    pub fn finish(&mut self, out: &mut Vec<PrefixResult>) {
+        {
+            let mut searcher = self.prefix.make_searcher(&self.tail);
+            while let Some(res) = searcher.search() {
+                out.push(res.shift(self.tail_pos));
+            }
+        }
+        self.tail_pos += self.tail.len();
+        self.tail.clear();
+    }
+}
+
+/// Checks whether an occurrence of one of the strings could overlap with an occurrence of another
+/// (or the same) one. That is, whether one of them contains another, or whether a suffix of one
+/// is a prefix of another.
+fn can_overlap(strings: &[(Vec<u8>, usize)]) -> bool {
+    for (i, s) in strings.iter().map(|x| &x.0).enumerate() {
+        for (j, t) in strings.iter().map(|x| &x.0).enumerate() {
+            if i != j && s.windows(t.len()).any(|w| w == &t[..]) {
+                return true;
+            }
+            if (1..cmp::min(s.len(), t.len())).any(|len| s[(s.len() - len)..] == t[..len]) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// A set of bytes is too common to be worth looking for if it makes up more than this fraction
+/// (one in this many) of the data.
+const COMMON_BYTES_DENOMINATOR: u64 = 8;
+
+/// When extracting literals in `from_program`, give up if there would be more than this many.
+const FROM_PROGRAM_MAX_LITS: usize = 32;
+
+/// When extracting literals in `from_program`, don't make them longer than this.
+const FROM_PROGRAM_MAX_LEN: usize = 32;
+
+/// When expanding case-insensitive strings into all of their variants, give up if there would be
+/// more than this many.
+const MAX_CASE_VARIANTS: usize = 64;
+
+/// Roughly how common the byte `b` is in typical (mostly English) text; larger is more common.
+fn byte_rank(b: u8) -> u8 {
+    const LETTERS: &[u8] = b"etaoinshrdlcumwfgypbvkjxqz";
+
+    match b {
+        b' ' => 255,
+        b'a'..=b'z' => 250 - 4 * LETTERS.iter().position(|&c| c == b).unwrap() as u8,
+        b'A'..=b'Z' => 120 - 2 * LETTERS.iter().position(|&c| c == b.to_ascii_lowercase()).unwrap() as u8,
+        b'\n' | b'\t' => 200,
+        b'0'..=b'9' => 130,
+        b',' | b'.' | b'-' | b'\'' | b'"' | b'/' | b'_' => 140,
+        0x21..=0x7e => 60,
+        0x80..=0xff => 40,
+        _ => 20,
+    }
+}
+
+/// Returns all of the ways of changing the case of ASCII letters in `s`.
+fn case_variants(s: &[u8]) -> Vec<Vec<u8>> {
+    let mut ret = vec![Vec::with_capacity(s.len())];
+    for &b in s {
+        if b.is_ascii_alphabetic() {
+            let mut upper = ret.clone();
+            for v in &mut ret {
+                v.push(b.to_ascii_lowercase());
+            }
+            for v in &mut upper {
+                v.push(b.to_ascii_uppercase());
+            }
+            ret.extend(upper);
+        } else {
+            for v in &mut ret {
+                v.push(b);
+            }
+        }
+    }
+    ret
+}
+
+/// If `lits` are all the case variants of a single string (of length at least 2), and they all
+/// lead to the same state, returns that string in lower case.
+///
+/// This is what `from_program` finds for a program compiled case-insensitively.
+fn caseless_lit(lits: &[(Vec<u8>, usize)]) -> Option<Vec<u8>> {
+    let lower = lits.first()?.0.to_ascii_lowercase();
+    let letters = lower.iter().filter(|b| b.is_ascii_alphabetic()).count();
+    if lits.len() < 2 || lower.len() < 2 || 1usize.checked_shl(letters as u32) != Some(lits.len()) {
+        return None;
+    }
+    // The literals are distinct, so if they all have the same lower case then they are all of
+    // the variants.
+    if lits.iter().all(|x| x.1 == lits[0].1 && x.0.to_ascii_lowercase() == lower) {
+        Some(lower)
+    } else {
+        None
+    }
+}
+
 trait SkipFn {
     fn skip(&self, input: &[u8]) -> Option<(usize, usize)>;
 }
@@ -136,20 +1028,137 @@ impl SimpleSkipFn for u8 {
     fn simple_skip(&self, input: &[u8]) -> Option<usize> { memchr(*self, input) }
 }
 
+impl SimpleSkipFn for (u8, u8) {
+    fn simple_skip(&self, input: &[u8]) -> Option<usize> { memchr2(self.0, self.1, input) }
+}
+
+impl SimpleSkipFn for (u8, u8, u8) {
+    fn simple_skip(&self, input: &[u8]) -> Option<usize> { memchr3(self.0, self.1, self.2, input) }
+}
+
 impl<'a> SimpleSkipFn for TwoWaySearcher<'a> {
     fn simple_skip(&self, input: &[u8]) -> Option<usize> { self.search_in(input) }
 }
 
-impl<'a> SimpleSkipFn for &'a [bool] {
+impl SimpleSkipFn for ByteSetFinder {
+    fn simple_skip(&self, input: &[u8]) -> Option<usize> { self.find(input) }
+}
+
+// Looks for a lower-case literal, ignoring ASCII case.
+struct CaselessLit<'a>(&'a [u8]);
+impl<'a> SimpleSkipFn for CaselessLit<'a> {
+    fn simple_skip(&self, input: &[u8]) -> Option<usize> {
+        let lit = self.0;
+        let first = lit[0];
+        let upper = first.to_ascii_uppercase();
+        let mut pos = 0;
+
+        while pos + lit.len() <= input.len() {
+            let hay = &input[pos..(input.len() - lit.len() + 1)];
+            let off = if upper != first { memchr2(first, upper, hay) } else { memchr(first, hay) }?;
+            pos += off;
+            if input[pos..(pos + lit.len())].eq_ignore_ascii_case(lit) {
+                return Some(pos);
+            }
+            pos += 1;
+        }
+        None
+    }
+}
+
+// Looks for a literal by first looking for the byte at a given offset.
+struct RareByteLit<'a>(&'a [u8], usize);
+impl<'a> SimpleSkipFn for RareByteLit<'a> {
+    fn simple_skip(&self, input: &[u8]) -> Option<usize> {
+        let (lit, offset) = (self.0, self.1);
+        let rare = lit[offset];
+        let mut pos = 0;
+
+        while pos + lit.len() <= input.len() {
+            let hay = &input[(pos + offset)..(input.len() - lit.len() + offset + 1)];
+            pos += memchr(rare, hay)?;
+            if &input[pos..(pos + lit.len())] == lit {
+                return Some(pos);
+            }
+            pos += 1;
+        }
+        None
+    }
+}
+
+// Looks for a literal that is followed by a byte in a set.
+struct LitFollowedBy<'a> {
+    lit: TwoWaySearcher<'a>,
+    len: usize,
+    set: &'a ByteSet,
+}
+impl<'a> SimpleSkipFn for LitFollowedBy<'a> {
     fn simple_skip(&self, input: &[u8]) -> Option<usize> {
-        input.iter().position(|c| self[*c as usize])
+        let mut pos = 0;
+        // We stop one byte early, because the literal needs to be followed by something.
+        while pos + self.len < input.len() {
+            pos += self.lit.search_in(&input[pos..(input.len() - 1)])?;
+            if self.set.contains(input[pos + self.len]) {
+                return Some(pos);
+            }
+            pos += 1;
+        }
+        None
     }
 }
 
-struct LoopWhile<'a>(&'a [bool]);
-impl<'a> SkipFn for LoopWhile<'a> {
+// Skips over runs of bytes in a set. It holds a finder for the bytes that are *not* in the set.
+struct LoopWhile(ByteSetFinder);
+impl SkipFn for LoopWhile {
     fn skip(&self, input: &[u8]) -> Option<(usize, usize)> {
-        Some((0, input.iter().position(|c| !self.0[*c as usize]).unwrap_or(input.len())))
+        Some((0, self.0.find(input).unwrap_or(input.len())))
+    }
+}
+
+// Finds the positions that are followed by at least `min` bytes in `set`.
+struct LoopAtLeastSearcher<'a> {
+    in_set: ByteSetFinder,
+    not_in_set: ByteSetFinder,
+    min: usize,
+    input: &'a [u8],
+    pos: usize,
+    // The end of the last run of bytes that we found. If `pos` is in that run, we don't need to
+    // look at the input again until we're within `min` of the end.
+    run_end: usize,
+}
+
+impl<'a> PrefixSearcher for LoopAtLeastSearcher<'a> {
+    fn skip_to(&mut self, pos: usize) {
+        self.pos = pos;
+        self.run_end = 0;
+    }
+
+    fn search(&mut self) -> Option<PrefixResult> {
+        if self.pos + self.min > self.run_end {
+            let mut start = self.pos;
+            loop {
+                if start >= self.input.len() {
+                    self.pos = self.input.len() + 1;
+                    return None;
+                }
+                start += self.in_set.find(&self.input[start..])?;
+                let len = self.not_in_set.find(&self.input[start..])
+                    .unwrap_or(self.input.len() - start);
+                if len >= self.min {
+                    self.pos = start;
+                    self.run_end = start + len;
+                    break;
+                }
+                start += len;
+            }
+        }
+
+        self.pos += 1;
+        Some(PrefixResult {
+            start_pos: self.pos - 1,
+            end_pos: self.pos - 1,
+            end_state: None,
+        })
     }
 }
 
@@ -162,8 +1171,8 @@ struct SimpleSearcher<'a, Skip: SkipFn> {
 impl<'a, Sk: SkipFn> SimpleSearcher<'a, Sk> {
     fn new(skip_fn: Sk, input: &'a [u8]) -> SimpleSearcher<'a, Sk> {
         SimpleSearcher {
-            skip_fn: skip_fn,
-            input: input,
+            skip_fn,
+            input,
             pos: 0,
         }
     }
@@ -173,16 +1182,15 @@ fn lit_searcher<'i, 'lit>(lit: &'lit [u8], input: &'i [u8])
 -> SimpleSearcher<'i, TwoWaySearcher<'lit>> {
     SimpleSearcher {
         skip_fn: TwoWaySearcher::new(lit),
-        input: input,
+        input,
         pos: 0,
     }
 }
 
-fn loop_searcher<'i, 'lo>(loop_while: &'lo [bool], input: &'i [u8])
--> SimpleSearcher<'i, LoopWhile<'lo>> {
+fn loop_searcher<'i>(loop_while: &ByteSet, input: &'i [u8]) -> SimpleSearcher<'i, LoopWhile> {
     SimpleSearcher {
-        skip_fn: LoopWhile(loop_while),
-        input: input,
+        skip_fn: LoopWhile(ByteSetFinder::new(&loop_while.complement())),
+        input,
         pos: 0,
     }
 }
@@ -199,7 +1207,7 @@ impl<'a, Sk: SkipFn> PrefixSearcher for SimpleSearcher<'a, Sk> {
             Some(PrefixResult {
                 start_pos: start,
                 end_pos: end,
-                end_state: 0,
+                end_state: None,
             })
         } else {
             None
@@ -209,23 +1217,135 @@ impl<'a, Sk: SkipFn> PrefixSearcher for SimpleSearcher<'a, Sk> {
     fn skip_to(&mut self, pos: usize) { self.pos = pos; }
 }
 
+struct InnerLitSearcher<'a> {
+    lit: TwoWaySearcher<'a>,
+    lit_len: usize,
+    before: &'a ByteSet,
+    input: &'a [u8],
+    // The first position that we haven't returned yet.
+    pos: usize,
+    // Where to start looking for the next occurrence of the literal.
+    lit_pos: usize,
+    // The last candidate position before the most recently found literal.
+    window_end: Option<usize>,
+}
+
+impl<'a> InnerLitSearcher<'a> {
+    fn new(lit: &'a [u8], before: &'a ByteSet, input: &'a [u8]) -> InnerLitSearcher<'a> {
+        InnerLitSearcher {
+            lit: TwoWaySearcher::new(lit),
+            lit_len: lit.len(),
+            before,
+            input,
+            pos: 0,
+            lit_pos: 0,
+            window_end: None,
+        }
+    }
+}
+
+impl<'a> PrefixSearcher for InnerLitSearcher<'a> {
+    fn skip_to(&mut self, pos: usize) {
+        self.pos = pos;
+        self.lit_pos = pos;
+        self.window_end = None;
+    }
+
+    fn search(&mut self) -> Option<PrefixResult> {
+        match self.window_end {
+            Some(end) if self.pos <= end => {},
+            _ => {
+                if self.lit_pos + self.lit_len > self.input.len() {
+                    return None;
+                }
+                let lit_start = self.lit_pos + self.lit.search_in(&self.input[self.lit_pos..])?;
+                let mut start = lit_start;
+                while start > self.pos && self.before.contains(self.input[start - 1]) {
+                    start -= 1;
+                }
+
+                self.pos = start;
+                self.lit_pos = lit_start + 1;
+                self.window_end = Some(lit_start);
+            },
+        }
+
+        let ret = PrefixResult {
+            start_pos: self.pos,
+            end_pos: self.pos,
+            end_state: None,
+        };
+        self.pos += 1;
+        Some(ret)
+    }
+}
+
+struct AfterByteSearcher<'a> {
+    byte: u8,
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PrefixSearcher for AfterByteSearcher<'a> {
+    fn skip_to(&mut self, pos: usize) { self.pos = pos; }
+
+    fn search(&mut self) -> Option<PrefixResult> {
+        let found = if self.pos == 0 {
+            0
+        } else if self.pos > self.input.len() {
+            return None;
+        } else {
+            // Look for the byte just before a candidate position.
+            self.pos + memchr(self.byte, &self.input[(self.pos - 1)..])?
+        };
+
+        self.pos = found + 1;
+        Some(PrefixResult {
+            start_pos: found,
+            end_pos: found,
+            end_state: None,
+        })
+    }
+}
+
+enum AcIter<'ac, 'i> {
+    Overlapping(MatchesOverlapping<'ac, 'i, Vec<u8>, FullAcAutomaton<Vec<u8>>>),
+    NonOverlapping(Matches<'ac, 'i, Vec<u8>, FullAcAutomaton<Vec<u8>>>),
+}
+
+impl<'ac, 'i> AcIter<'ac, 'i> {
+    fn new(ac: &'ac FullAcAutomaton<Vec<u8>>, kind: AcMatchKind, input: &'i [u8])
+    -> AcIter<'ac, 'i> {
+        match kind {
+            AcMatchKind::Overlapping => AcIter::Overlapping(ac.find_overlapping(input)),
+            AcMatchKind::NonOverlapping => AcIter::NonOverlapping(ac.find(input)),
+        }
+    }
+}
+
 struct AcSearcher<'ac, 'i, 'st> {
     ac: &'ac FullAcAutomaton<Vec<u8>>,
     state_map: &'st [usize],
+    kind: AcMatchKind,
     input: &'i [u8],
+    // `iter` runs over `input[pos..]`, so the positions it returns are relative to `pos`.
     pos: usize,
-    iter: MatchesOverlapping<'ac, 'i, Vec<u8>, FullAcAutomaton<Vec<u8>>>,
+    iter: AcIter<'ac, 'i>,
 }
 
 impl<'ac, 'i, 'st> AcSearcher<'ac, 'i, 'st> {
-    fn new(ac: &'ac FullAcAutomaton<Vec<u8>>, state_map: &'st [usize], input: &'i [u8])
+    fn new(ac: &'ac FullAcAutomaton<Vec<u8>>,
+           state_map: &'st [usize],
+           kind: AcMatchKind,
+           input: &'i [u8])
     -> AcSearcher<'ac, 'i, 'st> {
         AcSearcher {
-            ac: ac,
-            state_map: state_map,
-            input: input,
+            ac,
+            state_map,
+            kind,
+            input,
             pos: 0,
-            iter: ac.find_overlapping(input),
+            iter: AcIter::new(ac, kind, input),
         }
     }
 }
@@ -238,14 +1358,18 @@ impl<'ac, 'i, 'st> PrefixSearcher for AcSearcher<'ac, 'i, 'st> {
         } else {
             &self.input[self.pos..]
         };
-        self.iter = self.ac.find_overlapping(input);
+        self.iter = AcIter::new(self.ac, self.kind, input);
     }
 
     fn search(&mut self) -> Option<PrefixResult> {
-        self.iter.next().map(|mat| PrefixResult {
-            start_pos: mat.start,
-            end_pos: mat.end,
-            end_state: self.state_map[mat.pati],
+        let mat = match self.iter {
+            AcIter::Overlapping(ref mut it) => it.next(),
+            AcIter::NonOverlapping(ref mut it) => it.next(),
+        };
+        mat.map(|mat| PrefixResult {
+            start_pos: self.pos + mat.start,
+            end_pos: self.pos + mat.end,
+            end_state: Some(self.state_map[mat.pati]),
         })
     }
 }
@@ -254,7 +1378,7 @@ impl<'ac, 'i, 'st> PrefixSearcher for AcSearcher<'ac, 'i, 'st> {
 mod tests {
     use ::prefix::*;
 
-    impl<'a> Iterator for Box<PrefixSearcher + 'a> {
+    impl<'a> Iterator for Searcher<'a> {
         type Item = PrefixResult;
         fn next(&mut self) -> Option<PrefixResult> {
             self.search()
@@ -269,7 +1393,7 @@ mod tests {
         PrefixResult {
             start_pos: pos,
             end_pos: pos,
-            end_state: 0,
+            end_state: None,
         }
     }
 
@@ -290,6 +1414,80 @@ mod tests {
         assert_eq!(search(Prefix::Byte(b'a'), ""), vec![]);
     }
 
+    #[test]
+    fn test_byte2_byte3_search() {
+        assert_eq!(search(Prefix::Byte2(b'a', b'c'), "abracadabra"),
+            results(vec![0, 3, 4, 5, 7, 10]));
+        assert_eq!(search(Prefix::Byte3(b'a', b'b', b'c'), "abracadabra"),
+            results(vec![0, 1, 3, 4, 5, 7, 8, 10]));
+        assert_eq!(search(Prefix::Byte2(b'a', b'c'), ""), vec![]);
+        assert_eq!(search(Prefix::Byte3(b'x', b'y', b'z'), "abc"), vec![]);
+    }
+
+    #[test]
+    fn test_adaptive_search() {
+        // Every other byte is an 'a', so the prefix isn't helping.
+        let dense = "ab".repeat(100);
+        let pref = Prefix::Byte(b'a');
+        let mut searcher = AdaptiveSearcher::new(pref.make_searcher(dense.as_bytes()), dense.len());
+        let mut starts = Vec::new();
+        while let Some(res) = searcher.search() {
+            starts.push(res.start_pos);
+        }
+        assert!(searcher.is_disabled());
+        let cutoff = 2 * ADAPTIVE_MIN_CANDIDATES;
+        assert!(starts[..ADAPTIVE_MIN_CANDIDATES].iter().all(|&p| p % 2 == 0));
+        assert_eq!(&starts[ADAPTIVE_MIN_CANDIDATES..], &(cutoff - 1..dense.len() + 1).collect::<Vec<_>>()[..]);
+
+        // Here, the prefix skips lots of input so it stays enabled.
+        let sparse = "abbbbbbbbbbbbbbb".repeat(100);
+        let mut searcher = AdaptiveSearcher::new(pref.make_searcher(sparse.as_bytes()), sparse.len());
+        let mut count = 0;
+        while searcher.search().is_some() {
+            count += 1;
+        }
+        assert!(!searcher.is_disabled());
+        assert_eq!(count, 100);
+
+        // Exact prefixes always stay enabled.
+        let exact = Prefix::Exact(Box::new(pref));
+        let mut searcher = exact.make_adaptive_searcher(dense.as_bytes());
+        let mut count = 0;
+        while searcher.search().is_some() {
+            count += 1;
+        }
+        assert!(!searcher.is_disabled());
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn test_exact_search() {
+        // Apart from `Ac`, the searchers only find where the strings start, and the exact prefix
+        // adds their length.
+        let spans = |pref: Prefix, input: &str| -> Vec<(usize, usize)> {
+            let exact = Prefix::Exact(Box::new(pref));
+            let ret: Vec<_> = exact.make_searcher(input.as_bytes())
+                .map(|r| (r.start_pos, r.end_pos))
+                .collect();
+            let mut from_with = Vec::new();
+            exact.with_searcher(input.as_bytes(), &mut |s| {
+                while let Some(r) = s.search() {
+                    from_with.push((r.start_pos, r.end_pos));
+                }
+                None
+            });
+            assert_eq!(ret, from_with);
+            ret
+        };
+        assert_eq!(spans(Prefix::Lit(b"ab".to_vec()), "xabab"), vec![(1, 3), (3, 5)]);
+        assert_eq!(spans(Prefix::Lit(b"abc".to_vec()).with_rare_byte(), "abcxabc"),
+                   vec![(0, 3), (4, 7)]);
+        assert_eq!(spans(Prefix::LitCaseless(b"ab".to_vec()), "xAbaB"), vec![(1, 3), (3, 5)]);
+        assert_eq!(spans(Prefix::Byte2(b'a', b'b'), "xab"), vec![(1, 2), (2, 3)]);
+        assert_eq!(spans(Prefix::from_strings(vec![("ab", 0), ("cd", 0)].into_iter()), "abxcd"),
+                   vec![(0, 2), (3, 5)]);
+    }
+
     #[test]
     fn test_str_search() {
         fn lit_pref(s: &str) -> Prefix {
@@ -300,14 +1498,51 @@ mod tests {
         assert_eq!(search(lit_pref("aa"), ""), vec![]);
     }
 
+    #[test]
+    fn test_rare_byte_search() {
+        fn lit_pref(s: &str) -> Prefix {
+            Prefix::Lit(s.as_bytes().to_vec()).with_rare_byte()
+        }
+        assert!(matches!(lit_pref(" the zoo"), Prefix::LitRare(_, 5)));
+        assert!(matches!(lit_pref("e"), Prefix::LitRare(_, 0)));
+        assert!(matches!(Prefix::Byte(b'a').with_rare_byte(), Prefix::Byte(b'a')));
+
+        assert_eq!(search(lit_pref("aa"), "baa baa black sheep aa"), results(vec![1, 5, 20]));
+        assert_eq!(search(lit_pref("aa"), "aaa baaa black sheep"), results(vec![0, 1, 5, 6]));
+        assert_eq!(search(lit_pref("eek"), "eeeek eek ek"), results(vec![2, 6]));
+        assert_eq!(search(lit_pref("k!"), "k!"), results(vec![0]));
+        assert_eq!(search(lit_pref("aa"), ""), vec![]);
+    }
+
+    #[test]
+    fn test_caseless_search() {
+        fn pref(strs: Vec<&str>) -> Prefix {
+            let len = strs.len();
+            Prefix::from_strings_caseless(strs.into_iter().zip(0..len))
+        }
+
+        assert!(matches!(pref(vec!["Baa"]), Prefix::LitCaseless(_)));
+        assert_eq!(search(pref(vec!["Baa"]), "baa BAA bAa black sheep ba"),
+            results(vec![0, 4, 8]));
+        assert_eq!(search(pref(vec!["1a"]), "1A 1a 1b"), results(vec![0, 3]));
+
+        assert!(matches!(pref(vec!["a"]), Prefix::Byte2(b'a', b'A') | Prefix::Byte2(b'A', b'a')));
+        assert!(matches!(pref(vec!["1"]), Prefix::Byte(b'1')));
+        assert!(matches!(pref(vec!["a", "bc"]), Prefix::ByteSet(_)));
+
+        let ac = pref(vec!["aa", "Bb"]);
+        assert!(matches!(ac, Prefix::Ac(..)));
+        assert_eq!(search(ac, "xAabB").into_iter().map(|r| r.start_pos).collect::<Vec<_>>(),
+            vec![1, 3]);
+
+        // Too many variants to build an automaton.
+        assert!(matches!(pref(vec!["abcdefgh", "ijklmnop"]), Prefix::ByteSet(_)));
+    }
+
     #[test]
     fn test_byteset_search() {
         fn bs_pref(s: &str) -> Prefix {
-            let mut bytes = vec![false; 256];
-            for &b in s.as_bytes().iter() {
-                bytes[b as usize] = true;
-            }
-            Prefix::ByteSet(bytes)
+            Prefix::ByteSet(s.bytes().collect())
         }
         assert_eq!(search(bs_pref("aeiou"), "quick brown"), results(vec![1, 2, 8]));
         assert_eq!(search(bs_pref("aeiou"), "aabaa"), results(vec![0, 1, 3, 4]));
@@ -316,24 +1551,56 @@ mod tests {
 
     fn pair_results(posns: Vec<(usize, usize)>) -> Vec<PrefixResult> {
         posns.into_iter()
-            .map(|(s, e)| PrefixResult { start_pos: s, end_pos: e, end_state: 0 })
+            .map(|(s, e)| PrefixResult { start_pos: s, end_pos: e, end_state: None })
             .collect()
     }
 
     #[test]
     fn test_loop_search() {
         fn loop_pref(s: &str) -> Prefix {
-            let mut bytes = vec![false; 256];
-            for &b in s.as_bytes().iter() {
-                bytes[b as usize] = true;
-            }
-            Prefix::LoopWhile(bytes)
+            Prefix::LoopWhile(s.bytes().collect(), 0)
         }
         assert_eq!(search(loop_pref("aeiou"), "quick"),
             pair_results(vec![(0, 0), (1, 3), (4, 4), (5, 5)]));
         assert_eq!(search(loop_pref("aeiou"), "aabaa"),
             pair_results(vec![(0, 2), (3, 5)]));
         assert_eq!(search(loop_pref("aeiou"), ""), pair_results(vec![(0, 0)]));
+
+        let digits: ByteSet = (b'0'..=b'9').collect();
+        let at_least = |n| Prefix::LoopWhile(digits, n);
+        assert_eq!(search(at_least(4), "12 12345 x1234 123"), results(vec![3, 4, 10]));
+        assert_eq!(search(at_least(4), "123"), vec![]);
+        assert_eq!(search(at_least(1), "a1b22"), results(vec![1, 3, 4]));
+
+        let pref = at_least(2);
+        let mut searcher = pref.make_searcher(b"12345");
+        assert_eq!(searcher.search_from(2), Some(result(2)));
+        assert_eq!(searcher.search(), Some(result(3)));
+        assert_eq!(searcher.search(), None);
+    }
+
+    #[test]
+    fn test_inner_lit_search() {
+        fn inner_pref(lit: &str, before: &str) -> Prefix {
+            Prefix::InnerLit(lit.as_bytes().to_vec(), before.bytes().collect())
+        }
+        assert_eq!(search(inner_pref("foo", "ab"), "xabfoo bfoo foo"),
+            results(vec![1, 2, 3, 7, 8, 12]));
+        // Overlapping windows are only scanned once.
+        assert_eq!(search(inner_pref("foo", "fo"), "ofoofoo"),
+            results(vec![0, 1, 2, 3, 4]));
+        assert_eq!(search(inner_pref("foo", "ab"), "abfo"), vec![]);
+        assert_eq!(search(inner_pref("foo", "ab"), ""), vec![]);
+
+        let pref = inner_pref("foo", "ab");
+        let mut searcher = pref.make_searcher(b"abfoo abfoo");
+        assert_eq!(searcher.search(), Some(result(0)));
+        searcher.skip_to(4);
+        // The backwards scan stops at the position we skipped to.
+        assert_eq!(searcher.search(), Some(result(6)));
+        assert_eq!(searcher.search(), Some(result(7)));
+        assert_eq!(searcher.search(), Some(result(8)));
+        assert_eq!(searcher.search(), None);
     }
 
     #[test]
@@ -341,18 +1608,234 @@ mod tests {
         fn ac_pref(strs: Vec<&str>) -> Prefix {
             let len = strs.len();
             let pref = Prefix::from_strings(strs.into_iter().zip(0..len));
-            assert!(matches!(pref, Prefix::Ac(_, _)));
+            assert!(matches!(pref, Prefix::Ac(..)));
             pref
         }
 
         assert_eq!(search(ac_pref(vec!["baa", "aa"]), "baa aaa black sheep"),
             vec![
-                PrefixResult { start_pos: 0, end_pos: 3, end_state: 0 },
-                PrefixResult { start_pos: 1, end_pos: 3, end_state: 1 },
-                PrefixResult { start_pos: 4, end_pos: 6, end_state: 1 },
-                PrefixResult { start_pos: 5, end_pos: 7, end_state: 1 },
+                PrefixResult { start_pos: 0, end_pos: 3, end_state: Some(0) },
+                PrefixResult { start_pos: 1, end_pos: 3, end_state: Some(1) },
+                PrefixResult { start_pos: 4, end_pos: 6, end_state: Some(1) },
+                PrefixResult { start_pos: 5, end_pos: 7, end_state: Some(1) },
             ]);
         assert_eq!(search(ac_pref(vec!["baa", "aa"]), ""), vec![]);
+
+        // Positions are still relative to the start of the input after skipping.
+        let pref = ac_pref(vec!["baa", "aa"]);
+        let mut searcher = pref.make_searcher(b"baa aaa black sheep");
+        searcher.skip_to(2);
+        assert_eq!(searcher.search(),
+            Some(PrefixResult { start_pos: 4, end_pos: 6, end_state: Some(1) }));
+        assert_eq!(searcher.search_from(1),
+            Some(PrefixResult { start_pos: 1, end_pos: 3, end_state: Some(1) }));
+        assert_eq!(searcher.search_from(20), None);
+
+        // These strings can't overlap, so we don't need overlapping matches.
+        let pref = ac_pref(vec!["ab", "cd"]);
+        assert!(matches!(pref, Prefix::Ac(_, _, AcMatchKind::NonOverlapping)));
+        assert_eq!(search(pref, "abcdab").into_iter().map(|r| r.start_pos).collect::<Vec<_>>(),
+            vec![0, 2, 4]);
+        assert!(matches!(ac_pref(vec!["ab", "ba"]), Prefix::Ac(_, _, AcMatchKind::Overlapping)));
+        assert!(matches!(ac_pref(vec!["abc", "bc"]), Prefix::Ac(_, _, AcMatchKind::Overlapping)));
+        assert!(matches!(ac_pref(vec!["aa", "bb"]), Prefix::Ac(_, _, AcMatchKind::Overlapping)));
+
+        // Non-overlapping searches skip over matches that start inside other matches.
+        let pref = ac_pref(vec!["aa", "ab"]).with_ac_match_kind(AcMatchKind::NonOverlapping);
+        assert_eq!(search(pref, "aaab").into_iter().map(|r| r.start_pos).collect::<Vec<_>>(),
+            vec![0, 2]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let strs = [("abc", 0), ("bcd", 1), ("cde", 2)];
+        let mut limits = PrefixLimits::default();
+        assert!(matches!(Prefix::from_strings_with_limits(strs.iter().cloned(), &limits), Prefix::Ac(..)));
+
+        limits.max_strings = 2;
+        assert!(matches!(Prefix::from_strings_with_limits(strs.iter().cloned(), &limits),
+            Prefix::Byte3(b'a', b'b', b'c')));
+        limits.fallback = LimitFallback::Empty;
+        assert!(matches!(Prefix::from_strings_with_limits(strs.iter().cloned(), &limits), Prefix::Empty));
+
+        limits = PrefixLimits { max_total_len: 8, ..PrefixLimits::default() };
+        assert!(matches!(Prefix::from_strings_with_limits(strs.iter().cloned(), &limits), Prefix::Byte3(..)));
+        // A single literal is cheap to search for, no matter how long it is.
+        assert!(matches!(Prefix::from_strings_with_limits(vec![("abcdefghij", 0)].into_iter(), &limits),
+            Prefix::Lit(_)));
+    }
+
+    #[test]
+    fn test_byte_frequencies() {
+        use test_util::lit_prog;
+
+        let freqs = ByteFrequencies::from_sample(b"xxxxxxxx xxxxxxxx yz");
+        assert_eq!(freqs.count(b'x'), 16);
+        assert_eq!(freqs.count(b'a'), 0);
+        let limits = PrefixLimits { byte_frequencies: Some(freqs), ..PrefixLimits::default() };
+        let from_strings = |strs: &[&str]| {
+            Prefix::from_strings_with_limits(strs.iter().map(|s| (*s, 0)), &limits)
+        };
+
+        // 'x' is too common to look for, but ' ', 'y' and 'z' aren't.
+        assert!(matches!(from_strings(&["x"]), Prefix::Empty));
+        assert!(matches!(from_strings(&["x", "ab"]), Prefix::Empty));
+        assert!(matches!(from_strings(&["y", "z"]), Prefix::Byte2(b'y', b'z')));
+        // 'x' is common in this sample, even though it is rare in typical text.
+        assert!(matches!(from_strings(&["xe"]), Prefix::LitRare(_, 1)));
+        assert!(matches!(Prefix::from_strings(vec![("xe", 0)].into_iter()).with_rare_byte(),
+            Prefix::LitRare(_, 0)));
+
+        // An exact prefix that is too common to look for doesn't stay exact.
+        assert!(matches!(Prefix::from_program_with_limits(&lit_prog("x"), &limits), Prefix::Empty));
+        let pref = Prefix::from_program_with_limits(&lit_prog("xe"), &limits);
+        assert!(matches!(pref, Prefix::Exact(ref inner) if matches!(**inner, Prefix::LitRare(_, 1))));
+        assert_eq!(search(pref, "xxexe").into_iter().map(|r| r.start_pos).collect::<Vec<_>>(),
+            vec![1, 3]);
+    }
+
+    #[test]
+    fn test_from_program() {
+        use test_util::{lit_prog, lits_prog, table_prog};
+
+        fn exact_lit(p: &Prefix, lit: &[u8]) -> bool {
+            matches!(*p, Prefix::Exact(ref inner) if matches!(**inner, Prefix::Lit(ref l) if l == lit))
+        }
+
+        assert!(exact_lit(&Prefix::from_program(&lit_prog("abc")), b"abc"));
+        assert!(matches!(Prefix::from_program(&lits_prog(&["a", "bc"])), Prefix::Byte2(b'a', b'b')));
+        assert!(matches!(Prefix::from_program(&lits_prog(&["a", "b"])), Prefix::Exact(_)));
+        let ac = Prefix::from_program(&lits_prog(&["ab", "cd", "ef"]));
+        assert!(matches!(ac, Prefix::Exact(ref inner) if matches!(**inner, Prefix::Ac(..))));
+        // The literal stops at the first accepting state.
+        let ab_abcd = table_prog(6, &[(1, b'a', 2), (2, b'b', 3), (3, b'c', 4), (4, b'd', 5)], &[3, 5]);
+        assert!(exact_lit(&Prefix::from_program(&ab_abcd), b"ab"));
+        // A program for ab[\x00-\x27], which has too many literals to be exact.
+        let edges: Vec<_> = vec![(1, b'a', 2), (2, b'b', 3)].into_iter()
+            .chain((0..40).map(|b| (3, b as u8, 4)))
+            .collect();
+        let ab_class = table_prog(5, &edges, &[4]);
+        let class: ByteSet = (0..40).collect();
+        assert!(matches!(Prefix::from_program(&ab_class),
+            Prefix::LitThenSet(ref l, ref bs) if l == b"ab" && *bs == class));
+
+        // A program for "ab", ignoring case.
+        let caseless = table_prog(4, &[(1, b'a', 2), (1, b'A', 2), (2, b'b', 3), (2, b'B', 3)], &[3]);
+        assert!(matches!(Prefix::from_program(&caseless),
+            Prefix::Exact(ref inner) if matches!(**inner, Prefix::LitCaseless(ref l) if l == b"ab")));
+
+        // A program for [ab]*c.
+        let looping = table_prog(3, &[(1, b'a', 1), (1, b'b', 1), (1, b'c', 2)], &[2]);
+        let expected: ByteSet = b"ab".iter().cloned().collect();
+        assert!(matches!(Prefix::from_program(&looping), Prefix::LoopWhile(ref bs, 0) if *bs == expected));
+
+        // A program that can only start at the beginning of a line.
+        let mut multiline = lit_prog("ab");
+        multiline.init = InitStates::AfterByte(b'\n', 1);
+        assert!(matches!(Prefix::from_program(&multiline), Prefix::AfterByte(b'\n')));
+
+        // A program that accepts the empty string.
+        let empty = table_prog(2, &[(1, b'a', 1)], &[1]);
+        assert!(matches!(Prefix::from_program(&empty), Prefix::Empty));
+
+        // A program with a huge alternation.
+        let edges: Vec<_> = (0..200).map(|b| (1, b as u8, 2)).chain(Some((2, b'x', 3))).collect();
+        let wide = table_prog(4, &edges, &[3]);
+        assert!(matches!(Prefix::from_program(&wide), Prefix::ByteSet(_)));
+
+        // If there are too many strings, we only look for their first bytes, which aren't exact.
+        let ab_cd = lits_prog(&["ab", "cd"]);
+        for limits in &[PrefixLimits { max_strings: 1, ..PrefixLimits::default() },
+                        PrefixLimits { max_total_len: 3, ..PrefixLimits::default() }] {
+            let pref = Prefix::from_program_with_limits(&ab_cd, limits);
+            assert!(matches!(pref, Prefix::Byte2(b'a', b'c')), "{:?}", pref);
+        }
+    }
+
+    #[test]
+    fn test_search_from() {
+        let prefs = vec![
+            Prefix::Empty,
+            Prefix::Byte(b'a'),
+            Prefix::Lit(b"aa".to_vec()),
+            Prefix::from_strings(vec![("baa", 0), ("sh", 1)].into_iter()),
+            Prefix::InnerLit(b"aa".to_vec(), ByteSet::full()),
+            Prefix::AfterByte(b' '),
+        ];
+        let input = b"baa baa black sheep";
+        for pref in &prefs {
+            let all: Vec<_> = pref.make_searcher(input).collect();
+            for pos in 0..(input.len() + 2) {
+                let expected = all.iter().find(|r| r.start_pos >= pos).cloned();
+                let found = pref.make_searcher(input).search_from(pos);
+                assert_eq!(found.map(|r| r.start_pos), expected.map(|r| r.start_pos),
+                    "{:?} from {}", pref, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lit_then_set_search() {
+        let pref = Prefix::LitThenSet(b"GET ".to_vec(), b"/".iter().cloned().collect());
+        assert_eq!(search(pref.clone(), "GET x GET / GET /"), results(vec![6, 12]));
+        assert_eq!(search(pref.clone(), "GET "), vec![]);
+        assert_eq!(search(pref.clone(), ""), vec![]);
+
+        let pref = Prefix::LitThenSet(b"aa".to_vec(), b"ab".iter().cloned().collect());
+        assert_eq!(search(pref, "aaaab aac"), results(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn test_after_byte_search() {
+        let pref = Prefix::AfterByte(b'\n');
+        assert_eq!(search(pref.clone(), "ab\ncd\n\ne\n"), results(vec![0, 3, 6, 7, 9]));
+        assert_eq!(search(pref.clone(), "\n"), results(vec![0, 1]));
+        assert_eq!(search(pref.clone(), ""), results(vec![0]));
+
+        let mut searcher = pref.make_searcher(b"ab\ncd\nef");
+        searcher.skip_to(1);
+        assert_eq!(searcher.search().map(|r| r.start_pos), Some(3));
+        searcher.skip_to(4);
+        assert_eq!(searcher.search().map(|r| r.start_pos), Some(6));
+        assert_eq!(searcher.search(), None);
+    }
+
+    #[test]
+    fn test_stream_search() {
+        fn stream_search(pref: &Prefix, input: &str, chunk_len: usize) -> Vec<PrefixResult> {
+            let mut searcher = StreamSearcher::new(pref);
+            let mut ret = Vec::new();
+            for chunk in input.as_bytes().chunks(chunk_len) {
+                searcher.feed(chunk, &mut ret);
+            }
+            searcher.finish(&mut ret);
+            ret
+        }
+
+        let input = "baa baa black sheep aa";
+        let prefs = vec![
+            Prefix::Empty,
+            Prefix::Byte(b'a'),
+            Prefix::Lit(b"aa".to_vec()),
+            Prefix::Lit(b"aa bl".to_vec()),
+            Prefix::LitCaseless(b"sheep".to_vec()),
+            Prefix::from_strings(vec![("baa", 1), ("ack", 2), ("aa", 3)].into_iter()),
+            Prefix::LoopWhile(b"ab".iter().cloned().collect(), 2),
+            Prefix::LitThenSet(b"aa".to_vec(), b" c".iter().cloned().collect()),
+        ];
+        for pref in &prefs {
+            let mut expected = pref.make_searcher(input.as_bytes()).collect::<Vec<_>>();
+            expected.sort_by_key(|r| (r.start_pos, r.end_pos));
+            for chunk_len in 1..8 {
+                let mut found = stream_search(pref, input, chunk_len);
+                found.sort_by_key(|r| (r.start_pos, r.end_pos));
+                assert_eq!(found, expected, "{:?} with chunks of {}", pref, chunk_len);
+            }
+        }
+
+        // InnerLit falls back to returning every position.
+        let inner = Prefix::InnerLit(b"ee".to_vec(), b"hs".iter().cloned().collect());
+        assert_eq!(stream_search(&inner, "sheep", 2), results(vec![0, 1, 2, 3, 4, 5]));
     }
 
     #[test]
@@ -370,10 +1853,14 @@ mod tests {
         assert!(matches!(pref(vec!["", "a", ""]), Byte(_)));
         assert!(matches!(pref(vec!["abc"]), Lit(_)));
         assert!(matches!(pref(vec!["abc", ""]), Lit(_)));
-        assert!(matches!(pref(vec!["a", "b", "c"]), ByteSet(_)));
-        assert!(matches!(pref(vec!["a", "b", "", "c"]), ByteSet(_)));
-        assert!(matches!(pref(vec!["a", "baa", "", "c"]), ByteSet(_)));
-        assert!(matches!(pref(vec!["ab", "baa", "", "cb"]), Ac(_, _)));
+        assert!(matches!(pref(vec!["a", "ab"]), Byte(b'a')));
+        assert!(matches!(pref(vec!["a", "b"]), Byte2(_, _)));
+        assert!(matches!(pref(vec!["a", "b", "c"]), Byte3(_, _, _)));
+        assert!(matches!(pref(vec!["a", "b", "", "c"]), Byte3(_, _, _)));
+        assert!(matches!(pref(vec!["a", "baa", "", "c"]), Byte3(_, _, _)));
+        assert!(matches!(pref(vec!["a", "b", "c", "d"]), ByteSet(_)));
+        assert!(matches!(pref(vec!["a", "baa", "", "c", "dd"]), ByteSet(_)));
+        assert!(matches!(pref(vec!["ab", "baa", "", "cb"]), Ac(..)));
     }
 }
 
@@ -0,0 +1,113 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Counting how often the states of a program are visited.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use SearchConfig;
+use program::{match_ends, Instructions, Program};
+
+/// A histogram of the states that a program visited while searching some inputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    counts: Vec<u64>,
+}
+
+impl Profile {
+    /// Creates an empty profile for a program with `num_states` states.
+    pub fn new(num_states: usize) -> Profile {
+        Profile {
+            counts: vec![0; num_states],
+        }
+    }
+
+    /// Searches `input` with `prog`, recording every state that it visits.
+    ///
+    /// This searches like a `BacktrackingEngine` without a prefix: it runs `prog` from each
+    /// position in turn until it finds a match, which it returns.
+    pub fn search<I: Instructions>(&mut self, prog: &Program<I>, input: &[u8],
+                                   config: &SearchConfig)
+    -> Option<(usize, usize)> {
+        assert_eq!(self.counts.len(), prog.num_states());
+        let (start, end) = config.bounds(input)?;
+        let anchored = config.anchored_start || prog.init.anchored().is_some();
+        for pos in start..=end {
+            if anchored && pos > start {
+                break;
+            }
+            let state = prog.init.anchored().or_else(|| prog.init.state_at_pos(input, pos));
+            if let Some(e) = state.and_then(|s| self.run(prog, input, pos, end, s, config)) {
+                return Some((pos, e));
+            }
+        }
+        None
+    }
+
+    // Runs `prog` from `state` at `pos`, and returns the end of the shortest match.
+    fn run<I: Instructions>(&mut self, prog: &Program<I>, input: &[u8], pos: usize, end: usize,
+                            mut state: usize, config: &SearchConfig)
+    -> Option<usize> {
+        for (p, &b) in input[pos..end].iter().enumerate() {
+            self.counts[state] += 1;
+            let (next_state, accept) = prog.step_byte(state, b);
+            if let (Some(data), false) = (accept, config.anchored_end) {
+                return Some(match_ends(pos + p, data).0);
+            }
+            state = next_state?;
+        }
+        self.counts[state] += 1;
+        match prog.accept_at(input, end, state) {
+            Some((_, last)) if config.anchored_end => if last == end { Some(end) } else { None },
+            Some((first, _)) => Some(first),
+            None => None,
+        }
+    }
+
+    /// The number of times that each state was visited.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Returns the `n` most visited states, along with their counts, starting with the most
+    /// visited one.
+    pub fn hottest(&self, n: usize) -> Vec<(usize, u64)> {
+        let mut ret: Vec<(usize, u64)> = self.counts.iter().cloned().enumerate().collect();
+        ret.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ret.truncate(n);
+        ret
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use prefix::Prefix;
+    use profile::Profile;
+    use program::Instructions;
+    use test_util::lits_prog;
+    use SearchConfig;
+
+    #[test]
+    fn test_search() {
+        let prog = lits_prog(&["ab", "cd"]);
+        let bt = BacktrackingEngine::new(prog.clone(), Prefix::Empty);
+        let mut profile = Profile::new(prog.num_states());
+        let config = SearchConfig::default();
+        for input in &["xxab", "cacd", "", "aaa"] {
+            let input = input.as_bytes();
+            assert_eq!(profile.search(&prog, input, &config), bt.search(input, &config));
+        }
+
+        // The start state is visited once for every position that we tried.
+        let start = prog.init.state_at_pos(b"", 0).unwrap();
+        assert_eq!(profile.counts()[start], 3 + 3 + 1 + 4);
+        assert_eq!(profile.hottest(1), vec![(start, 11)]);
+        assert_eq!(profile.counts()[0], 0);
+    }
+}
@@ -6,78 +6,349 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use {vec_bytes, SearchConfig};
+use byte_set::{ByteClasses, ByteSet};
+#[cfg(feature = "std")]
+use error::LoadError;
+use error::ProgramError;
+#[cfg(feature = "std")]
+use serialize::{read_vec, Serialize};
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter, Error as FmtError};
-use std::{u32, usize};
+use std::sync::Arc;
 
 pub trait RegexSearcher {
     fn shortest_match(&self, haystack: &str) -> Option<(usize, usize)>;
 }
 
-// TODO: get rid of this in favor of a bool saying whether we're anchored. From now on,
-// start state is always zero.
-#[derive(Clone, Debug)]
+/// Describes where a program is allowed to start matching, and in which state.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum InitStates {
     Anchored(usize),
     Constant(usize),
+    /// We can start at the beginning of the input, or right after the given byte (for example,
+    /// right after a newline).
+    AfterByte(u8, usize),
+    /// We start in the first state at the beginning of the input or right after the given byte,
+    /// and in the second state everywhere else. This is for programs that can start anywhere,
+    /// but that can match more at the start of a line.
+    Contextual(u8, usize, usize),
+    /// We start in the given state at the beginning of the input. Everywhere else, the start
+    /// state depends on the byte before: it is `table[b]` after the byte `b`.
+    ByPrevByte(usize, Vec<usize>),
 }
 
 impl InitStates {
     /// Returns the starting state if we are at the given pos in the input.
-    pub fn state_at_pos(&self, _: &[u8], pos: usize) -> Option<usize> {
+    pub fn state_at_pos(&self, input: &[u8], pos: usize) -> Option<usize> {
         use program::InitStates::*;
 
-        match self {
-            &Anchored(s) => if pos == 0 { Some(s) } else { None },
-            &Constant(s) => Some(s),
+        match *self {
+            Anchored(s) => if pos == 0 { Some(s) } else { None },
+            Constant(s) => Some(s),
+            AfterByte(b, s) => if pos == 0 || input[pos - 1] == b { Some(s) } else { None },
+            Contextual(b, s, t) => if pos == 0 || input[pos - 1] == b { Some(s) } else { Some(t) },
+            ByPrevByte(s, ref table) => if pos == 0 { Some(s) } else { Some(table[input[pos - 1] as usize]) },
         }
     }
 
     /// If we can start only at the beginning of the input, return the start state.
     pub fn anchored(&self) -> Option<usize> {
-        match self {
-            &InitStates::Anchored(s) => Some(s),
+        match *self {
+            InitStates::Anchored(s) => Some(s),
             _ => None,
         }
     }
 
+    /// Applies `f` to all of the start states.
+    pub fn map<F: Fn(usize) -> usize>(&self, f: F) -> InitStates {
+        match *self {
+            InitStates::Anchored(s) => InitStates::Anchored(f(s)),
+            InitStates::Constant(s) => InitStates::Constant(f(s)),
+            InitStates::AfterByte(b, s) => InitStates::AfterByte(b, f(s)),
+            InitStates::Contextual(b, s, t) => InitStates::Contextual(b, f(s), f(t)),
+            InitStates::ByPrevByte(s, ref table) =>
+                InitStates::ByPrevByte(f(s), table.iter().map(|&t| f(t)).collect()),
+        }
+    }
+
+    /// Returns all of the start states.
+    pub fn states(&self) -> Vec<usize> {
+        match *self {
+            InitStates::Anchored(s) | InitStates::Constant(s) | InitStates::AfterByte(_, s) =>
+                vec![s],
+            InitStates::Contextual(_, s, t) => vec![s, t],
+            InitStates::ByPrevByte(s, ref table) => {
+                let mut ret = table.clone();
+                ret.push(s);
+                ret.sort_unstable();
+                ret.dedup();
+                ret
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Inst {
     Byte(u8),
+    /// Matches any byte in the inclusive range.
+    Range(u8, u8),
+    /// `Seq(offset, len)` matches the `len` bytes of `VmInsts::literals` starting at `offset`.
+    ///
+    /// A literal of length `n` takes up `n` consecutive instructions, `Seq(offset, n)`,
+    /// `Seq(offset + 1, n - 1)`, ..., `Seq(offset + n - 1, 1)`, so that stepping one byte at a
+    /// time works as usual. `run`, on the other hand, matches the whole literal in one go.
+    Seq(usize, usize),
+    /// Matches any byte in `VmInsts::byte_sets[idx]`.
     ByteSet(usize),
     Acc(usize),
     Branch(usize),
 }
 
+/// A flag in accept data, meaning that besides the match that ended some bytes ago (given by the
+/// rest of the data), another match ends right here.
+///
+/// Programs that look past the end of a match (like the ones from `Nfa::determinize`) can find
+/// both at once. Searches for the shortest match want the first one, but searches that need a
+/// match to end at a certain place (or that want the longest match) want the second.
+pub const ENDS_HERE_TOO: usize = !(usize::MAX >> 1);
+
+/// Returns the earliest and the latest end of the matches that accept data `data` reports at
+/// position `pos`.
+pub fn match_ends(pos: usize, data: usize) -> (usize, usize) {
+    // We need to use saturating_sub here because a program that looks ahead past the end of a
+    // match can have a positive bytes_ago even at the start of the input.
+    let first = pos.saturating_sub(data & !ENDS_HERE_TOO);
+    (first, if data & ENDS_HERE_TOO != 0 { pos } else { first })
+}
+
+// For a reversed program that runs backwards, returns the leftmost start of the matches that
+// accept data `data` reports at position `pos`.
+fn rev_match_start(pos: usize, data: usize) -> usize {
+    if data & ENDS_HERE_TOO != 0 { pos } else { pos + data }
+}
+
+/// The result of running a program over a chunk of input using `Instructions::run`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunResult {
+    /// We reached an accepting state after consuming `pos` bytes of the input. `data` is the data
+    /// associated with the acceptance.
+    Accept { pos: usize, data: usize },
+    /// The program has no transition for the byte at `pos`.
+    Dead { pos: usize },
+    /// We consumed the whole input without accepting or dying, and ended up in `state`.
+    Eoi { state: usize },
+}
+
 pub trait Instructions: Clone + Debug {
     /// Returns (next_state, accept), where
     ///   - next_state is the next state to try
     ///   - accept gives some data associated with the acceptance.
-    fn step(&self, state: usize, input: &[u8]) -> (Option<usize>, Option<usize>);
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>);
+
+    /// Starting in `state`, runs through `input` until we either accept, die, or run out of input.
+    ///
+    /// This is equivalent to calling `step_byte` repeatedly, but implementations are encouraged
+    /// to override it with something faster.
+    fn run(&self, mut state: usize, input: &[u8]) -> RunResult {
+        for (pos, &b) in input.iter().enumerate() {
+            let (next_state, accept) = self.step_byte(state, b);
+            if let Some(data) = accept {
+                return RunResult::Accept { pos, data };
+            } else if let Some(next_state) = next_state {
+                state = next_state;
+            } else {
+                return RunResult::Dead { pos };
+            }
+        }
+        RunResult::Eoi { state }
+    }
+
+    /// Returns the data associated with acceptance if `state` is an accepting state.
+    fn accept(&self, state: usize) -> Option<usize>;
+
+    /// Returns true if `state` can never accept, no matter what input follows.
+    ///
+    /// This is only a conservative check, so a state returning `false` may still turn out to be a
+    /// dead end.
+    fn is_dead(&self, state: usize) -> bool;
 
     /// The number of states in this program.
     fn num_states(&self) -> usize;
+
+    /// Checks that running these instructions can't index out of bounds or panic, for example
+    /// because a transition goes to a state that doesn't exist.
+    ///
+    /// This is for instructions that were built from data that might be corrupted. The default
+    /// implementation doesn't check anything.
+    fn validate(&self) -> Result<(), ProgramError> {
+        Ok(())
+    }
+
+    /// Returns roughly how many bytes these instructions hold on the heap. The default
+    /// implementation returns 0.
+    fn memory_usage(&self) -> usize {
+        0
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Program<Insts: Instructions> {
     pub accept_at_eoi: Vec<usize>,
     pub instructions: Insts,
-    pub is_anchored: bool,
+    pub init: InitStates,
 }
 
 impl<Insts: Instructions> Instructions for Program<Insts> {
-    fn step(&self, state: usize, input: &[u8]) -> (Option<usize>, Option<usize>) {
-        self.instructions.step(state, input)
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
+        self.instructions.step_byte(state, input)
+    }
+
+    fn run(&self, state: usize, input: &[u8]) -> RunResult {
+        self.instructions.run(state, input)
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        self.instructions.accept(state)
+    }
+
+    fn is_dead(&self, state: usize) -> bool {
+        self.instructions.is_dead(state)
     }
 
     fn num_states(&self) -> usize {
         self.instructions.num_states()
     }
+
+    /// Checks the instructions, and also that `accept_at_eoi` and `init` agree with them.
+    fn validate(&self) -> Result<(), ProgramError> {
+        self.instructions.validate()?;
+        let n = self.num_states();
+        if self.accept_at_eoi.len() != n {
+            return Err(ProgramError::WrongSize);
+        } else if let InitStates::ByPrevByte(_, ref table) = self.init {
+            if table.len() != 256 {
+                return Err(ProgramError::WrongSize);
+            }
+        }
+        match self.init.states().into_iter().find(|&s| s >= n) {
+            Some(s) => Err(ProgramError::BadInitState(s)),
+            None => Ok(()),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let init = match self.init {
+            InitStates::ByPrevByte(_, ref table) => vec_bytes(table),
+            _ => 0,
+        };
+        vec_bytes(&self.accept_at_eoi) + init + self.instructions.memory_usage()
+    }
+}
+
+/// A state of a `Program`, for running it one byte at a time with `Program::start` and
+/// `Program::next`.
+///
+/// The states that these return are always valid, as long as the program is (see
+/// `Instructions::validate`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StateId(usize);
+
+impl StateId {
+    /// The number of this state, which is less than the program's `num_states`.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Shared instructions, so that several engines can run one table without copying it. See
+/// `Program::into_shared`.
+impl<Insts: Instructions> Instructions for Arc<Insts> {
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
+        (**self).step_byte(state, input)
+    }
+
+    fn run(&self, state: usize, input: &[u8]) -> RunResult {
+        (**self).run(state, input)
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        (**self).accept(state)
+    }
+
+    fn is_dead(&self, state: usize) -> bool {
+        (**self).is_dead(state)
+    }
+
+    fn num_states(&self) -> usize {
+        (**self).num_states()
+    }
+
+    fn validate(&self) -> Result<(), ProgramError> {
+        (**self).validate()
+    }
+
+    fn memory_usage(&self) -> usize {
+        (**self).memory_usage()
+    }
 }
 
 impl<Insts: Instructions> Program<Insts> {
+    /// Creates a program, after checking (with `Instructions::validate`) that running it can't
+    /// panic.
+    pub fn try_new(accept_at_eoi: Vec<usize>, instructions: Insts, init: InitStates)
+    -> Result<Program<Insts>, ProgramError> {
+        let ret = Program {
+            accept_at_eoi,
+            instructions,
+            init,
+        };
+        ret.validate()?;
+        Ok(ret)
+    }
+
+    /// Puts the instructions behind an `Arc`, so that cloning the program (for example, to run it
+    /// with several engines) doesn't copy them.
+    pub fn into_shared(self) -> Program<Arc<Insts>> {
+        Program {
+            accept_at_eoi: self.accept_at_eoi,
+            instructions: Arc::new(self.instructions),
+            init: self.init,
+        }
+    }
+
+    /// Returns the state to start in, for running the program one byte at a time (with `next`).
+    ///
+    /// `prev` is the byte before the position where the match would start, or `None` at the
+    /// beginning of the input. Returns `None` if the program can't start a match there.
+    pub fn start(&self, prev: Option<u8>) -> Option<StateId> {
+        match prev {
+            Some(b) => self.init.state_at_pos(&[b], 1),
+            None => self.init.state_at_pos(&[], 0),
+        }.map(StateId)
+    }
+
+    /// Returns the state after `byte`, or `None` if the program can't match any more.
+    pub fn next(&self, state: StateId, byte: u8) -> Option<StateId> {
+        self.step_byte(state.0, byte).0.map(StateId)
+    }
+
+    /// If the program accepts in `state` when there is another byte to come, returns the data
+    /// associated with the match. For programs built by this crate, this is the number of bytes
+    /// ago that the match ended.
+    pub fn accepts(&self, state: StateId) -> Option<usize> {
+        self.accept(state.0)
+    }
+
+    /// Like `accepts`, but for when the input ends in `state`.
+    pub fn eoi(&self, state: StateId) -> Option<usize> {
+        self.check_eoi(state.0)
+    }
+
     /// If the program should accept at the end of input in state `state`, returns the data
     /// associated with the match.
     pub fn check_eoi(&self, state: usize) -> Option<usize> {
@@ -87,55 +358,372 @@ impl<Insts: Instructions> Program<Insts> {
             None
         }
     }
+
+    /// If the program accepts in state `state` at position `end` of `input`, returns the earliest
+    /// and the latest end of the matches that it reports there (see `match_ends`).
+    ///
+    /// If `end` is the end of `input`, this uses `check_eoi`. Otherwise, there is another byte
+    /// after `end`, so this uses `accept`. Since a match that ends at `end` might only be reported
+    /// after the byte following it, this also looks at the state after that byte.
+    pub fn accept_at(&self, input: &[u8], end: usize, state: usize) -> Option<(usize, usize)> {
+        if end == input.len() {
+            return self.check_eoi(state).map(|data| match_ends(end, data));
+        }
+        let here = self.accept(state).map(|data| match_ends(end, data));
+        let ahead = self.step_byte(state, input[end]).0
+            .and_then(|next| self.accept(next))
+            .map(|data| match_ends(end + 1, data & !ENDS_HERE_TOO).0)
+            .filter(|&e| e <= end);
+        match (here, ahead) {
+            (Some((first, last)), Some(e)) => Some((min(first, e), max(last, e))),
+            (here, ahead) => here.or(ahead.map(|e| (e, e))),
+        }
+    }
+
+    /// Returns true if, at the end of the input in `state`, the program accepts a match that ends
+    /// right there.
+    pub fn ends_at_eoi(&self, state: usize) -> bool {
+        self.check_eoi(state).is_some_and(|data| data == 0 || data & ENDS_HERE_TOO != 0)
+    }
+
+    /// Runs the program from `state` at position `pos` of `input`, without looking past `end`.
+    /// Returns the end of the shortest match, if there is one.
+    pub fn shortest_match_from(&self, input: &[u8], pos: usize, end: usize, state: usize)
+    -> Option<usize> {
+        match self.run_from(input, pos, end, state) {
+            RunResult::Accept { pos: off, data } => Some(match_ends(pos + off, data).0),
+            RunResult::Dead { .. } => None,
+            RunResult::Eoi { state: st } => {
+                let accept = self.accept_at(input, end, st);
+                trace_step!(start: pos, pos: end, byte: None, state: st,
+                            accept: accept.map(|e| end - e.0));
+                accept.map(|e| e.0)
+            },
+        }
+    }
+
+    // Runs the program on `input[pos..end]`. With the `trace` feature, this goes one step at a
+    // time so that it can report them.
+    #[cfg(not(feature = "trace"))]
+    fn run_from(&self, input: &[u8], pos: usize, end: usize, state: usize) -> RunResult {
+        self.run(state, &input[pos..end])
+    }
+
+    #[cfg(feature = "trace")]
+    fn run_from(&self, input: &[u8], pos: usize, end: usize, mut state: usize) -> RunResult {
+        for (off, &b) in input[pos..end].iter().enumerate() {
+            let (next_state, accept) = self.step_byte(state, b);
+            trace_step!(start: pos, pos: pos + off, byte: Some(b), state: state, accept: accept);
+            if let Some(data) = accept {
+                return RunResult::Accept { pos: off, data };
+            }
+            match next_state {
+                Some(s) => state = s,
+                None => return RunResult::Dead { pos: off },
+            }
+        }
+        RunResult::Eoi { state }
+    }
+
+    /// Runs the program from `state` at position `pos` of `input` until it dies or reaches `end`,
+    /// and returns the end of the last match that it found.
+    pub fn last_match_from(&self, input: &[u8], pos: usize, end: usize, mut state: usize)
+    -> Option<usize> {
+        let mut ret = None;
+        for (p, &b) in input[pos..end].iter().enumerate() {
+            let (next_state, accept) = self.step_byte(state, b);
+            trace_step!(start: pos, pos: pos + p, byte: Some(b), state: state, accept: accept);
+            if let Some(data) = accept {
+                ret = max(ret, Some(match_ends(pos + p, data).1));
+            }
+            match next_state {
+                Some(s) => state = s,
+                None => return ret,
+            }
+        }
+        let accept = self.accept_at(input, end, state);
+        trace_step!(start: pos, pos: end, byte: None, state: state,
+                    accept: accept.map(|e| end - e.0));
+        max(ret, accept.map(|e| e.1))
+    }
+
+    /// If `config` asks for leftmost-first matches, extends the match `(start, end)` to the last
+    /// match that starts at `start`.
+    pub fn extend_match(&self, input: &[u8], config: &SearchConfig, m: (usize, usize))
+    -> (usize, usize) {
+        if !config.leftmost_first || config.anchored_end {
+            return m;
+        }
+        let end = match config.bounds(input) {
+            Some(b) => b.1,
+            None => return m,
+        };
+        let state = self.init.anchored().or_else(|| self.init.state_at_pos(input, m.0));
+        let e = state.and_then(|state| self.last_match_from(input, m.0, end, state));
+        (m.0, max(m.1, e.unwrap_or(m.1)))
+    }
+
+    /// Runs the program from the start of `m` to its end, and returns the position and state at
+    /// each step, or `None` if it dies before the end (or if `m` isn't a range of `input`).
+    pub fn explain(&self, input: &[u8], m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        let (start, end) = m;
+        let bytes = input.get(start..end)?;
+        let mut state = self.init.anchored().or_else(|| self.init.state_at_pos(input, start))?;
+        let mut ret = vec![(start, state)];
+        for (p, &b) in bytes.iter().enumerate() {
+            state = self.step_byte(state, b).0?;
+            ret.push((start + p + 1, state));
+        }
+        Some(ret)
+    }
+
+    /// Returns true if running the program from `state` at position `pos` of `input` gives a match
+    /// that ends exactly at `end`.
+    pub fn matches_to(&self, input: &[u8], pos: usize, end: usize, mut state: usize) -> bool {
+        for (p, &b) in input[pos..end].iter().enumerate() {
+            let (next_state, accept) = self.step_byte(state, b);
+            trace_step!(start: pos, pos: pos + p, byte: Some(b), state: state, accept: accept);
+            match next_state {
+                Some(next_state) => state = next_state,
+                None => return false,
+            }
+        }
+        let accept = self.accept_at(input, end, state);
+        trace_step!(start: pos, pos: end, byte: None, state: state,
+                    accept: accept.map(|e| end - e.0));
+        accept.is_some_and(|e| e.1 == end)
+    }
+
+    /// Treating this as a reversed program, runs it backwards from `end` and returns the smallest
+    /// position (not before `start`) at which it accepts.
+    ///
+    /// If this is the reversal of some program, the result is the leftmost start of a match of
+    /// that program ending at `end`.
+    pub fn leftmost_start(&self, input: &[u8], start: usize, end: usize) -> Option<usize> {
+        let mut state = self.init.state_at_pos(&[], 0)?;
+        let mut ret = None;
+        let mut pos = end;
+        while pos > start {
+            let (next_state, accept) = self.step_byte(state, input[pos - 1]);
+            trace_step!(start: end, pos: pos - 1, byte: Some(input[pos - 1]), state: state,
+                        accept: accept);
+            if let Some(data) = accept {
+                ret = Some(rev_match_start(pos, data));
+            }
+            match next_state {
+                Some(s) => state = s,
+                None => return ret,
+            }
+            pos -= 1;
+        }
+
+        // As in `accept_at`, the start of the window is only the end of the (reversed) input if it
+        // is the start of the real input. Otherwise, a match that starts there might only be
+        // reported after the byte before it.
+        if start == 0 {
+            let accept = self.check_eoi(state);
+            trace_step!(start: end, pos: pos, byte: None, state: state, accept: accept);
+            return accept.map(|data| rev_match_start(pos, data)).or(ret);
+        }
+        let (next_state, accept) = self.step_byte(state, input[start - 1]);
+        trace_step!(start: end, pos: pos, byte: None, state: state, accept: accept);
+        let ahead = next_state
+            .and_then(|next| self.accept(next))
+            .map(|data| start - 1 + (data & !ENDS_HERE_TOO))
+            .filter(|&s| s >= start);
+        match (accept.map(|data| rev_match_start(pos, data)), ahead) {
+            (Some(s), Some(t)) => Some(min(s, t)),
+            (here, ahead) => here.or(ahead).or(ret),
+        }
+    }
+
+    /// If this program is anchored at both ends (that is, it can only start at the beginning of
+    /// the input and it can only accept at the end), returns its start state.
+    pub fn full_match_start(&self) -> Option<usize> {
+        let state = self.init.anchored()?;
+        if (0..self.num_states()).all(|s| self.accept(s).is_none()) {
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    /// Matches all of `input`, starting from `state`.
+    ///
+    /// This is only correct for the state returned by `full_match_start`.
+    pub fn full_match(&self, state: usize, input: &[u8]) -> Option<(usize, usize)> {
+        match self.run(state, input) {
+            RunResult::Eoi { state } =>
+                self.check_eoi(state).map(|data| (0, match_ends(input.len(), data).0)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, PartialEq)]
 pub struct VmInsts {
-    pub byte_sets: Vec<bool>,
-    pub branch_table: Vec<u32>,
+    pub byte_sets: Vec<ByteSet>,
+    /// The tables used by `Inst::Branch`, each with one entry per byte. As in `TableInsts`, 0
+    /// means that there is no transition, and `i + 1` means that we go to instruction `i`.
+    pub branch_table: Vec<TableStateIdx>,
+    /// The pool of literals referenced by `Inst::Seq`.
+    pub literals: Vec<u8>,
     pub insts: Vec<Inst>,
 }
 
+impl VmInsts {
+    /// Expands these instructions into a lookup table.
+    ///
+    /// Since state 0 of the table is the dead state, state `i + 1` of the returned table behaves
+    /// exactly like instruction `i`.
+    pub fn to_table(&self) -> TableInsts {
+        let mut table = vec![0; 256];
+        let mut accept = vec![usize::MAX];
+        table.reserve(self.insts.len() * 256);
+        accept.reserve(self.insts.len());
+
+        for state in 0..self.insts.len() {
+            for b in 0..256 {
+                let next = self.step_byte(state, b as u8).0;
+                table.push(next.map(|s| s as TableStateIdx + 1).unwrap_or(0));
+            }
+            accept.push(self.accept(state).unwrap_or(usize::MAX));
+        }
+
+        TableInsts::new(table, accept)
+    }
+}
+
+impl Program<VmInsts> {
+    /// Converts this program into one that uses a lookup table. See `VmInsts::to_table`.
+    pub fn to_table(&self) -> Program<TableInsts> {
+        let mut accept_at_eoi = vec![usize::MAX];
+        accept_at_eoi.extend_from_slice(&self.accept_at_eoi);
+        Program {
+            accept_at_eoi,
+            instructions: self.instructions.to_table(),
+            init: self.init.map(|s| s + 1),
+        }
+    }
+}
+
 impl Instructions for VmInsts {
     #[inline(always)]
-    fn step(&self, state: usize, input: &[u8]) -> (Option<usize>, Option<usize>) {
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
         use program::Inst::*;
         match self.insts[state] {
             Acc(a) => {
                 return (Some(state + 1), Some(a));
             },
             Byte(b) => {
-                if b == input[0] {
+                if b == input {
+                    return (Some(state + 1), None);
+                }
+            },
+            Range(lo, hi) => {
+                if lo <= input && input <= hi {
+                    return (Some(state + 1), None);
+                }
+            },
+            Seq(offset, _) => {
+                if self.literals[offset] == input {
                     return (Some(state + 1), None);
                 }
             },
             ByteSet(bs_idx) => {
-                if self.byte_sets[bs_idx + input[0] as usize] {
+                if self.byte_sets[bs_idx].contains(input) {
                     return (Some(state + 1), None);
                 }
             },
             Branch(table_idx) => {
-                let next_state = self.branch_table[table_idx + input[0] as usize];
-                if next_state != u32::MAX {
-                    return (Some(next_state as usize), None);
+                let next_state = self.branch_table[table_idx + input as usize];
+                if next_state != 0 {
+                    return (Some(next_state as usize - 1), None);
                 }
             },
         }
         (None, None)
     }
 
+    fn run(&self, mut state: usize, input: &[u8]) -> RunResult {
+        let mut pos = 0;
+        while pos < input.len() {
+            if let Inst::Seq(offset, len) = self.insts[state] {
+                let lit = &self.literals[offset..(offset + len)];
+                if input[pos..].starts_with(lit) {
+                    state += len;
+                    pos += len;
+                    continue;
+                }
+            }
+
+            let (next_state, accept) = self.step_byte(state, input[pos]);
+            if let Some(data) = accept {
+                return RunResult::Accept { pos, data };
+            } else if let Some(next_state) = next_state {
+                state = next_state;
+                pos += 1;
+            } else {
+                return RunResult::Dead { pos };
+            }
+        }
+        RunResult::Eoi { state }
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        match self.insts[state] {
+            Inst::Acc(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    fn is_dead(&self, state: usize) -> bool {
+        match self.insts[state] {
+            Inst::Acc(_) | Inst::Byte(_) | Inst::Seq(..) => false,
+            Inst::Range(lo, hi) => lo > hi,
+            Inst::ByteSet(bs_idx) => self.byte_sets[bs_idx].is_empty(),
+            Inst::Branch(table_idx) =>
+                self.branch_table[table_idx..(table_idx + 256)].iter().all(|&s| s == 0),
+        }
+    }
+
     fn num_states(&self) -> usize {
         self.insts.len()
     }
+
+    fn validate(&self) -> Result<(), ProgramError> {
+        let n = self.insts.len();
+        for (s, inst) in self.insts.iter().enumerate() {
+            let ok = match *inst {
+                Inst::Branch(idx) => self.branch_table.get(idx..idx.saturating_add(256))
+                    .is_some_and(|t| t.iter().all(|&t| t as usize <= n)),
+                Inst::Seq(offset, len) => len > 0 && s + len < n
+                    && offset.checked_add(len).is_some_and(|end| end <= self.literals.len()),
+                // The other instructions go on to the next one, unless they never match.
+                Inst::ByteSet(idx) =>
+                    idx < self.byte_sets.len() && (s + 1 < n || self.is_dead(s)),
+                Inst::Byte(_) | Inst::Range(..) | Inst::Acc(_) => s + 1 < n || self.is_dead(s),
+            };
+            if !ok {
+                return Err(ProgramError::BadState(s));
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        vec_bytes(&self.byte_sets) + vec_bytes(&self.branch_table) + vec_bytes(&self.literals)
+            + vec_bytes(&self.insts)
+    }
 }
 
 
 impl Debug for VmInsts {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
-        try!(f.write_fmt(format_args!("VmInsts ({} instructions):\n", self.insts.len())));
+        f.write_fmt(format_args!("VmInsts ({} instructions):\n", self.insts.len()))?;
 
         for (idx, inst) in self.insts.iter().enumerate() {
-            try!(f.write_fmt(format_args!("\tInst {}: {:?}\n", idx, inst)));
+            f.write_fmt(format_args!("\tInst {}: {:?}\n", idx, inst))?;
         }
         Ok(())
     }
@@ -144,34 +732,208 @@ impl Debug for VmInsts {
 pub type TableStateIdx = u32;
 
 /// A DFA program implemented as a lookup table.
+///
+/// State 0 is the dead state: it never accepts, and all of its transitions lead back to itself.
+/// A transition to state 0 means that there is no way to continue matching.
 #[derive(Clone)]
 pub struct TableInsts {
-    /// A `256 x num_instructions`-long table.
+    /// A `stride x num_instructions`-long table, where the stride is the number of byte classes.
+    /// The transition out of state `st` on the byte `b` is at `st * stride + classes.class(b)`.
     pub table: Vec<TableStateIdx>,
     /// If `accept[st]` is not `usize::MAX`, then it gives some data to return if we match the
     /// input when we're in state `st`.
     pub accept: Vec<usize>,
+    /// The semi-open range of states `[start, end)` containing all the accepting states. Only
+    /// states in this range look at `accept`, so if the accepting states are contiguous then
+    /// checking for acceptance costs a single comparison. If `accept` is changed, this needs to
+    /// be updated too (`validate` checks that it contains all the accepting states).
+    pub accept_range: (usize, usize),
+    /// The classes of bytes that share a column of `table`.
+    pub classes: ByteClasses,
+}
+
+impl TableInsts {
+    /// Creates a new `TableInsts`, with `accept_range` as small as possible. The table has a
+    /// column for every byte (see `with_classes` for tables with fewer columns).
+    pub fn new(table: Vec<TableStateIdx>, accept: Vec<usize>) -> TableInsts {
+        let start = accept.iter().position(|&a| a != usize::MAX).unwrap_or(0);
+        let end = accept.iter().rposition(|&a| a != usize::MAX).map(|x| x + 1).unwrap_or(0);
+        TableInsts {
+            table,
+            accept,
+            accept_range: (start, end),
+            classes: ByteClasses::singletons(),
+        }
+    }
+
+    /// Says that the table has one column for each of these classes, instead of one for each
+    /// byte.
+    pub fn with_classes(mut self, classes: ByteClasses) -> TableInsts {
+        self.classes = classes;
+        self
+    }
+
+    /// The number of transitions stored for each state.
+    #[inline(always)]
+    pub fn stride(&self) -> usize {
+        self.classes.num_classes()
+    }
+
+    /// The state that we go to from `state` on the byte `b` (0 if we die).
+    #[inline(always)]
+    pub fn transition(&self, state: usize, b: u8) -> TableStateIdx {
+        self.table[state * self.stride() + self.classes.class(b)]
+    }
+
+    /// The transitions out of `state`, one for each class.
+    pub fn row(&self, state: usize) -> &[TableStateIdx] {
+        let stride = self.stride();
+        &self.table[(state * stride)..((state + 1) * stride)]
+    }
+
+    /// The transitions out of `state`, one for each byte.
+    pub fn full_row(&self, state: usize) -> Vec<TableStateIdx> {
+        (0..256).map(|b| self.transition(state, b as u8)).collect()
+    }
+
+    /// Returns an equivalent table with one column for each byte.
+    pub fn to_full(&self) -> TableInsts {
+        if self.classes.is_singletons() {
+            return self.clone();
+        }
+        let table = (0..self.accept.len()).flat_map(|s| self.full_row(s)).collect();
+        TableInsts::new(table, self.accept.clone())
+    }
+
+    /// Reads a table in the format written by `Serialize::write_to`, for example one that was
+    /// embedded with `include_bytes!`.
+    ///
+    /// Every transition is checked to lead to a state that exists, so searching with the result
+    /// can't index out of bounds. The data doesn't need to be aligned, since it gets copied.
+    #[cfg(feature = "std")]
+    pub fn from_bytes(bytes: &[u8]) -> Result<TableInsts, LoadError> {
+        let mut r = bytes;
+        let accept: Vec<usize> = read_vec(&mut r).map_err(|_| LoadError::TooShort)?;
+        let table: Vec<TableStateIdx> = read_vec(&mut r).map_err(|_| LoadError::TooShort)?;
+        let classes = ByteClasses::read_from(&mut r).map_err(|_| LoadError::TooShort)?;
+        if !r.is_empty() {
+            return Err(LoadError::TooLong);
+        } else if table.len() != accept.len() * classes.num_classes() {
+            return Err(LoadError::WrongSize);
+        }
+        if let Some(i) = table.iter().position(|&s| s as usize >= accept.len()) {
+            return Err(LoadError::BadState(i));
+        }
+        Ok(TableInsts::new(table, accept).with_classes(classes))
+    }
+
+    /// Borrows the tables.
+    #[inline(always)]
+    pub fn as_table_ref(&self) -> TableRef<'_> {
+        TableRef {
+            table: &self.table,
+            accept: &self.accept,
+            accept_range: self.accept_range,
+            classes: &self.classes,
+        }
+    }
+
+    /// Compresses this table into VM instructions, if that can be done faithfully.
+    ///
+    /// Instruction `i` of the result behaves exactly like state `i + 1` of the table (the dead
+    /// state doesn't need an instruction). Since all the instructions (except `Branch`) proceed
+    /// to the next instruction, this fails if there is an accepting state that doesn't
+    /// unconditionally transition to the state after it.
+    pub fn to_vm(&self) -> Option<VmInsts> {
+        let mut ret = VmInsts {
+            byte_sets: Vec::new(),
+            branch_table: Vec::new(),
+            literals: Vec::new(),
+            insts: Vec::with_capacity(self.accept.len()),
+        };
+
+        for state in 1..self.accept.len() {
+            let row = self.full_row(state);
+            let next = (state + 1) as TableStateIdx;
+
+            if let Some(acc) = self.accept(state) {
+                if row.iter().any(|&s| s != next) {
+                    return None;
+                }
+                ret.insts.push(Inst::Acc(acc));
+            } else if row.iter().all(|&s| s == next || s == 0) {
+                let first = row.iter().position(|&s| s == next);
+                let last = row.iter().rposition(|&s| s == next);
+                match (first, last) {
+                    (Some(lo), Some(hi)) if row[lo..=hi].iter().all(|&s| s == next) => {
+                        ret.insts.push(if lo == hi {
+                            Inst::Byte(lo as u8)
+                        } else {
+                            Inst::Range(lo as u8, hi as u8)
+                        });
+                    },
+                    _ => {
+                        ret.insts.push(Inst::ByteSet(ret.byte_sets.len()));
+                        ret.byte_sets.push((0..256)
+                            .filter(|&b| row[b] == next)
+                            .map(|b| b as u8)
+                            .collect());
+                    },
+                }
+            } else {
+                ret.insts.push(Inst::Branch(ret.branch_table.len()));
+                // The branch table uses the same numbering as the table, so it's just a copy.
+                ret.branch_table.extend_from_slice(&row);
+            }
+        }
+
+        Some(ret)
+    }
+}
+
+impl Program<TableInsts> {
+    /// Converts this program into one that uses VM instructions. See `TableInsts::to_vm`.
+    pub fn to_vm(&self) -> Option<Program<VmInsts>> {
+        // A program that starts in the dead state can't be converted, since the dead state
+        // doesn't correspond to any instruction.
+        if self.init.state_at_pos(&[], 0) == Some(0) {
+            return None;
+        }
+        self.instructions.to_vm().map(|insts| Program {
+            accept_at_eoi: self.accept_at_eoi[1..].to_vec(),
+            instructions: insts,
+            init: self.init.map(|s| s - 1),
+        })
+    }
+
+    /// Converts this program into one that uses a two-level table. See `AsciiTableInsts`.
+    pub fn to_ascii_table(&self) -> Program<AsciiTableInsts> {
+        Program {
+            accept_at_eoi: self.accept_at_eoi.clone(),
+            instructions: AsciiTableInsts::from_table(&self.instructions),
+            init: self.init.clone(),
+        }
+    }
 }
 
 impl Debug for TableInsts {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
-        try!(f.write_fmt(format_args!("TableInsts ({} instructions):\n", self.accept.len())));
+        f.write_fmt(format_args!("TableInsts ({} instructions):\n", self.accept.len()))?;
 
         for idx in 0..self.accept.len() {
-            try!(f.write_fmt(format_args!("State {}:\n", idx)));
-            try!(f.debug_map()
+            f.write_fmt(format_args!("State {}:\n", idx))?;
+            f.debug_map()
                 .entries((0usize..255)
-                    .map(|c| (c, self.table[idx * 256 + c]))
-                    .filter(|x| x.1 != u32::MAX))
-                .finish());
-            try!(f.write_str("\n"));
+                    .map(|c| (c, self.transition(idx, c as u8)))
+                    .filter(|x| x.1 != 0))
+                .finish()?;
+            f.write_str("\n")?;
         }
 
-        try!(f.write_str("Accept: "));
-        for idx in 0..self.accept.len() {
-            let val = self.accept[idx];
+        f.write_str("Accept: ")?;
+        for (idx, &val) in self.accept.iter().enumerate() {
             if val != usize::MAX {
-                try!(f.write_fmt(format_args!("{} -> {}, ", idx, val)));
+                f.write_fmt(format_args!("{} -> {}, ", idx, val))?;
             }
         }
         Ok(())
@@ -181,18 +943,723 @@ impl Debug for TableInsts {
 
 impl Instructions for TableInsts {
     #[inline(always)]
-    fn step(&self, state: usize, input: &[u8]) -> (Option<usize>, Option<usize>) {
-        let accept = self.accept[state];
-        let next_state = self.table[state * 256 + input[0] as usize];
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
+        self.as_table_ref().step_byte(state, input)
+    }
+
+    fn run(&self, state: usize, input: &[u8]) -> RunResult {
+        self.as_table_ref().run(state, input)
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        self.as_table_ref().accept(state)
+    }
+
+    fn is_dead(&self, state: usize) -> bool {
+        state == 0
+    }
+
+    fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    fn validate(&self) -> Result<(), ProgramError> {
+        self.as_table_ref().validate()
+    }
+
+    fn memory_usage(&self) -> usize {
+        vec_bytes(&self.table) + vec_bytes(&self.accept)
+    }
+}
+
+/// A `TableInsts` whose tables are borrowed, for example from `static` arrays written by
+/// `codegen::write_program`.
+///
+/// Running a program with these instructions never touches the heap, since the tables don't need
+/// to be copied anywhere.
+#[derive(Clone, Copy, Debug)]
+pub struct TableRef<'a> {
+    /// A `stride x num_instructions`-long table, as in `TableInsts::table`.
+    pub table: &'a [TableStateIdx],
+    /// The data to return when matching in each state, as in `TableInsts::accept`.
+    pub accept: &'a [usize],
+    /// The range of states containing all the accepting states, as in
+    /// `TableInsts::accept_range`.
+    pub accept_range: (usize, usize),
+    /// The classes of bytes that share a column of `table`, as in `TableInsts::classes`.
+    pub classes: &'a ByteClasses,
+}
+
+static SINGLETONS: ByteClasses = ByteClasses::singletons();
+
+impl<'a> TableRef<'a> {
+    /// Creates a new `TableRef`, with `accept_range` as small as possible. The table has a column
+    /// for every byte.
+    pub fn new(table: &'a [TableStateIdx], accept: &'a [usize]) -> TableRef<'a> {
+        let start = accept.iter().position(|&a| a != usize::MAX).unwrap_or(0);
+        let end = accept.iter().rposition(|&a| a != usize::MAX).map(|x| x + 1).unwrap_or(0);
+        TableRef {
+            table,
+            accept,
+            accept_range: (start, end),
+            classes: &SINGLETONS,
+        }
+    }
+
+    /// Says that the table has one column for each of these classes, as in
+    /// `TableInsts::with_classes`.
+    pub fn with_classes(mut self, classes: &'a ByteClasses) -> TableRef<'a> {
+        self.classes = classes;
+        self
+    }
 
-        let accept = if accept != usize::MAX { Some(accept) } else { None };
-        let next_state = if next_state != u32::MAX { Some(next_state as usize) } else { None };
+    #[inline(always)]
+    fn next(&self, state: usize, b: u8) -> TableStateIdx {
+        // Full tables don't need the class lookup, or the multiplication by a stride that isn't
+        // known at compile time.
+        if self.classes.is_singletons() {
+            self.table[state * 256 + b as usize]
+        } else {
+            self.table[state * self.classes.num_classes() + self.classes.class(b)]
+        }
+    }
+
+    // Runs the program like `Instructions::run`, using `next` to look up transitions.
+    #[inline(always)]
+    fn run_with<F>(&self, mut state: usize, input: &[u8], next: F) -> RunResult
+    where F: Fn(usize, u8) -> TableStateIdx {
+        // Takes a single step, returning early from `run` if we accept or die.
+        macro_rules! step {
+            ($pos:expr) => {{
+                let pos = $pos;
+                if self.in_accept_range(state) {
+                    let accept = self.accept[state];
+                    if accept != usize::MAX {
+                        return RunResult::Accept { pos, data: accept };
+                    }
+                }
+                let next_state = next(state, input[pos]);
+                if next_state == 0 {
+                    return RunResult::Dead { pos };
+                }
+                state = next_state as usize;
+            }}
+        }
+
+        let mut pos = 0;
+        while pos + 4 <= input.len() {
+            step!(pos);
+            step!(pos + 1);
+            step!(pos + 2);
+            step!(pos + 3);
+            pos += 4;
+        }
+        while pos < input.len() {
+            step!(pos);
+            pos += 1;
+        }
+        RunResult::Eoi { state }
+    }
+
+    #[inline(always)]
+    fn in_accept_range(&self, state: usize) -> bool {
+        state.wrapping_sub(self.accept_range.0) < self.accept_range.1 - self.accept_range.0
+    }
+}
+
+impl<'a> Instructions for TableRef<'a> {
+    #[inline(always)]
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
+        let accept = self.accept(state);
+        let next_state = self.next(state, input);
+        let next_state = if next_state != 0 { Some(next_state as usize) } else { None };
 
         (next_state, accept)
     }
 
+    fn run(&self, state: usize, input: &[u8]) -> RunResult {
+        // Choose the lookup once, instead of at every byte.
+        if self.classes.is_singletons() {
+            self.run_with(state, input, |s, b| self.table[s * 256 + b as usize])
+        } else {
+            let (stride, classes) = (self.classes.num_classes(), self.classes);
+            self.run_with(state, input, |s, b| self.table[s * stride + classes.class(b)])
+        }
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        if !self.in_accept_range(state) {
+            return None;
+        }
+        let accept = self.accept[state];
+        if accept != usize::MAX { Some(accept) } else { None }
+    }
+
+    fn is_dead(&self, state: usize) -> bool {
+        state == 0
+    }
+
     fn num_states(&self) -> usize {
         self.accept.len()
     }
+
+    fn validate(&self) -> Result<(), ProgramError> {
+        let n = self.accept.len();
+        let stride = self.classes.num_classes();
+        if self.table.len() != n * stride || self.accept_range.0 > self.accept_range.1
+                || self.accept_range.1 > n {
+            return Err(ProgramError::WrongSize);
+        }
+        // If `accept` was changed after `accept_range` was computed, some accepting states might
+        // be skipped.
+        let skipped = |s: &usize| self.accept[*s] != usize::MAX && !self.in_accept_range(*s);
+        if let Some(s) = (0..n).find(skipped) {
+            return Err(ProgramError::BadAcceptRange(s));
+        }
+        match self.table.iter().position(|&s| s as usize >= n) {
+            Some(i) => Err(ProgramError::BadState(i / stride)),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// A DFA program implemented as a two-level lookup table.
+///
+/// Transitions on ASCII bytes are stored in a dense table with 128 entries per state. The
+/// transitions on non-ASCII bytes are stored as sorted lists of byte ranges, and states with the
+/// same non-ASCII transitions share a list. For DFAs that mostly care about ASCII input, this is
+/// about half the size of a `TableInsts`.
+///
+/// As in `TableInsts`, state 0 is the dead state.
+#[derive(Clone, Debug)]
+pub struct AsciiTableInsts {
+    /// A `128 x num_instructions`-long table.
+    pub ascii: Vec<TableStateIdx>,
+    /// For each state, the range of `ranges` holding its non-ASCII transitions.
+    pub range_idx: Vec<(usize, usize)>,
+    /// Non-ASCII transitions of the form `(lo, hi, next_state)`, with `lo` and `hi` inclusive.
+    pub ranges: Vec<(u8, u8, TableStateIdx)>,
+    /// If `accept[st]` is not `usize::MAX`, then it gives some data to return if we match the
+    /// input when we're in state `st`.
+    pub accept: Vec<usize>,
+}
+
+impl AsciiTableInsts {
+    /// Converts a `TableInsts` into the two-level layout.
+    pub fn from_table(insts: &TableInsts) -> AsciiTableInsts {
+        let mut ret = AsciiTableInsts {
+            ascii: Vec::with_capacity(insts.accept.len() * 128),
+            range_idx: Vec::with_capacity(insts.accept.len()),
+            ranges: Vec::new(),
+            // Only the states in `accept_range` accept.
+            accept: (0..insts.num_states()).map(|s| insts.accept(s).unwrap_or(usize::MAX))
+                .collect(),
+        };
+        let mut shared = BTreeMap::new();
+
+        for state in 0..insts.accept.len() {
+            let row = insts.full_row(state);
+            ret.ascii.extend_from_slice(&row[..128]);
+
+            let mut ranges = Vec::new();
+            for (b, &next) in row.iter().enumerate().skip(128) {
+                if next == 0 {
+                    continue;
+                }
+                match ranges.last_mut() {
+                    Some(&mut (_, ref mut hi, st)) if st == next && *hi as usize + 1 == b =>
+                        *hi = b as u8,
+                    _ => ranges.push((b as u8, b as u8, next)),
+                }
+            }
+
+            let ranges_len = ret.ranges.len();
+            let idx = *shared.entry(ranges.clone()).or_insert((ranges_len, ranges_len + ranges.len()));
+            if idx.0 == ranges_len {
+                ret.ranges.extend(ranges);
+            }
+            ret.range_idx.push(idx);
+        }
+        ret
+    }
+
+    #[inline(always)]
+    fn next(&self, state: usize, input: u8) -> TableStateIdx {
+        if input < 128 {
+            self.ascii[state * 128 + input as usize]
+        } else {
+            let (start, end) = self.range_idx[state];
+            let ranges = &self.ranges[start..end];
+            match ranges.binary_search_by(|&(lo, _, _)| lo.cmp(&input)) {
+                Ok(i) => ranges[i].2,
+                Err(0) => 0,
+                Err(i) => if input <= ranges[i - 1].1 { ranges[i - 1].2 } else { 0 },
+            }
+        }
+    }
 }
 
+impl Instructions for AsciiTableInsts {
+    #[inline(always)]
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
+        let next_state = self.next(state, input);
+        let next_state = if next_state != 0 { Some(next_state as usize) } else { None };
+        (next_state, self.accept(state))
+    }
+
+    fn run(&self, mut state: usize, input: &[u8]) -> RunResult {
+        for (pos, &b) in input.iter().enumerate() {
+            let accept = self.accept[state];
+            if accept != usize::MAX {
+                return RunResult::Accept { pos, data: accept };
+            }
+            let next_state = self.next(state, b);
+            if next_state == 0 {
+                return RunResult::Dead { pos };
+            }
+            state = next_state as usize;
+        }
+        RunResult::Eoi { state }
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        let accept = self.accept[state];
+        if accept != usize::MAX { Some(accept) } else { None }
+    }
+
+    fn is_dead(&self, state: usize) -> bool {
+        state == 0
+    }
+
+    fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    fn validate(&self) -> Result<(), ProgramError> {
+        let n = self.accept.len();
+        if self.ascii.len() != n * 128 || self.range_idx.len() != n {
+            return Err(ProgramError::WrongSize);
+        }
+        for (s, &(start, end)) in self.range_idx.iter().enumerate() {
+            let ok = self.ascii[s * 128..(s + 1) * 128].iter().all(|&t| (t as usize) < n)
+                && self.ranges.get(start..end)
+                    .is_some_and(|r| r.iter().all(|&(_, _, t)| (t as usize) < n));
+            if !ok {
+                return Err(ProgramError::BadState(s));
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        vec_bytes(&self.ascii) + vec_bytes(&self.range_idx) + vec_bytes(&self.ranges)
+            + vec_bytes(&self.accept)
+    }
+}
+
+/// Instructions given by closures, for trying out other ways of computing transitions (like hash
+/// tables, or functions that don't need a table at all) with the existing engines.
+///
+/// `step(state, byte)` returns the next state, or `None` to stop. `accept(state)` returns the data
+/// associated with accepting in `state` (before another byte), as in `Instructions::accept`.
+/// Nothing is known about which states are dead, so `is_dead` always returns false.
+#[derive(Clone)]
+pub struct FnInsts<S, A> {
+    step: S,
+    accept: A,
+    num_states: usize,
+}
+
+impl<S, A> FnInsts<S, A>
+where S: Fn(usize, u8) -> Option<usize> + Clone, A: Fn(usize) -> Option<usize> + Clone {
+    pub fn new(num_states: usize, step: S, accept: A) -> FnInsts<S, A> {
+        FnInsts { step, accept, num_states }
+    }
+}
+
+impl<S, A> Debug for FnInsts<S, A> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "FnInsts({} states)", self.num_states)
+    }
+}
+
+impl<S, A> Instructions for FnInsts<S, A>
+where S: Fn(usize, u8) -> Option<usize> + Clone, A: Fn(usize) -> Option<usize> + Clone {
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
+        ((self.step)(state, input), (self.accept)(state))
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        (self.accept)(state)
+    }
+
+    fn is_dead(&self, _state: usize) -> bool {
+        false
+    }
+
+    fn num_states(&self) -> usize {
+        self.num_states
+    }
+
+    /// Checks every transition, by calling `step` on every state and byte.
+    fn validate(&self) -> Result<(), ProgramError> {
+        for s in 0..self.num_states {
+            if (0..=255u8).any(|b| (self.step)(s, b).is_some_and(|t| t >= self.num_states)) {
+                return Err(ProgramError::BadState(s));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use program::*;
+
+    // A table that starts in state 1 and accepts (with data 0) once it has seen "ab".
+    fn ab_table() -> TableInsts {
+        let mut table = vec![0; 256 * 4];
+        table[256 + b'a' as usize] = 2;
+        table[512 + b'b' as usize] = 3;
+        TableInsts::new(table, vec![usize::MAX, usize::MAX, usize::MAX, 0])
+    }
+
+    #[test]
+    fn test_fn_insts() {
+        use error::ProgramError;
+
+        // Accepts after "ab", like `ab_table`, but without a table.
+        let step = |s: usize, b: u8| match (s, b) {
+            (1, b'a') => Some(2),
+            (2, b'b') => Some(3),
+            _ => None,
+        };
+        let accept = |s: usize| if s == 3 { Some(0) } else { None };
+        let insts = FnInsts::new(4, step, accept);
+        assert_eq!(insts.run(1, b"abc"), RunResult::Accept { pos: 2, data: 0 });
+        assert_eq!(insts.run(1, b"aab"), RunResult::Dead { pos: 1 });
+        assert_eq!(insts.validate(), Ok(()));
+
+        let prog = Program::try_new(vec![usize::MAX, usize::MAX, usize::MAX, 0], insts,
+                                    InitStates::Constant(1)).unwrap();
+        assert_eq!(prog.shortest_match_from(b"xabx", 1, 4, 1), Some(3));
+
+        let bad = FnInsts::new(3, step, accept);
+        assert_eq!(bad.validate(), Err(ProgramError::BadState(2)));
+    }
+
+    #[test]
+    fn test_table_run() {
+        let insts = ab_table();
+        assert_eq!(insts.run(1, b"abc"), RunResult::Accept { pos: 2, data: 0 });
+        assert_eq!(insts.run(1, b"aab"), RunResult::Dead { pos: 1 });
+        assert_eq!(insts.run(1, b"ab"), RunResult::Eoi { state: 3 });
+        assert_eq!(insts.run(1, b""), RunResult::Eoi { state: 1 });
+        assert_eq!(insts.run(2, b"bbbbbbbb"), RunResult::Accept { pos: 1, data: 0 });
+        assert_eq!(insts.run(0, b"ab"), RunResult::Dead { pos: 0 });
+    }
+
+    #[test]
+    fn test_table_classes() {
+        use byte_set::ByteClasses;
+
+        // The same table as `ab_table`, but with a column for "a", one for "b", and one for
+        // everything else.
+        let mut classes = [0; 256];
+        classes[b'a' as usize] = 1;
+        classes[b'b' as usize] = 2;
+        let classes = ByteClasses::new(classes);
+        let table = vec![0, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0];
+        let small = TableInsts::new(table, vec![usize::MAX, usize::MAX, usize::MAX, 0])
+            .with_classes(classes.clone());
+        assert_eq!(small.stride(), 3);
+        assert!(small.validate().is_ok());
+        let full = ab_table();
+        for b in 0..256 {
+            for st in 0..4 {
+                assert_eq!(small.step_byte(st, b as u8), full.step_byte(st, b as u8));
+            }
+        }
+        for input in &[&b"abc"[..], b"aab", b"ab", b"xab", b""] {
+            assert_eq!(small.run(1, input), full.run(1, input));
+            assert_eq!(small.as_table_ref().run(1, input), full.run(1, input));
+        }
+        assert_eq!(small.to_full().table, full.table);
+        let borrowed = TableRef::new(&small.table, &small.accept).with_classes(&classes);
+        assert_eq!(borrowed.run(1, b"abc"), RunResult::Accept { pos: 2, data: 0 });
+        // Without the classes, the table is too short.
+        assert!(TableRef::new(&small.table, &small.accept).validate().is_err());
+
+        #[cfg(feature = "std")]
+        {
+            use serialize::Serialize;
+            let mut bytes = Vec::new();
+            small.write_to(&mut bytes).unwrap();
+            let loaded = TableInsts::from_bytes(&bytes).unwrap();
+            assert_eq!(loaded.stride(), 3);
+            assert_eq!(loaded.run(1, b"abc"), RunResult::Accept { pos: 2, data: 0 });
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_table_from_bytes() {
+        use error::LoadError;
+        use serialize::Serialize;
+
+        let mut bytes = Vec::new();
+        ab_table().write_to(&mut bytes).unwrap();
+        let insts = TableInsts::from_bytes(&bytes).unwrap();
+        assert_eq!(insts.run(1, b"abc"), RunResult::Accept { pos: 2, data: 0 });
+
+        assert_eq!(TableInsts::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(),
+                   LoadError::TooShort);
+        let mut long = bytes.clone();
+        long.push(0);
+        assert_eq!(TableInsts::from_bytes(&long).unwrap_err(), LoadError::TooLong);
+
+        let mut bad = ab_table();
+        bad.table[256 + b'x' as usize] = 4;
+        let mut bytes = Vec::new();
+        bad.write_to(&mut bytes).unwrap();
+        assert_eq!(TableInsts::from_bytes(&bytes).unwrap_err(),
+                   LoadError::BadState(256 + b'x' as usize));
+
+        let mut bytes = Vec::new();
+        TableInsts::new(vec![0; 256], vec![usize::MAX; 2]).write_to(&mut bytes).unwrap();
+        assert_eq!(TableInsts::from_bytes(&bytes).unwrap_err(), LoadError::WrongSize);
+    }
+
+    #[test]
+    fn test_table_predicates() {
+        let insts = ab_table();
+        assert_eq!(insts.accept(2), None);
+        assert_eq!(insts.accept(3), Some(0));
+        assert!(insts.is_dead(0));
+        assert!(!insts.is_dead(1));
+        assert!(!insts.is_dead(3));
+
+        // States outside the accept range never accept.
+        let mut out_of_range = ab_table();
+        out_of_range.accept_range = (0, 3);
+        assert_eq!(out_of_range.accept(3), None);
+        assert_eq!(out_of_range.run(1, b"abc"), RunResult::Dead { pos: 2 });
+    }
+
+    #[test]
+    fn test_vm_run() {
+        let insts = VmInsts {
+            byte_sets: vec![],
+            branch_table: vec![],
+            literals: vec![],
+            insts: vec![Inst::Byte(b'a'), Inst::Byte(b'b'), Inst::Acc(0)],
+        };
+        assert_eq!(insts.run(0, b"abc"), RunResult::Accept { pos: 2, data: 0 });
+        assert_eq!(insts.run(0, b"aab"), RunResult::Dead { pos: 1 });
+        assert_eq!(insts.run(0, b"a"), RunResult::Eoi { state: 1 });
+    }
+
+    #[test]
+    fn test_vm_range() {
+        let insts = VmInsts {
+            byte_sets: vec![],
+            branch_table: vec![],
+            literals: vec![],
+            insts: vec![Inst::Range(b'0', b'9'), Inst::Acc(0)],
+        };
+        assert_eq!(insts.step_byte(0, b'0'), (Some(1), None));
+        assert_eq!(insts.step_byte(0, b'5'), (Some(1), None));
+        assert_eq!(insts.step_byte(0, b'9'), (Some(1), None));
+        assert_eq!(insts.step_byte(0, b'a'), (None, None));
+        assert_eq!(insts.step_byte(0, b'/'), (None, None));
+        assert!(!insts.is_dead(0));
+    }
+
+    #[test]
+    fn test_vm_seq() {
+        let insts = VmInsts {
+            byte_sets: vec![],
+            branch_table: vec![],
+            literals: b"abc".to_vec(),
+            insts: vec![Inst::Seq(0, 3), Inst::Seq(1, 2), Inst::Seq(2, 1), Inst::Acc(0)],
+        };
+        assert_eq!(insts.run(0, b"abcd"), RunResult::Accept { pos: 3, data: 0 });
+        assert_eq!(insts.run(0, b"abd"), RunResult::Dead { pos: 2 });
+        assert_eq!(insts.run(0, b"ab"), RunResult::Eoi { state: 2 });
+        assert_eq!(insts.run(1, b"bcd"), RunResult::Accept { pos: 2, data: 0 });
+        assert_eq!(insts.step_byte(0, b'a'), (Some(1), None));
+        assert_eq!(insts.step_byte(1, b'a'), (None, None));
+    }
+
+    #[test]
+    fn test_conversions() {
+        let vm = VmInsts {
+            byte_sets: vec![],
+            branch_table: vec![],
+            literals: b"xy".to_vec(),
+            insts: vec![Inst::Range(b'0', b'9'), Inst::Seq(0, 2), Inst::Seq(1, 1), Inst::Acc(3)],
+        };
+        // The table has an extra dead state at the beginning.
+        let table = vm.to_table();
+        let table_run = |input| match table.run(1, input) {
+            RunResult::Eoi { state } => RunResult::Eoi { state: state - 1 },
+            x => x,
+        };
+        for input in &[&b"0xyz"[..], b"5x", b"9xx", b"a", b"", b"0xy"] {
+            assert_eq!(vm.run(0, input), table_run(input));
+        }
+
+        let back = table.to_vm().unwrap();
+        assert_eq!(back.insts[0], Inst::Range(b'0', b'9'));
+        assert_eq!(back.insts[1], Inst::Byte(b'x'));
+        assert_eq!(back.insts[3], Inst::Acc(3));
+        for input in &[&b"0xyz"[..], b"5x", b"9xx", b"a", b"", b"0xy"] {
+            assert_eq!(back.run(0, input), table_run(input));
+        }
+
+        // A branch to instruction 1 on "a" and to instruction 2 on "b".
+        let mut branch_table = vec![0; 256];
+        branch_table[b'a' as usize] = 2;
+        branch_table[b'b' as usize] = 3;
+        let vm = VmInsts {
+            byte_sets: vec![],
+            branch_table,
+            literals: vec![],
+            // `Acc` goes on to the next instruction, so the last one needs something after it.
+            insts: vec![Inst::Branch(0), Inst::Acc(1), Inst::Acc(2), Inst::Range(1, 0)],
+        };
+        assert!(vm.validate().is_ok());
+        let table = vm.to_table();
+        let table_run = |input| match table.run(1, input) {
+            RunResult::Eoi { state } => RunResult::Eoi { state: state - 1 },
+            x => x,
+        };
+        let back = table.to_vm().unwrap();
+        assert_eq!(back.insts[0], Inst::Branch(0));
+        assert_eq!(back.branch_table, vm.branch_table);
+        for input in &[&b"ax"[..], b"bx", b"c", b"", b"a"] {
+            assert_eq!(vm.run(0, input), table_run(input));
+            assert_eq!(back.run(0, input), vm.run(0, input));
+        }
+
+        // The accepting state in this table has a transition, so it can't be a VM instruction.
+        assert!(ab_table().to_vm().is_none());
+    }
+
+    #[test]
+    fn test_ascii_table() {
+        let mut table = ab_table();
+        // Add some non-ASCII transitions: 0x80-0x9f and 0xc0 go from state 1 to state 2, and
+        // state 2 has the same transitions as state 1 for non-ASCII bytes.
+        for b in (0x80..0xa0).chain(Some(0xc0)) {
+            table.table[256 + b] = 2;
+            table.table[512 + b] = 2;
+        }
+        let ascii = AsciiTableInsts::from_table(&table);
+        assert_eq!(ascii.ranges.len(), 2);
+        assert_eq!(ascii.range_idx[1], ascii.range_idx[2]);
+
+        for b in 0..256 {
+            for st in 0..4 {
+                assert_eq!(ascii.step_byte(st, b as u8), table.step_byte(st, b as u8));
+            }
+        }
+        for input in &[&b"ab"[..], b"\x80\xc0b", b"\xa0", b"a\x9fb", b"abab"] {
+            assert_eq!(ascii.run(1, input), table.run(1, input));
+        }
+        assert!(ascii.is_dead(0));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let insts = ab_table();
+        let table_bytes = 256 * 4 * 4 + 4 * 8;
+        assert_eq!(insts.memory_usage(), table_bytes);
+        assert_eq!(insts.as_table_ref().memory_usage(), 0);
+        let prog = Program {
+            accept_at_eoi: vec![usize::MAX; 4],
+            instructions: insts,
+            init: InitStates::ByPrevByte(1, vec![1; 256]),
+        };
+        assert_eq!(prog.memory_usage(), table_bytes + 4 * 8 + 256 * 8);
+    }
+
+    #[test]
+    fn test_stepping() {
+        let prog = Program {
+            accept_at_eoi: vec![usize::MAX, usize::MAX, usize::MAX, 0],
+            instructions: ab_table(),
+            init: InitStates::AfterByte(b'\n', 1),
+        };
+        let run = |prev, input: &[u8]| {
+            let mut state = prog.start(prev)?;
+            for &b in input {
+                state = prog.next(state, b)?;
+            }
+            prog.eoi(state)
+        };
+        assert_eq!(run(None, b"ab"), Some(0));
+        assert_eq!(run(Some(b'\n'), b"ab"), Some(0));
+        assert_eq!(run(Some(b'x'), b"ab"), None);
+        assert_eq!(run(None, b"a"), None);
+        assert_eq!(run(None, b"ax"), None);
+
+        let b = prog.next(prog.start(None).unwrap(), b'a').unwrap();
+        assert_eq!(b.index(), 2);
+        assert_eq!(prog.accepts(b), None);
+        assert_eq!(prog.accepts(prog.next(b, b'b').unwrap()), Some(0));
+    }
+
+    #[test]
+    fn test_validate() {
+        use error::ProgramError;
+
+        let prog = |insts: TableInsts, init| {
+            Program::try_new(vec![usize::MAX; insts.num_states()], insts, init)
+        };
+        assert!(prog(ab_table(), InitStates::Constant(1)).is_ok());
+        assert_eq!(prog(ab_table(), InitStates::Contextual(b'\n', 1, 4)).unwrap_err(),
+                   ProgramError::BadInitState(4));
+        assert_eq!(prog(ab_table(), InitStates::ByPrevByte(1, vec![1; 10])).unwrap_err(),
+                   ProgramError::WrongSize);
+        assert_eq!(Program::try_new(vec![], ab_table(), InitStates::Constant(1)).unwrap_err(),
+                   ProgramError::WrongSize);
+
+        let mut bad = ab_table();
+        bad.table[512 + b'x' as usize] = 4;
+        assert_eq!(bad.validate(), Err(ProgramError::BadState(2)));
+        assert_eq!(AsciiTableInsts::from_table(&bad).validate(), Err(ProgramError::BadState(2)));
+        bad.table.pop();
+        assert_eq!(bad.validate(), Err(ProgramError::WrongSize));
+        assert_eq!(AsciiTableInsts::from_table(&ab_table()).validate(), Ok(()));
+
+        // An accepting state that was added after `accept_range` was computed.
+        let mut stale = ab_table();
+        stale.accept[2] = 5;
+        assert_eq!(stale.validate(), Err(ProgramError::BadAcceptRange(2)));
+        assert_eq!(AsciiTableInsts::from_table(&stale).accept(2), None);
+        stale.accept_range = (1, 5);
+        assert_eq!(stale.validate(), Err(ProgramError::WrongSize));
+        stale.accept_range = (2, 4);
+        assert_eq!(stale.validate(), Ok(()));
+
+        let vm = |insts| VmInsts {
+            byte_sets: vec![ByteSet::new()],
+            branch_table: vec![0; 256],
+            literals: b"ab".to_vec(),
+            insts,
+        };
+        assert_eq!(vm(vec![Inst::Seq(0, 2), Inst::Seq(1, 1), Inst::Acc(0), Inst::ByteSet(0)])
+                   .validate(), Ok(()));
+        assert_eq!(vm(vec![Inst::Seq(1, 2), Inst::Seq(2, 1), Inst::Acc(0), Inst::ByteSet(0)])
+                   .validate(), Err(ProgramError::BadState(0)));
+        assert_eq!(vm(vec![Inst::Byte(b'a'), Inst::ByteSet(1)]).validate(),
+                   Err(ProgramError::BadState(1)));
+        // The last instruction can't go on to the next one, unless it never matches.
+        assert_eq!(vm(vec![Inst::Branch(0), Inst::Acc(0)]).validate(),
+                   Err(ProgramError::BadState(1)));
+        assert_eq!(vm(vec![Inst::Branch(1)]).validate(), Err(ProgramError::BadState(0)));
+        assert_eq!(vm(vec![Inst::Branch(0), Inst::Range(1, 0)]).validate(), Ok(()));
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Generating random strings that a program matches (or almost matches).
+//!
+//! A program matches a string if, starting from its start state at the beginning of the string,
+//! it accepts at the end of the string (that is, if `Program::matches_to` succeeds on the whole
+//! string).
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use program::{Instructions, Program};
+
+/// A source of random numbers.
+pub trait Rng {
+    /// Returns a random number, uniformly distributed over all the `u64`s.
+    fn next_u64(&mut self) -> u64;
+
+    /// Returns a random number less than `n`, which must be positive.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// A small and fast random number generator. It isn't at all secure, but it is deterministic, so
+/// it is good for reproducible tests.
+#[derive(Clone, Debug)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        // The state must never be zero.
+        XorShift(seed.max(1))
+    }
+}
+
+impl Rng for XorShift {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// How many times `sample_near_miss` tries to change a string before giving up.
+const NEAR_MISS_TRIES: usize = 32;
+
+impl<Insts: Instructions> Program<Insts> {
+    /// Returns a random string, of length at most `max_len`, that this program matches.
+    ///
+    /// This is a random walk through the program that only takes transitions from which it can
+    /// still accept in time, so it doesn't give every string the same probability. Returns `None`
+    /// if the program doesn't match any string that short.
+    pub fn sample<R: Rng>(&self, rng: &mut R, max_len: usize) -> Option<Vec<u8>> {
+        let dist = self.distances_to_accept();
+        let mut state = self.init.state_at_pos(&[], 0)?;
+        if dist[state] > max_len {
+            return None;
+        }
+
+        let mut ret = Vec::new();
+        loop {
+            let left = max_len - ret.len();
+            // Stop a quarter of the time that we can, so that we get strings of different lengths.
+            if dist[state] == 0 && (left == 0 || rng.below(4) == 0) {
+                return Some(ret);
+            }
+            let next: Vec<(u8, usize)> = (0..=255u8)
+                .filter_map(|b| self.step_byte(state, b).0.map(|t| (b, t)))
+                .filter(|&(_, t)| dist[t] < left)
+                .collect();
+            if next.is_empty() {
+                // We must be in an accepting state, or `dist[state]` would be bigger.
+                return Some(ret);
+            }
+            let (b, t) = next[rng.below(next.len())];
+            ret.push(b);
+            state = t;
+        }
+    }
+
+    /// Returns a random string that this program doesn't match, but that is one byte (added,
+    /// removed or changed) away from a string of length at most `max_len` that it does match.
+    ///
+    /// Returns `None` if there aren't any matching strings that short, or if we didn't manage to
+    /// find a near miss.
+    pub fn sample_near_miss<R: Rng>(&self, rng: &mut R, max_len: usize) -> Option<Vec<u8>> {
+        let start = self.init.state_at_pos(&[], 0)?;
+        for _ in 0..NEAR_MISS_TRIES {
+            let mut s = self.sample(rng, max_len)?;
+            let pos = rng.below(s.len() + 1);
+            let byte = rng.below(256) as u8;
+            match rng.below(3) {
+                0 => s.insert(pos, byte),
+                1 if pos < s.len() => { s.remove(pos); },
+                _ if pos < s.len() => s[pos] = byte,
+                _ => s.push(byte),
+            }
+            if !self.matches_to(&s, 0, s.len(), start) {
+                return Some(s);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "std"))]
+    use std::prelude::v1::*;
+    use program::{Program, TableInsts};
+    use sample::XorShift;
+    use test_util::{lits_prog, table_prog};
+
+    fn matches(prog: &Program<TableInsts>, s: &[u8]) -> bool {
+        prog.matches_to(s, 0, s.len(), prog.init.state_at_pos(&[], 0).unwrap())
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut rng = XorShift::new(5);
+        let prog = lits_prog(&["ab", "cd"]);
+        assert_eq!(prog.sample(&mut rng, 1), None);
+        let samples: Vec<Vec<u8>> = (0..20).map(|_| prog.sample(&mut rng, 5).unwrap()).collect();
+        assert!(samples.iter().all(|s| s == b"ab" || s == b"cd"));
+        assert!(samples.iter().any(|s| s == b"ab"));
+        assert!(samples.iter().any(|s| s == b"cd"));
+
+        // a*
+        let prog = table_prog(2, &[(1, b'a', 1)], &[1]);
+        for _ in 0..20 {
+            let s = prog.sample(&mut rng, 3).unwrap();
+            assert!(s.len() <= 3 && s.iter().all(|&b| b == b'a'));
+        }
+    }
+
+    #[test]
+    fn test_sample_near_miss() {
+        let mut rng = XorShift::new(5);
+        let prog = lits_prog(&["ab", "cd"]);
+        for _ in 0..20 {
+            let s = prog.sample_near_miss(&mut rng, 5).unwrap();
+            assert!(!matches(&prog, &s));
+            assert!((1..=3).contains(&s.len()));
+        }
+
+        // Everything is a match, so there are no near misses.
+        let all: Vec<_> = (0..=255u8).map(|b| (1, b, 1)).collect();
+        assert_eq!(table_prog(2, &all, &[1]).sample_near_miss(&mut rng, 5), None);
+    }
+}
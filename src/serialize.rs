@@ -0,0 +1,798 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A simple binary format for saving programs and prefixes.
+//!
+//! All integers are written in little-endian order, and `usize`s are written as 64-bit integers.
+//!
+//! Whole engines are saved with `Engine::save`, which starts with a header (see `write_header`)
+//! and then writes a byte saying what kind of engine it is, followed by the engine's parts. For
+//! engines that run a `Program`, the next byte says what kind of instructions the program has.
+
+use Engine;
+use ac::AcEngine;
+use aho_corasick::{Automaton, AcAutomaton, FullAcAutomaton};
+use backtracking::BacktrackingEngine;
+use byte_set::{ByteClasses, ByteSet};
+use lazy::LazyDfaEngine;
+use nfa::{Nfa, NfaState};
+use pikevm::PikeVmEngine;
+use prefix::{AcMatchKind, Prefix};
+use program::{InitStates, Inst, Instructions, Program, TableInsts, TableStateIdx, VmInsts};
+use std::any::{Any, TypeId};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Arc;
+use threaded::ThreadedEngine;
+use twopass::TwoPassEngine;
+
+/// Things that can be written to, and read back from, a byte stream.
+pub trait Serialize: Sized {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The bytes that saved engines start with.
+pub const MAGIC: &[u8; 4] = b"DFAR";
+
+/// The version of the format written by `write_header`. This changes whenever the format does.
+pub const FORMAT_VERSION: u32 = 3;
+
+// The byte order that is declared in the header. We only ever write little-endian data.
+const LITTLE_ENDIAN: u8 = 1;
+
+/// Writes the header that identifies the format: `MAGIC`, then `FORMAT_VERSION`, then a byte for
+/// the byte order of the data that follows, then the sizes (in bytes) of the `usize`s and of the
+/// states in `TableInsts`.
+pub fn write_header<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    write_u32(w, FORMAT_VERSION)?;
+    w.write_all(&[LITTLE_ENDIAN, 8, mem::size_of::<TableStateIdx>() as u8])
+}
+
+/// Reads a header written by `write_header`, and fails unless it describes data that we can read.
+pub fn read_header<R: Read>(r: &mut R) -> io::Result<()> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a saved engine"));
+    }
+    let version = read_u32(r)?;
+    if version != FORMAT_VERSION {
+        return Err(invalid("unsupported format version"));
+    }
+    if read_u8(r)? != LITTLE_ENDIAN {
+        return Err(invalid("unsupported byte order"));
+    }
+    if read_u8(r)? != 8 || read_u8(r)? != mem::size_of::<TableStateIdx>() as u8 {
+        return Err(invalid("unsupported integer size"));
+    }
+    Ok(())
+}
+
+/// The error for trying to save something that can't be saved.
+pub fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "this engine can't be saved")
+}
+
+pub fn write_u8<W: Write>(w: &mut W, x: u8) -> io::Result<()> {
+    w.write_all(&[x])
+}
+
+pub fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn write_u32<W: Write>(w: &mut W, x: u32) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+pub fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Writes a `usize` as a `u64`. `usize::MAX` (which we often use as a sentinel) is written as
+/// `u64::MAX`, so that it survives moving between platforms.
+pub fn write_usize<W: Write>(w: &mut W, x: usize) -> io::Result<()> {
+    let x = if x == usize::MAX { u64::MAX } else { x as u64 };
+    w.write_all(&x.to_le_bytes())
+}
+
+pub fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    let x = u64::from_le_bytes(buf);
+    if x == u64::MAX {
+        Ok(usize::MAX)
+    } else if x >= usize::MAX as u64 {
+        Err(invalid("integer too large for this platform"))
+    } else {
+        Ok(x as usize)
+    }
+}
+
+/// Reads a length, checking that it isn't unreasonably large. Since every element takes up at
+/// least one byte, a corrupted length would otherwise make us try to allocate a huge buffer
+/// before noticing that the input is too short.
+fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    let len = read_usize(r)?;
+    if len > (1 << 40) {
+        Err(invalid("length too large"))
+    } else {
+        Ok(len)
+    }
+}
+
+pub fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_usize(w, bytes.len())?;
+    w.write_all(bytes)
+}
+
+pub fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_len(r)?;
+    let mut ret = Vec::new();
+    r.take(len as u64).read_to_end(&mut ret)?;
+    if ret.len() != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input too short"));
+    }
+    Ok(ret)
+}
+
+pub fn write_vec<T: Serialize, W: Write>(w: &mut W, xs: &[T]) -> io::Result<()> {
+    write_usize(w, xs.len())?;
+    for x in xs {
+        x.write_to(w)?;
+    }
+    Ok(())
+}
+
+pub fn read_vec<T: Serialize, R: Read>(r: &mut R) -> io::Result<Vec<T>> {
+    let len = read_len(r)?;
+    let mut ret = Vec::new();
+    for _ in 0..len {
+        ret.push(T::read_from(r)?);
+    }
+    Ok(ret)
+}
+
+impl Serialize for usize {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> { write_usize(w, *self) }
+    fn read_from<R: Read>(r: &mut R) -> io::Result<usize> { read_usize(r) }
+}
+
+impl Serialize for u32 {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> { write_u32(w, *self) }
+    fn read_from<R: Read>(r: &mut R) -> io::Result<u32> { read_u32(r) }
+}
+
+impl Serialize for ByteSet {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut bits = [0u8; 32];
+        for b in self {
+            bits[(b >> 3) as usize] |= 1 << (b & 7);
+        }
+        w.write_all(&bits)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<ByteSet> {
+        let mut bits = [0u8; 32];
+        r.read_exact(&mut bits)?;
+        Ok((0..256usize).filter(|&b| bits[b >> 3] & (1 << (b & 7)) != 0).map(|b| b as u8).collect())
+    }
+}
+
+impl Serialize for ByteClasses {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(self.as_array())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<ByteClasses> {
+        let mut classes = [0u8; 256];
+        r.read_exact(&mut classes)?;
+        Ok(ByteClasses::new(classes))
+    }
+}
+
+impl Serialize for Prefix {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use prefix::Prefix::*;
+
+        match *self {
+            Empty => write_u8(w, 0),
+            ByteSet(ref bs) => { write_u8(w, 1)?; bs.write_to(w) },
+            Byte(b) => w.write_all(&[2, b]),
+            Byte2(b1, b2) => w.write_all(&[3, b1, b2]),
+            Byte3(b1, b2, b3) => w.write_all(&[4, b1, b2, b3]),
+            Lit(ref l) => { write_u8(w, 5)?; write_bytes(w, l) },
+            LitRare(ref l, off) => { write_u8(w, 6)?; write_bytes(w, l)?; write_usize(w, off) },
+            LitCaseless(ref l) => { write_u8(w, 7)?; write_bytes(w, l) },
+            Ac(ref ac, ref map, kind) => {
+                // We only save the literals, and rebuild the automaton when loading.
+                write_u8(w, 8)?;
+                write_u8(w, match kind {
+                    AcMatchKind::Overlapping => 0,
+                    AcMatchKind::NonOverlapping => 1,
+                })?;
+                write_usize(w, ac.patterns().len())?;
+                for pat in ac.patterns() {
+                    write_bytes(w, pat)?;
+                }
+                write_vec(w, map)
+            },
+            LoopWhile(ref bs, min) => { write_u8(w, 9)?; bs.write_to(w)?; write_usize(w, min) },
+            InnerLit(ref l, ref bs) => { write_u8(w, 10)?; write_bytes(w, l)?; bs.write_to(w) },
+            Exact(ref inner) => { write_u8(w, 11)?; inner.write_to(w) },
+            AfterByte(b) => w.write_all(&[12, b]),
+            LitThenSet(ref l, ref bs) => { write_u8(w, 13)?; write_bytes(w, l)?; bs.write_to(w) },
+        }
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Prefix> {
+        read_prefix(r, false)
+    }
+}
+
+// Reads a prefix, which is inside an `Exact` prefix if `nested` is true.
+//
+// An `Exact` prefix is never nested inside another one, and refusing to read them means that
+// untrusted data can't make us recurse more than once.
+fn read_prefix<R: Read>(r: &mut R, nested: bool) -> io::Result<Prefix> {
+    fn nonempty(lit: Vec<u8>) -> io::Result<Vec<u8>> {
+        if lit.is_empty() { Err(invalid("empty literal")) } else { Ok(lit) }
+    }
+
+    Ok(match read_u8(r)? {
+        0 => Prefix::Empty,
+        1 => Prefix::ByteSet(ByteSet::read_from(r)?),
+        2 => Prefix::Byte(read_u8(r)?),
+        3 => Prefix::Byte2(read_u8(r)?, read_u8(r)?),
+        4 => Prefix::Byte3(read_u8(r)?, read_u8(r)?, read_u8(r)?),
+        5 => Prefix::Lit(nonempty(read_bytes(r)?)?),
+        6 => {
+            let lit = nonempty(read_bytes(r)?)?;
+            let off = read_usize(r)?;
+            if off >= lit.len() {
+                return Err(invalid("rare byte offset out of bounds"));
+            }
+            Prefix::LitRare(lit, off)
+        },
+        7 => Prefix::LitCaseless(nonempty(read_bytes(r)?)?),
+        8 => {
+            let kind = match read_u8(r)? {
+                0 => AcMatchKind::Overlapping,
+                1 => AcMatchKind::NonOverlapping,
+                _ => return Err(invalid("unknown match kind")),
+            };
+            let num_pats = read_len(r)?;
+            let mut pats = Vec::new();
+            for _ in 0..num_pats {
+                pats.push(nonempty(read_bytes(r)?)?);
+            }
+            let map: Vec<usize> = read_vec(r)?;
+            if map.len() != pats.len() {
+                return Err(invalid("wrong number of states for literals"));
+            }
+            Prefix::Ac(FullAcAutomaton::new(AcAutomaton::new(pats)), map, kind)
+        },
+        9 => Prefix::LoopWhile(ByteSet::read_from(r)?, read_usize(r)?),
+        10 => Prefix::InnerLit(nonempty(read_bytes(r)?)?, ByteSet::read_from(r)?),
+        11 if !nested => Prefix::Exact(Box::new(read_prefix(r, true)?)),
+        11 => return Err(invalid("nested exact prefix")),
+        12 => Prefix::AfterByte(read_u8(r)?),
+        13 => Prefix::LitThenSet(nonempty(read_bytes(r)?)?, ByteSet::read_from(r)?),
+        _ => return Err(invalid("unknown prefix type")),
+    })
+}
+
+impl Serialize for InitStates {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            InitStates::Anchored(s) => { write_u8(w, 0)?; write_usize(w, s) },
+            InitStates::Constant(s) => { write_u8(w, 1)?; write_usize(w, s) },
+            InitStates::AfterByte(b, s) => { w.write_all(&[2, b])?; write_usize(w, s) },
+            InitStates::Contextual(b, s, t) => {
+                w.write_all(&[3, b])?;
+                write_usize(w, s)?;
+                write_usize(w, t)
+            },
+            InitStates::ByPrevByte(s, ref table) => {
+                write_u8(w, 4)?;
+                write_usize(w, s)?;
+                write_vec(w, table)
+            },
+        }
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<InitStates> {
+        match read_u8(r)? {
+            0 => Ok(InitStates::Anchored(read_usize(r)?)),
+            1 => Ok(InitStates::Constant(read_usize(r)?)),
+            2 => Ok(InitStates::AfterByte(read_u8(r)?, read_usize(r)?)),
+            3 => Ok(InitStates::Contextual(read_u8(r)?, read_usize(r)?, read_usize(r)?)),
+            4 => {
+                let s = read_usize(r)?;
+                let table: Vec<usize> = read_vec(r)?;
+                if table.len() != 256 {
+                    return Err(invalid("wrong number of initial states"));
+                }
+                Ok(InitStates::ByPrevByte(s, table))
+            },
+            _ => Err(invalid("unknown kind of initial state")),
+        }
+    }
+}
+
+impl Serialize for TableInsts {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_vec(w, &self.accept)?;
+        write_vec(w, &self.table)?;
+        self.classes.write_to(w)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<TableInsts> {
+        let accept: Vec<usize> = read_vec(r)?;
+        let table: Vec<u32> = read_vec(r)?;
+        let classes = ByteClasses::read_from(r)?;
+        if table.len() != accept.len() * classes.num_classes() {
+            return Err(invalid("table has the wrong size"));
+        } else if table.iter().any(|&s| s as usize >= accept.len()) {
+            return Err(invalid("bad state index"));
+        }
+        Ok(TableInsts::new(table, accept).with_classes(classes))
+    }
+}
+
+impl Serialize for Inst {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            Inst::Byte(b) => w.write_all(&[0, b]),
+            Inst::Range(lo, hi) => w.write_all(&[1, lo, hi]),
+            Inst::Seq(off, len) => { write_u8(w, 2)?; write_usize(w, off)?; write_usize(w, len) },
+            Inst::ByteSet(idx) => { write_u8(w, 3)?; write_usize(w, idx) },
+            Inst::Acc(data) => { write_u8(w, 4)?; write_usize(w, data) },
+            Inst::Branch(off) => { write_u8(w, 5)?; write_usize(w, off) },
+        }
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Inst> {
+        Ok(match read_u8(r)? {
+            0 => Inst::Byte(read_u8(r)?),
+            1 => Inst::Range(read_u8(r)?, read_u8(r)?),
+            2 => Inst::Seq(read_usize(r)?, read_usize(r)?),
+            3 => Inst::ByteSet(read_usize(r)?),
+            4 => Inst::Acc(read_usize(r)?),
+            5 => Inst::Branch(read_usize(r)?),
+            _ => return Err(invalid("unknown instruction")),
+        })
+    }
+}
+
+impl Serialize for VmInsts {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_vec(w, &self.byte_sets)?;
+        write_vec(w, &self.branch_table)?;
+        write_bytes(w, &self.literals)?;
+        write_vec(w, &self.insts)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<VmInsts> {
+        Ok(VmInsts {
+            byte_sets: read_vec(r)?,
+            branch_table: read_vec(r)?,
+            literals: read_bytes(r)?,
+            insts: read_vec(r)?,
+        })
+    }
+}
+
+/// Shared values are written like the values themselves, and they aren't shared when they are
+/// read back.
+impl<T: Serialize> Serialize for Arc<T> {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (**self).write_to(w)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Arc<T>> {
+        T::read_from(r).map(Arc::new)
+    }
+}
+
+impl<I: Instructions + Serialize> Serialize for Program<I> {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.init.write_to(w)?;
+        write_vec(w, &self.accept_at_eoi)?;
+        self.instructions.write_to(w)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Program<I>> {
+        let init = InitStates::read_from(r)?;
+        let accept_at_eoi: Vec<usize> = read_vec(r)?;
+        let instructions = I::read_from(r)?;
+        Program::try_new(accept_at_eoi, instructions, init)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Serialize for NfaState {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_usize(w, self.transitions.len())?;
+        for &(ref bytes, target) in &self.transitions {
+            bytes.write_to(w)?;
+            write_usize(w, target)?;
+        }
+        write_vec(w, &self.eps)?;
+        write_vec(w, &self.line_start_eps)?;
+        write_vec(w, &self.word_boundary_eps)?;
+        let flags = self.accept as u8 | (self.accept_at_eoi as u8) << 1
+            | (self.accept_at_line_end as u8) << 2;
+        write_u8(w, flags)?;
+        write_usize(w, self.save.unwrap_or(usize::MAX))
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<NfaState> {
+        let num_trans = read_len(r)?;
+        let mut transitions = Vec::new();
+        for _ in 0..num_trans {
+            transitions.push((ByteSet::read_from(r)?, read_usize(r)?));
+        }
+        let eps = read_vec(r)?;
+        let line_start_eps = read_vec(r)?;
+        let word_boundary_eps = read_vec(r)?;
+        let flags = read_u8(r)?;
+        if flags >= 8 {
+            return Err(invalid("unknown state flags"));
+        }
+        let save = read_usize(r)?;
+        Ok(NfaState {
+            transitions,
+            eps,
+            line_start_eps,
+            word_boundary_eps,
+            accept: flags & 1 != 0,
+            accept_at_eoi: flags & 2 != 0,
+            accept_at_line_end: flags & 4 != 0,
+            save: if save == usize::MAX { None } else { Some(save) },
+        })
+    }
+}
+
+impl Serialize for Nfa {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u8(w, self.anchored as u8)?;
+        write_vec(w, &self.states)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Nfa> {
+        let anchored = read_u8(r)? != 0;
+        let states: Vec<NfaState> = read_vec(r)?;
+        let n = states.len();
+        let targets_ok = states.iter().all(|st| {
+            st.transitions.iter().map(|t| &t.1)
+                .chain(&st.eps)
+                .chain(&st.line_start_eps)
+                .chain(&st.word_boundary_eps)
+                .all(|&t| t < n)
+        });
+        if n == 0 || !targets_ok {
+            return Err(invalid("bad state index"));
+        }
+        // The engines that report captures allocate every slot up to the largest one, for every
+        // thread. Each capture group needs two slots, and a state at most one of them.
+        if states.iter().any(|st| st.save.is_some_and(|s| s >= 2 * n)) {
+            return Err(invalid("bad capture slot"));
+        }
+        Ok(Nfa { states, anchored })
+    }
+}
+
+/// Writes the tag for the kind of instructions `I`, as used in saved engines.
+pub fn write_insts_kind<I: 'static, W: Write>(w: &mut W) -> io::Result<()> {
+    let kind = TypeId::of::<I>();
+    if kind == TypeId::of::<TableInsts>() || kind == TypeId::of::<Arc<TableInsts>>() {
+        write_u8(w, 0)
+    } else if kind == TypeId::of::<VmInsts>() || kind == TypeId::of::<Arc<VmInsts>>() {
+        write_u8(w, 1)
+    } else {
+        Err(unsupported())
+    }
+}
+
+/// Writes `x`, which must be a `Program<TableInsts>`, a `Program<VmInsts>` (or one of those with
+/// shared instructions) or a `Prefix`.
+///
+/// This is for the parts of engines that are generic over their instructions and prefixes.
+pub fn write_any<T: 'static, W: Write>(w: &mut W, x: &T) -> io::Result<()> {
+    let x = x as &dyn Any;
+    if let Some(prog) = x.downcast_ref::<Program<TableInsts>>() {
+        prog.write_to(w)
+    } else if let Some(prog) = x.downcast_ref::<Program<VmInsts>>() {
+        prog.write_to(w)
+    } else if let Some(prog) = x.downcast_ref::<Program<Arc<TableInsts>>>() {
+        prog.write_to(w)
+    } else if let Some(prog) = x.downcast_ref::<Program<Arc<VmInsts>>>() {
+        prog.write_to(w)
+    } else if let Some(pref) = x.downcast_ref::<Prefix>() {
+        pref.write_to(w)
+    } else {
+        Err(unsupported())
+    }
+}
+
+/// Reads an engine that was written by `Engine::save`.
+pub fn read_engine<R: Read>(r: &mut R) -> io::Result<Box<dyn Engine>> {
+    match read_u8(r)? {
+        3 => {
+            let num_pats = read_len(r)?;
+            let mut pats = Vec::new();
+            for _ in 0..num_pats {
+                let pat = read_bytes(r)?;
+                if pat.is_empty() {
+                    return Err(invalid("empty literal"));
+                }
+                pats.push(pat);
+            }
+            Ok(Box::new(AcEngine::new(pats)))
+        },
+        4 => {
+            let nfa = Nfa::read_from(r)?;
+            let pref = Prefix::read_from(r)?;
+            let capacity = read_usize(r)?;
+            Ok(Box::new(LazyDfaEngine::new(nfa, pref).with_cache_capacity(capacity)))
+        },
+        5 => Ok(Box::new(PikeVmEngine::new(Nfa::read_from(r)?, Prefix::read_from(r)?))),
+        kind @ 0..=2 => match read_u8(r)? {
+            0 => read_program_engine::<TableInsts, R>(kind, r),
+            1 => read_program_engine::<VmInsts, R>(kind, r),
+            _ => Err(invalid("unknown kind of instructions")),
+        },
+        _ => Err(invalid("unknown kind of engine")),
+    }
+}
+
+// Returns true if all the states that `pref` can give are less than `num_states`.
+fn prefix_states_ok(pref: &Prefix, num_states: usize) -> bool {
+    match *pref {
+        Prefix::Ac(_, ref map, _) => map.iter().all(|&s| s < num_states),
+        Prefix::Exact(ref inner) => prefix_states_ok(inner, num_states),
+        _ => true,
+    }
+}
+
+// Reads an engine that runs a `Program<I>`.
+fn read_program_engine<I, R>(kind: u8, r: &mut R) -> io::Result<Box<dyn Engine>>
+where I: Instructions + Serialize + Clone + 'static, R: Read {
+    let prog = Program::<I>::read_from(r)?;
+    let pref = Prefix::read_from(r)?;
+    // The engines start running the program in whatever state the prefix says.
+    if !prefix_states_ok(&pref, prog.num_states()) {
+        return Err(invalid("prefix refers to a nonexistent state"));
+    }
+    match kind {
+        0 => {
+            let eng = BacktrackingEngine::new(prog, pref);
+            Ok(Box::new(match read_u8(r)? {
+                0 => eng,
+                1 => eng.with_reverse_program(Program::read_from(r)?),
+                _ => return Err(invalid("bad reverse program")),
+            }))
+        },
+        1 => Ok(Box::new(ThreadedEngine::new(prog, pref).with_max_threads(read_usize(r)?))),
+        _ => {
+            let rev = Program::read_from(r)?;
+            let eng = TwoPassEngine::new(prog, rev, pref).ok_or_else(|| invalid("DFA too big"))?;
+            Ok(Box::new(eng))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ac::AcEngine;
+    use backtracking::BacktrackingEngine;
+    use byte_set::ByteSet;
+    use lazy::LazyDfaEngine;
+    use nfa::Nfa;
+    use onepass::OnePassEngine;
+    use pikevm::PikeVmEngine;
+    use prefix::Prefix;
+    use program::{InitStates, Inst, Program, TableInsts, VmInsts};
+    use serialize::{Serialize, MAGIC};
+    use std::fmt::Debug;
+    use test_util::{lit_prog, lits_prog};
+    use threaded::ThreadedEngine;
+    use twopass::TwoPassEngine;
+    use Engine;
+
+    fn round_trip<T: Serialize>(x: &T) -> T {
+        let mut buf = Vec::new();
+        x.write_to(&mut buf).unwrap();
+        let mut slice = &buf[..];
+        let ret = T::read_from(&mut slice).unwrap();
+        assert!(slice.is_empty());
+        ret
+    }
+
+    fn check_prefix(pref: Prefix) {
+        let input = b"abc cab abcd ddd ab";
+        let a: Vec<_> = pref.make_searcher(input).collect();
+        let round = round_trip(&pref);
+        let b: Vec<_> = round.make_searcher(input).collect();
+        assert_eq!(format!("{:?}", pref), format!("{:?}", round));
+        assert_eq!(a, b);
+    }
+
+    fn check_debug_eq<T: Debug>(a: &T, b: &T) {
+        assert_eq!(format!("{:?}", a), format!("{:?}", b));
+    }
+
+    #[test]
+    fn test_prefix() {
+        let bs: ByteSet = b"bd".iter().cloned().collect();
+        check_prefix(Prefix::Empty);
+        check_prefix(Prefix::ByteSet(bs));
+        check_prefix(Prefix::Byte(b'c'));
+        check_prefix(Prefix::Byte2(b'c', b'd'));
+        check_prefix(Prefix::Byte3(b'c', b'd', b' '));
+        check_prefix(Prefix::Lit(b"ab".to_vec()));
+        check_prefix(Prefix::Lit(b"abc".to_vec()).with_rare_byte());
+        check_prefix(Prefix::LitCaseless(b"cab".to_vec()));
+        check_prefix(Prefix::from_strings(vec![("ab", 3), ("dd", 4)].into_iter()));
+        check_prefix(Prefix::LoopWhile(bs, 0));
+        check_prefix(Prefix::LoopWhile(bs, 2));
+        check_prefix(Prefix::InnerLit(b"d".to_vec(), bs));
+        check_prefix(Prefix::AfterByte(b' '));
+        check_prefix(Prefix::LitThenSet(b"ab".to_vec(), bs));
+        check_prefix(Prefix::from_program(&lits_prog(&["ab", "cd"])));
+
+        let mut bad: &[u8] = &[5, 255, 255, 255, 255, 255, 255, 255, 0];
+        assert!(Prefix::read_from(&mut bad).is_err());
+        let mut bad: &[u8] = &[42];
+        assert!(Prefix::read_from(&mut bad).is_err());
+        // Exact prefixes can't be nested, however deeply they go.
+        let mut bad: &[u8] = &[11, 11, 0];
+        assert!(Prefix::read_from(&mut bad).is_err());
+        let deep = vec![11u8; 1 << 20];
+        assert!(Prefix::read_from(&mut &deep[..]).is_err());
+    }
+
+    #[test]
+    fn test_program() {
+        let prog = lit_prog("abc");
+        let round: Program<TableInsts> = round_trip(&prog);
+        check_debug_eq(&prog, &round);
+        assert_eq!(prog.instructions.accept_range, round.instructions.accept_range);
+
+        let mut multiline = lits_prog(&["ab", "c"]);
+        multiline.init = InitStates::Contextual(b'\n', 1, 2);
+        let round: Program<TableInsts> = round_trip(&multiline);
+        assert_eq!(round.init, multiline.init);
+        multiline.init = InitStates::ByPrevByte(1, (0..256).map(|b| b % 3).collect());
+        let round: Program<TableInsts> = round_trip(&multiline);
+        assert_eq!(round.init, multiline.init);
+
+        let vm = VmInsts {
+            byte_sets: vec![b"xyz".iter().cloned().collect()],
+            branch_table: vec![2, 3, 0],
+            literals: b"ab".to_vec(),
+            insts: vec![
+                Inst::Seq(0, 2),
+                Inst::Seq(1, 1),
+                Inst::ByteSet(0),
+                Inst::Range(b'a', b'c'),
+                Inst::Byte(b'd'),
+                Inst::Branch(0),
+                Inst::Acc(0),
+            ],
+        };
+        check_debug_eq(&vm, &round_trip(&vm));
+
+        // Programs are checked when they are read, and this one's branch table is too short.
+        let mut buf = Vec::new();
+        let prog = Program { accept_at_eoi: vec![usize::MAX; 7], instructions: vm,
+                             init: InitStates::Constant(0) };
+        prog.write_to(&mut buf).unwrap();
+        assert!(Program::<VmInsts>::read_from(&mut &buf[..]).is_err());
+    }
+
+    // "abc" or "de", with "de" only at the end of the input.
+    fn nfa() -> Nfa {
+        let mut nfa = Nfa::new();
+        let (s1, s2) = (nfa.add_state(), nfa.add_state());
+        nfa.add_eps(0, s1);
+        nfa.add_eps(0, s2);
+        let abc = nfa.add_literal(s1, b"abc");
+        let de = nfa.add_literal(s2, b"de");
+        nfa.states[abc].accept = true;
+        nfa.states[de].accept_at_eoi = true;
+        nfa.states[s1].save = Some(0);
+        nfa
+    }
+
+    #[test]
+    fn test_nfa() {
+        let nfa = nfa();
+        assert_eq!(round_trip(&nfa), nfa);
+
+        let mut bad = nfa.clone();
+        bad.states[1].eps.push(100);
+        let mut buf = Vec::new();
+        bad.write_to(&mut buf).unwrap();
+        assert!(Nfa::read_from(&mut &buf[..]).is_err());
+
+        // A huge capture slot would make the Pike VM allocate that many slots for each thread.
+        let mut bad = nfa.clone();
+        bad.states[1].save = Some(usize::MAX - 1);
+        let mut buf = Vec::new();
+        PikeVmEngine::new(bad, Prefix::Empty).save(&mut buf).unwrap();
+        assert!(<dyn Engine>::load(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn test_engine() {
+        let prog = lits_prog(&["abc", "de"]);
+        let rev = lits_prog(&["cba", "ed"]);
+        let pref = Prefix::from_program(&prog);
+        let engines: Vec<Box<dyn Engine>> = vec![
+            Box::new(BacktrackingEngine::new(prog.clone(), pref.clone())),
+            Box::new(BacktrackingEngine::new(prog.clone(), Prefix::Empty)
+                     .with_reverse_program(rev.clone())),
+            Box::new(ThreadedEngine::new(prog.clone(), pref.clone()).with_max_threads(2)),
+            Box::new(TwoPassEngine::new(prog, rev, pref).unwrap()),
+            Box::new(AcEngine::new(["abc", "de"])),
+            Box::new(LazyDfaEngine::new(nfa(), Prefix::Empty).with_cache_capacity(1000)),
+            Box::new(PikeVmEngine::new(nfa(), Prefix::Empty)),
+        ];
+        for eng in &engines {
+            let mut buf = Vec::new();
+            eng.save(&mut buf).unwrap();
+            let loaded = <dyn Engine>::load(&mut &buf[..]).unwrap();
+            assert_eq!(format!("{:?}", eng).split('{').next(),
+                       format!("{:?}", loaded).split('{').next());
+            for input in &["xxabcxde", "dex", "", "abde"] {
+                assert_eq!(eng.shortest_match(input), loaded.shortest_match(input));
+            }
+        }
+
+        // The prefix can't send the program to a state that it doesn't have, even from inside an
+        // exact prefix.
+        let bad_ac = Prefix::from_strings(vec![("abc", 100), ("de", 1)].into_iter());
+        for pref in [bad_ac.clone(), Prefix::Exact(Box::new(bad_ac))] {
+            let mut buf = Vec::new();
+            BacktrackingEngine::new(lits_prog(&["abc", "de"]), pref).save(&mut buf).unwrap();
+            assert!(<dyn Engine>::load(&mut &buf[..]).is_err());
+        }
+
+        let one_pass = OnePassEngine::new(&nfa(), Prefix::Empty).unwrap();
+        assert!(one_pass.save(&mut Vec::new()).is_err());
+        assert!(<dyn Engine>::load(&mut &[42u8][..]).is_err());
+    }
+
+    #[test]
+    fn test_header() {
+        let mut buf = Vec::new();
+        AcEngine::new(["a"]).save(&mut buf).unwrap();
+        assert!(buf.starts_with(MAGIC));
+        assert!(<dyn Engine>::load(&mut &buf[..]).is_ok());
+
+        // The previous version, a big-endian file, and a file with 16-bit states.
+        for &(idx, val) in &[(4, 2), (8, 2), (10, 2)] {
+            let mut bad = buf.clone();
+            bad[idx] = val;
+            assert!(<dyn Engine>::load(&mut &bad[..]).is_err());
+        }
+        assert!(<dyn Engine>::load(&mut &buf[1..]).is_err());
+    }
+}
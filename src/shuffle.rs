@@ -0,0 +1,311 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Instructions for DFAs with at most 16 states, which run a block of input at a time.
+//!
+//! With at most 16 states, the transition function for a single byte fits in a 16-byte vector
+//! whose `i`th entry is the state that state `i` goes to. Composing two such functions is a single
+//! byte shuffle, so we can compute the effect of a whole block of input without knowing which
+//! state we start the block in. To make sure that we don't miss the point at which we accept or
+//! die, the accepting states and the dead state are made absorbing in these functions: if the
+//! block takes us to one of them, we run the block again one byte at a time.
+//!
+//! With the `simd` feature on x86_64 (and a CPU supporting SSSE3), the shuffles are done with
+//! `pshufb`. If there are not too many byte classes, we also precompute the function for every pair
+//! of classes, so that a block of 16 bytes takes 8 shuffles instead of 16. Without SIMD, composing
+//! the functions would take 16 lookups per byte, so we just step through the transition table one
+//! byte at a time.
+
+use byte_set::ByteClasses;
+use program::{Instructions, Program, RunResult, TableInsts};
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+use std::cmp;
+use vec_bytes;
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+const BLOCK: usize = 16;
+
+// The most byte classes for which we precompute the functions for pairs of classes (which take
+// 16 bytes for each pair).
+const MAX_PAIR_CLASSES: usize = 32;
+
+/// Instructions for a DFA with at most 16 states.
+#[derive(Clone, Debug)]
+pub struct ShuffleInsts {
+    classes: ByteClasses,
+    // `table[state * num_classes + class]` is the next state.
+    table: Vec<u8>,
+    // For each class, the transition function with the accepting and dead states made absorbing.
+    maps: Vec<[u8; 16]>,
+    // `pairs[c1 * num_classes + c2]` is `maps[c1]` followed by `maps[c2]`. This is empty if we
+    // aren't using SIMD, or if there are too many classes.
+    pairs: Vec<[u8; 16]>,
+    accept: Vec<usize>,
+    // `stops[state]` is true if `state` is accepting or dead.
+    #[cfg_attr(not(all(feature = "simd", target_arch = "x86_64")), allow(dead_code))]
+    stops: [bool; 16],
+    #[cfg_attr(not(all(feature = "simd", target_arch = "x86_64")), allow(dead_code))]
+    simd: bool,
+}
+
+impl ShuffleInsts {
+    /// Converts `insts` into `ShuffleInsts`, or returns `None` if it has too many states.
+    pub fn from_table(insts: &TableInsts) -> Option<ShuffleInsts> {
+        let num_states = insts.num_states();
+        if num_states > 16 {
+            return None;
+        }
+
+        let classes = ByteClasses::from_table(insts);
+        let reps = classes.representatives();
+        let mut table = Vec::with_capacity(num_states * reps.len());
+        for state in 0..num_states {
+            table.extend(reps.iter().map(|&b| insts.transition(state, b) as u8));
+        }
+
+        let mut stops = [true; 16];
+        for (state, stop) in stops.iter_mut().enumerate().take(num_states) {
+            *stop = state == 0 || insts.accept(state).is_some();
+        }
+        let maps = (0..reps.len()).map(|class| {
+            let mut map = [0u8; 16];
+            for (state, next) in map.iter_mut().enumerate().take(num_states) {
+                *next = if stops[state] {
+                    state as u8
+                } else {
+                    table[state * reps.len() + class]
+                };
+            }
+            map
+        }).collect::<Vec<_>>();
+
+        let simd = ShuffleInsts::detect();
+        let mut pairs = Vec::new();
+        if simd && maps.len() <= MAX_PAIR_CLASSES {
+            pairs.reserve_exact(maps.len() * maps.len());
+            for first in &maps {
+                pairs.extend(maps.iter().map(|second| {
+                    let mut map = [0u8; 16];
+                    for (state, next) in map.iter_mut().enumerate() {
+                        *next = second[first[state] as usize];
+                    }
+                    map
+                }));
+            }
+        }
+
+        Some(ShuffleInsts {
+            classes,
+            table,
+            maps,
+            pairs,
+            accept: (0..num_states).map(|s| insts.accept(s).unwrap_or(usize::MAX)).collect(),
+            stops,
+            simd,
+        })
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    fn detect() -> bool {
+        is_x86_feature_detected!("ssse3")
+    }
+
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    fn detect() -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn next(&self, state: usize, b: u8) -> usize {
+        self.table[state * self.maps.len() + self.classes.class(b)] as usize
+    }
+
+    // Steps through `input[pos..end]` one byte at a time, returning the state at `end` unless we
+    // accept or die first.
+    fn run_bytes(&self, mut state: usize, input: &[u8], mut pos: usize, end: usize)
+    -> Result<usize, RunResult> {
+        while pos < end {
+            if let Some(data) = self.accept(state) {
+                return Err(RunResult::Accept { pos, data });
+            }
+            state = self.next(state, input[pos]);
+            if state == 0 {
+                return Err(RunResult::Dead { pos });
+            }
+            pos += 1;
+        }
+        Ok(state)
+    }
+
+    // Runs a block of input at a time, with the block composed by `pshufb`.
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    fn run_blocks(&self, mut state: usize, input: &[u8]) -> RunResult {
+        let mut pos = 0;
+        while pos < input.len() {
+            let end = cmp::min(pos + BLOCK, input.len());
+            // This is safe because we checked for SSSE3 when setting `self.simd`.
+            let next_state = unsafe { self.compose_ssse3(&input[pos..end]) }[state] as usize;
+            if !self.stops[next_state] {
+                state = next_state;
+                pos = end;
+                continue;
+            }
+
+            // We stopped somewhere in this block, so find out where.
+            match self.run_bytes(state, input, pos, end) {
+                Ok(s) => state = s,
+                Err(res) => return res,
+            }
+            pos = end;
+        }
+        RunResult::Eoi { state }
+    }
+
+    // Returns the state that each state ends up in after `block`, stopping at accepting or dead
+    // states.
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn compose_ssse3(&self, block: &[u8]) -> [u8; 16] {
+        use std::arch::x86_64::*;
+
+        let mut cur = _mm_setr_epi8(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+        let mut apply = |map: &[u8; 16]| {
+            cur = _mm_shuffle_epi8(_mm_loadu_si128(map.as_ptr() as *const __m128i), cur);
+        };
+        if self.pairs.is_empty() {
+            for &b in block {
+                apply(&self.maps[self.classes.class(b)]);
+            }
+        } else {
+            let num_classes = self.maps.len();
+            let mut chunks = block.chunks_exact(2);
+            for pair in &mut chunks {
+                let (c1, c2) = (self.classes.class(pair[0]), self.classes.class(pair[1]));
+                apply(&self.pairs[c1 * num_classes + c2]);
+            }
+            for &b in chunks.remainder() {
+                apply(&self.maps[self.classes.class(b)]);
+            }
+        }
+        let mut ret = [0u8; 16];
+        _mm_storeu_si128(ret.as_mut_ptr() as *mut __m128i, cur);
+        ret
+    }
+}
+
+impl Instructions for ShuffleInsts {
+    fn step_byte(&self, state: usize, input: u8) -> (Option<usize>, Option<usize>) {
+        let next_state = self.next(state, input);
+        (if next_state != 0 { Some(next_state) } else { None }, self.accept(state))
+    }
+
+    fn run(&self, state: usize, input: &[u8]) -> RunResult {
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        {
+            if self.simd {
+                return self.run_blocks(state, input);
+            }
+        }
+
+        match self.run_bytes(state, input, 0, input.len()) {
+            Ok(state) => RunResult::Eoi { state },
+            Err(res) => res,
+        }
+    }
+
+    fn accept(&self, state: usize) -> Option<usize> {
+        let accept = self.accept[state];
+        if accept != usize::MAX { Some(accept) } else { None }
+    }
+
+    fn is_dead(&self, state: usize) -> bool {
+        state == 0
+    }
+
+    fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        vec_bytes(&self.table) + vec_bytes(&self.maps) + vec_bytes(&self.pairs)
+            + vec_bytes(&self.accept)
+    }
+}
+
+impl Program<TableInsts> {
+    /// Converts this program to use `ShuffleInsts`, if it has at most 16 states.
+    ///
+    /// It is worth optimizing the program first, since that can reduce the number of states.
+    pub fn to_shuffle(&self) -> Option<Program<ShuffleInsts>> {
+        ShuffleInsts::from_table(&self.instructions).map(|instructions| Program {
+            accept_at_eoi: self.accept_at_eoi.clone(),
+            instructions,
+            init: self.init.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use prefix::Prefix;
+    use program::{Instructions, RunResult};
+    use test_util::{lits_prog, table_prog};
+    use Engine;
+
+    #[test]
+    fn test_run() {
+        let prog = lits_prog(&["abc", "de"]);
+        let shuffle = prog.to_shuffle().unwrap();
+        // 7 states, 6 byte classes and a map for each class (and each pair of classes, with SIMD).
+        let pairs = if shuffle.instructions.simd { 6 * 6 * 16 } else { 0 };
+        assert_eq!(shuffle.instructions.pairs.len() * 16, pairs);
+        assert_eq!(shuffle.instructions.memory_usage(),
+                   7 * 6 + 6 * 16 + pairs + 7 * ::std::mem::size_of::<usize>());
+        let inputs: [&[u8]; 6] = [
+            b"abc", b"abx", b"de", b"", b"ab", b"abcdeabcdeabcdeabcdeabcdeabcde",
+        ];
+        for input in &inputs {
+            for state in 0..prog.num_states() {
+                assert_eq!(shuffle.run(state, input), prog.run(state, input));
+            }
+        }
+
+        // A long input that stays in a loop until the very end.
+        let mut edges: Vec<_> = (0..=255u8).map(|b| (1, b, 1)).collect();
+        edges.push((1, b'!', 2));
+        let prog = table_prog(3, &edges, &[2]);
+        let shuffle = prog.to_shuffle().unwrap();
+        let mut input = vec![b'x'; 100];
+        assert_eq!(shuffle.run(1, &input), RunResult::Eoi { state: 1 });
+        input.extend_from_slice(b"!y");
+        assert_eq!(shuffle.run(1, &input), RunResult::Accept { pos: 101, data: 0 });
+        assert_eq!(shuffle.run(1, &input), prog.run(1, &input));
+
+        // Only the states in `accept_range` look at `accept`.
+        let mut prog = lits_prog(&["abc"]);
+        assert_eq!(prog.instructions.accept_range, (4, 5));
+        prog.instructions.accept[2] = 7;
+        let shuffle = prog.to_shuffle().unwrap();
+        assert_eq!(shuffle.instructions.accept(2), None);
+        for input in &inputs {
+            assert_eq!(shuffle.run(1, input), prog.run(1, input));
+        }
+    }
+
+    #[test]
+    fn test_engine() {
+        let prog = lits_prog(&["abc", "de"]);
+        let eng = BacktrackingEngine::new(prog.to_shuffle().unwrap(), Prefix::Empty);
+        assert_eq!(eng.shortest_match("xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxabc"), Some((30, 33)));
+        assert_eq!(eng.shortest_match("xxdxabx"), None);
+
+        let big = lits_prog(&["abcdefghijklmnop"]);
+        assert!(big.to_shuffle().is_none());
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Fast scanning for bytes belonging to a `ByteSet`.
+//!
+//! With the `simd` feature on x86_64, we test 16 or 32 bytes at a time, depending on whether the
+//! CPU supports SSSE3 or AVX2. Otherwise (or if the CPU supports neither), we test one byte at a
+//! time.
+//!
+//! The vectorized test splits each byte into its high and low nibbles. For each low nibble, a
+//! table (looked up with a byte shuffle) gives a bitmask saying which high nibbles make the byte
+//! a member of the set. Since there are 16 possible high nibbles and only 8 bits in a byte, there
+//! are two tables: one for bytes below 0x80 and one for the rest.
+
+use byte_set::ByteSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Level {
+    Scalar,
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    Ssse3,
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    Avx2,
+}
+
+/// Finds the first byte in some input that belongs to a `ByteSet`.
+#[derive(Clone, Debug)]
+pub struct ByteSetFinder {
+    set: ByteSet,
+    level: Level,
+    // `tables[i][lo]` has bit `h` set if the byte `((8 * i + h) << 4) | lo` is in the set.
+    #[cfg_attr(not(all(feature = "simd", target_arch = "x86_64")), allow(dead_code))]
+    tables: [[u8; 16]; 2],
+}
+
+impl ByteSetFinder {
+    pub fn new(set: &ByteSet) -> ByteSetFinder {
+        let mut tables = [[0u8; 16]; 2];
+        for b in set {
+            let (hi, lo) = ((b >> 4) as usize, (b & 0xf) as usize);
+            tables[hi >> 3][lo] |= 1 << (hi & 7);
+        }
+        ByteSetFinder {
+            set: *set,
+            level: ByteSetFinder::detect(),
+            tables,
+        }
+    }
+
+    /// Like `new`, but never uses vector instructions. This is mainly useful for testing.
+    pub fn new_scalar(set: &ByteSet) -> ByteSetFinder {
+        ByteSetFinder {
+            level: Level::Scalar,
+            ..ByteSetFinder::new(set)
+        }
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    fn detect() -> Level {
+        if is_x86_feature_detected!("avx2") {
+            Level::Avx2
+        } else if is_x86_feature_detected!("ssse3") {
+            Level::Ssse3
+        } else {
+            Level::Scalar
+        }
+    }
+
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    fn detect() -> Level {
+        Level::Scalar
+    }
+
+    /// Returns the position of the first byte in `input` that belongs to the set.
+    pub fn find(&self, input: &[u8]) -> Option<usize> {
+        match self.level {
+            Level::Scalar => self.find_scalar(input, 0),
+            // These are safe because we checked for the CPU features when choosing the level.
+            #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+            Level::Ssse3 => unsafe { self.find_ssse3(input) },
+            #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+            Level::Avx2 => unsafe { self.find_avx2(input) },
+        }
+    }
+
+    fn find_scalar(&self, input: &[u8], start: usize) -> Option<usize> {
+        input[start..].iter().position(|&b| self.set.contains(b)).map(|p| p + start)
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn find_ssse3(&self, input: &[u8]) -> Option<usize> {
+        use std::arch::x86_64::*;
+
+        let t0 = _mm_loadu_si128(self.tables[0].as_ptr() as *const __m128i);
+        let t1 = _mm_loadu_si128(self.tables[1].as_ptr() as *const __m128i);
+        let bits = _mm_setr_epi8(1, 2, 4, 8, 16, 32, 64, -128, 1, 2, 4, 8, 16, 32, 64, -128);
+        let nibble = _mm_set1_epi8(0xf);
+        let zero = _mm_setzero_si128();
+
+        let mut i = 0;
+        while i + 16 <= input.len() {
+            let v = _mm_loadu_si128(input.as_ptr().add(i) as *const __m128i);
+            let lo = _mm_and_si128(v, nibble);
+            let hi = _mm_and_si128(_mm_srli_epi16(v, 4), nibble);
+            // The bytes that are at least 0x80 are the ones that are negative as `i8`s.
+            let high_half = _mm_cmpgt_epi8(zero, v);
+            let row = _mm_or_si128(
+                _mm_andnot_si128(high_half, _mm_shuffle_epi8(t0, lo)),
+                _mm_and_si128(high_half, _mm_shuffle_epi8(t1, lo)));
+            let bit = _mm_shuffle_epi8(bits, hi);
+            let hits = _mm_cmpeq_epi8(_mm_and_si128(row, bit), bit);
+            let mask = _mm_movemask_epi8(hits) as u32;
+            if mask != 0 {
+                return Some(i + mask.trailing_zeros() as usize);
+            }
+            i += 16;
+        }
+        self.find_scalar(input, i)
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    unsafe fn find_avx2(&self, input: &[u8]) -> Option<usize> {
+        use std::arch::x86_64::*;
+
+        // `_mm256_shuffle_epi8` shuffles within each 128-bit half, so the tables are repeated.
+        let t0 = _mm256_broadcastsi128_si256(
+            _mm_loadu_si128(self.tables[0].as_ptr() as *const __m128i));
+        let t1 = _mm256_broadcastsi128_si256(
+            _mm_loadu_si128(self.tables[1].as_ptr() as *const __m128i));
+        let bits = _mm256_broadcastsi128_si256(
+            _mm_setr_epi8(1, 2, 4, 8, 16, 32, 64, -128, 1, 2, 4, 8, 16, 32, 64, -128));
+        let nibble = _mm256_set1_epi8(0xf);
+        let zero = _mm256_setzero_si256();
+
+        let mut i = 0;
+        while i + 32 <= input.len() {
+            let v = _mm256_loadu_si256(input.as_ptr().add(i) as *const __m256i);
+            let lo = _mm256_and_si256(v, nibble);
+            let hi = _mm256_and_si256(_mm256_srli_epi16(v, 4), nibble);
+            let high_half = _mm256_cmpgt_epi8(zero, v);
+            let row = _mm256_or_si256(
+                _mm256_andnot_si256(high_half, _mm256_shuffle_epi8(t0, lo)),
+                _mm256_and_si256(high_half, _mm256_shuffle_epi8(t1, lo)));
+            let bit = _mm256_shuffle_epi8(bits, hi);
+            let hits = _mm256_cmpeq_epi8(_mm256_and_si256(row, bit), bit);
+            let mask = _mm256_movemask_epi8(hits) as u32;
+            if mask != 0 {
+                return Some(i + mask.trailing_zeros() as usize);
+            }
+            i += 32;
+        }
+        if i + 16 <= input.len() && is_x86_feature_detected!("ssse3") {
+            self.find_ssse3(&input[i..]).map(|p| p + i)
+        } else {
+            self.find_scalar(input, i)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_set::ByteSet;
+    use simd::ByteSetFinder;
+
+    #[test]
+    fn test_find() {
+        // A simple linear congruential generator, so that the test is repeatable.
+        let mut seed = 12345u32;
+        let mut next = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        let input: Vec<u8> = (0..1000).map(|_| next()).collect();
+
+        let sets: [ByteSet; 6] = [
+            ByteSet::new(),
+            ByteSet::full(),
+            b"aeiou".iter().cloned().collect(),
+            vec![0, 0x7f, 0x80, 0xff].into_iter().collect(),
+            (0x80..=0xff).collect(),
+            (0..200).map(|_| next()).collect(),
+        ];
+        for set in &sets {
+            let fast = ByteSetFinder::new(set);
+            let slow = ByteSetFinder::new_scalar(set);
+            for start in 0..input.len() {
+                let expected = input[start..].iter().position(|&b| set.contains(b));
+                assert_eq!(slow.find(&input[start..]), expected);
+                assert_eq!(fast.find(&input[start..]), expected, "{:?} from {}", set, start);
+            }
+            // Check the positions of single bytes inside a long haystack.
+            let mut hay = vec![b'x'; 100];
+            if !set.contains(b'x') {
+                for b in set {
+                    for &pos in &[0, 15, 16, 31, 32, 63, 99] {
+                        hay[pos] = b;
+                        assert_eq!(fast.find(&hay), Some(pos));
+                        hay[pos] = b'x';
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use {Engine, SearchConfig};
+use program::{Instructions, Program};
+
+/// An engine that runs its program from each position in turn, without a prefix.
+///
+/// This is much slower than the `BacktrackingEngine` on most inputs, but it is also much smaller,
+/// and it is the only engine that is available without the `std` feature. Searching never
+/// allocates, so with a `TableRef` this can search tables in static memory without using the
+/// heap at all. Like the
+/// `BacktrackingEngine`, it always returns the first match that it finds, so
+/// `SearchConfig::earliest` makes no difference.
+#[derive(Clone, Debug)]
+pub struct SimpleEngine<Insts: Instructions> {
+    prog: Program<Insts>,
+}
+
+impl<Insts: Instructions> SimpleEngine<Insts> {
+    pub fn new(prog: Program<Insts>) -> SimpleEngine<Insts> {
+        SimpleEngine {
+            prog,
+        }
+    }
+
+    // Finds the leftmost match, and the shortest one starting there.
+    fn leftmost_shortest(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        let (start, end) = config.bounds(input)?;
+        if self.prog.num_states() == 0 {
+            return None;
+        } else if config.anchored_start || self.prog.init.anchored().is_some() {
+            let state = self.prog.init.anchored()
+                .or_else(|| self.prog.init.state_at_pos(input, start))?;
+            return self.match_at(input, start, end, state, config).map(|x| (start, x));
+        }
+
+        (start..=end).filter_map(|pos| {
+            let state = self.prog.init.state_at_pos(input, pos)?;
+            self.match_at(input, pos, end, state, config).map(|x| (pos, x))
+        }).next()
+    }
+
+    // Returns the end of a match starting from `state` at position `pos`.
+    fn match_at(&self, input: &[u8], pos: usize, end: usize, state: usize, config: &SearchConfig)
+    -> Option<usize> {
+        if config.anchored_end {
+            if self.prog.matches_to(input, pos, end, state) { Some(end) } else { None }
+        } else {
+            self.prog.shortest_match_from(input, pos, end, state)
+        }
+    }
+}
+
+impl<Insts: Instructions + 'static> Engine for SimpleEngine<Insts> {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        self.leftmost_shortest(input, config).map(|m| self.prog.extend_match(input, config, m))
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(self.clone())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.prog.memory_usage()
+    }
+
+    fn explain(&self, input: &[u8], m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        self.prog.explain(input, m)
+    }
+
+    fn matches_prefix(&self, input: &[u8]) -> Option<bool> {
+        Some(self.prog.matches_prefix(input))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use prefix::Prefix;
+    use program::{InitStates, Program, TableRef};
+    use simple::SimpleEngine;
+    use test_util::{lits_prog, table_prog};
+    use {Engine, SearchConfig};
+
+    #[test]
+    fn test_search() {
+        let mut multiline = table_prog(4, &[(1, b'a', 2), (2, b'b', 3)], &[3]);
+        multiline.init = InitStates::AfterByte(b'\n', 1);
+        let progs = vec![lits_prog(&["ab", "cd", "abcd"]), multiline];
+        let default = SearchConfig::default();
+        let configs = [
+            default,
+            SearchConfig { anchored_start: true, ..default },
+            SearchConfig { anchored_end: true, ..default },
+            SearchConfig { leftmost_first: true, ..default },
+            SearchConfig { start: 2, ..default },
+        ];
+        for prog in progs {
+            let simple = SimpleEngine::new(prog.clone());
+            let bt = BacktrackingEngine::new(prog, Prefix::Empty);
+            for input in &["", "ab", "xabcd", "xx\nab", "cdab\nabcd"] {
+                let input = input.as_bytes();
+                for config in &configs {
+                    assert_eq!(simple.search(input, config), bt.search(input, config));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_table_ref() {
+        let prog = lits_prog(&["ab", "cd"]);
+        // Static tables would usually come from `codegen`.
+        let table: &'static [u32] = Box::leak(prog.instructions.table.clone().into_boxed_slice());
+        let accept: &'static [usize] =
+            Box::leak(prog.instructions.accept.clone().into_boxed_slice());
+        let borrowed = Program {
+            accept_at_eoi: prog.accept_at_eoi.clone(),
+            instructions: TableRef::new(table, accept),
+            init: prog.init.clone(),
+        };
+        let eng = SimpleEngine::new(prog);
+        let borrowed = SimpleEngine::new(borrowed);
+        for input in &["", "ab", "xxcd", "acbd"] {
+            let input = input.as_bytes();
+            let config = SearchConfig::default();
+            assert_eq!(eng.search(input, &config), borrowed.search(input, &config));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for building small programs in tests.
+
+#[cfg(feature = "std")]
+use prefix::{PrefixResult, PrefixSearcher, PrefixSearcherFactory};
+#[cfg(feature = "std")]
+use nfa::Nfa;
+use program::{InitStates, Program, TableInsts};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+/// Makes a program with `n` states (including the dead state 0) that starts in state 1.
+///
+/// `edges` are `(from, byte, to)` triples, and the states in `accept` accept (with data 0) both
+/// before a byte and at the end of the input.
+pub fn table_prog(n: usize, edges: &[(usize, u8, usize)], accept: &[usize]) -> Program<TableInsts> {
+    let mut table = vec![0; n * 256];
+    for &(s, b, t) in edges {
+        table[s * 256 + b as usize] = t as u32;
+    }
+    let mut acc = vec![usize::MAX; n];
+    for &s in accept {
+        acc[s] = 0;
+    }
+    Program {
+        accept_at_eoi: acc.clone(),
+        instructions: TableInsts::new(table, acc),
+        init: InitStates::Constant(1),
+    }
+}
+
+/// Makes a program that matches the alternation of some literals.
+pub fn lits_prog(lits: &[&str]) -> Program<TableInsts> {
+    // States are numbered so that `(lit_idx, pos)` is state `offsets[lit_idx] + pos`, except that
+    // position 0 is always the start state.
+    let mut n = 2;
+    let mut edges = Vec::new();
+    let mut accept = Vec::new();
+    for lit in lits {
+        let bytes = lit.as_bytes();
+        let mut prev = 1;
+        for &b in bytes {
+            edges.push((prev, b, n));
+            prev = n;
+            n += 1;
+        }
+        accept.push(prev);
+    }
+    table_prog(n, &edges, &accept)
+}
+
+/// Makes a program that matches a single literal.
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub fn lit_prog(lit: &str) -> Program<TableInsts> {
+    lits_prog(&[lit])
+}
+
+/// Makes an `Nfa` for `^ab$|\bcd\b` (in multiline mode), which uses every kind of assertion.
+#[cfg(feature = "std")]
+pub fn assertions_nfa() -> Nfa {
+    let mut nfa = Nfa::new();
+    let (s1, s2) = (nfa.add_state(), nfa.add_state());
+    nfa.states[0].line_start_eps.push(s1);
+    nfa.states[0].word_boundary_eps.push(s2);
+    let ab = nfa.add_literal(s1, b"ab");
+    nfa.states[ab].accept_at_line_end = true;
+    let cd = nfa.add_literal(s2, b"cd");
+    let end = nfa.add_state();
+    nfa.states[cd].word_boundary_eps.push(end);
+    nfa.states[end].accept = true;
+    nfa
+}
+
+/// Some inputs for `assertions_nfa`, and the match that each one should give.
+#[cfg(feature = "std")]
+pub const ASSERTIONS_MATCHES: &[(&str, Option<(usize, usize)>)] = &[
+    ("ab", Some((0, 2))),
+    ("x\nab", Some((2, 4))),
+    ("xab", None),
+    ("ab\nx", Some((0, 2))),
+    ("abx\nab", Some((4, 6))),
+    ("cd", Some((0, 2))),
+    ("x cd.", Some((2, 4))),
+    ("xcd cdx", None),
+    ("\ncd\n", Some((1, 3))),
+];
+
+/// A prefilter that only allows matches to start right after a '|'.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct AfterPipe;
+
+#[cfg(feature = "std")]
+struct AfterPipeSearcher<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+#[cfg(feature = "std")]
+impl<'a> PrefixSearcher for AfterPipeSearcher<'a> {
+    fn skip_to(&mut self, pos: usize) { self.pos = pos; }
+
+    fn search(&mut self) -> Option<PrefixResult> {
+        while self.pos < self.input.len() {
+            self.pos += 1;
+            if self.input[self.pos - 1] == b'|' {
+                return Some(PrefixResult {
+                    start_pos: self.pos,
+                    end_pos: self.pos,
+                    end_state: None,
+                });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+impl PrefixSearcherFactory for AfterPipe {
+    fn searcher<'a>(&'a self, input: &'a [u8]) -> Box<dyn PrefixSearcher + 'a> {
+        Box::new(AfterPipeSearcher { input, pos: 0 })
+    }
+}
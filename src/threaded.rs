@@ -6,12 +6,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use Engine;
-use prefix::{Prefix, PrefixSearcher};
-use program::{Program, Instructions};
-use std::mem;
+use {vec_bytes, Engine, SearchConfig};
+use error::{ProgramError, SearchError};
+use pool::Pool;
+use prefix::{Prefix, PrefixSearcher, PrefixSearcherFactory};
+use program::{match_ends, Instructions, Program};
+use serialize::{write_any, write_insts_kind, write_u8, write_usize};
 use std::cell::RefCell;
-use std::ops::DerefMut;
+use std::cmp;
+use std::io::{self, Write};
+use std::mem;
 
 #[derive(Clone, Debug, PartialEq)]
 struct Thread {
@@ -19,29 +23,47 @@ struct Thread {
     start_idx: usize,
 }
 
+/// A set of threads, with at most one thread per state.
+///
+/// The states are stored as a sparse set, so that clearing the set doesn't need to touch memory
+/// for every state in the program.
 #[derive(Clone, Debug, PartialEq)]
 struct Threads {
     threads: Vec<Thread>,
-    states: Vec<u8>,
+    // If `state` has a thread, it is `threads[sparse[state]]`. Other entries are arbitrary.
+    sparse: Vec<usize>,
 }
 
 impl Threads {
     fn with_capacity(n: usize) -> Threads {
+        // `threads` only grows as big as the number of live threads, which is usually much
+        // smaller than the number of states.
         Threads {
-            threads: Vec::with_capacity(n),
-            states: vec![0; n],
+            threads: Vec::new(),
+            sparse: vec![0; n],
         }
     }
 
+    fn contains(&self, state: usize) -> bool {
+        let idx = self.sparse[state];
+        idx < self.threads.len() && self.threads[idx].state == state
+    }
+
+    /// Adds a thread, or merges it with the thread that is already in `state`.
+    ///
+    /// When merging, we keep the earlier start index because we're looking for the leftmost match.
     fn add(&mut self, state: usize, start_idx: usize) {
-        if self.states[state] == 0 {
-            self.states[state] = 1;
-            self.threads.push(Thread { state: state, start_idx: start_idx });
+        if self.contains(state) {
+            let th = &mut self.threads[self.sparse[state]];
+            th.start_idx = cmp::min(th.start_idx, start_idx);
+        } else {
+            self.sparse[state] = self.threads.len();
+            self.threads.push(Thread { state, start_idx });
         }
     }
 
     fn starts_after(&self, start_idx: usize) -> bool {
-        self.threads.is_empty() || self.threads[0].start_idx >= start_idx
+        self.threads.iter().all(|th| th.start_idx >= start_idx)
     }
 }
 
@@ -67,50 +89,204 @@ impl ProgThreads {
     fn clear(&mut self) {
         self.cur.threads.clear();
         self.next.threads.clear();
+    }
+}
 
-        for s in &mut self.cur.states {
-            *s = 0;
-        }
-        for s in &mut self.next.states {
-            *s = 0;
+/// Scratch space for a `ThreadedEngine`.
+///
+/// A `Cache` can be used with any number of engines, but not with more than one search at a time.
+/// To share caches between threads, use `ThreadedEngine::cache_pool`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cache(ProgThreads);
+
+impl Cache {
+    /// Creates a cache that is big enough for programs with `num_states` states.
+    ///
+    /// The cache will grow if it is later used with a bigger program.
+    pub fn new(num_states: usize) -> Cache {
+        Cache(ProgThreads::with_capacity(num_states))
+    }
+
+    /// Returns how many bytes this cache holds on the heap.
+    pub fn memory_usage(&self) -> usize {
+        let threads = |t: &Threads| vec_bytes(&t.threads) + vec_bytes(&t.sparse);
+        threads(&self.0.cur) + threads(&self.0.next)
+    }
+
+    fn threads(&mut self, num_states: usize) -> &mut ProgThreads {
+        if self.0.cur.sparse.len() < num_states {
+            self.0.cur.sparse.resize(num_states, 0);
+            self.0.next.sparse.resize(num_states, 0);
         }
+        &mut self.0
+    }
+}
+
+impl Default for Cache {
+    /// Creates an empty cache, which will allocate when it is first used.
+    fn default() -> Cache {
+        Cache::new(0)
     }
 }
 
+thread_local! {
+    // The scratch space used by `Engine::shortest_match`, which doesn't take a `Cache`.
+    static CACHE: RefCell<Cache> = RefCell::new(Cache::default());
+}
+
+/// An engine that runs the program from every candidate position at once, so that it only needs
+/// to look at each byte of the input once.
+///
+/// Searching doesn't recurse and uses a fixed amount of stack. There is at most one thread for
+/// each state of the program, so a `Cache` never grows beyond a few words per state; to bound it
+/// further, use `with_max_threads`.
 #[derive(Clone, Debug)]
-pub struct ThreadedEngine<Insts: Instructions> {
+pub struct ThreadedEngine<Insts: Instructions, P: PrefixSearcherFactory = Prefix> {
     prog: Program<Insts>,
-    threads: RefCell<ProgThreads>,
-    prefix: Prefix,
+    prefix: P,
+    full_match: Option<usize>,
+    max_threads: usize,
 }
 
-impl<Insts: Instructions> ThreadedEngine<Insts> {
-    pub fn new(prog: Program<Insts>, pref: Prefix) -> ThreadedEngine<Insts> {
-        let len = prog.num_states();
+impl<Insts: Instructions, P: PrefixSearcherFactory> ThreadedEngine<Insts, P> {
+    pub fn new(prog: Program<Insts>, pref: P) -> ThreadedEngine<Insts, P> {
         ThreadedEngine {
-            prog: prog,
-            threads: RefCell::new(ProgThreads::with_capacity(len)),
+            full_match: prog.full_match_start(),
+            prog,
             prefix: pref,
+            max_threads: usize::MAX,
+        }
+    }
+
+    /// Like `new`, but first checks (with `Instructions::validate`) that running `prog` can't
+    /// panic, for programs that were built from untrusted data.
+    pub fn try_new(prog: Program<Insts>, pref: P)
+    -> Result<ThreadedEngine<Insts, P>, ProgramError> {
+        prog.validate()?;
+        Ok(ThreadedEngine::new(prog, pref))
+    }
+
+    /// Limits the number of threads that a search can use at once.
+    ///
+    /// This bounds the memory that a search needs, beyond the two words per state that a `Cache`
+    /// always uses. If a search needs more threads, `try_search_with` will fail and the other
+    /// searching methods will fall back to trying one position at a time, which needs no threads
+    /// but can take time quadratic in the length of the input.
+    pub fn with_max_threads(mut self, max_threads: usize) -> ThreadedEngine<Insts, P> {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// Creates a `Cache` that is big enough for this engine.
+    pub fn cache(&self) -> Cache {
+        Cache::new(self.prog.num_states())
+    }
+
+    /// Creates a `Pool` of caches that are big enough for this engine, for sharing between the
+    /// threads that search with it.
+    pub fn cache_pool(&self) -> Pool<Cache> {
+        let num_states = self.prog.num_states();
+        Pool::new(move || Cache::new(num_states))
+    }
+
+    /// Like `Engine::shortest_match`, but uses `cache` for scratch space.
+    ///
+    /// Unlike `shortest_match`, this doesn't need to look up any thread-local storage.
+    pub fn shortest_match_with(&self, cache: &mut Cache, s: &[u8]) -> Option<(usize, usize)> {
+        self.search_with(cache, s, &SearchConfig::default())
+    }
+
+    /// Like `Engine::search`, but uses `cache` for scratch space.
+    pub fn search_with(&self, cache: &mut Cache, s: &[u8], config: &SearchConfig)
+    -> Option<(usize, usize)> {
+        match self.try_search_with(cache, s, config) {
+            Ok(ret) => ret,
+            Err(SearchError::TooManyThreads(_)) =>
+                self.backtrack(s, config).map(|m| self.prog.extend_match(s, config, m)),
+        }
+    }
+
+    /// Like `search_with`, but fails instead of falling back when there are too many threads.
+    pub fn try_search_with(&self, cache: &mut Cache, s: &[u8], config: &SearchConfig)
+    -> Result<Option<(usize, usize)>, SearchError> {
+        let ret = self.try_leftmost_shortest(cache, s, config)?;
+        Ok(ret.map(|m| self.prog.extend_match(s, config, m)))
+    }
+
+    // Finds the leftmost match, and the shortest one starting there.
+    fn try_leftmost_shortest(&self, cache: &mut Cache, s: &[u8], config: &SearchConfig)
+    -> Result<Option<(usize, usize)>, SearchError> {
+        let (start, end) = match config.bounds(s) {
+            Some(b) if self.prog.num_states() > 0 => b,
+            _ => return Ok(None),
+        };
+        if let (Some(state), 0, true) = (self.full_match, start, end == s.len()) {
+            return Ok(self.prog.full_match(state, s));
+        } else if config.anchored_start || self.prog.init.anchored().is_some() {
+            // There is only ever one thread, and it starts at the beginning. The prefix might not
+            // find that position, so we don't use it.
+            return Ok(self.match_at(s, start, end, config));
+        }
+
+        let threads = cache.threads(self.prog.num_states());
+        let exact = self.prefix.is_exact();
+        let mut err = None;
+        let ret = self.prefix.with_searcher(&s[..end], &mut |searcher| {
+            if exact && !config.anchored_end {
+                searcher.skip_to(start);
+                searcher.leftmost_match(self.prefix.out_of_order_len(),
+                                        &mut |res| Some((res.start_pos, res.end_pos)))
+            } else {
+                // This works even for exact prefixes, because we only use the start positions.
+                searcher.skip_to(start);
+                self.shortest_match_from_searcher(threads, s, start, end, config, searcher)
+                    .unwrap_or_else(|e| { err = Some(e); None })
+            }
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(ret),
+        }
+    }
+
+    /// Returns a match starting at `pos`, if there is one.
+    fn match_at(&self, s: &[u8], pos: usize, end: usize, config: &SearchConfig)
+    -> Option<(usize, usize)> {
+        let state = self.prog.init.anchored().or_else(|| self.prog.init.state_at_pos(s, pos))?;
+        if config.anchored_end {
+            if self.prog.matches_to(s, pos, end, state) { Some((pos, end)) } else { None }
+        } else {
+            self.prog.shortest_match_from(s, pos, end, state).map(|x| (pos, x))
         }
     }
 
+    /// Searches without using any threads, by trying one candidate position at a time.
+    fn backtrack(&self, s: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        let (start, end) = config.bounds(s)?;
+        self.prefix.with_searcher(&s[..end], &mut |searcher| {
+            searcher.skip_to(start);
+            searcher.leftmost_match(self.prefix.out_of_order_len(),
+                                    &mut |res| self.match_at(s, res.start_pos, end, config))
+        })
+    }
+
     fn advance_thread(&self,
             threads: &mut ProgThreads,
             acc: &mut Option<(usize, usize)>,
             i: usize,
             input: &[u8],
-            pos: usize) {
+            pos: usize,
+            config: &SearchConfig) {
         let state = threads.cur.threads[i].state;
         let start_idx = threads.cur.threads[i].start_idx;
-        threads.cur.states[state] = 0;
-
-        let (next_state, accept) = self.prog.step(state, &input[pos..]);
-        if let Some(bytes_ago) = accept {
-            // We need to use saturating_sub here because Nfa::determinize_for_shortest_match
-            // makes it so that bytes_ago can be positive even when start_idx == 0.
-            let acc_idx = start_idx.saturating_sub(bytes_ago as usize);
-            if acc.is_none() || acc_idx < acc.unwrap().0 {
-                *acc = Some((acc_idx, pos));
+
+        let (next_state, accept) = self.prog.step_byte(state, input[pos]);
+        trace_step!(start: start_idx, pos: pos, byte: Some(input[pos]), state: state,
+                    accept: accept);
+        if let (Some(data), false) = (accept, config.anchored_end) {
+            // As in `Program::shortest_match_from`, the match might have ended before this byte.
+            if acc.is_none() || start_idx < acc.unwrap().0 {
+                *acc = Some((start_idx, match_ends(pos, data).0));
             }
         }
         if let Some(next_state) = next_state {
@@ -118,30 +294,80 @@ impl<Insts: Instructions> ThreadedEngine<Insts> {
         }
     }
 
-    fn shortest_match_from_searcher<'a>(&'a self, s: &[u8], skip: &mut PrefixSearcher)
-    -> Option<(usize, usize)> {
+    /// Advances the only thread for as long as it stays the only thread, and returns the new
+    /// position.
+    ///
+    /// This stops (without consuming the byte) at the first position where the thread accepts or
+    /// dies, or where a new thread would start in a different state.
+    fn run_single_thread(&self,
+            threads: &mut ProgThreads,
+            s: &[u8],
+            mut pos: usize,
+            end: usize,
+            config: &SearchConfig)
+    -> usize {
+        let mut state = threads.cur.threads[0].state;
+        let start_idx = threads.cur.threads[0].start_idx;
+        while pos < end {
+            let (next_state, accept) = self.prog.step_byte(state, s[pos]);
+            if accept.is_some() && !config.anchored_end {
+                break;
+            }
+            trace_step!(start: start_idx, pos: pos, byte: Some(s[pos]), state: state,
+                        accept: accept);
+            // We can keep going if the thread that starts at the next position merges with this one.
+            let merges = |next| self.prog.init.state_at_pos(s, pos + 1).is_none_or(|st| st == next);
+            match next_state {
+                Some(next) if merges(next) => {
+                    state = next;
+                    pos += 1;
+                },
+                _ => break,
+            }
+        }
+        threads.cur.threads.clear();
+        threads.cur.add(state, start_idx);
+        pos
+    }
+
+    fn shortest_match_from_searcher(&self,
+            threads: &mut ProgThreads,
+            s: &[u8],
+            start: usize,
+            end: usize,
+            config: &SearchConfig,
+            skip: &mut dyn PrefixSearcher)
+    -> Result<Option<(usize, usize)>, SearchError> {
         let mut acc: Option<(usize, usize)> = None;
+        // We always start at the beginning of the prefix, because we don't know
+        // whether we will need to add new threads while matching the prefix. If the candidates
+        // can come out of order, an earlier one might start before this one.
+        let out_of_order_len = self.prefix.out_of_order_len();
         let mut pos = match skip.search() {
-            // We always start at the beginning of the prefix, because we don't know
-            // whether we will need to add new threads while matching the prefix.
-            Some(x) => x.start_pos,
-            None => return None,
+            Some(res) => cmp::max(start, res.earliest_start(out_of_order_len)),
+            None => return Ok(None),
         };
-        let mut threads_guard = self.threads.borrow_mut();
-        let threads = threads_guard.deref_mut();
 
         threads.clear();
-        threads.cur.threads.push(Thread { state: 0, start_idx: pos });
-        while pos < s.len() {
+        if let Some(state) = self.prog.init.state_at_pos(s, pos) {
+            threads.cur.add(state, pos);
+        }
+        while pos < end {
+            if threads.cur.threads.len() == 1 {
+                pos = self.run_single_thread(threads, s, pos, end, config);
+                if pos == end {
+                    break;
+                }
+            }
             for i in 0..threads.cur.threads.len() {
-                self.advance_thread(threads, &mut acc, i, s, pos);
+                self.advance_thread(threads, &mut acc, i, s, pos, config);
             }
             threads.swap();
 
             // If one of our threads accepted and it started sooner than any of our active
             // threads, we can stop early.
-            if acc.is_some() && threads.cur.starts_after(acc.unwrap().0) {
-                return acc;
+            if acc.is_some() && (config.earliest || threads.cur.starts_after(acc.unwrap().0)) {
+                return Ok(acc);
             }
 
             // If we're out of threads, skip ahead to the next good position (but be sure to
@@ -150,39 +376,306 @@ impl<Insts: Instructions> ThreadedEngine<Insts> {
             if threads.cur.threads.is_empty() {
                 skip.skip_to(pos);
                 if let Some(search_result) = skip.search() {
-                    pos = search_result.start_pos;
-                    threads.cur.add(0, pos);
+                    pos = cmp::max(pos, search_result.earliest_start(out_of_order_len));
                 } else {
-                    return None
+                    return Ok(None)
                 }
-            } else {
-                threads.cur.add(0, pos);
+            }
+            if let Some(state) = self.prog.init.state_at_pos(s, pos) {
+                threads.cur.add(state, pos);
+            }
+            if threads.cur.threads.len() > self.max_threads {
+                return Err(SearchError::TooManyThreads(self.max_threads));
             }
         }
 
+        // A thread that accepts at the end of the input could start before the match that we
+        // already found, and we need the leftmost one.
         for th in &threads.cur.threads {
-            if let Some(bytes_ago) = self.prog.check_eoi(th.state) {
-                return Some((th.start_idx, s.len().saturating_sub(bytes_ago)));
+            let accept = self.prog.accept_at(s, end, th.state);
+            trace_step!(start: th.start_idx, pos: end, byte: None, state: th.state,
+                        accept: accept.map(|e| end - e.0));
+            if let Some((first, last)) = accept {
+                if config.anchored_end && last != end {
+                    continue;
+                }
+                let e = if config.anchored_end { last } else { first };
+                if acc.is_none_or(|(start, _)| th.start_idx < start) {
+                    acc = Some((th.start_idx, e));
+                }
             }
         }
-        None
+        Ok(acc)
     }
 
 }
 
-impl<I: Instructions + 'static> Engine for ThreadedEngine<I> {
-    fn shortest_match(&self, s: &str) -> Option<(usize, usize)> {
-        if self.prog.num_states() == 0 {
-            return None;
-        }
-
-        let s = s.as_bytes();
-        let mut searcher = self.prefix.make_searcher(s);
-        self.shortest_match_from_searcher(s, &mut *searcher)
+impl<I, P> Engine for ThreadedEngine<I, P>
+where I: Instructions + 'static, P: PrefixSearcherFactory + Clone + 'static {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        // Take the cache out while we use it, in case a custom prefix searches with this engine.
+        let mut cache = CACHE.with(|c| mem::take(&mut *c.borrow_mut()));
+        let ret = self.search_with(&mut cache, input, config);
+        CACHE.with(|c| *c.borrow_mut() = cache);
+        ret
     }
 
-    fn clone_box(&self) -> Box<Engine> {
+    fn clone_box(&self) -> Box<dyn Engine> {
         Box::new(self.clone())
     }
+
+    fn memory_usage(&self) -> usize {
+        self.prog.memory_usage() + self.prefix.memory_usage()
+    }
+
+    fn explain(&self, input: &[u8], m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        self.prog.explain(input, m)
+    }
+
+    fn matches_prefix(&self, input: &[u8]) -> Option<bool> {
+        Some(self.prog.matches_prefix(input))
+    }
+
+    fn write_engine(&self, mut w: &mut dyn Write) -> io::Result<()> {
+        write_u8(&mut w, 1)?;
+        write_insts_kind::<I, _>(&mut w)?;
+        write_any(&mut w, &self.prog)?;
+        write_any(&mut w, &self.prefix)?;
+        write_usize(&mut w, self.max_threads)
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use error::{ProgramError, SearchError};
+    use prefix::Prefix;
+    use program::{InitStates, Instructions, TableInsts};
+    use test_util::{lit_prog, lits_prog, table_prog, AfterPipe};
+    use std::sync::Arc;
+    use std::thread;
+    use threaded::{Cache, ThreadedEngine, Threads};
+    use {Engine, SearchConfig};
+
+    #[test]
+    fn test_custom_prefix() {
+        let eng = ThreadedEngine::new(lit_prog("ab"), Prefix::Empty);
+        assert_eq!(eng.shortest_match("ab|ab"), Some((0, 2)));
+
+        let eng = ThreadedEngine::new(lit_prog("ab"), AfterPipe);
+        assert_eq!(eng.shortest_match("ab|ab"), Some((3, 5)));
+        assert_eq!(eng.shortest_match("ab|cab"), None);
+        assert_eq!(eng.shortest_match("abab"), None);
+    }
+
+    #[test]
+    fn test_exact_prefix() {
+        let prog = lits_prog(&["abc", "de"]);
+        let eng = ThreadedEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match("xxabcdex"), Some((2, 5)));
+        assert_eq!(eng.shortest_match("xxdxab"), None);
+
+        // "bc" ends first, but "abcd" starts first.
+        let prog = lits_prog(&["abcd", "bc"]);
+        let eng = ThreadedEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match("abcd"), Some((0, 4)));
+        assert_eq!(eng.shortest_match("xabcx"), Some((2, 4)));
+
+        // The match comes straight from the prefix, which isn't an `Ac` here.
+        let edges = [(1, b'a', 2), (1, b'A', 2), (2, b'b', 3), (2, b'B', 3)];
+        let caseless = table_prog(4, &edges, &[3]);
+        let pref = Prefix::from_program(&caseless);
+        assert!(matches!(pref, Prefix::Exact(ref p) if matches!(**p, Prefix::LitCaseless(_))));
+        let eng = ThreadedEngine::new(caseless, pref);
+        assert_eq!(eng.shortest_match("xxaBx"), Some((2, 4)));
+    }
+
+    #[test]
+    fn test_after_byte() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::AfterByte(b'\n', 1);
+        let eng = ThreadedEngine::new(prog.clone(), Prefix::from_program(&prog));
+        assert_eq!(eng.shortest_match("ab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("xab\nab"), Some((4, 6)));
+        assert_eq!(eng.shortest_match("xab\nxab"), None);
+
+        // The program is still correct with a prefix that doesn't know about lines.
+        let eng = ThreadedEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.shortest_match("xab\nab"), Some((4, 6)));
+    }
+
+    #[test]
+    fn test_full_match() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::Anchored(1);
+        let table = prog.instructions.table.clone();
+        prog.instructions = TableInsts::new(table, vec![usize::MAX; 4]);
+        // The prefix would never find a match at position 0, but it isn't used.
+        let eng = ThreadedEngine::new(prog, AfterPipe);
+        assert_eq!(eng.shortest_match("ab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("abab"), None);
+        assert_eq!(eng.shortest_match("|ab"), None);
+    }
+
+    #[test]
+    fn test_cache() {
+        let small = ThreadedEngine::new(lit_prog("a"), Prefix::Empty);
+        let big = ThreadedEngine::new(lits_prog(&["abc", "abd", "xyz"]), Prefix::Empty);
+        let mut cache = Cache::default();
+        assert_eq!(small.shortest_match_with(&mut cache, b"xa"), Some((1, 2)));
+        let small_usage = cache.memory_usage();
+        // The cache grows to fit the bigger program.
+        assert_eq!(big.shortest_match_with(&mut cache, b"xxyz"), Some((1, 4)));
+        assert!(cache.memory_usage() > small_usage);
+        assert_eq!(small.shortest_match_with(&mut cache, b"xyz"), None);
+    }
+
+    #[test]
+    fn test_lazy_allocation() {
+        let prog = lits_prog(&["abc", "xyz"]);
+        let eng = ThreadedEngine::new(prog.clone(), Prefix::Empty);
+        // Building the engine doesn't allocate any scratch space, and neither does the cache.
+        assert_eq!(eng.memory_usage(), prog.memory_usage());
+        let mut cache = Cache::default();
+        assert_eq!(cache.memory_usage(), 0);
+
+        assert_eq!(eng.shortest_match_with(&mut cache, b"xxyz"), Some((1, 4)));
+        let usage = cache.memory_usage();
+        assert!(usage >= 2 * prog.num_states() * ::std::mem::size_of::<usize>());
+        // Later searches reuse the same space.
+        let inputs: [&[u8]; 3] = [b"xxyz", b"xyz", b""];
+        for input in &inputs {
+            eng.shortest_match_with(&mut cache, input);
+            assert_eq!(cache.memory_usage(), usage);
+        }
+    }
+
+    #[test]
+    fn test_concurrent() {
+        fn assert_sync<T: Sync>(_: &T) {}
+
+        let eng = Arc::new(ThreadedEngine::new(lits_prog(&["abc", "de"]), Prefix::Empty));
+        assert_sync(&*eng);
+        let handles: Vec<_> = (0..4).map(|i| {
+            let eng = eng.clone();
+            thread::spawn(move || {
+                let input = format!("{}abc", "x".repeat(i));
+                for _ in 0..100 {
+                    assert_eq!(eng.shortest_match(&input), Some((i, i + 3)));
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        let mut threads = Threads::with_capacity(4);
+        threads.add(2, 0);
+        threads.add(3, 1);
+        threads.add(2, 5);
+        assert_eq!(threads.threads.len(), 2);
+        assert_eq!(threads.threads[0].start_idx, 0);
+
+        // Stale entries in `sparse` don't count after clearing.
+        threads.threads.clear();
+        assert!(!threads.contains(2) && !threads.contains(3));
+        threads.add(3, 4);
+        assert!(threads.contains(3) && !threads.contains(2));
+    }
+
+    #[test]
+    fn test_leftmost() {
+        let eng = ThreadedEngine::new(lits_prog(&["abcde", "bc"]), Prefix::Empty);
+        assert_eq!(eng.shortest_match("abcde"), Some((0, 5)));
+        // The long literal never finishes, so the later match is the leftmost one.
+        assert_eq!(eng.shortest_match("abcd"), Some((1, 3)));
+        assert_eq!(eng.shortest_match("abcx"), Some((1, 3)));
+
+        // Two threads that meet in the same state keep the earlier start.
+        let mut threads = Threads::with_capacity(4);
+        threads.add(2, 5);
+        threads.add(2, 3);
+        assert_eq!(threads.threads[0].start_idx, 3);
+        assert!(!threads.starts_after(4));
+    }
+
+    #[test]
+    fn test_anchored() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::Anchored(1);
+        // The prefix never finds position 0, but anchored programs don't use it.
+        let eng = ThreadedEngine::new(prog, AfterPipe);
+        assert_eq!(eng.shortest_match("ab|ab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("x|ab"), None);
+        assert_eq!(eng.shortest_match("a"), None);
+    }
+
+    #[test]
+    fn test_search_config() {
+        // Matches "a" or "ab".
+        let prog = table_prog(4, &[(1, b'a', 2), (2, b'b', 3)], &[2, 3]);
+        let eng = ThreadedEngine::new(prog.clone(), Prefix::from_program(&prog));
+        let config = SearchConfig::default();
+        assert_eq!(eng.search(b"xab", &config), Some((1, 2)));
+
+        let anchored = SearchConfig { anchored_start: true, ..config };
+        assert_eq!(eng.search(b"xab", &anchored), None);
+        assert_eq!(eng.search(b"xab", &SearchConfig { start: 1, ..anchored }), Some((1, 2)));
+
+        let to_end = SearchConfig { anchored_end: true, ..config };
+        assert_eq!(eng.search(b"xab", &to_end), Some((1, 3)));
+        assert_eq!(eng.search(b"xabx", &to_end), None);
+        assert_eq!(eng.search(b"xabx", &SearchConfig { end: Some(3), ..to_end }), Some((1, 3)));
+        let both = SearchConfig { anchored_start: true, anchored_end: true, ..config };
+        assert_eq!(eng.search(b"xab", &SearchConfig { start: 1, ..both }), Some((1, 3)));
+        assert_eq!(eng.search(b"xaab", &SearchConfig { start: 1, ..both }), None);
+
+        let eng = ThreadedEngine::new(lits_prog(&["abcde", "bc"]), Prefix::Empty);
+        assert_eq!(eng.search(b"abcdex", &config), Some((0, 5)));
+        let earliest = SearchConfig { earliest: true, ..config };
+        assert_eq!(eng.search(b"abcdex", &earliest), Some((1, 3)));
+    }
+
+    #[test]
+    fn test_max_threads() {
+        let eng = ThreadedEngine::new(lits_prog(&["abcde", "bc"]), Prefix::Empty)
+            .with_max_threads(2);
+        let mut cache = eng.cache();
+        let config = SearchConfig::default();
+        assert_eq!(eng.try_search_with(&mut cache, b"abcd", &config),
+                   Err(SearchError::TooManyThreads(2)));
+        assert_eq!(eng.try_search_with(&mut cache, b"xbcx", &config), Ok(Some((1, 3))));
+        assert_eq!(eng.search_with(&mut cache, b"abcd", &config), Some((1, 3)));
+        assert_eq!(eng.shortest_match("abcde"), Some((0, 5)));
+        let to_end = SearchConfig { anchored_end: true, ..config };
+        assert_eq!(eng.search_with(&mut cache, b"abcbc", &to_end), Some((3, 5)));
+    }
+
+    #[test]
+    fn test_try_new() {
+        let mut prog = lits_prog(&["ab"]);
+        assert!(ThreadedEngine::try_new(prog.clone(), Prefix::Empty).is_ok());
+        prog.accept_at_eoi.pop();
+        assert_eq!(ThreadedEngine::try_new(prog, Prefix::Empty).unwrap_err(),
+                   ProgramError::WrongSize);
+    }
+
+    #[test]
+    fn test_single_thread() {
+        // Matches anything ending in "ab", so the start thread merges with the running one until
+        // we see an 'a'.
+        let mut edges: Vec<_> = (0..=255u8).map(|b| (1, b, 1)).chain((0..=255u8).map(|b| (2, b, 1)))
+            .collect();
+        edges.extend_from_slice(&[(1, b'a', 2), (2, b'a', 2), (2, b'b', 3)]);
+        let prog = table_prog(4, &edges, &[3]);
+        let eng = ThreadedEngine::new(prog, Prefix::Empty);
+        assert_eq!(eng.shortest_match("xxab"), Some((0, 4)));
+        assert_eq!(eng.shortest_match("xxabxx"), Some((0, 4)));
+        assert_eq!(eng.shortest_match("xxaxx"), None);
+        let to_end = SearchConfig { anchored_end: true, ..SearchConfig::default() };
+        assert_eq!(eng.search(b"abxab", &to_end), Some((1, 5)));
+    }
+}
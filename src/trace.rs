@@ -0,0 +1,97 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reporting every step of a search, for debugging.
+//!
+//! With the `trace` feature, the engines that run a `Program` (the backtracking, threaded and
+//! two-pass engines) report each step that they take to the tracer of the current thread, if
+//! there is one. Without it, tracers can't be set and nothing is reported.
+
+#[cfg(feature = "trace")]
+use std::cell::RefCell;
+
+/// One step of running a program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Step {
+    /// The position at which this run of the program started. For a reversed program, this is
+    /// where the match ends.
+    pub start: usize,
+    /// The current position in the input.
+    pub pos: usize,
+    /// The byte that was read at `pos`, or `None` if this is the end of the part of the input that
+    /// is being searched.
+    pub byte: Option<u8>,
+    /// The state of the program before reading `byte`.
+    pub state: usize,
+    /// The accept data of `state`, if it accepts here.
+    pub accept: Option<usize>,
+}
+
+/// A function that gets called on every step.
+#[cfg(feature = "trace")]
+pub type Tracer = Box<dyn FnMut(&Step)>;
+
+#[cfg(feature = "trace")]
+thread_local! {
+    static TRACER: RefCell<Option<Tracer>> = RefCell::new(None);
+}
+
+/// Sets the function that is called on every step of the searches on this thread, or removes
+/// it (if `tracer` is `None`). Returns the previous one.
+#[cfg(feature = "trace")]
+pub fn set_tracer(tracer: Option<Tracer>) -> Option<Tracer> {
+    TRACER.with(|t| ::std::mem::replace(&mut *t.borrow_mut(), tracer))
+}
+
+/// Passes `step` to the tracer, if there is one.
+#[cfg(feature = "trace")]
+pub fn report(step: &Step) {
+    TRACER.with(|t| {
+        if let Some(ref mut f) = *t.borrow_mut() {
+            f(step);
+        }
+    });
+}
+
+#[cfg(all(test, feature = "trace"))]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use prefix::Prefix;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use test_util::lit_prog;
+    use threaded::ThreadedEngine;
+    use trace::{set_tracer, Step};
+    use Engine;
+
+    fn trace<E: Engine>(eng: &E, input: &str) -> Vec<Step> {
+        let steps = Rc::new(RefCell::new(Vec::new()));
+        let steps_clone = steps.clone();
+        set_tracer(Some(Box::new(move |s: &Step| steps_clone.borrow_mut().push(*s))));
+        eng.shortest_match(input);
+        set_tracer(None);
+        let ret = steps.borrow().clone();
+        ret
+    }
+
+    #[test]
+    fn test_trace() {
+        let bt = BacktrackingEngine::new(lit_prog("ab"), Prefix::Empty);
+        let steps = trace(&bt, "aab");
+        // The run starting at 0 dies after "a".
+        let dies = Step { start: 0, pos: 1, byte: Some(b'a'), state: 2, accept: None };
+        assert!(steps.contains(&dies));
+        // The run starting at 1 accepts after "ab".
+        let accepts = Step { start: 1, pos: 3, byte: None, state: 3, accept: Some(0) };
+        assert_eq!(steps.last(), Some(&accepts));
+
+        let th = ThreadedEngine::new(lit_prog("ab"), Prefix::Empty);
+        let steps = trace(&th, "aab");
+        assert!(steps.iter().any(|s| s.start == 1 && s.pos == 2 && s.byte == Some(b'b')));
+    }
+}
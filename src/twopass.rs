@@ -0,0 +1,345 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An engine that finds matches with one forward pass and one backward pass.
+//!
+//! The forward pass runs a DFA that tracks all the possible match starts at once (by adding the
+//! start state after every byte), so it finds the end of the leftmost match in a single scan,
+//! without backtracking or threads. Then the reversed program runs backwards from that end to find
+//! where the match starts.
+
+use {Engine, SearchConfig};
+use prefix::{Prefix, PrefixSearcherFactory};
+use program::{match_ends, Instructions, Program, TableInsts, TableStateIdx};
+use serialize::{write_any, write_insts_kind, write_u8};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// The maximum number of states that we will create for the forward pass.
+const MAX_STATES: usize = 10_000;
+
+/// An engine that runs a forward pass to find where a match ends, and then a backward pass to
+/// find where it starts.
+///
+/// The forward pass doesn't stop when the first match ends, but keeps going until every match that
+/// started earlier has either died or ended. So this engine finds the same matches as the other
+/// engines: if the program matches "abcde" and "bc" then in the input "abcde" it finds "abcde",
+/// even though "bc" ends first.
+#[derive(Clone, Debug)]
+pub struct TwoPassEngine<Insts: Instructions, P: PrefixSearcherFactory = Prefix> {
+    prog: Program<Insts>,
+    rev: Program<Insts>,
+    // The unanchored version of `prog`, or `None` if `prog` is anchored.
+    fwd: Option<Program<TableInsts>>,
+    // The state of `fwd` in which no match has started yet.
+    empty: usize,
+    prefix: P,
+}
+
+impl<Insts: Instructions, P: PrefixSearcherFactory> TwoPassEngine<Insts, P> {
+    /// Creates a new engine. `rev` must be the reversal of `prog`, as in
+    /// `BacktrackingEngine::with_reverse_program`.
+    ///
+    /// Returns `None` if the DFA for the forward pass would be too big.
+    pub fn new(prog: Program<Insts>, rev: Program<Insts>, pref: P)
+    -> Option<TwoPassEngine<Insts, P>> {
+        let (fwd, empty) = if prog.init.anchored().is_some() {
+            (None, 0)
+        } else {
+            let (fwd, empty) = unanchored(&prog)?;
+            (Some(fwd), empty)
+        };
+        Some(TwoPassEngine {
+            prog,
+            rev,
+            fwd,
+            empty,
+            prefix: pref,
+        })
+    }
+
+    pub fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        self.leftmost(input, config).map(|m| self.prog.extend_match(input, config, m))
+    }
+
+    // Finds the leftmost match (and the shortest one among those that start there).
+    fn leftmost(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        let (start, end) = config.bounds(input)?;
+        let fwd = match self.fwd {
+            Some(ref fwd) if !config.anchored_start => fwd,
+            _ => {
+                // The match has to start at `start`, so there's no need for the backward pass.
+                let state = self.prog.init.anchored()
+                    .or_else(|| self.prog.init.state_at_pos(input, start))?;
+                return if !config.anchored_end {
+                    self.prog.shortest_match_from(input, start, end, state).map(|e| (start, e))
+                } else if self.prog.matches_to(input, start, end, state) {
+                    Some((start, end))
+                } else {
+                    None
+                };
+            },
+        };
+
+        if config.anchored_end {
+            // We already know where the match ends.
+            return self.rev.leftmost_start(input, start, end).map(|s| (s, end));
+        }
+
+        let out_of_order_len = self.prefix.out_of_order_len();
+        self.prefix.with_searcher(&input[..end], &mut |searcher| {
+            // The forward pass tries every start from here on, so it's enough to begin at the
+            // first place where the prefix might have matched.
+            let res = searcher.search_from(start)?;
+            let pos = max(start, res.earliest_start(out_of_order_len));
+            let state = fwd.init.state_at_pos(input, pos).unwrap_or(self.empty);
+            let e = last_accept(fwd, input, pos, end, state)?;
+            // Nothing that starts before the leftmost match can end at `e`, so this finds its
+            // start.
+            self.rev.leftmost_start(input, start, e).map(|s| (s, e))
+        })
+    }
+}
+
+// Runs the forward DFA from `state` at position `pos` of `input` until it dies or reaches `end`,
+// and returns where it last accepted. Unlike `Program::last_match_from`, this doesn't take the
+// largest end: a later match comes from an earlier start, so it wins even if it ends earlier.
+fn last_accept(fwd: &Program<TableInsts>, input: &[u8], pos: usize, end: usize, mut state: usize)
+-> Option<usize> {
+    let mut ret = None;
+    for (p, &b) in input[pos..end].iter().enumerate() {
+        let (next_state, accept) = fwd.step_byte(state, b);
+        trace_step!(start: pos, pos: pos + p, byte: Some(b), state: state, accept: accept);
+        if let Some(data) = accept {
+            ret = Some(match_ends(pos + p, data).0);
+        }
+        match next_state {
+            Some(s) => state = s,
+            None => return ret,
+        }
+    }
+    let accept = fwd.accept_at(input, end, state);
+    trace_step!(start: pos, pos: end, byte: None, state: state,
+                accept: accept.map(|e| end - e.0));
+    accept.map(|e| e.0).or(ret)
+}
+
+/// Builds a DFA that runs `prog` from every position where a match could start, and accepts
+/// wherever the leftmost match might end.
+///
+/// A state of the DFA is a list of states of `prog`, one for each position where a match started
+/// (and hasn't died yet), ordered by that position. When two of them are in the same state, only
+/// the earlier one is kept, because the later one can only find the same matches. The DFA adds the
+/// start state of `prog` at every position until some match ends. After that, it only keeps the
+/// matches that started before the one that ended, and it accepts whenever one of those ends. So
+/// the last position where it accepts (before it dies) is the end of the leftmost match.
+///
+/// Also returns the state of the DFA containing no states of `prog`, which is where we are at
+/// positions that can't start a match.
+fn unanchored<I: Instructions>(prog: &Program<I>) -> Option<(Program<TableInsts>, usize)> {
+    if prog.init.anchored().is_some() {
+        return None;
+    }
+
+    // A state of the DFA is a list of states of `prog`, and whether we have stopped adding the
+    // start state because some match ended. State 0 is the dead state.
+    let mut lists: Vec<(Vec<usize>, bool)> = vec![(vec![], true)];
+    let mut ids: HashMap<(Vec<usize>, bool), usize> = HashMap::new();
+    ids.insert((vec![], true), 0);
+    let mut table: Vec<TableStateIdx> = Vec::new();
+    let mut add = |list: (Vec<usize>, bool), lists: &mut Vec<(Vec<usize>, bool)>| -> usize {
+        *ids.entry(list.clone()).or_insert_with(|| {
+            lists.push(list);
+            lists.len() - 1
+        })
+    };
+    let starts: Vec<(usize, usize)> = prog.init.states().into_iter()
+        .map(|s| (s, add((vec![s], false), &mut lists)))
+        .collect();
+    let init = prog.init.map(|s| starts.iter().find(|x| x.0 == s).unwrap().1);
+    let empty = add((vec![], false), &mut lists);
+
+    let mut seen = vec![false; prog.num_states()];
+    let mut cur = 1;
+    while cur < lists.len() {
+        if lists.len() > MAX_STATES {
+            return None;
+        }
+        // If some match ends here, the ones that started after it don't matter any more.
+        let (ref list, stopped) = lists[cur];
+        let (live, stopped) = match list.iter().position(|&s| prog.accept(s).is_some()) {
+            Some(i) => (list[..i].to_vec(), true),
+            None => (list.clone(), stopped),
+        };
+        for b in 0..=255u8 {
+            let mut next: Vec<usize> = live.iter()
+                .filter_map(|&s| prog.step_byte(s, b).0)
+                .collect();
+            if !stopped {
+                // The state that a match starting after `b` would start in.
+                next.extend(prog.init.state_at_pos(&[b], 1));
+            }
+            next.retain(|&s| !::std::mem::replace(&mut seen[s], true));
+            for &s in &next {
+                seen[s] = false;
+            }
+            let id = add((next, stopped), &mut lists);
+            table.push(id as TableStateIdx);
+        }
+        cur += 1;
+    }
+
+    // The dead state's transitions.
+    let mut full_table = vec![0; 256];
+    full_table.extend_from_slice(&table);
+
+    // A state accepts if any of its states do, and the earliest match is the one that counts.
+    let acc = |f: &dyn Fn(usize) -> Option<usize>| -> Vec<usize> {
+        lists.iter()
+            .map(|list| list.0.iter().filter_map(|&s| f(s)).next().unwrap_or(usize::MAX))
+            .collect()
+    };
+    let accept = acc(&|s| prog.accept(s));
+    let accept_at_eoi = acc(&|s| prog.check_eoi(s));
+
+    Some((Program {
+        accept_at_eoi,
+        instructions: TableInsts::new(full_table, accept),
+        init,
+    }, empty))
+}
+
+impl<I, P> Engine for TwoPassEngine<I, P>
+where I: Instructions + 'static, P: PrefixSearcherFactory + Clone + 'static {
+    fn search(&self, input: &[u8], config: &SearchConfig) -> Option<(usize, usize)> {
+        TwoPassEngine::search(self, input, config)
+    }
+
+    fn clone_box(&self) -> Box<dyn Engine> {
+        Box::new(self.clone())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.prog.memory_usage() + self.rev.memory_usage()
+            + self.fwd.as_ref().map_or(0, |f| f.memory_usage()) + self.prefix.memory_usage()
+    }
+
+    fn explain(&self, input: &[u8], m: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        self.prog.explain(input, m)
+    }
+
+    fn matches_prefix(&self, input: &[u8]) -> Option<bool> {
+        Some(self.prog.matches_prefix(input))
+    }
+
+    fn write_engine(&self, mut w: &mut dyn Write) -> io::Result<()> {
+        // The forward DFA gets rebuilt when loading.
+        write_u8(&mut w, 2)?;
+        write_insts_kind::<I, _>(&mut w)?;
+        write_any(&mut w, &self.prog)?;
+        write_any(&mut w, &self.prefix)?;
+        write_any(&mut w, &self.rev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use prefix::Prefix;
+    use program::InitStates;
+    use test_util::{lit_prog, lits_prog};
+    use twopass::TwoPassEngine;
+    use {Engine, SearchConfig};
+
+    #[test]
+    fn test_search() {
+        let prog = lits_prog(&["abc", "de"]);
+        let rev = lits_prog(&["cba", "ed"]);
+        let eng = TwoPassEngine::new(prog.clone(), rev.clone(), Prefix::Empty).unwrap();
+        assert_eq!(eng.shortest_match("xxabcxde"), Some((2, 5)));
+        assert_eq!(eng.shortest_match("xxabxdxe"), None);
+        assert_eq!(eng.shortest_match("ababcde"), Some((2, 5)));
+        assert_eq!(eng.shortest_match(""), None);
+
+        let eng = TwoPassEngine::new(prog.clone(), rev, Prefix::from_program(&prog)).unwrap();
+        assert_eq!(eng.shortest_match("xxxxxde"), Some((5, 7)));
+
+        // The leftmost match wins, even though another one ends first.
+        let prog = lits_prog(&["abcde", "bc"]);
+        let rev = lits_prog(&["edcba", "cb"]);
+        let eng = TwoPassEngine::new(prog, rev, Prefix::Empty).unwrap();
+        assert_eq!(eng.shortest_match("abcde"), Some((0, 5)));
+        assert_eq!(eng.shortest_match("abcdx"), Some((1, 3)));
+        assert_eq!(eng.shortest_match("xabcdebc"), Some((1, 6)));
+    }
+
+    #[test]
+    fn test_same_as_backtracking() {
+        let prog = lits_prog(&["abcd", "bc", "cx"]);
+        let rev = lits_prog(&["dcba", "cb", "xc"]);
+        let expected = BacktrackingEngine::new(prog.clone(), Prefix::Empty);
+        let engines = [
+            TwoPassEngine::new(prog.clone(), rev.clone(), Prefix::Empty).unwrap(),
+            TwoPassEngine::new(prog.clone(), rev, Prefix::from_program(&prog)).unwrap(),
+        ];
+
+        // Every string of length at most 6 over these bytes.
+        let mut inputs = vec![Vec::new()];
+        let mut last = 0;
+        for _ in 0..6 {
+            let longer: Vec<Vec<u8>> = inputs[last..].iter()
+                .flat_map(|i| b"abcdx".iter().map(move |&b| {
+                    let mut i = i.clone();
+                    i.push(b);
+                    i
+                }))
+                .collect();
+            last = inputs.len();
+            inputs.extend(longer);
+        }
+
+        for input in &inputs {
+            for start in 0..=input.len() {
+                for &leftmost_first in &[false, true] {
+                    let config = SearchConfig { start, leftmost_first, ..SearchConfig::default() };
+                    for eng in &engines {
+                        assert_eq!(eng.search(input, &config), expected.search(input, &config),
+                                   "{:?} from {}", input, start);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_config() {
+        let eng = TwoPassEngine::new(lits_prog(&["abc", "de"]), lits_prog(&["cba", "ed"]),
+                                     Prefix::Empty).unwrap();
+        let config = SearchConfig::default();
+        assert_eq!(eng.search(b"dexabc", &SearchConfig { start: 1, ..config }), Some((3, 6)));
+        assert_eq!(eng.search(b"dexabc", &SearchConfig { end: Some(5), ..config }), Some((0, 2)));
+
+        let anchored = SearchConfig { anchored_start: true, ..config };
+        assert_eq!(eng.search(b"xabc", &anchored), None);
+        assert_eq!(eng.search(b"xabc", &SearchConfig { start: 1, ..anchored }), Some((1, 4)));
+
+        let to_end = SearchConfig { anchored_end: true, ..config };
+        assert_eq!(eng.search(b"abcde", &to_end), Some((3, 5)));
+        assert_eq!(eng.search(b"abcdex", &to_end), None);
+    }
+
+    #[test]
+    fn test_anchored() {
+        let mut prog = lit_prog("ab");
+        prog.init = InitStates::Anchored(1);
+        let eng = TwoPassEngine::new(prog, lit_prog("ba"), Prefix::Empty).unwrap();
+        assert_eq!(eng.shortest_match("abab"), Some((0, 2)));
+        assert_eq!(eng.shortest_match("xab"), None);
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2015 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Searching a sliding window over a stream, like the most recent bytes kept in a ring buffer.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use {Engine, SearchConfig};
+use std::ops::ControlFlow;
+
+/// Searches a window that slides along a stream, reporting matches by their positions in the
+/// whole stream.
+///
+/// The window is given as two slices, so that it can be the two halves of a ring buffer. Only the
+/// window is searched, so matches must fit inside it, and the end of the window counts as the end
+/// of the input. Each match is only reported once, even if it is still in the window the next time
+/// that it is searched.
+#[derive(Clone, Debug, Default)]
+pub struct WindowSearcher {
+    // Where the window is copied when it is in two pieces.
+    buf: Vec<u8>,
+    // The position in the stream where the next match can start.
+    next: usize,
+    config: SearchConfig,
+}
+
+impl WindowSearcher {
+    pub fn new() -> WindowSearcher {
+        WindowSearcher::default()
+    }
+
+    /// Searches with these options. The `start` and `end` fields are ignored.
+    pub fn with_config(mut self, config: &SearchConfig) -> WindowSearcher {
+        self.config = SearchConfig { start: 0, end: None, ..*config };
+        self
+    }
+
+    /// Calls `f` on each new match in the window made of `head` followed by `tail`, which starts
+    /// at position `window_start` in the stream, until `f` returns `ControlFlow::Break`.
+    ///
+    /// The positions passed to `f` are positions in the stream. Matches that start before the end
+    /// of the last match that was reported aren't reported again (but the bytes before them are
+    /// still used for deciding where a match can start).
+    pub fn for_each_match<E: Engine + ?Sized>(&mut self, engine: &E, window_start: usize,
+                                              head: &[u8], tail: &[u8],
+                                              f: &mut dyn FnMut((usize, usize)) -> ControlFlow<()>)
+    -> ControlFlow<()> {
+        // Don't copy the window unless it is in two pieces.
+        let input = if tail.is_empty() {
+            head
+        } else if head.is_empty() {
+            tail
+        } else {
+            self.buf.clear();
+            self.buf.extend_from_slice(head);
+            self.buf.extend_from_slice(tail);
+            &self.buf[..]
+        };
+
+        let start = self.next.saturating_sub(window_start);
+        if start > input.len() {
+            return ControlFlow::Continue(());
+        }
+        let next = &mut self.next;
+        let config = SearchConfig { start, ..self.config };
+        engine.for_each_match(input, &config, &mut |(s, e)| {
+            // An empty match is reported once, and then we look for matches after it.
+            *next = window_start + if s < e { e } else { e + 1 };
+            f((window_start + s, window_start + e))
+        })
+    }
+
+    /// Like `for_each_match`, but returns the new matches.
+    pub fn find_matches<E: Engine + ?Sized>(&mut self, engine: &E, window_start: usize,
+                                            head: &[u8], tail: &[u8]) -> Vec<(usize, usize)> {
+        let mut ret = Vec::new();
+        let _ = self.for_each_match(engine, window_start, head, tail, &mut |m| {
+            ret.push(m);
+            ControlFlow::Continue(())
+        });
+        ret
+    }
+
+    /// Forgets about the matches that were reported, for searching a new stream.
+    pub fn reset(&mut self) {
+        self.next = 0;
+    }
+
+    /// The position in the stream where the next reported match can start.
+    pub fn next_pos(&self) -> usize {
+        self.next
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use backtracking::BacktrackingEngine;
+    use prefix::Prefix;
+    use test_util::lits_prog;
+    use window::WindowSearcher;
+
+    #[test]
+    fn test_ring() {
+        let eng = BacktrackingEngine::new(lits_prog(&["ab", "cd"]), Prefix::Empty);
+        let mut searcher = WindowSearcher::new();
+
+        // The stream is "xabcdxab...", and the window has 4 bytes.
+        assert_eq!(searcher.find_matches(&eng, 0, b"xabc", b""), vec![(1, 3)]);
+        // The ring buffer has wrapped around: "d" overwrote "x".
+        assert_eq!(searcher.find_matches(&eng, 1, b"abc", b"d"), vec![(3, 5)]);
+        assert_eq!(searcher.next_pos(), 5);
+        // Nothing new in the window "cdxa".
+        assert_eq!(searcher.find_matches(&eng, 3, b"c", b"dxa"), vec![]);
+        assert_eq!(searcher.find_matches(&eng, 4, b"", b"dxab"), vec![(6, 8)]);
+
+        // A match that is split between two windows is found in the second one.
+        searcher.reset();
+        assert_eq!(searcher.find_matches(&eng, 0, b"xxxa", b""), vec![]);
+        assert_eq!(searcher.find_matches(&eng, 2, b"x", b"ab"), vec![(3, 5)]);
+        // The window skipped ahead, past all the matches that were reported.
+        assert_eq!(searcher.find_matches(&eng, 100, b"cd", b""), vec![(100, 102)]);
+    }
+}